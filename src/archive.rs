@@ -0,0 +1,118 @@
+use std::io::Write;
+
+use chess_repertoire_optimizer::error::Error;
+use chess_repertoire_optimizer::position::MoveSequence;
+
+/// A prepared line removed from the repertoire, with why and how it stood at the time
+#[derive(Debug, Clone)]
+pub struct ArchivedLine {
+    pub line: MoveSequence,
+    pub player: String,
+    pub reason: String,
+    /// Share of games reaching the position the line was removed at
+    pub frequency: f64,
+    /// Number of my moves dropped at that position
+    pub prepared_moves: usize,
+}
+
+/// Formats seconds since the Unix epoch as a PGN date, e.g. "2020.03.01"
+pub fn pgn_date(seconds: u64) -> String {
+    // Days to a proleptic Gregorian date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (seconds / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}.{:02}.{:02}", year, month, day)
+}
+
+/// Writes the lines as PGN games, so they can be restored by importing the file again
+pub fn write_archive(lines: &[ArchivedLine], date: &str, out: &mut dyn Write) -> Result<(), Error> {
+    for archived in lines {
+        writeln!(out, "[Event \"Archived repertoire line\"]")?;
+        writeln!(out, "[Date \"{}\"]", date)?;
+        writeln!(out, "[Player \"{}\"]", archived.player)?;
+        writeln!(out, "[Reason \"{}\"]", archived.reason.replace('"', "'"))?;
+        writeln!(out, "[Frequency \"{:.6}%\"]", 100.0 * archived.frequency)?;
+        writeln!(out, "[PreparedMoves \"{}\"]", archived.prepared_moves)?;
+        writeln!(out, "[Result \"*\"]")?;
+        writeln!(out)?;
+        writeln!(out, "{} *", archived.line.san())?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::*;
+    use chess_repertoire_optimizer::position::Fen;
+    use chess_repertoire_optimizer::repertoire_optimizer::RepertoireOptimizer;
+    use pleco::Player;
+
+    // The only line of the White repertoire of `pgn`
+    fn imported_line(pgn: &str) -> MoveSequence {
+        RepertoireOptimizer::builder(Player::White)
+            .pgn(pgn)
+            .build()
+            .unwrap()
+            .lines_through(&Fen::starting_board())
+            .remove(0)
+    }
+
+    #[test]
+    fn it_formats_pgn_dates() {
+        assert_eq!(pgn_date(0), "1970.01.01");
+        assert_eq!(pgn_date(951_782_400), "2000.02.29");
+        assert_eq!(pgn_date(1_609_459_199), "2020.12.31");
+    }
+
+    #[test]
+    fn it_writes_lines_with_their_metadata() {
+        let line = imported_line("1. e4 e5 2. Nf3 *\n\n");
+        let lines = vec![ArchivedLine {
+            line,
+            player: "White".to_owned(),
+            reason: "Rarely reached".to_owned(),
+            frequency: 0.0005,
+            prepared_moves: 2,
+        }];
+        let mut out = Vec::new();
+        write_archive(&lines, "2020.03.01", &mut out).unwrap();
+        let pgn = String::from_utf8(out).unwrap();
+
+        assert!(pgn.contains("[Date \"2020.03.01\"]\n"));
+        assert!(pgn.contains("[Reason \"Rarely reached\"]\n"));
+        assert!(pgn.contains("[Frequency \"0.050000%\"]\n"));
+        assert!(pgn.contains("[PreparedMoves \"2\"]\n"));
+        assert!(pgn.ends_with("\n1.e4 e5 2.Nf3 *\n\n"));
+        assert_eq!(chess_pgn_parser::read_games(&pgn).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_writes_lines_that_import_again() {
+        let moves = "1.e4 d5 2.exd5 c6 3.dxc6 Nf6 4.cxb7 e5 5.bxa8=Q Bd6 6.Nf3 O-O 7.Be2 Qc7 8.O-O";
+        let lines = vec![ArchivedLine {
+            line: imported_line(&format!("{} *\n\n", moves)),
+            player: "White".to_owned(),
+            reason: "Rarely reached".to_owned(),
+            frequency: 0.0005,
+            prepared_moves: 1,
+        }];
+        let mut out = Vec::new();
+        write_archive(&lines, "2020.03.01", &mut out).unwrap();
+        let pgn = String::from_utf8(out).unwrap();
+
+        assert!(pgn.ends_with(&format!("\n{} *\n\n", moves)));
+        assert_eq!(imported_line(&pgn).san(), moves);
+    }
+}
@@ -0,0 +1,112 @@
+//! Generated repertoires and opening books of any size, for measuring performance on
+//! realistic workloads without network access
+
+use pleco::core::piece_move::BitMove;
+use pleco::{Board, Player};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaChaRng;
+
+use crate::conversion::san;
+use crate::error::Error;
+use crate::opening_book::{BookMove, BookMoves, OpeningBook};
+use crate::position::Fen;
+
+// The opponent's moves in generated lines are chosen among this many legal moves, so lines
+// share their first moves like in a real repertoire
+static OPPONENT_CHOICES: usize = 3;
+
+/// Generates a PGN text with `lines` games of up to `plies` half-moves, as a repertoire for
+/// `me` with a single prepared move in each of my positions; The same seed gives the same games
+pub fn repertoire_pgn(me: Player, lines: usize, plies: usize, seed: u64) -> String {
+    let mut rng = ChaChaRng::seed_from_u64(seed);
+    let mut pgn = String::new();
+    for _ in 0..lines {
+        let mut board = Board::start_pos();
+        for ply in 0..plies {
+            let moves: Vec<BitMove> = board.generate_moves().into_iter().collect();
+            if moves.is_empty() {
+                break;
+            }
+            // My move only depends on the position, the opponent's differs between games
+            let index = if board.turn() == me {
+                let key = Fen::new(&board.fen()).key();
+                ChaChaRng::seed_from_u64(seed ^ key).gen_range(0, moves.len())
+            } else {
+                rng.gen_range(0, moves.len().min(OPPONENT_CHOICES))
+            };
+            if ply % 2 == 0 {
+                pgn.push_str(&format!("{}. ", ply / 2 + 1));
+            }
+            pgn.push_str(&san(&board, moves[index]));
+            pgn.push(' ');
+            board.apply_move(moves[index]);
+        }
+        pgn.push_str("*\n\n");
+    }
+    pgn
+}
+
+/// Knows up to `width` legal moves in every position, with popularity, score and year
+/// derived from the position, so lookups are reproducible and instant
+pub struct RandomBook {
+    width: usize,
+    seed: u64,
+}
+
+impl RandomBook {
+    pub fn new(width: usize, seed: u64) -> Self {
+        RandomBook { width, seed }
+    }
+}
+
+impl OpeningBook for RandomBook {
+    fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error> {
+        let board = Board::from_fen(fen.fen_str())
+            .map_err(|_| Error::Options(format!("Invalid position '{}'", fen.fen_str())))?;
+        let mut rng = ChaChaRng::seed_from_u64(self.seed ^ fen.key());
+        let moves: Vec<BitMove> = board.generate_moves().into_iter().collect();
+        let count = moves.len().min(self.width);
+        // Popularity falls off with the rank of the move, like in real games
+        let total: f64 = (1..=count).map(|rank| 1.0 / rank as f64).sum();
+        Ok(moves
+            .into_iter()
+            .take(count)
+            .enumerate()
+            .map(|(rank, bmv)| BookMove {
+                uci: bmv.stringify(),
+                frequency: 1.0 / (rank + 1) as f64 / total,
+                score: rng.gen_range(0.3, 0.7),
+                year: Some(rng.gen_range(2010, 2025)),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::benchmark::*;
+    use crate::repertoire_optimizer::RepertoireOptimizer;
+
+    #[test]
+    fn it_generates_importable_reproducible_repertoires() {
+        let pgn = repertoire_pgn(Player::White, 20, 16, 7);
+        assert_eq!(pgn, repertoire_pgn(Player::White, 20, 16, 7));
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        assert!(optimizer.add_pgn(&pgn).unwrap() > 1);
+        // A single prepared move in each of my positions
+        assert!(optimizer
+            .own_positions()
+            .iter()
+            .all(|pos| pos.transition_count() <= 1));
+    }
+
+    #[test]
+    fn it_knows_the_same_moves_on_every_lookup() {
+        let mut book = RandomBook::new(4, 7);
+        let book_moves = book.moves(&Fen::starting_board()).unwrap();
+        assert_eq!(book_moves.len(), 4);
+        assert_eq!(book_moves, book.moves(&Fen::starting_board()).unwrap());
+        let total: f64 = book_moves.iter().map(|book_move| book_move.frequency).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+    }
+}
@@ -1,7 +1,7 @@
 use chess_pgn_parser as cpg;
 use chess_pgn_parser::{Move, Square};
 use pleco::core::piece_move::BitMove;
-use pleco::core::{sq::SQ, File, Piece, Rank};
+use pleco::core::{sq::SQ, File, Piece, PieceType, Rank};
 use pleco::Board;
 
 pub fn move_matches_bitmove(mv: &Move, bmv: BitMove, board: &Board) -> bool {
@@ -210,3 +210,52 @@ fn equal_promotion(po: Option<cpg::Piece>, bmv: BitMove) -> bool {
         !bmv.is_promo()
     }
 }
+
+/// The move in algebraic notation, naming the whole departure square if another piece of the
+/// same kind could move to the same square
+pub fn san(board: &Board, bmv: BitMove) -> String {
+    if bmv.is_king_castle() {
+        return "O-O".to_owned();
+    }
+    if bmv.is_queen_castle() {
+        return "O-O-O".to_owned();
+    }
+    let uci = bmv.stringify();
+    let piece = board.piece_at_sq(bmv.get_src()).type_of();
+    let mut san = String::new();
+    if piece == PieceType::P {
+        if bmv.is_capture() {
+            san.push_str(&uci[..1]);
+        }
+    } else {
+        san.push_str(piece_letter(piece));
+        let ambiguous = board.generate_moves().into_iter().any(|other| {
+            other.get_dest() == bmv.get_dest()
+                && other.get_src() != bmv.get_src()
+                && board.piece_at_sq(other.get_src()).type_of() == piece
+        });
+        if ambiguous {
+            san.push_str(&uci[..2]);
+        }
+    }
+    if bmv.is_capture() {
+        san.push('x');
+    }
+    san.push_str(&uci[2..4]);
+    if bmv.is_promo() {
+        san.push('=');
+        san.push_str(piece_letter(bmv.promo_piece()));
+    }
+    san
+}
+
+fn piece_letter(piece: PieceType) -> &'static str {
+    match piece {
+        PieceType::N => "N",
+        PieceType::B => "B",
+        PieceType::R => "R",
+        PieceType::Q => "Q",
+        PieceType::K => "K",
+        _ => "",
+    }
+}
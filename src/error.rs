@@ -1,13 +1,27 @@
 #[derive(Debug)]
 pub enum Error {
     Bincode(bincode::Error),
+    Json(serde_json::Error),
     StdIo(std::io::Error),
     PgnParser,
+    CorruptCache(String),
+    Options(String),
     FileNotFound,
     IllegalMove { fen_str: String, mv: String },
     AmbiguousMove { fen_str: String, mv: String },
+    InvalidFen(String),
+    UnknownColor,
     Http,
+    RateLimited,
+    Interrupted,
+    Engine,
+    Script(String),
+    #[cfg(feature = "network")]
     Reqwest(reqwest::Error),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+    #[cfg(feature = "native")]
+    Zip(zip::result::ZipError),
     Log(log::SetLoggerError),
 }
 
@@ -17,18 +31,42 @@ impl std::fmt::Display for Error {
             Error::Bincode(e) => {
                 fmt.write_str(&format!("An error occured during serialization: {}", e))?;
             }
+            Error::Json(e) => {
+                fmt.write_str(&format!(
+                    "An error occured while reading or writing JSON: {}",
+                    e
+                ))?;
+            }
             Error::StdIo(e) => {
                 fmt.write_str(&format!(
                     "An error occured while operating on a file: {}",
                     e
                 ))?;
             }
+            #[cfg(feature = "network")]
             Error::Reqwest(e) => {
                 fmt.write_str(&format!("An error occured during network request: {}", e))?;
             }
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(e) => {
+                fmt.write_str(&format!("An error occured in the cache database: {}", e))?;
+            }
+            #[cfg(feature = "native")]
+            Error::Zip(e) => {
+                fmt.write_str(&format!(
+                    "An error occured while reading a ZIP archive: {}",
+                    e
+                ))?;
+            }
             Error::PgnParser => {
                 fmt.write_str("Reading PGN file failed; Format might be incorrect")?;
             }
+            Error::CorruptCache(e) => {
+                fmt.write_str(&format!("The cache file is damaged: {}", e))?;
+            }
+            Error::Options(e) => {
+                fmt.write_str(&format!("Invalid options: {}", e))?;
+            }
             Error::FileNotFound => {
                 fmt.write_str("File does not exist")?;
             }
@@ -44,9 +82,30 @@ impl std::fmt::Display for Error {
                     mv, fen_str
                 ))?;
             }
+            Error::InvalidFen(fen_str) => {
+                fmt.write_str(&format!("Position '{}' is no valid FEN", fen_str))?;
+            }
+            Error::UnknownColor => {
+                fmt.write_str(
+                    "Could not tell which color the game is preparation for; \
+                     Add a RepertoireColor header",
+                )?;
+            }
             Error::Http => {
                 fmt.write_str("Received an unexpected HTTP return code")?;
             }
+            Error::RateLimited => {
+                fmt.write_str("The opening explorer kept rejecting requests due to rate limiting")?;
+            }
+            Error::Interrupted => {
+                fmt.write_str("The analysis was interrupted")?;
+            }
+            Error::Engine => {
+                fmt.write_str("The chess engine terminated unexpectedly")?;
+            }
+            Error::Script(e) => {
+                fmt.write_str(&format!("The scoring script failed: {}", e))?;
+            }
             Error::Log(e) => {
                 fmt.write_str(&format!("Error initializing logger: {}", e))?;
             }
@@ -67,12 +126,33 @@ impl From<bincode::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Json(error)
+    }
+}
+
+#[cfg(feature = "network")]
 impl From<reqwest::Error> for Error {
     fn from(error: reqwest::Error) -> Self {
         Error::Reqwest(error)
     }
 }
 
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        Error::Sqlite(error)
+    }
+}
+
+#[cfg(feature = "native")]
+impl From<zip::result::ZipError> for Error {
+    fn from(error: zip::result::ZipError) -> Self {
+        Error::Zip(error)
+    }
+}
+
 impl From<log::SetLoggerError> for Error {
     fn from(error: log::SetLoggerError) -> Self {
         Error::Log(error)
@@ -83,8 +163,14 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::Bincode(e) => Some(e),
+            Error::Json(e) => Some(e),
             Error::StdIo(e) => Some(e),
+            #[cfg(feature = "network")]
             Error::Reqwest(e) => Some(e),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(e) => Some(e),
+            #[cfg(feature = "native")]
+            Error::Zip(e) => Some(e),
             _ => None,
         }
     }
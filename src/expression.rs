@@ -0,0 +1,326 @@
+//! Arithmetic expressions over a position's numbers, for tweaking how recommendations are
+//! ranked from the command line, like `frequency / (1 + transitions)`
+//!
+//! Expressions combine numbers and the variables `frequency` (share of games reaching the
+//! position), `transitions` (number of known moves) and `ply` (-1 if unknown) with `+`, `-`,
+//! `*`, `/`, `^`, parentheses and the functions `ln`, `sqrt` and `abs`.
+
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+use crate::position::Position;
+use crate::recommender::Recommender;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variable {
+    Frequency,
+    Transitions,
+    Ply,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Ln,
+    Sqrt,
+    Abs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+/// A parsed score expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    Variable(Variable),
+    Negate(Box<Expression>),
+    Call(Function, Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    pub fn evaluate(&self, position: &Position) -> f64 {
+        match self {
+            Expression::Number(number) => *number,
+            Expression::Variable(Variable::Frequency) => *position.frequency(),
+            Expression::Variable(Variable::Transitions) => position.transition_count() as f64,
+            Expression::Variable(Variable::Ply) => position.fen().ply().map_or(-1.0, f64::from),
+            Expression::Negate(operand) => -operand.evaluate(position),
+            Expression::Call(function, argument) => {
+                let argument = argument.evaluate(position);
+                match function {
+                    Function::Ln => argument.ln(),
+                    Function::Sqrt => argument.sqrt(),
+                    Function::Abs => argument.abs(),
+                }
+            }
+            Expression::Binary(operator, left, right) => {
+                let (left, right) = (left.evaluate(position), right.evaluate(position));
+                match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    Operator::Divide => left / right,
+                    Operator::Power => left.powf(right),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(char),
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let literal = take_while(&mut chars, |c| c.is_ascii_digit() || c == '.');
+            let number = literal
+                .parse()
+                .map_err(|_| format!("Invalid number '{}' in score expression", literal))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            tokens.push(Token::Name(take_while(&mut chars, |c| {
+                c.is_ascii_alphanumeric() || c == '_'
+            })));
+        } else if "+-*/^()".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(format!("Unexpected '{}' in score expression", c));
+        }
+    }
+    Ok(tokens)
+}
+
+fn take_while<F: Fn(char) -> bool>(chars: &mut Peekable<Chars>, accept: F) -> String {
+    let mut taken = String::new();
+    while let Some(&c) = chars.peek() {
+        if !accept(c) {
+            break;
+        }
+        taken.push(c);
+        chars.next();
+    }
+    taken
+}
+
+// Recursive descent over the tokens, one method per precedence level
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn advance(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.next)
+            .cloned()
+            .ok_or_else(|| "Unexpected end of score expression".to_owned())?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        match self.advance()? {
+            Token::Symbol(c) if c == symbol => Ok(()),
+            token => Err(unexpected(&token)),
+        }
+    }
+
+    // sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<Expression, String> {
+        let mut left = self.product()?;
+        while let Some(Token::Symbol(c @ '+')) | Some(Token::Symbol(c @ '-')) = self.peek() {
+            let operator = if *c == '+' {
+                Operator::Add
+            } else {
+                Operator::Subtract
+            };
+            self.next += 1;
+            left = Expression::Binary(operator, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    // product := unary (('*' | '/') unary)*
+    fn product(&mut self) -> Result<Expression, String> {
+        let mut left = self.unary()?;
+        while let Some(Token::Symbol(c @ '*')) | Some(Token::Symbol(c @ '/')) = self.peek() {
+            let operator = if *c == '*' {
+                Operator::Multiply
+            } else {
+                Operator::Divide
+            };
+            self.next += 1;
+            left = Expression::Binary(operator, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    // unary := '-' unary | power, so -2 ^ 2 is -4
+    fn unary(&mut self) -> Result<Expression, String> {
+        if let Some(Token::Symbol('-')) = self.peek() {
+            self.next += 1;
+            return Ok(Expression::Negate(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    // power := atom ('^' unary)?, so powers associate to the right
+    fn power(&mut self) -> Result<Expression, String> {
+        let base = self.atom()?;
+        if let Some(Token::Symbol('^')) = self.peek() {
+            self.next += 1;
+            let exponent = self.unary()?;
+            return Ok(Expression::Binary(
+                Operator::Power,
+                Box::new(base),
+                Box::new(exponent),
+            ));
+        }
+        Ok(base)
+    }
+
+    // atom := number | variable | function '(' sum ')' | '(' sum ')'
+    fn atom(&mut self) -> Result<Expression, String> {
+        match self.advance()? {
+            Token::Number(number) => Ok(Expression::Number(number)),
+            Token::Symbol('(') => {
+                let inner = self.sum()?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Token::Name(name) => {
+                let function = match name.as_str() {
+                    "ln" => Function::Ln,
+                    "sqrt" => Function::Sqrt,
+                    "abs" => Function::Abs,
+                    _ => return variable(&name).map(Expression::Variable),
+                };
+                self.expect('(')?;
+                let argument = self.sum()?;
+                self.expect(')')?;
+                Ok(Expression::Call(function, Box::new(argument)))
+            }
+            token => Err(unexpected(&token)),
+        }
+    }
+}
+
+fn variable(name: &str) -> Result<Variable, String> {
+    match name {
+        "frequency" => Ok(Variable::Frequency),
+        "transitions" => Ok(Variable::Transitions),
+        "ply" => Ok(Variable::Ply),
+        _ => Err(format!(
+            "Unknown name '{}' in score expression; Expected one of: frequency, transitions, \
+             ply, ln, sqrt, abs",
+            name
+        )),
+    }
+}
+
+fn unexpected(token: &Token) -> String {
+    match token {
+        Token::Number(number) => format!("Unexpected number {} in score expression", number),
+        Token::Name(name) => format!("Unexpected '{}' in score expression", name),
+        Token::Symbol(c) => format!("Unexpected '{}' in score expression", c),
+    }
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?,
+            next: 0,
+        };
+        let parsed = parser.sum()?;
+        match parser.peek() {
+            Some(token) => Err(unexpected(token)),
+            None => Ok(parsed),
+        }
+    }
+}
+
+/// Ranks the positions a base recommender applies to by the value of an expression
+pub struct Scored {
+    base: Box<dyn Recommender>,
+    expression: Expression,
+}
+
+impl Scored {
+    pub fn new(base: Box<dyn Recommender>, expression: Expression) -> Self {
+        Scored { base, expression }
+    }
+}
+
+impl Recommender for Scored {
+    fn applies_to(&self, position: &Position) -> bool {
+        self.base.applies_to(position)
+    }
+
+    // Positions the expression is undefined for, like after dividing 0 by 0, are recommended last
+    fn score(&self, position: &Position) -> f64 {
+        let score = self.expression.evaluate(position);
+        if score.is_nan() {
+            f64::NEG_INFINITY
+        } else {
+            score
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expression::*;
+    use crate::position::{Fen, PositionCache};
+
+    #[test]
+    fn it_evaluates_with_the_usual_precedence() {
+        let mut cache = PositionCache::new();
+        let fen = Fen::new("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 3");
+        cache.position(&fen).increase_frequency(0.5);
+        let position = cache.get(&fen).unwrap();
+        let evaluate =
+            |expression: &str| expression.parse::<Expression>().unwrap().evaluate(position);
+        assert!((evaluate("frequency / (1 + transitions)") - 0.5).abs() < 1e-9);
+        assert!((evaluate("1 + 2 * 3 ^ 2") - 19.0).abs() < 1e-9);
+        assert!((evaluate("2 ^ 3 ^ 2") - 512.0).abs() < 1e-9);
+        assert!((evaluate("-2 ^ 2 * 2 ^ -1") + 2.0).abs() < 1e-9);
+        assert!((evaluate("-frequency * sqrt(ply)") + 1.0).abs() < 1e-9);
+        assert!((evaluate("(1 - 3) / abs(-4)") + 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_rejects_malformed_expressions() {
+        assert!("frequency / (1 + transitions"
+            .parse::<Expression>()
+            .is_err());
+        assert!("frequency transitions".parse::<Expression>().is_err());
+        assert!("popularity * 2".parse::<Expression>().is_err());
+        assert!("frequency % 2".parse::<Expression>().is_err());
+        assert!("".parse::<Expression>().is_err());
+    }
+}
@@ -0,0 +1,467 @@
+//! C-compatible interface, so tools written in other languages can run an analysis
+//! without spawning the command line tool.
+//!
+//! Both arguments of `cro_analyze` and its result are UTF-8 encoded JSON strings.
+//! The result must be released with `cro_free_string`.
+//!
+//! Applications keeping the repertoire in memory, like chess GUIs, use an opaque handle
+//! instead: `cro_new` creates it, `cro_add_pgn` adds PGN text, `cro_run` analyzes it and
+//! `cro_next_recommendation` iterates over the recommendations as JSON strings, until
+//! `cro_free` releases the handle.
+//!
+//! Panics never unwind into the calling application; The functions return what they return
+//! for invalid arguments instead.
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::frequency_model;
+use crate::import::{self, import_repertoire, resolve_to_files};
+use crate::opening_book::cache::Cache;
+use crate::opening_book::lichess::{self, HttpOptions, Lichess, Query};
+use crate::opening_book::ErrorPolicy;
+use crate::repertoire_optimizer::RepertoireOptimizer;
+use crate::summary::Summary;
+use crate::warnings::{REJECTED_MOVE, SKIPPED_GAME};
+use pleco::Player;
+
+/// PGN files or directories containing the repertoires
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Paths {
+    white: Vec<PathBuf>,
+    black: Vec<PathBuf>,
+}
+
+/// Settings of an analysis; missing fields take the command line tool's defaults
+#[derive(Deserialize)]
+#[serde(default)]
+struct Options {
+    cache_file: Option<PathBuf>,
+    explorer_url: String,
+    ratings: Vec<u32>,
+    speeds: Vec<String>,
+    own_model: String,
+    opponent_model: String,
+    http_timeout: u64,
+    http_retries: u32,
+    concurrency: usize,
+    proxy: Option<String>,
+    api_token: Option<String>,
+    best: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            cache_file: None,
+            explorer_url: lichess::DEFAULT_BASE_URL.to_owned(),
+            ratings: vec![1600, 1800, 2000, 2200, 2500],
+            speeds: vec![
+                "blitz".to_owned(),
+                "rapid".to_owned(),
+                "classical".to_owned(),
+            ],
+            own_model: "uniform".to_owned(),
+            opponent_model: "popularity".to_owned(),
+            http_timeout: 30,
+            http_retries: 5,
+            concurrency: 4,
+            proxy: None,
+            api_token: None,
+            best: 10,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Recommendation {
+    fen: String,
+    frequency: f64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Outcome {
+    Completed {
+        summary: Summary,
+        recommendations: Vec<Recommendation>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// Opaque handle to repertoires built from PGN text, and the recommendations of their last
+/// analysis
+pub struct CroOptimizer {
+    options: Options,
+    repertoires: Vec<RepertoireOptimizer>,
+    recommendations: std::vec::IntoIter<Recommendation>,
+}
+
+/// Analyzes the repertoires in `paths`, e.g. `{"white": ["white.pgn"], "black": []}`,
+/// using `options`, e.g. `{"cache_file": "cache.bin", "best": 5}`, which may be null.
+/// Returns `{"status": "completed", "summary": {..}, "recommendations": [..]}`
+/// or `{"status": "failed", "error": ".."}`, also if the analysis panicked.
+///
+/// # Safety
+/// `paths` and, unless null, `options` must point to NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn cro_analyze(paths: *const c_char, options: *const c_char) -> *mut c_char {
+    let outcome = catching(
+        || Outcome::Failed {
+            error: "The analysis failed unexpectedly".to_owned(),
+        },
+        || match parse_arguments(paths, options) {
+            Ok((paths, options)) => match analyze(&mut import_paths(paths), &options) {
+                Ok((summary, recommendations)) => Outcome::Completed {
+                    summary,
+                    recommendations,
+                },
+                Err(e) => Outcome::Failed {
+                    error: e.to_string(),
+                },
+            },
+            Err(error) => Outcome::Failed { error },
+        },
+    );
+    to_c_string(&outcome)
+}
+
+/// Releases a string returned by `cro_analyze`, `cro_run` or `cro_next_recommendation`
+///
+/// # Safety
+/// `string` must have been returned by one of these functions and not been released before.
+#[no_mangle]
+pub unsafe extern "C" fn cro_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Creates a handle analyzing with `options` as for `cro_analyze`, which may be null.
+/// Returns null if the options are invalid.
+///
+/// # Safety
+/// Unless null, `options` must point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cro_new(options: *const c_char) -> *mut CroOptimizer {
+    catching(std::ptr::null_mut, || {
+        let options = if options.is_null() {
+            Options::default()
+        } else {
+            match serde_json::from_slice(CStr::from_ptr(options).to_bytes()) {
+                Ok(options) => options,
+                Err(_) => return std::ptr::null_mut(),
+            }
+        };
+        Box::into_raw(Box::new(CroOptimizer {
+            options,
+            repertoires: vec![
+                RepertoireOptimizer::new(Player::White),
+                RepertoireOptimizer::new(Player::Black),
+            ],
+            recommendations: Vec::new().into_iter(),
+        }))
+    })
+}
+
+/// Adds the games of a PGN text to the repertoire of `color`, 0 for White and 1 for Black.
+/// Like the import of files, games with a bad move are added up to it. Returns the number of
+/// games read, or -1 if the text is no valid PGN.
+///
+/// # Safety
+/// `optimizer` must have been returned by `cro_new` and `pgn` must point to a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn cro_add_pgn(
+    optimizer: *mut CroOptimizer,
+    color: c_int,
+    pgn: *const c_char,
+) -> c_int {
+    catching(
+        || -1,
+        || {
+            let optimizer = match optimizer.as_mut() {
+                Some(optimizer) if !pgn.is_null() => optimizer,
+                _ => return -1,
+            };
+            let player = match color {
+                0 => Player::White,
+                1 => Player::Black,
+                _ => return -1,
+            };
+            let games = match CStr::from_ptr(pgn).to_str().map(import::parse_games) {
+                Ok(Ok(games)) => games,
+                _ => return -1,
+            };
+            let read = games.len();
+            let repertoire = optimizer
+                .repertoires
+                .iter_mut()
+                .find(|repertoire| repertoire.player() == player)
+                .expect("The handle has a repertoire of each color");
+            for (chapter, game) in (1..).zip(games) {
+                match repertoire.add_chapter_to_repertoire(game, chapter) {
+                    Ok(_) => {}
+                    Err(e @ Error::IllegalMove { .. }) | Err(e @ Error::AmbiguousMove { .. }) => {
+                        warn!(
+                            target: REJECTED_MOVE,
+                            "The PGN text contains bad move in game {}; Imported its moves \
+                             before: {}",
+                            chapter,
+                            e
+                        )
+                    }
+                    Err(e) => warn!(
+                        target: SKIPPED_GAME,
+                        "The PGN text contains bad game {}: {}",
+                        chapter,
+                        e
+                    ),
+                }
+            }
+            read as c_int
+        },
+    )
+}
+
+/// Analyzes the PGN text added so far, like `cro_analyze`, and returns the outcome without the
+/// recommendations, which are iterated with `cro_next_recommendation` instead.
+/// The result must be released with `cro_free_string`; It is null if the analysis panicked.
+///
+/// # Safety
+/// `optimizer` must have been returned by `cro_new`.
+#[no_mangle]
+pub unsafe extern "C" fn cro_run(optimizer: *mut CroOptimizer) -> *mut c_char {
+    catching(std::ptr::null_mut, || {
+        let outcome = match optimizer.as_mut() {
+            Some(optimizer) => {
+                // Repertoires without prepared moves would count as leaving book right away
+                let repertoires = &mut optimizer.repertoires;
+                repertoires.sort_by_key(|repertoire| repertoire.prepared_move_count() == 0);
+                let prepared = repertoires
+                    .iter()
+                    .take_while(|repertoire| repertoire.prepared_move_count() > 0)
+                    .count();
+                match analyze(&mut repertoires[..prepared], &optimizer.options) {
+                    Ok((summary, recommendations)) => {
+                        optimizer.recommendations = recommendations.into_iter();
+                        Outcome::Completed {
+                            summary,
+                            recommendations: Vec::new(),
+                        }
+                    }
+                    Err(e) => Outcome::Failed {
+                        error: e.to_string(),
+                    },
+                }
+            }
+            None => Outcome::Failed {
+                error: "No optimizer given".to_owned(),
+            },
+        };
+        to_c_string(&outcome)
+    })
+}
+
+/// The next recommendation of the last `cro_run`, e.g. `{"fen": "..", "frequency": 0.1}`,
+/// or null once all have been returned. The result must be released with `cro_free_string`.
+///
+/// # Safety
+/// `optimizer` must have been returned by `cro_new`.
+#[no_mangle]
+pub unsafe extern "C" fn cro_next_recommendation(optimizer: *mut CroOptimizer) -> *mut c_char {
+    catching(std::ptr::null_mut, || {
+        match optimizer
+            .as_mut()
+            .and_then(|optimizer| optimizer.recommendations.next())
+        {
+            Some(recommendation) => to_c_string(&recommendation),
+            None => std::ptr::null_mut(),
+        }
+    })
+}
+
+/// Releases a handle returned by `cro_new`
+///
+/// # Safety
+/// `optimizer` must have been returned by `cro_new` and not been released before.
+#[no_mangle]
+pub unsafe extern "C" fn cro_free(optimizer: *mut CroOptimizer) {
+    if !optimizer.is_null() {
+        drop(Box::from_raw(optimizer));
+    }
+}
+
+// Runs `call`, returning the result of `on_panic` if it panics, as unwinding into the calling
+// application is undefined behaviour
+fn catching<T>(on_panic: impl FnOnce() -> T, call: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(call)).unwrap_or_else(|_| on_panic())
+}
+
+fn to_c_string<T: Serialize>(value: &T) -> *mut c_char {
+    let json = serde_json::to_string(value).expect("Results are always serializable");
+    CString::new(json)
+        .expect("JSON never contains NUL characters")
+        .into_raw()
+}
+
+unsafe fn parse_arguments(
+    paths: *const c_char,
+    options: *const c_char,
+) -> Result<(Paths, Options), String> {
+    if paths.is_null() {
+        return Err("No repertoire paths given".to_owned());
+    }
+    let paths = serde_json::from_slice(CStr::from_ptr(paths).to_bytes())
+        .map_err(|e| format!("Invalid repertoire paths: {}", e))?;
+    let options = if options.is_null() {
+        Options::default()
+    } else {
+        serde_json::from_slice(CStr::from_ptr(options).to_bytes())
+            .map_err(|e| format!("Invalid options: {}", e))?
+    };
+    Ok((paths, options))
+}
+
+// The repertoires with prepared moves
+fn import_paths(paths: Paths) -> Vec<RepertoireOptimizer> {
+    let mut optimizers = Vec::new();
+    for (player, files) in [(Player::White, paths.white), (Player::Black, paths.black)] {
+        let mut optimizer = RepertoireOptimizer::new(player);
+        import_repertoire(&mut optimizer, resolve_to_files(files, false));
+        if optimizer.prepared_move_count() > 0 {
+            optimizers.push(optimizer);
+        }
+    }
+    optimizers
+}
+
+// Analyzes repertoires with prepared moves; Analyzing them again takes book moves added in
+// between into account
+fn analyze(
+    optimizers: &mut [RepertoireOptimizer],
+    options: &Options,
+) -> Result<(Summary, Vec<Recommendation>), Error> {
+    let now = Instant::now();
+    let own_model = frequency_model::by_name(&options.own_model).map_err(Error::Options)?;
+    let opponent_model =
+        frequency_model::by_name(&options.opponent_model).map_err(Error::Options)?;
+    let query = Query {
+        ratings: options.ratings.clone(),
+        speeds: options.speeds.clone(),
+        since: None,
+        until: None,
+    };
+    let http = HttpOptions {
+        timeout: Duration::from_secs(options.http_timeout),
+        retries: options.http_retries,
+        concurrency: options.concurrency,
+        proxy: options.proxy.clone(),
+        token: options.api_token.clone(),
+    };
+    let filters = query.to_string();
+    let mut opening_book = Cache::new(Lichess::new(&options.explorer_url, 0, query, http)?);
+    opening_book.set_filters(filters);
+    if let Some(ref path) = options.cache_file {
+        if path.exists() {
+            opening_book.load(File::open(path)?)?;
+        }
+    }
+
+    for optimizer in optimizers.iter_mut() {
+        optimizer.set_own_move_stats(own_model.uses_stats());
+        optimizer.add_opponents_moves_from_book(&mut opening_book, ErrorPolicy::Abort)?;
+        optimizer.set_own_move_frequencies(own_model.as_ref());
+        optimizer.set_opponent_move_frequencies(opponent_model.as_ref());
+        optimizer.update_position_frequencies();
+    }
+
+    if let Some(ref path) = options.cache_file {
+        if opening_book.has_changed() {
+            opening_book.save(File::create(path)?)?;
+        }
+    }
+
+    let mut summary = Summary::new(optimizers);
+    let positions: Vec<_> = optimizers
+        .iter()
+        .flat_map(|optimizer| optimizer.own_positions())
+        .collect();
+    let recommendations = RepertoireOptimizer::recommend_for_addition(&positions, options.best)
+        .into_iter()
+        .map(|position| Recommendation {
+            fen: position.fen().fen_str().to_owned(),
+            frequency: *position.frequency(),
+        })
+        .collect();
+    summary.runtime_seconds = now.elapsed().as_millis() as f64 / 1000.0;
+    Ok((summary, recommendations))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ffi::*;
+
+    unsafe fn call(paths: &str, options: Option<&str>) -> serde_json::Value {
+        let paths = CString::new(paths).unwrap();
+        let options = options.map(|options| CString::new(options).unwrap());
+        let result = cro_analyze(
+            paths.as_ptr(),
+            options
+                .as_ref()
+                .map_or(std::ptr::null(), |options| options.as_ptr()),
+        );
+        let json = serde_json::from_slice(CStr::from_ptr(result).to_bytes()).unwrap();
+        cro_free_string(result);
+        json
+    }
+
+    #[test]
+    fn it_reports_invalid_arguments_as_failures() {
+        let outcome = unsafe { call("[not json", None) };
+        assert_eq!(outcome["status"], "failed");
+        let outcome = unsafe { call("{}", Some(r#"{"own_model": "random"}"#)) };
+        assert_eq!(outcome["status"], "failed");
+    }
+
+    #[test]
+    fn it_reads_pgn_text_into_a_handle() {
+        unsafe {
+            let optimizer = cro_new(std::ptr::null());
+            let pgn = CString::new("1. e4 e5 2. Nf3 *\n\n").unwrap();
+            assert_eq!(cro_add_pgn(optimizer, 0, pgn.as_ptr()), 1);
+            assert_eq!(cro_add_pgn(optimizer, 2, pgn.as_ptr()), -1);
+            // Games are read up to a bad move, and repeated games are read but not added again
+            let pgn = CString::new("1. e4 e5 2. Nf3 *\n\n1. d4 d5 2. Ke3 *\n\n").unwrap();
+            assert_eq!(cro_add_pgn(optimizer, 0, pgn.as_ptr()), 2);
+            assert_eq!((*optimizer).repertoires[0].prepared_move_count(), 3);
+            // Nothing to recommend before the first run
+            assert!(cro_next_recommendation(optimizer).is_null());
+            cro_free(optimizer);
+            assert!(cro_new(CString::new("[").unwrap().as_ptr()).is_null());
+        }
+    }
+
+    #[test]
+    fn it_does_not_unwind_into_the_caller() {
+        assert_eq!(catching(|| -1, || panic!("Unexpected")), -1);
+    }
+
+    #[test]
+    fn it_completes_without_any_repertoire() {
+        let outcome = unsafe { call(r#"{"white": [], "black": []}"#, Some(r#"{"best": 3}"#)) };
+        assert_eq!(outcome["status"], "completed");
+        assert_eq!(outcome["summary"]["prepared_positions"], 0);
+        assert_eq!(outcome["recommendations"], serde_json::json!([]));
+    }
+}
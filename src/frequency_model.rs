@@ -0,0 +1,181 @@
+/// Statistics an opening book knows about a single move
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveStats {
+    /// Share of games in which the move was played
+    pub popularity: f64,
+    /// Expected score for the side making the move
+    pub score: f64,
+    /// Most recent year the move was seen in the book's sample games
+    pub year: Option<u32>,
+}
+
+// Recency weighting halves a move's weight for every this many years of age
+static RECENCY_HALF_LIFE: f64 = 3.0;
+
+/// Decides how likely each move in a position is to be played.
+/// `candidates` holds the book statistics of every move, if known;
+/// the returned frequencies correspond to the candidates by index.
+pub trait FrequencyModel: std::fmt::Debug {
+    fn frequencies(&self, candidates: &[Option<&MoveStats>]) -> Vec<f64>;
+
+    /// Whether the frequencies depend on the book statistics, which then have to be looked up
+    /// for my own moves as well
+    fn uses_stats(&self) -> bool {
+        true
+    }
+}
+
+/// Every move is equally likely
+#[derive(Debug)]
+pub struct Uniform;
+
+/// Moves are played as often as in the opening book
+#[derive(Debug)]
+pub struct Popularity;
+
+/// Like `Popularity`, but moves scoring well for the mover are played more often
+#[derive(Debug)]
+pub struct ScoreWeighted;
+
+/// Like `Popularity`, but moves seen recently are played more often
+#[derive(Debug)]
+pub struct RecencyWeighted;
+
+/// The built-in model of that name; Built-in models can be shared between threads
+pub fn by_name(name: &str) -> Result<Box<dyn FrequencyModel + Send + Sync>, String> {
+    match name {
+        "uniform" => Ok(Box::new(Uniform)),
+        "popularity" => Ok(Box::new(Popularity)),
+        "score" => Ok(Box::new(ScoreWeighted)),
+        "recency" => Ok(Box::new(RecencyWeighted)),
+        _ => Err(format!(
+            "Unknown frequency model '{}'; Expected one of: uniform, popularity, score, recency",
+            name
+        )),
+    }
+}
+
+impl FrequencyModel for Uniform {
+    fn frequencies(&self, candidates: &[Option<&MoveStats>]) -> Vec<f64> {
+        vec![1.0 / candidates.len() as f64; candidates.len()]
+    }
+
+    fn uses_stats(&self) -> bool {
+        false
+    }
+}
+
+impl FrequencyModel for Popularity {
+    fn frequencies(&self, candidates: &[Option<&MoveStats>]) -> Vec<f64> {
+        weighted(candidates, |_| 1.0)
+    }
+}
+
+impl FrequencyModel for ScoreWeighted {
+    fn frequencies(&self, candidates: &[Option<&MoveStats>]) -> Vec<f64> {
+        weighted(candidates, |stats| stats.score)
+    }
+}
+
+impl FrequencyModel for RecencyWeighted {
+    fn frequencies(&self, candidates: &[Option<&MoveStats>]) -> Vec<f64> {
+        let known_years = candidates
+            .iter()
+            .filter_map(|stats| stats.and_then(|stats| stats.year));
+        let newest = known_years.clone().max();
+        let oldest = known_years.min();
+        weighted(candidates, |stats| match (newest, oldest) {
+            (Some(newest), Some(oldest)) => {
+                let age = newest - stats.year.unwrap_or(oldest);
+                0.5f64.powf(f64::from(age) / RECENCY_HALF_LIFE)
+            }
+            _ => 1.0,
+        })
+    }
+}
+
+// Scales the popularity of each move by a factor, keeping the total popularity unchanged.
+// Moves without statistics are never played, unless no move has statistics at all.
+fn weighted<F>(candidates: &[Option<&MoveStats>], factor: F) -> Vec<f64>
+where
+    F: Fn(&MoveStats) -> f64,
+{
+    if candidates.iter().all(Option::is_none) {
+        return Uniform.frequencies(candidates);
+    }
+    let weights: Vec<f64> = candidates
+        .iter()
+        .map(|stats| stats.map_or(0.0, |stats| stats.popularity * factor(stats)))
+        .collect();
+    let total_popularity: f64 = candidates
+        .iter()
+        .filter_map(|stats| stats.map(|stats| stats.popularity))
+        .sum();
+    let total_weight: f64 = weights.iter().sum();
+    if total_weight == 0.0 {
+        return weights;
+    }
+    weights
+        .into_iter()
+        .map(|weight| weight * total_popularity / total_weight)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frequency_model::*;
+
+    fn stats(popularity: f64, score: f64, year: Option<u32>) -> MoveStats {
+        MoveStats {
+            popularity,
+            score,
+            year,
+        }
+    }
+
+    #[test]
+    fn it_distributes_uniformly() {
+        let a = stats(0.6, 0.5, None);
+        assert_eq!(Uniform.frequencies(&[Some(&a), None]), vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn it_uses_the_book_popularity() {
+        let a = stats(0.6, 0.5, None);
+        let b = stats(0.3, 0.5, None);
+        let frequencies = Popularity.frequencies(&[Some(&a), Some(&b), None]);
+        assert!((frequencies[0] - 0.6).abs() < 1e-9);
+        assert!((frequencies[1] - 0.3).abs() < 1e-9);
+        assert_eq!(frequencies[2], 0.0);
+    }
+
+    #[test]
+    fn it_falls_back_to_uniform_without_any_statistics() {
+        assert_eq!(Popularity.frequencies(&[None, None]), vec![0.5, 0.5]);
+        assert_eq!(ScoreWeighted.frequencies(&[None, None]), vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn it_favors_well_scoring_moves_while_keeping_the_total() {
+        let a = stats(0.4, 0.6, None);
+        let b = stats(0.4, 0.2, None);
+        let frequencies = ScoreWeighted.frequencies(&[Some(&a), Some(&b)]);
+        assert!((frequencies[0] - 0.6).abs() < 1e-9);
+        assert!((frequencies[1] - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_favors_recent_moves() {
+        let a = stats(0.4, 0.5, Some(2020));
+        let b = stats(0.4, 0.5, Some(2014));
+        let frequencies = RecencyWeighted.frequencies(&[Some(&a), Some(&b)]);
+        assert!((frequencies[0] - 0.64).abs() < 1e-9);
+        assert!((frequencies[1] - 0.16).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_rejects_unknown_model_names() {
+        assert!(by_name("popularity").is_ok());
+        assert!(by_name("random").is_err());
+    }
+}
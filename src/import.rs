@@ -0,0 +1,841 @@
+use log::{info, warn};
+use pleco::Player;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::opening_book::explorer::HttpOptions;
+#[cfg(feature = "network")]
+use crate::opening_book::lichess;
+use crate::repertoire_optimizer::RepertoireOptimizer;
+use crate::warnings::{FAILED_FILE, REJECTED_MOVE, SKIPPED_GAME};
+
+/// Expands directories to the files within, recursively, glob patterns like
+/// `repertoire/**/*.pgn` to the paths matching, and ZIP archives to the PGN files they contain,
+/// named like `course.zip/chapter1.pgn`; HTTP(S) URLs are kept, and their PGN is downloaded when
+/// importing. Files found in directories or by patterns are skipped unless they are PGN files or
+/// ZIP archives, or `all_extensions` is set
+pub fn resolve_to_files(paths: Vec<PathBuf>, all_extensions: bool) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths {
+        if is_url(&path) {
+            files.push(path);
+        } else if is_glob(&path) {
+            match glob::glob(&path.to_string_lossy()) {
+                Ok(matches) => {
+                    let matches: Vec<_> = matches
+                        .filter_map(Result::ok)
+                        .filter(|found| is_importable(found, all_extensions))
+                        .collect();
+                    if matches.is_empty() {
+                        warn!("No files to import match '{}'", path.display());
+                    }
+                    files.append(&mut resolve_to_files(matches, all_extensions));
+                }
+                Err(e) => warn!("Invalid pattern '{}': {}", path.display(), e),
+            }
+        } else if path.is_dir() {
+            info!(
+                "'{}' is a directory; Importing all files from within...",
+                path.display()
+            );
+            let subpaths = path
+                .read_dir()
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|found| is_importable(found, all_extensions))
+                .collect();
+            files.append(&mut resolve_to_files(subpaths, all_extensions));
+        } else if is_zip(&path) && path.is_file() {
+            info!(
+                "'{}' is a ZIP archive; Importing all PGN files from within...",
+                path.display()
+            );
+            match zip_entries(&path) {
+                Ok(names) => files.extend(names.into_iter().map(|name| path.join(name))),
+                Err(e) => warn!(target: FAILED_FILE, "Reading '{}' failed: {}", path.display(), e),
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+/// Splits PGN text into the texts of its games, reading one line at a time, so files of any size
+/// are read without holding all of their text in memory
+pub struct GameTexts<R> {
+    lines: io::Lines<R>,
+    // First line of the next game, read while looking for the end of the previous one
+    next_line: Option<String>,
+}
+
+impl<R: BufRead> GameTexts<R> {
+    pub fn new(reader: R) -> Self {
+        GameTexts {
+            lines: reader.lines(),
+            next_line: None,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for GameTexts<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut text = String::new();
+        let mut has_moves = false;
+        let mut comment_depth = 0;
+        loop {
+            let line = match self.next_line.take() {
+                Some(line) => line,
+                None => match self.lines.next() {
+                    Some(Ok(line)) => line,
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => break,
+                },
+            };
+            let trimmed = line.trim_start();
+            // Blank lines after the result of the previous game
+            if text.is_empty() && trimmed.is_empty() {
+                continue;
+            }
+            if comment_depth == 0 && trimmed.starts_with('[') {
+                // Tags after the moves of a game start the next one
+                if has_moves {
+                    self.next_line = Some(line);
+                    break;
+                }
+            } else if comment_depth == 0 && !trimmed.is_empty() && !trimmed.starts_with('%') {
+                has_moves = true;
+            }
+            let (depth, result_end) = scan_line(&line, comment_depth);
+            comment_depth = depth;
+            // The result ends the game, even without tags before the next one
+            if let Some(end) = result_end.filter(|_| has_moves) {
+                text.push_str(&line[..end]);
+                text.push('\n');
+                let rest = line[end..].trim();
+                if !rest.is_empty() {
+                    self.next_line = Some(rest.to_owned());
+                }
+                break;
+            }
+            text.push_str(&line);
+            text.push('\n');
+        }
+        if text.trim().is_empty() {
+            None
+        } else {
+            text.push('\n');
+            Some(Ok(text))
+        }
+    }
+}
+
+static NULL_MOVES: [&str; 4] = ["--", "Z0", "0000", "@@@@"];
+static RESULTS: [&str; 4] = ["1-0", "0-1", "1/2-1/2", "*"];
+
+// The comment depth after a line of move text starting at `depth`, and where the first result
+// outside comments ends, if there is one
+fn scan_line(line: &str, mut depth: usize) -> (usize, Option<usize>) {
+    let mut word_start = None;
+    for (index, c) in line.char_indices().chain(iter::once((line.len(), ' '))) {
+        if c == '{' || c == '}' || c.is_whitespace() {
+            if let Some(start) = word_start.take() {
+                if RESULTS.contains(&&line[start..index]) {
+                    return (depth, Some(index));
+                }
+            }
+        }
+        match c {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            c if c.is_whitespace() => {}
+            _ if depth == 0 && word_start.is_none() => word_start = Some(index),
+            _ => {}
+        }
+    }
+    (depth, None)
+}
+
+// The position in the text a variation starts at, and whether it has a move yet
+struct Variation {
+    start: usize,
+    has_move: bool,
+}
+
+/// Removes what the PGN parser does not understand: annotation glyphs like `$1` or `!?`, and
+/// null moves like `--` or `Z0` together with the rest of their line, which no game can reach;
+/// Variations starting with a null move are removed entirely
+pub fn strip_unsupported(pgn: &str) -> String {
+    let mut stripped = String::with_capacity(pgn.len());
+    let mut chars = pgn.chars().peekable();
+    let mut variations: Vec<Variation> = Vec::new();
+    // Variation depth from which on the rest of the line is skipped after a null move
+    let mut skip_from: Option<usize> = None;
+    // Start of the last move number, which is removed along with a null move following it
+    let mut move_number: Option<usize> = None;
+    let mut line_start = true;
+    while let Some(c) = chars.next() {
+        let skipping = skip_from.is_some();
+        let mut line_end = c == '\n';
+        match c {
+            '[' if line_start => {
+                // Tags start the next game
+                variations.clear();
+                skip_from = None;
+                stripped.push(c);
+                while let Some(c) = chars.next_if(|&c| c != '\n') {
+                    stripped.push(c);
+                }
+            }
+            '{' | ';' => {
+                let end = if c == '{' { '}' } else { '\n' };
+                line_end = end == '\n';
+                let mut comment = c.to_string();
+                for c in chars.by_ref() {
+                    comment.push(c);
+                    if c == end {
+                        break;
+                    }
+                }
+                if !skipping {
+                    stripped.push_str(&comment);
+                }
+            }
+            '(' => {
+                variations.push(Variation {
+                    start: stripped.len(),
+                    has_move: false,
+                });
+                if !skipping {
+                    stripped.push(c);
+                    move_number = None;
+                }
+            }
+            ')' => {
+                let depth = variations.len();
+                let variation = variations.pop();
+                if skip_from == Some(depth) {
+                    skip_from = None;
+                    match variation {
+                        Some(variation) if !variation.has_move => {
+                            stripped.truncate(variation.start)
+                        }
+                        _ => stripped.push(c),
+                    }
+                } else if !skipping {
+                    stripped.push(c);
+                }
+            }
+            c if c.is_whitespace() => {
+                if !skipping {
+                    stripped.push(c);
+                }
+            }
+            c => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"(){};".contains(*c)) {
+                    token.push(c);
+                }
+                // Move numbers like "12." or "12..." may be written together with the move
+                let length = token
+                    .find(|c: char| !c.is_ascii_digit() && c != '.')
+                    .unwrap_or(token.len());
+                let (number, san) = if token[..length].contains('.') {
+                    token.split_at(length)
+                } else {
+                    ("", &token[..])
+                };
+                if RESULTS.contains(&san) && variations.is_empty() {
+                    skip_from = None;
+                    stripped.push_str(san);
+                } else if NULL_MOVES.contains(&san) {
+                    if !skipping {
+                        skip_from = Some(variations.len());
+                        if let Some(start) = move_number.take() {
+                            stripped.truncate(start);
+                        }
+                    }
+                } else if !skipping {
+                    let san = san.trim_end_matches(&['!', '?'][..]);
+                    if !san.is_empty() && !san.starts_with('$') {
+                        if let Some(variation) = variations.last_mut() {
+                            variation.has_move = true;
+                        }
+                        move_number = None;
+                        stripped.push_str(number);
+                        stripped.push_str(san);
+                    } else if !number.is_empty() {
+                        move_number = Some(stripped.len());
+                        stripped.push_str(number);
+                    }
+                }
+            }
+        }
+        line_start = line_end;
+    }
+    stripped
+}
+
+/// Parses the games of a PGN text, leaving out what the parser does not understand
+pub fn parse_games(pgn: &str) -> Result<Vec<chess_pgn_parser::Game>, Error> {
+    chess_pgn_parser::read_games(&strip_unsupported(pgn)).map_err(|_| Error::PgnParser)
+}
+
+/// Reads the games of PGN text one at a time, e.g. from a `BufReader` of a file
+pub fn read_games<R: BufRead>(
+    reader: R,
+) -> impl Iterator<Item = Result<chess_pgn_parser::Game, Error>> {
+    GameTexts::new(reader).flat_map(|text| {
+        let games = text
+            .map_err(Error::from)
+            .and_then(|text| parse_games(&text));
+        match games {
+            Ok(games) => games.into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        }
+    })
+}
+
+/// Selects games by the value of a PGN header, like "Event=Repertoire"
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderFilter {
+    pub name: String,
+    pub value: String,
+}
+
+impl HeaderFilter {
+    /// Whether the headers match all filters; Filters on the same header are alternatives
+    pub fn match_all(filters: &[HeaderFilter], tags: &[(String, String)]) -> bool {
+        filters.iter().all(|filter| {
+            filters
+                .iter()
+                .filter(|other| other.name == filter.name)
+                .any(|other| {
+                    tags.iter()
+                        .any(|(name, value)| *name == other.name && value.trim() == other.value)
+                })
+        })
+    }
+}
+
+impl FromStr for HeaderFilter {
+    type Err = String;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        match filter.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => Ok(HeaderFilter {
+                name: name.trim().to_owned(),
+                value: value.trim().to_owned(),
+            }),
+            _ => Err(format!(
+                "Invalid header filter '{}'; Expected NAME=VALUE, like Event=Repertoire",
+                filter
+            )),
+        }
+    }
+}
+
+/// Adds the games of all PGN files to the repertoire, skipping games imported before.
+/// Files are read one game at a time, so even very large files are imported in little memory.
+/// URLs are downloaded with the default `HttpOptions`.
+pub fn import_repertoire(optimizer: &mut RepertoireOptimizer, files: Vec<PathBuf>) {
+    // Lenient imports only warn about bad games
+    let http = HttpOptions::default();
+    let _ = import_matching(optimizer, files, &[], ImportMode::Lenient, &http);
+}
+
+/// How games with illegal or ambiguous moves are imported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    /// Stop the import with the error
+    Strict,
+    /// Add the moves up to the bad one, noting where the game stopped
+    Lenient,
+}
+
+/// Like `import_repertoire`, but only adds the games whose headers match all `filters`, and
+/// downloads URLs with the timeout, proxy and token of `http`
+pub fn import_matching(
+    optimizer: &mut RepertoireOptimizer,
+    files: Vec<PathBuf>,
+    filters: &[HeaderFilter],
+    mode: ImportMode,
+    http: &HttpOptions,
+) -> Result<(), Error> {
+    import_games(files, filters, mode, http, |game, chapter, path| {
+        optimizer.add_chapter_from_file(game, chapter, path)
+    })
+}
+
+/// Imports files holding both White and Black preparation, adding each game to the repertoire
+/// of its `repertoire_color`; Games of unknown color are skipped with a warning
+pub fn import_mixed(
+    white: &mut RepertoireOptimizer,
+    black: &mut RepertoireOptimizer,
+    files: Vec<PathBuf>,
+    filters: &[HeaderFilter],
+    mode: ImportMode,
+    http: &HttpOptions,
+) -> Result<(), Error> {
+    import_games(
+        files,
+        filters,
+        mode,
+        http,
+        |game, chapter, path| match repertoire_color(&game) {
+            Some(Player::White) => white.add_chapter_from_file(game, chapter, path),
+            Some(Player::Black) => black.add_chapter_from_file(game, chapter, path),
+            None => Err(Error::UnknownColor),
+        },
+    )
+}
+
+/// The color a game of a mixed repertoire file is preparation for: from its "RepertoireColor"
+/// header, or the "Orientation" header of Lichess study exports, or else the side with more
+/// commented moves
+pub fn repertoire_color(game: &chess_pgn_parser::Game) -> Option<Player> {
+    let tag = |name: &str| {
+        game.tags
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.trim().to_lowercase())
+    };
+    match tag("RepertoireColor")
+        .or_else(|| tag("Orientation"))
+        .as_deref()
+    {
+        Some("white") => return Some(Player::White),
+        Some("black") => return Some(Player::Black),
+        _ => {}
+    }
+    let black_starts = tag("FEN").map_or(false, |fen| fen.split(' ').nth(1) == Some("b"));
+    let (mut white, mut black) = (0, 0);
+    for (index, mv) in game.moves.iter().enumerate() {
+        if mv.comment.is_some() {
+            if (index % 2 == 1) == black_starts {
+                white += 1;
+            } else {
+                black += 1;
+            }
+        }
+    }
+    match white.cmp(&black) {
+        Ordering::Greater => Some(Player::White),
+        Ordering::Less => Some(Player::Black),
+        Ordering::Equal => None,
+    }
+}
+
+// Whether the source is an HTTP(S) URL rather than a local path
+fn is_url(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+// Whether the path is a pattern rather than the name of an existing file
+fn is_glob(path: &Path) -> bool {
+    !path.exists() && path.to_string_lossy().contains(&['*', '?', '['][..])
+}
+
+// Whether a path found in a directory or by a pattern should be imported, as opposed to notes,
+// backups and the like
+fn is_importable(path: &Path, all_extensions: bool) -> bool {
+    let importable = all_extensions || path.is_dir() || is_pgn(path) || is_zip(path);
+    if !importable {
+        info!("Skipping '{}'; Not a PGN file", path.display());
+    }
+    importable
+}
+
+fn is_pgn(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pgn"))
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+// The archive a path from `resolve_to_files` lies in, and the name of the file within it
+fn archive_entry(path: &Path) -> Option<(&Path, String)> {
+    let archive = path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| is_zip(ancestor) && ancestor.is_file())?;
+    let name = path
+        .strip_prefix(archive)
+        .ok()?
+        .iter()
+        .map(|component| component.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    Some((archive, name))
+}
+
+// Reads a local file, a file within a ZIP archive, or downloads from a URL like a Lichess study
+// export link
+fn open(path: &Path, http: &HttpOptions) -> Result<Box<dyn BufRead>, Error> {
+    if is_url(path) {
+        download(&path.to_string_lossy(), http)
+    } else if let Some((archive, name)) = archive_entry(path) {
+        read_zip_entry(archive, &name)
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+// Names of the PGN files in a ZIP archive, in the order they were stored
+#[cfg(feature = "native")]
+fn zip_entries(archive: &Path) -> Result<Vec<String>, Error> {
+    let mut archive = zip::ZipArchive::new(File::open(archive)?)?;
+    let mut names = Vec::new();
+    for index in 0..archive.len() {
+        let entry = archive.by_index(index)?;
+        if !entry.is_dir() && is_pgn(Path::new(entry.name())) {
+            names.push(entry.name().to_owned());
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(feature = "native")]
+fn read_zip_entry(archive: &Path, name: &str) -> Result<Box<dyn BufRead>, Error> {
+    let mut archive = zip::ZipArchive::new(File::open(archive)?)?;
+    let mut pgn = Vec::new();
+    io::Read::read_to_end(&mut archive.by_name(name)?, &mut pgn)?;
+    Ok(Box::new(io::Cursor::new(pgn)))
+}
+
+#[cfg(not(feature = "native"))]
+fn zip_entries(_: &Path) -> Result<Vec<String>, Error> {
+    Err(Error::Options(
+        "Importing ZIP archives requires building with the 'native' feature".to_owned(),
+    ))
+}
+
+#[cfg(not(feature = "native"))]
+fn read_zip_entry(_: &Path, _: &str) -> Result<Box<dyn BufRead>, Error> {
+    Err(Error::Options(
+        "Importing ZIP archives requires building with the 'native' feature".to_owned(),
+    ))
+}
+
+#[cfg(feature = "network")]
+fn download(url: &str, http: &HttpOptions) -> Result<Box<dyn BufRead>, Error> {
+    info!("Downloading '{}'...", url);
+    Ok(Box::new(BufReader::new(lichess::download(url, http)?)))
+}
+
+#[cfg(not(feature = "network"))]
+fn download(_: &str, _: &HttpOptions) -> Result<Box<dyn BufRead>, Error> {
+    Err(Error::Options(
+        "Importing from URLs requires building with the 'network' feature".to_owned(),
+    ))
+}
+
+// For an illegal or ambiguous move, the number of the move it was played as, like "12..." for a
+// move of Black
+fn bad_move_number(error: &Error) -> Option<String> {
+    let fen_str = match error {
+        Error::IllegalMove { fen_str, .. } | Error::AmbiguousMove { fen_str, .. } => fen_str,
+        _ => return None,
+    };
+    let mut fields = fen_str.split_whitespace().skip(1);
+    let dots = match fields.next() {
+        Some("b") => "...",
+        _ => ".",
+    };
+    let number = fields.nth(3).unwrap_or("1");
+    Some(format!("{}{}", number, dots))
+}
+
+// Adds the games of all files matching the filters with `add`, reporting how many were found
+fn import_games<F>(
+    files: Vec<PathBuf>,
+    filters: &[HeaderFilter],
+    mode: ImportMode,
+    http: &HttpOptions,
+    mut add: F,
+) -> Result<(), Error>
+where
+    F: FnMut(chess_pgn_parser::Game, u32, &Path) -> Result<bool, Error>,
+{
+    for path in files {
+        let reader = match open(&path, http) {
+            Ok(reader) => reader,
+            Err(e) => {
+                warn!(target: FAILED_FILE, "Import of '{}' failed: {}", path.display(), e);
+                continue;
+            }
+        };
+        let mut found = 0;
+        let mut duplicates = 0;
+        let mut filtered = 0;
+        // Chapters keep their number in the file when games before them are filtered out
+        let games = (1..)
+            .zip(read_games(reader))
+            .filter(|(_, game)| match game {
+                Ok(game) if !HeaderFilter::match_all(filters, &game.tags) => {
+                    filtered += 1;
+                    false
+                }
+                _ => true,
+            });
+        for (chapter, game) in games {
+            let added = game.and_then(|game| add(game, chapter, &path));
+            match added {
+                Ok(true) => found += 1,
+                Ok(false) => {
+                    found += 1;
+                    duplicates += 1;
+                }
+                Err(Error::StdIo(e)) => {
+                    warn!(target: FAILED_FILE, "Import of '{}' failed: {}", path.display(), e);
+                    break;
+                }
+                Err(Error::UnknownColor) => warn!(
+                    target: SKIPPED_GAME,
+                    "Import of '{}' skipped game {}: {}",
+                    path.display(),
+                    chapter,
+                    Error::UnknownColor
+                ),
+                Err(Error::PgnParser) => {
+                    warn!(
+                        target: SKIPPED_GAME,
+                        "'{}' contains unreadable game {}",
+                        path.display(),
+                        chapter
+                    )
+                }
+                Err(e) => match (mode, bad_move_number(&e)) {
+                    (ImportMode::Strict, Some(_)) => {
+                        warn!("'{}' contains bad move in game {}", path.display(), chapter);
+                        return Err(e);
+                    }
+                    (ImportMode::Lenient, Some(number)) => warn!(
+                        target: REJECTED_MOVE,
+                        "'{}' contains bad move in game {}; Imported its moves before {}: {}",
+                        path.display(),
+                        chapter,
+                        number,
+                        e
+                    ),
+                    (_, None) => warn!(
+                        target: SKIPPED_GAME,
+                        "'{}' contains bad game {}: {}",
+                        path.display(),
+                        chapter,
+                        e
+                    ),
+                },
+            }
+        }
+        info!("Import of '{}': Found {} games", path.display(), found);
+        if filtered > 0 {
+            info!(
+                "Import of '{}': Skipped {} games not matching the header filters",
+                path.display(),
+                filtered
+            );
+        }
+        if duplicates > 0 {
+            info!(
+                "Import of '{}': Skipped {} duplicate games",
+                path.display(),
+                duplicates
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::import::*;
+
+    #[test]
+    fn it_splits_pgn_text_into_games() {
+        let pgn = "[Event \"A\"]\n[Site \"?\"]\n\n1. e4 e5\n2. Nf3 *\n\n\
+                   [Event \"B\"]\n\n1. d4 {A comment\n[spanning lines]} d5 *\n\n\
+                   1. c4 *\n";
+        let texts: Vec<String> = GameTexts::new(pgn.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(texts.len(), 3);
+        assert!(texts[0].starts_with("[Event \"A\"]"));
+        assert!(texts[0].contains("2. Nf3 *"));
+        assert!(texts[1].contains("[spanning lines]"));
+        assert!(texts[2].contains("1. c4 *"));
+    }
+
+    #[test]
+    fn it_splits_games_after_their_result() {
+        let pgn = "1. e4 e5 1-0 1. d4 {draw by 1/2-1/2 agreement} d5 1/2-1/2\n1. c4 0-1\n";
+        let texts: Vec<String> = GameTexts::new(pgn.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(texts.len(), 3);
+        assert_eq!(texts[0].trim(), "1. e4 e5 1-0");
+        assert!(texts[1].contains("{draw by 1/2-1/2 agreement} d5 1/2-1/2"));
+        assert_eq!(texts[2].trim(), "1. c4 0-1");
+    }
+
+    #[test]
+    fn it_strips_annotation_glyphs_and_null_moves() {
+        assert_eq!(
+            strip_unsupported("1. e4! $1 e5?! 2. Nf3 $14 *"),
+            "1. e4  e5 2. Nf3  *"
+        );
+        assert_eq!(
+            strip_unsupported("1. e4 e5 2. Nf3 Nc6 3. -- Nf6 (3... d6) 4. d4 *"),
+            "1. e4 e5 2. Nf3 Nc6 *"
+        );
+        assert_eq!(
+            strip_unsupported("1. e4 e5 (1... Z0 2. d4) (1... c5 2.Nf3 -- 3. d4) 2. Nf3 *"),
+            "1. e4 e5  (1... c5 2.Nf3 ) 2. Nf3 *"
+        );
+        assert_eq!(
+            strip_unsupported("[Event \"?\"]\n\n1. e4 {Best by test!} -- *"),
+            "[Event \"?\"]\n\n1. e4 {Best by test!} *"
+        );
+    }
+
+    #[test]
+    fn it_selects_games_by_their_headers() {
+        let tags = vec![
+            ("Event".to_owned(), "Repertoire".to_owned()),
+            ("White".to_owned(), "Me".to_owned()),
+        ];
+        let filters = |filters: &[&str]| -> Vec<HeaderFilter> {
+            filters
+                .iter()
+                .map(|filter| filter.parse().unwrap())
+                .collect()
+        };
+        assert!(HeaderFilter::match_all(&[], &tags));
+        assert!(HeaderFilter::match_all(
+            &filters(&["Event=Repertoire"]),
+            &tags
+        ));
+        assert!(!HeaderFilter::match_all(&filters(&["Event=Blitz"]), &tags));
+        assert!(HeaderFilter::match_all(
+            &filters(&["Event=Blitz", "Event=Repertoire", "White = Me"]),
+            &tags
+        ));
+        assert!(!HeaderFilter::match_all(
+            &filters(&["Event=Repertoire", "Black=Me"]),
+            &tags
+        ));
+        assert!("Event".parse::<HeaderFilter>().is_err());
+    }
+
+    #[test]
+    fn it_tells_the_color_of_repertoire_games() {
+        let color = |pgn: &str| repertoire_color(&parse_games(pgn).unwrap()[0]);
+        assert_eq!(
+            color("[RepertoireColor \"Black\"]\n\n1. e4 {Main line} c5 *\n\n"),
+            Some(Player::Black)
+        );
+        assert_eq!(
+            color("[Orientation \"white\"]\n\n1. e4 c5 *\n\n"),
+            Some(Player::White)
+        );
+        assert_eq!(
+            color("1. e4 c5 {The Sicilian} 2. Nf3 d6 {Najdorf next} *\n\n"),
+            Some(Player::Black)
+        );
+        assert_eq!(color("1. e4 c5 *\n\n"), None);
+    }
+
+    #[test]
+    fn it_tells_where_a_game_with_a_bad_move_stopped() {
+        let bad_move = |fen_str: &str| Error::IllegalMove {
+            fen_str: fen_str.to_owned(),
+            mv: "Ke3".to_owned(),
+        };
+        let after_nc6 = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+        assert_eq!(bad_move_number(&bad_move(after_nc6)).unwrap(), "3.");
+        let after_e4 = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        assert_eq!(bad_move_number(&bad_move(after_e4)).unwrap(), "1...");
+        assert_eq!(bad_move_number(&Error::PgnParser), None);
+    }
+
+    #[test]
+    fn it_recognizes_urls_among_paths() {
+        assert!(is_url(Path::new("https://lichess.org/study/abc123.pgn")));
+        assert!(is_url(Path::new("http://localhost:8080/course.pgn")));
+        assert!(!is_url(Path::new("repertoire/https.pgn")));
+    }
+
+    #[test]
+    fn it_reads_games_one_at_a_time() {
+        let pgn = "[Event \"A\"]\n\n1. e4 e5 *\n\n[Event \"B\"]\n\n1. d4 d5 2. c4 *\n";
+        let games: Vec<_> = read_games(pgn.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[1].moves.len(), 3);
+    }
+
+    #[test]
+    fn it_imports_the_pgn_files_of_zip_archives() {
+        use std::io::Write;
+        let path = std::env::temp_dir().join(format!("cro-course-{}.zip", std::process::id()));
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = zip::write::FileOptions::default();
+        writer.start_file("README.txt", options).unwrap();
+        writer.write_all(b"Thanks for buying this course").unwrap();
+        writer
+            .start_file("chapters/1 Open Sicilian.pgn", options)
+            .unwrap();
+        writer
+            .write_all(b"1. e4 c5 2. Nf3 *\n\n1. e4 c5 2. Nc3 *\n")
+            .unwrap();
+        writer.finish().unwrap();
+
+        let files = resolve_to_files(vec![path.clone()], false);
+        assert_eq!(files, vec![path.join("chapters/1 Open Sicilian.pgn")]);
+        let mut optimizer = RepertoireOptimizer::new(Player::Black);
+        import_repertoire(&mut optimizer, files);
+        std::fs::remove_file(&path).unwrap();
+        let sources: Vec<_> = optimizer
+            .positions()
+            .filter_map(|position| optimizer.source(position.fen()))
+            .collect();
+        assert!(!sources.is_empty());
+        assert!(sources
+            .iter()
+            .all(|source| source.to_string().starts_with("1 Open Sicilian.pgn, game")));
+    }
+
+    #[test]
+    fn it_imports_pgn_files_from_directories_and_patterns() {
+        let dir = std::env::temp_dir().join(format!("cro-repertoire-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sicilian")).unwrap();
+        for file in [
+            "main.pgn",
+            "notes.txt",
+            "sicilian/najdorf.PGN",
+            "sicilian/najdorf.bak",
+        ] {
+            File::create(dir.join(file)).unwrap();
+        }
+
+        let mut pgn_files = resolve_to_files(vec![dir.clone()], false);
+        pgn_files.sort();
+        let mut all_files = resolve_to_files(vec![dir.clone()], true);
+        all_files.sort();
+        let matching = resolve_to_files(vec![dir.join("**").join("*.pgn")], false);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            pgn_files,
+            vec![dir.join("main.pgn"), dir.join("sicilian/najdorf.PGN")]
+        );
+        assert_eq!(all_files.len(), 4);
+        assert_eq!(matching, vec![dir.join("main.pgn")]);
+    }
+}
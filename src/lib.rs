@@ -0,0 +1,74 @@
+//! Finds the positions where preparing a chess opening repertoire pays off the most.
+//!
+//! A [`RepertoireOptimizer`] holds the prepared lines of one color as a tree of positions.
+//! The opponent's replies are taken from an [`OpeningBook`], such as the Lichess opening
+//! explorer, preferably wrapped in a [`Cache`] so each position is only requested once.
+//! Frequency models then assign how often each move is played, and the resulting position
+//! frequencies drive the recommendations. The builder takes care of these steps:
+//!
+//! ```
+//! use chess_repertoire_optimizer::{
+//!     BookMoves, Error, ErrorPolicy, Fen, OpeningBook, Player, RepertoireOptimizer,
+//! };
+//!
+//! // Stands in for a book like `Lichess`, which needs network access
+//! struct EmptyBook;
+//!
+//! impl OpeningBook for EmptyBook {
+//!     fn moves(&mut self, _: &Fen) -> Result<BookMoves, Error> {
+//!         Ok(Vec::new())
+//!     }
+//! }
+//!
+//! let optimizer = RepertoireOptimizer::builder(Player::White)
+//!     .pgn("1. e4 e5 2. Nf3 *\n\n1. e4 c5 *\n\n")
+//!     .book(&mut EmptyBook, ErrorPolicy::Abort)
+//!     .build()?;
+//!
+//! let positions = optimizer.own_positions();
+//! let additions = RepertoireOptimizer::recommend_for_addition(&positions, 5);
+//! assert_eq!(additions.len(), 1);
+//! assert_eq!(*additions[0].frequency(), 0.5);
+//! # Ok::<(), Error>(())
+//! ```
+//!
+//! The command line tool is built on this interface alone, so anything it reports can be
+//! computed by embedding applications as well.
+//!
+//! Without the default `network` feature, the crate leaves out the `Lichess` book along with
+//! its HTTP client, for applications reading local books only. Without the default `native`
+//! feature as well, it also leaves out cache files, so the analysis builds for
+//! `wasm32-unknown-unknown` and runs in a browser. The opening explorer is then reached through
+//! [`opening_book::explorer::Explorer`], which sends its requests through an `HttpTransport`
+//! provided by the host, the same abstraction `Lichess` sends its requests through. As a
+//! browser's `fetch` answers asynchronously, the explorer is then used as an [`AsyncOpeningBook`]
+//! with [`RepertoireOptimizer::add_opponents_moves_from_async_book`].
+
+pub mod benchmark;
+mod conversion; // Adapter tools between crates chess_pgn_parser and pleco
+pub mod error;
+pub mod expression;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod frequency_model;
+pub mod import;
+pub mod opening_book;
+pub mod position;
+pub mod position_filter;
+pub mod recommender;
+pub mod repertoire_optimizer;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod summary;
+pub mod trend;
+pub mod warnings;
+
+pub use crate::error::Error;
+#[cfg(feature = "native")]
+pub use crate::opening_book::cache::Cache;
+pub use crate::opening_book::{AsyncOpeningBook, BookMove, BookMoves, ErrorPolicy, OpeningBook};
+pub use crate::position::{Fen, MoveSequence, Position};
+pub use crate::recommender::Recommender;
+pub use crate::repertoire_optimizer::{RepertoireOptimizer, RepertoireOptimizerBuilder};
+pub use crate::summary::Summary;
+pub use pleco::Player;
@@ -1,59 +1,631 @@
-mod conversion; // Adapter tools between crates chess_pgn_parser and pleco
-mod error;
-mod opening_book;
-mod position;
-mod repertoire_optimizer;
-
-use crate::error::Error;
-use crate::opening_book::cache::Cache;
-use crate::opening_book::lichess::Lichess;
-use crate::repertoire_optimizer::RepertoireOptimizer;
-
-use log::{error, info, warn, LevelFilter, Metadata, Record};
+mod archive;
+mod notification;
+mod section;
+mod todo;
+mod tree;
+
+use crate::archive::{pgn_date, write_archive, ArchivedLine};
+use crate::notification::Notifier;
+use crate::section::{Section, DEFAULT_SECTIONS};
+use crate::todo::{write_todos, Todo, TodoFormat};
+use crate::tree::write_trees;
+use chess_repertoire_optimizer::benchmark::{repertoire_pgn, RandomBook};
+use chess_repertoire_optimizer::error::Error;
+use chess_repertoire_optimizer::expression::{Expression, Scored};
+use chess_repertoire_optimizer::frequency_model::{self, FrequencyModel};
+use chess_repertoire_optimizer::import::{
+    import_matching, import_mixed, resolve_to_files, HeaderFilter, ImportMode,
+};
+use chess_repertoire_optimizer::opening_book::cache::{self, Cache, CacheEntry, Checkpoint};
+use chess_repertoire_optimizer::opening_book::engine::Engine;
+use chess_repertoire_optimizer::opening_book::fallback::Fallback;
+use chess_repertoire_optimizer::opening_book::lichess::{self, HttpOptions, Lichess, Query};
+#[cfg(feature = "sqlite")]
+use chess_repertoire_optimizer::opening_book::sqlite::Database;
+use chess_repertoire_optimizer::opening_book::{BookMoves, ErrorPolicy, OpeningBook};
+use chess_repertoire_optimizer::position::{by_frequency_then_fen, Fen, Position};
+use chess_repertoire_optimizer::position_filter::{self, PositionFilter};
+use chess_repertoire_optimizer::recommender::{Addition, Recommender};
+use chess_repertoire_optimizer::repertoire_optimizer::{RepertoireOptimizer, StressTest};
+#[cfg(feature = "scripting")]
+use chess_repertoire_optimizer::script::Scripted;
+use chess_repertoire_optimizer::summary::Summary;
+use chess_repertoire_optimizer::trend::Trend;
+use chess_repertoire_optimizer::warnings::{
+    self, CONFLICTING_MOVES, DETACHED_CHAPTER, DUPLICATE_LINE, EMPTY_BOOK, FAILED_LOOKUP,
+    REPETITION_LINE, TRUNCATED_LINE, UNREACHABLE_LINE, WRONG_SIDE,
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, info, warn, Level, LevelFilter, Metadata, Record};
 use pleco::Player;
-use std::fs::File;
-use std::path::PathBuf;
-use std::time::Instant;
+use rand::SeedableRng;
+use rand_chacha::ChaChaRng;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
 /// Cover the most ground with the least amount of lines prepared!
 #[derive(StructOpt, Debug)]
-#[structopt(name = "Chess Repertoire Optimizer")]
+#[structopt(
+    name = "Chess Repertoire Optimizer",
+    after_help = "Options showing an [env: ...] can also be set by that environment variable; \
+                  Command line flags take precedence over the environment."
+)]
 struct Opt {
-    /// PGN files containing your White repertoire
+    /// PGN files, directories, glob patterns like "repertoire/**/*.pgn" or HTTP(S) URLs
+    /// containing your White repertoire
     #[structopt(short, long, parse(from_os_str))]
     white_repertoire: Vec<PathBuf>,
 
-    /// PGN files containing your Black repertoire
+    /// PGN files, directories, glob patterns or HTTP(S) URLs containing your Black repertoire
     #[structopt(short, long, parse(from_os_str))]
     black_repertoire: Vec<PathBuf>,
 
-    /// Local file for caching opening book moves
+    /// PGN files containing preparation for both colors; Each game is added to the repertoire
+    /// named by its RepertoireColor or Orientation header, or else of the side with more comments
     #[structopt(short, long, parse(from_os_str))]
+    repertoire: Vec<PathBuf>,
+
+    /// Import every file found in directories or by glob patterns, not only .pgn files and .zip
+    /// archives
+    #[structopt(long)]
+    all_extensions: bool,
+
+    /// Abort on the first illegal or ambiguous move in the repertoire files, instead of
+    /// importing its game up to that move
+    #[structopt(long)]
+    strict: bool,
+
+    /// Only import games whose PGN header has this value, like "Event=Repertoire"; Games must
+    /// match a value for every header named, if several are given for the same header any of them
+    #[structopt(long, number_of_values = 1)]
+    filter_header: Vec<HeaderFilter>,
+
+    /// White's first moves your Black repertoire is meant to answer, e.g. e2e4,d2d4; Positions
+    /// after any other first move are reported as everything else instead of as gaps
+    #[structopt(long, use_delimiter = true)]
+    black_scope: Vec<String>,
+
+    /// Only analyze the repertoire of this color ('white' or 'black'), skipping the other entirely
+    #[structopt(long, parse(try_from_str = parse_color))]
+    only: Option<Player>,
+
+    /// Local file for caching opening book moves
+    #[structopt(short, long, env = "CRO_CACHE_FILE", parse(from_os_str))]
     cache_file: Option<PathBuf>,
 
+    /// SQLite database for caching opening book moves instead of a cache file; Entries are
+    /// written as they are fetched and can be shared between concurrent runs.
+    /// Requires building with the `sqlite` feature
+    #[structopt(
+        long,
+        env = "CRO_CACHE_DB",
+        parse(from_os_str),
+        conflicts_with = "cache-file"
+    )]
+    cache_db: Option<PathBuf>,
+
+    /// Write the cache file whenever this many new positions have been fetched
+    #[structopt(long)]
+    checkpoint_entries: Option<usize>,
+
+    /// Write the cache file whenever this many minutes have passed since it was last written
+    #[structopt(long)]
+    checkpoint_minutes: Option<u64>,
+
+    /// Fetch cached book moves again once they are older than this, e.g. '90d';
+    /// Units are s(econds), m(inutes), h(ours), d(ays) and w(eeks); The daemon refreshes
+    /// entries older than 30 days by default
+    #[structopt(long, parse(try_from_str = cache::parse_age))]
+    cache_max_age: Option<Duration>,
+
+    /// Fetch all book moves again, replacing the cached ones
+    #[structopt(long, conflicts_with = "offline")]
+    refresh_cache: bool,
+
+    /// Only use book moves from the cache file; Positions not cached are treated as out of book
+    #[structopt(long)]
+    offline: bool,
+
+    /// Print a quick report from the cached book moves first, then fetch the missing ones and
+    /// print the refreshed report, starting with what changed
+    #[structopt(long, requires = "cache-file", conflicts_with = "offline")]
+    instant: bool,
+
+    /// Base URL of the opening explorer, e.g. a self-hosted lila-openingexplorer or a proxy mirror
+    #[structopt(long, default_value = lichess::DEFAULT_BASE_URL)]
+    explorer_url: String,
+
+    /// How many explorer requests may be in flight at the same time
+    #[structopt(long, default_value = "4")]
+    concurrency: usize,
+
+    /// Seconds after which a stalled explorer request or repertoire download is aborted
+    #[structopt(long, default_value = "30")]
+    http_timeout: u64,
+
+    /// How often a failed explorer request is retried before giving up
+    #[structopt(long, default_value = "5")]
+    http_retries: u32,
+
+    /// Proxy for explorer requests and repertoire downloads, e.g. http://proxy.example.com:8080;
+    /// Defaults to the HTTPS_PROXY environment variable
+    #[structopt(long, env = "CRO_PROXY")]
+    proxy: Option<String>,
+
+    /// Personal lichess API token for authenticating explorer requests and repertoire downloads
+    /// from lichess.org, e.g. of private studies; Never sent to other hosts
+    #[structopt(long, env = "CRO_API_TOKEN", hide_env_values = true)]
+    api_token: Option<String>,
+
+    /// Rating bands of the explorer games, e.g. 1600,1800,2000
+    #[structopt(
+        long,
+        use_delimiter = true,
+        env = "CRO_RATINGS",
+        default_value = "1600,1800,2000,2200,2500",
+        parse(try_from_str = lichess::parse_rating)
+    )]
+    ratings: Vec<u32>,
+
+    /// Time controls of the explorer games, e.g. blitz,rapid (also: ultraBullet, bullet, classical, correspondence)
+    #[structopt(
+        long,
+        use_delimiter = true,
+        default_value = "blitz,rapid,classical",
+        parse(try_from_str = lichess::parse_speed)
+    )]
+    speeds: Vec<String>,
+
+    /// Only use explorer games played in or after this month, e.g. 2022-01
+    #[structopt(long, parse(try_from_str = lichess::parse_month))]
+    since: Option<String>,
+
+    /// Only use explorer games played in or before this month, e.g. 2024-06
+    #[structopt(long, parse(try_from_str = lichess::parse_month))]
+    until: Option<String>,
+
+    /// What to do when book moves can't be fetched: abort, skip or retry:<count>
+    #[structopt(long, default_value = "abort")]
+    on_book_error: ErrorPolicy,
+
+    /// UCI engine (e.g. Stockfish) used as book for positions with too few games
+    #[structopt(long, parse(from_os_str))]
+    engine: Option<PathBuf>,
+
+    /// Minimum number of explorer games before the engine is consulted instead
+    #[structopt(long, default_value = "10")]
+    engine_min_games: u32,
+
+    /// How many of the engine's top moves to turn into book moves
+    #[structopt(long, default_value = "3")]
+    engine_lines: usize,
+
+    /// Search depth for engine analysis
+    #[structopt(long, default_value = "18")]
+    engine_depth: u32,
+
+    /// Softmax temperature in pawns; higher values spread frequencies more evenly
+    #[structopt(long, default_value = "0.5")]
+    engine_temperature: f64,
+
+    /// How your own prepared moves are weighted: uniform, popularity, score or recency
+    #[structopt(long, default_value = "uniform", parse(try_from_str = frequency_model::by_name))]
+    own_model: Box<dyn FrequencyModel + Send + Sync>,
+
+    /// Weight your own moves by the order of the chapters in your PGN files instead of by
+    /// --own-model, as the first chapters of a study usually hold the main recommendations;
+    /// A "Priority" header overrides the chapter number. Groups recommended additions by chapter
+    #[structopt(long)]
+    chapter_priority: bool,
+
+    /// Group the positions recommended for addition by the position after your move they stem
+    /// from, so gaps in the same branch of your preparation are listed together
+    #[structopt(long, conflicts_with = "chapter-priority")]
+    cluster: bool,
+
+    /// How your opponents' moves are weighted: uniform, popularity, score or recency
+    #[structopt(long, default_value = "popularity", parse(try_from_str = frequency_model::by_name))]
+    opponent_model: Box<dyn FrequencyModel + Send + Sync>,
+
     /// How many frequent positions to recommend for addition
     #[structopt(long, default_value = "10")]
     best: usize,
 
+    /// Rhai script ranking the positions recommended for addition by its `score(position)`
+    /// function instead of by frequency. Requires building with the `scripting` feature
+    #[structopt(long, parse(from_os_str))]
+    score_script: Option<PathBuf>,
+
+    /// Rank the positions recommended for addition by this expression over their frequency,
+    /// transitions and ply instead, e.g. "frequency / (1 + transitions)"
+    #[structopt(long, conflicts_with = "score-script")]
+    score_expr: Option<Expression>,
+
+    /// Rank the positions recommended for addition by their frequency per position of yours to
+    /// learn to cover them this many half-moves deep, following the most popular book moves;
+    /// Only your own moves take learning, so an even depth ranks like the odd one below it
+    #[structopt(long, default_value = "0")]
+    lookahead: u32,
+
+    /// Instead of the most frequent positions, recommend the set of additions keeping you in book
+    /// for the most half-moves with at most this many positions of yours to learn, covering each
+    /// as deep as --lookahead or one half-move; Chosen among three times --best of the most
+    /// frequent
+    #[structopt(long)]
+    budget: Option<usize>,
+
+    /// How many candidate replies to suggest for each position recommended for addition,
+    /// with their popularity, score and the number of common answers to prepare after them
+    #[structopt(long, default_value = "0")]
+    candidates: usize,
+
+    /// For each position recommended for addition, look for earlier moves of yours avoiding it
+    /// that reach positions you have prepared by other move orders
+    #[structopt(long)]
+    move_orders: bool,
+
+    /// Estimate the rating points per 100 games you give away by improvising in unprepared
+    /// positions, from the --engine's evaluation of the common book moves against its best move,
+    /// or from the scores of the book moves without an engine
+    #[structopt(long)]
+    rating_at_stake: bool,
+
     /// How many infrequent positions to recommend for removal
     #[structopt(long, default_value = "0")]
     worst: usize,
 
+    /// PGN file to append the lines through positions recommended for removal to, with the date,
+    /// reason and statistics, so they can be restored by importing the file again
+    #[structopt(long, parse(from_os_str))]
+    archive: Option<PathBuf>,
+
     /// How many positions with many candidates to show
     #[structopt(long, default_value = "0")]
     most: usize,
 
+    /// How many positions to show where opponents most often leave your prepared lines
+    #[structopt(long, default_value = "0")]
+    hotspots: usize,
+
+    /// How many of the opponent's most frequent branching moves to show popularity trends for,
+    /// based on the explorer's monthly game counts
+    #[structopt(long, default_value = "0")]
+    trends: usize,
+
     /// How many expensive choices to show
     #[structopt(long, default_value = "0")]
     costly: usize,
 
+    /// How many of your prepared positions to show where your move is easiest to confuse with
+    /// common alternatives, with how often to drill them relative to obvious ones
+    #[structopt(long, default_value = "0")]
+    difficult: usize,
+
+    /// How many of your most frequent prepared positions to show where your move is hardly ever
+    /// played at your level; This looks up your own positions in the book too, which takes
+    /// additional requests
+    #[structopt(long, default_value = "0")]
+    exotic: usize,
+
+    /// How many plies from the start your lines should reach for an ECO chapter to count as
+    /// complete in the scoreboard; Chapters are taken from the ECO headers of your PGN files
+    #[structopt(long, default_value = "16")]
+    completeness_depth: usize,
+
+    /// Show every prepared line with the most recent year it was seen in strong practice
+    #[structopt(long)]
+    line_ages: bool,
+
+    /// Flag lines for review whose most recent year in strong practice is more than this
+    /// many years ago
+    #[structopt(long, default_value = "10")]
+    stale_after: u32,
+
+    /// Flag prepared lines longer than this many half-moves, with where they could be cut short
+    #[structopt(long)]
+    max_line_length: Option<usize>,
+
+    /// Cross-check the statistics by simulating this many random games through your repertoire
+    #[structopt(long, default_value = "0")]
+    simulate: usize,
+
+    /// Seed for the random number generator, to make simulations reproducible
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Measure the repertoire in unique decisions, counting a move prepared in several positions
+    /// once, as suits system openings like the London where your moves hardly depend on the
+    /// opponent's
+    #[structopt(long)]
+    systems: bool,
+
+    /// Only recommend positions where one side has given up material
+    #[structopt(long)]
+    only_gambits: bool,
+
+    /// Don't recommend positions with little material left on the board
+    #[structopt(long)]
+    exclude_endgames: bool,
+
+    /// Only recommend positions with at least this many pieces (including pawns and kings)
+    #[structopt(long)]
+    min_pieces: Option<u32>,
+
+    /// Only recommend positions with at most this many pieces (including pawns and kings)
+    #[structopt(long)]
+    max_pieces: Option<u32>,
+
+    /// Only recommend positions where the material differs by at most this many pawns
+    #[structopt(long)]
+    max_imbalance: Option<u32>,
+
+    /// Which sections of the report to print, in this order; Sections whose count is zero are
+    /// left out regardless
+    #[structopt(
+        long,
+        use_delimiter = true,
+        env = "CRO_SECTIONS",
+        default_value = DEFAULT_SECTIONS
+    )]
+    sections: Vec<Section>,
+
+    /// Write the position tree of each repertoire to this file as JSON, with the frequency
+    /// assigned to every move and its popularity in the book
+    #[structopt(long, parse(from_os_str))]
+    tree: Option<PathBuf>,
+
+    /// Report the outcome when done: 'webhook:<url>' posts a JSON summary, 'desktop' shows a notification
+    #[structopt(long)]
+    notify: Vec<Notifier>,
+
+    /// Never prompt or draw progress output, e.g. when running as a scheduled job
+    #[structopt(long)]
+    non_interactive: bool,
+
+    /// Directory to write the report, summary and cache file to, instead of printing the report
+    #[structopt(long, env = "CRO_OUTPUT_DIR", parse(from_os_str))]
+    output_dir: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
     /// Print more additional information
     #[structopt(name="verbose", long, parse(from_occurrences = log_level))]
     log_level: LevelFilter,
 }
 
-struct Logger;
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Export the recommendations instead of printing the report
+    Export {
+        /// Write the recommendations as a checklist: markdown or todotxt
+        #[structopt(long)]
+        todo: TodoFormat,
+
+        /// File to write the checklist to, instead of printing it
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Only download book moves for your repertoire into the cache file, without analyzing it
+    Prefetch,
+    /// Check your repertoire for duplicate, conflicting, unreachable and truncated lines and files
+    /// passed for the wrong color, without looking up any book moves
+    Lint,
+    /// Play your repertoire against an opponent sticking to popular book moves,
+    /// reporting where games leave your preparation ahead of the usual report
+    StressTest {
+        /// How many games to play per color
+        #[structopt(long, default_value = "1000")]
+        games: usize,
+
+        /// Share of games below which the opponent never plays a book move
+        #[structopt(long, default_value = "0.05")]
+        min_share: f64,
+
+        /// How many of the positions where games leave your preparation to show
+        #[structopt(long, default_value = "5")]
+        exits: usize,
+    },
+    /// Work with cache files directly, without analyzing a repertoire
+    Cache(CacheCommand),
+    /// Keep running in the background, slowly refreshing stale cache entries and looking up
+    /// the positions beyond your most frequent unprepared ones, so later runs are served from
+    /// the cache
+    Daemon {
+        /// Seconds to wait before each request to the explorer
+        #[structopt(long, default_value = "30")]
+        pause: u64,
+
+        /// Only look beyond unprepared positions reached in at least this share of games
+        #[structopt(long, default_value = "0.01")]
+        min_frequency: f64,
+    },
+    /// Measure how long importing, looking up book moves, propagating frequencies and
+    /// writing the results take on a generated repertoire and book, without network access;
+    /// Use --seed to compare runs on the same workload
+    Benchmark {
+        /// Games in the generated repertoire of each color
+        #[structopt(long, default_value = "1000")]
+        lines: usize,
+
+        /// Half-moves in each generated game
+        #[structopt(long, default_value = "20")]
+        plies: usize,
+
+        /// Moves the generated book knows in each position
+        #[structopt(long, default_value = "5")]
+        book_moves: usize,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum CacheCommand {
+    /// Combine cache files, e.g. from teammates or other machines; Of positions cached in
+    /// several files, the most recently fetched book moves are kept
+    Merge {
+        /// Cache files to combine
+        #[structopt(parse(from_os_str), required = true, min_values = 2)]
+        inputs: Vec<PathBuf>,
+
+        /// File to write the combined cache to
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Show the number of entries, the size on disk and how deep into the game the cached
+    /// positions are
+    Stats {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Remove entries which are unlikely to be needed again, so the cache file doesn't grow
+    /// without bound as your repertoire changes
+    Prune {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Remove positions reached after more than this many half-moves
+        #[structopt(long)]
+        max_ply: Option<u32>,
+
+        /// Remove positions not used by any run for this long, e.g. '180d';
+        /// Units are s(econds), m(inutes), h(ours), d(ays) and w(eeks)
+        #[structopt(long, parse(try_from_str = cache::parse_age))]
+        unused_for: Option<Duration>,
+
+        /// File to write the pruned cache to, instead of replacing the original one
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Print the cached book moves, of all positions or a single one; The cache doesn't keep
+    /// the FEN of positions, so they are listed by their Zobrist hash
+    Dump {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Only print the book moves of this position, given as FEN
+        #[structopt(long, parse(try_from_str = parse_fen))]
+        fen: Option<Fen>,
+    },
+    /// Write all entries in a portable format, which stays readable when the layout of cache
+    /// files changes
+    Export {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Format of the export; Only 'json' is supported
+        #[structopt(long, default_value = "json")]
+        format: ExportFormat,
+
+        /// File to write the export to, instead of the standard output
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Read an export into a cache file; Entries of positions it already holds are replaced if
+    /// the exported ones were fetched more recently
+    Import {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Format of the export; Only 'json' is supported
+        #[structopt(long, default_value = "json")]
+        format: ExportFormat,
+
+        /// Cache file to import into, which is created if it doesn't exist
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
+}
+
+/// Portable formats of cache exports
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExportFormat {
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!(
+                "Unknown export format '{}'; Expected: json",
+                format
+            )),
+        }
+    }
+}
+
+// Repertoires with fewer prepared moves are too small for meaningful statistics
+static MIN_PREPARED_MOVES: usize = 3;
+
+// How many of the moves prepared in the most positions to list for system openings
+static MOST_REUSED_DECISIONS: usize = 5;
+
+// Ranking by learning cost looks this many times as many additions up as it shows, as looking up
+// the book moves for every unprepared position would take too long
+static LOOKAHEAD_POOL: usize = 3;
+
+// How many first moves to suggest when getting started with a repertoire
+static GETTING_STARTED_MOVES: usize = 5;
+
+// Average length of a year in the Gregorian calendar
+static SECONDS_PER_YEAR: u64 = 31_556_952;
+
+// How long the daemon waits before looking for work again when there was none
+static DAEMON_IDLE: Duration = Duration::from_secs(60 * 60);
+
+// The daemon refreshes entries older than this, unless --cache-max-age is given
+static DAEMON_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// Names of the files written to the output directory
+static REPORT_FILE: &str = "report.txt";
+static SUMMARY_FILE: &str = "summary.json";
+static CACHE_FILE: &str = "cache.bin";
+
+struct Logger {
+    // How many warnings were logged for each target, for the summary at the end of the run
+    warnings: Mutex<BTreeMap<String, usize>>,
+}
+
+impl Logger {
+    // Counts warnings which were only logged at debug level, as there are too many to show
+    fn count_warnings(&self, target: &str, count: usize) {
+        if count > 0 {
+            *self
+                .warnings
+                .lock()
+                .unwrap()
+                .entry(target.to_owned())
+                .or_default() += count;
+        }
+    }
+
+    // Repeats how many of each kind of warnings were logged, once they have scrolled off screen
+    fn summarize_warnings(&self) {
+        let warnings = self.warnings.lock().unwrap();
+        let total: usize = warnings.values().sum();
+        if total > 0 {
+            println!("{} - {} warnings during the run:", Level::Warn, total);
+            let counts = warnings
+                .iter()
+                .map(|(target, count)| (target.as_str(), *count));
+            for line in warnings::summary(counts) {
+                println!("  {}", line);
+            }
+        }
+    }
+}
 
 impl log::Log for Logger {
     fn enabled(&self, _: &Metadata) -> bool {
@@ -62,6 +634,14 @@ impl log::Log for Logger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
+            if record.level() == Level::Warn {
+                *self
+                    .warnings
+                    .lock()
+                    .unwrap()
+                    .entry(record.target().to_owned())
+                    .or_default() += 1;
+            }
             println!("{} - {}", record.level(), record.args());
         }
     }
@@ -69,7 +649,9 @@ impl log::Log for Logger {
     fn flush(&self) {}
 }
 
-static LOGGER: Logger = Logger;
+static LOGGER: Logger = Logger {
+    warnings: Mutex::new(BTreeMap::new()),
+};
 
 fn log_level(verbosity: u64) -> LevelFilter {
     match verbosity {
@@ -79,195 +661,2027 @@ fn log_level(verbosity: u64) -> LevelFilter {
     }
 }
 
-fn resolve_to_files(paths: Vec<PathBuf>) -> Vec<PathBuf> {
-    let mut files = Vec::new();
-    for path in paths {
-        if path.is_dir() {
-            info!(
-                "'{}' is a directory; Importing all files from within...",
-                path.display()
-            );
-            let subpaths = path
-                .read_dir()
-                .unwrap()
-                .map(|entry| entry.unwrap().path())
-                .collect();
-            files.append(&mut resolve_to_files(subpaths));
-        } else {
-            files.push(path);
+fn position_filter(opt: &Opt) -> position_filter::All {
+    let mut filter = position_filter::All::new();
+    if opt.only_gambits {
+        filter.add(position_filter::is_gambit);
+    }
+    if opt.exclude_endgames {
+        filter.add(|pos: &Position| !position_filter::is_endgame(pos));
+    }
+    if let Some(min_pieces) = opt.min_pieces {
+        filter.add(move |pos: &Position| position_filter::piece_count(pos) >= min_pieces);
+    }
+    if let Some(max_pieces) = opt.max_pieces {
+        filter.add(move |pos: &Position| position_filter::piece_count(pos) <= max_pieces);
+    }
+    if let Some(max_imbalance) = opt.max_imbalance {
+        filter.add(move |pos: &Position| position_filter::material_imbalance(pos) <= max_imbalance);
+    }
+    filter
+}
+
+fn print_getting_started(
+    me: Player,
+    opening_book: &mut dyn OpeningBook,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let mut book_moves = opening_book.moves(&Fen::starting_board())?;
+    book_moves.sort_by(|a, b| {
+        b.frequency
+            .total_cmp(&a.frequency)
+            .then_with(|| a.uci.cmp(&b.uci))
+    });
+    book_moves.truncate(GETTING_STARTED_MOVES);
+
+    writeln!(out)?;
+    writeln!(out, "## Getting started with your {} repertoire ##", me)?;
+    match me {
+        Player::White => {
+            writeln!(
+                out,
+                "Begin by choosing your first move. These are the most popular at your level:"
+            )?;
+        }
+        Player::Black => {
+            writeln!(
+                out,
+                "Begin by preparing a reply to each of White's most popular first moves:"
+            )?;
         }
     }
-    files
+    writeln!(out)?;
+    for book_move in &book_moves {
+        writeln!(
+            out,
+            "1.{} (played in {:.1}% of games)",
+            book_move.uci,
+            100.0 * book_move.frequency
+        )?;
+    }
+    if me == Player::Black {
+        writeln!(
+            out,
+            "=> Replies to these moves cover {:.1}% of your games as Black",
+            100.0 * book_moves.iter().map(|mv| mv.frequency).sum::<f64>()
+        )?;
+    }
+    Ok(())
 }
 
-pub fn main() -> Result<(), Error> {
-    let now = Instant::now();
+fn print_stress_test(
+    optimizer: &RepertoireOptimizer,
+    result: &StressTest,
+    exits: usize,
+    seed: u64,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let games = result.games.max(1) as f64;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "## Stress test of your {} repertoire ##",
+        optimizer.player()
+    )?;
+    writeln!(
+        out,
+        "Played {} games against popular book moves (seed {})",
+        result.games, seed
+    )?;
+    writeln!(
+        out,
+        "The opponent left your preparation in {} games ({:.1}%)",
+        result.left_preparation,
+        100.0 * result.left_preparation as f64 / games
+    )?;
+    writeln!(
+        out,
+        "You played {:.2} prepared moves per game (analysis expects {:.2})",
+        result.prepared_moves_played as f64 / games,
+        optimizer.average_book_length
+    )?;
+    if exits > 0 && result.left_preparation > 0 {
+        writeln!(out, "Most frequent positions where you were out-of-book:")?;
+        for (count, line) in result.most_frequent_exits(exits) {
+            writeln!(out, "{} games: {}", count, line)?;
+        }
+    }
+    Ok(())
+}
 
-    let opt = Opt::from_args();
-    let mut positions = Vec::new();
+// Stays invisible when stderr is not a terminal
+fn fetch_progress() -> ProgressBar {
+    let style = ProgressStyle::with_template(
+        "Fetching book moves {bar:40} {pos}/{len} positions ({msg}), ETA {eta}",
+    )
+    .expect("Progress template is valid");
+    ProgressBar::new(0).with_style(style)
+}
+
+fn print_trends(
+    explorer: &Lichess,
+    optimizers: &[RepertoireOptimizer],
+    count: usize,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let mut moves: Vec<(&Position, &Position)> = optimizers
+        .iter()
+        .flat_map(|optimizer| optimizer.branching_moves())
+        .collect();
+    moves.sort_by(|(a_parent, a), (b_parent, b)| {
+        by_frequency_then_fen(
+            (*a.frequency(), a_parent.fen()),
+            (*b.frequency(), b_parent.fen()),
+        )
+        .then_with(|| a.fen().tie_break(b.fen()))
+    });
+    moves.truncate(count);
+    info!("fetching popularity trends...");
+    let fens: Vec<Fen> = moves
+        .iter()
+        .flat_map(|(parent, child)| vec![parent.fen().clone(), child.fen().clone()])
+        .collect();
+    let mut histories = explorer.histories(&fens).into_iter();
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "## Popularity trends of your opponents' most frequent branching moves ##"
+    )?;
+    writeln!(
+        out,
+        "Consider prioritizing lines which are gaining popularity"
+    )?;
+    writeln!(out)?;
+    for (_, child) in moves {
+        let (parent_history, child_history) = match (histories.next(), histories.next()) {
+            (Some(Ok(parent)), Some(Ok(child))) => (parent, child),
+            (Some(Err(e)), _) | (_, Some(Err(e))) => {
+                warn!("Failed to fetch the history of {}: {}", child.sequence(), e);
+                continue;
+            }
+            _ => continue,
+        };
+        match Trend::new(&parent_history, &child_history) {
+            Some(trend) => writeln!(out, "{}: {}", child.sequence(), trend)?,
+            None => writeln!(out, "{}: too few games to tell", child.sequence())?,
+        }
+    }
+    Ok(())
+}
+
+fn parse_color(color: &str) -> Result<Player, String> {
+    match color {
+        "white" => Ok(Player::White),
+        "black" => Ok(Player::Black),
+        _ => Err(format!(
+            "Unknown color '{}'; Expected 'white' or 'black'",
+            color
+        )),
+    }
+}
+
+fn parse_fen(fen: &str) -> Result<Fen, String> {
+    match fen.split_whitespace().count() {
+        6 => Ok(Fen::new(fen)),
+        // The move counters don't matter for looking up a position
+        4 => Ok(Fen::new(&format!("{} 0 1", fen))),
+        _ => Err(format!(
+            "Invalid FEN '{}'; Expected 6 fields separated by spaces",
+            fen
+        )),
+    }
+}
+
+fn current_year() -> u32 {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    1970 + (seconds / SECONDS_PER_YEAR) as u32
+}
+
+// Describes where a position occurs, for naming todos
+fn after_line(position: &Position) -> String {
+    if position.sequence().is_empty() {
+        "in the starting position".to_owned()
+    } else {
+        format!("after {}", position.sequence().san())
+    }
+}
+
+fn settle_on_fewer_moves(position: &Position) -> Todo {
+    Todo {
+        action: format!("Settle on fewer moves {}", after_line(position)),
+        frequency: *position.frequency(),
+        cost: position.transition_count() - 1,
+    }
+}
+
+pub fn main() -> Result<(), Error> {
+    let mut opt = Opt::from_args();
+    if let Some(ref dir) = opt.output_dir {
+        if opt.cache_file.is_none() && opt.cache_db.is_none() {
+            opt.cache_file = Some(dir.join(CACHE_FILE));
+        }
+    }
 
     log::set_logger(&LOGGER).map(|()| log::set_max_level(opt.log_level))?;
+    if opt.non_interactive && opt.notify.contains(&Notifier::Desktop) {
+        warn!("Desktop notifications are not available in non-interactive mode");
+        opt.notify.retain(|notifier| notifier != &Notifier::Desktop);
+    }
 
-    let mut white_repertoire_optimizer = RepertoireOptimizer::new(Player::White);
-    let mut black_repertoire_optimizer = RepertoireOptimizer::new(Player::Black);
-    let mut opening_book = Cache::new(Lichess::new());
+    let result = run(&opt);
+    LOGGER.summarize_warnings();
+    for notifier in &opt.notify {
+        if let Err(e) = notifier.notify(&result) {
+            error!("Failed to send notification: {}", e);
+        }
+    }
+    result.map(|_| ())
+}
 
-    if let Some(ref path) = opt.cache_file {
-        if path.exists() {
-            match opening_book.load(File::open(path)?) {
-                Err(e) => {
-                    error!("Failed to read cache file '{}': {:?}", path.display(), e);
-                    return Err(e);
+fn prefetch(
+    opt: &Opt,
+    mut optimizers: Vec<RepertoireOptimizer>,
+    mut opening_book: Cache,
+    started: Instant,
+) -> Result<Summary, Error> {
+    let path = opt
+        .cache_file
+        .as_ref()
+        .or(opt.cache_db.as_ref())
+        .ok_or_else(|| {
+            Error::Options("Prefetching requires a cache file or database".to_owned())
+        })?;
+    info!("Prefetching book moves...");
+    add_book_moves(opt, &mut optimizers, &mut opening_book)?;
+    save_cache(opt, &mut opening_book)?;
+    info!("Book moves saved to '{}'", path.display());
+    Ok(Summary {
+        runtime_seconds: started.elapsed().as_millis() as f64 / 1000.0,
+        ..Summary::default()
+    })
+}
+
+// Waits in short steps, so an interrupt is noticed promptly; Returns false if interrupted
+fn pause(duration: Duration, interrupted: &AtomicBool) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        if interrupted.load(Ordering::SeqCst) {
+            return false;
+        }
+        std::thread::sleep(
+            duration
+                .saturating_sub(started.elapsed())
+                .min(Duration::from_secs(1)),
+        );
+    }
+    !interrupted.load(Ordering::SeqCst)
+}
+
+// Looks up a position, pausing first if that takes a request, which is counted in `fetched`;
+// Failed requests are logged, as the daemon tries again in the next round anyway
+fn fetch_slowly(
+    opening_book: &mut Cache,
+    fen: &Fen,
+    delay: Duration,
+    interrupted: &AtomicBool,
+    fetched: &mut usize,
+) -> Result<Option<BookMoves>, Error> {
+    let cached = opening_book.contains(fen);
+    if !cached && !pause(delay, interrupted) {
+        return Err(Error::Interrupted);
+    }
+    match opening_book.moves(fen) {
+        Ok(book_moves) => {
+            if !cached {
+                *fetched += 1;
+            }
+            Ok(Some(book_moves))
+        }
+        Err(Error::Interrupted) => Err(Error::Interrupted),
+        Err(e) => {
+            warn!(target: FAILED_LOOKUP, "Failed to fetch '{}': {}", fen.fen_str(), e);
+            Ok(None)
+        }
+    }
+}
+
+// Looks up the book moves of the repertoires at the daemon's pace, so starting it doesn't send
+// a burst of requests; Positions failing to fetch are left out of book, and later rounds try
+// them again
+fn add_book_moves_slowly(
+    opt: &Opt,
+    optimizers: &mut [RepertoireOptimizer],
+    opening_book: &mut Cache,
+    delay: Duration,
+    interrupted: &AtomicBool,
+) -> Result<usize, Error> {
+    let mut fetched = 0;
+    for optimizer in optimizers.iter_mut() {
+        let mut fens = Vec::new();
+        let mut results = Vec::new();
+        for fen in optimizer.book_positions() {
+            if let Some(book_moves) =
+                fetch_slowly(opening_book, &fen, delay, interrupted, &mut fetched)?
+            {
+                fens.push(fen);
+                results.push(Ok(book_moves));
+            }
+        }
+        optimizer.add_book_moves(&fens, results, opening_book, opt.on_book_error)?;
+    }
+    Ok(fetched)
+}
+
+fn stop_daemon(opt: &Opt, opening_book: &mut Cache, started: Instant) -> Result<Summary, Error> {
+    save_cache(opt, opening_book)?;
+    info!("Daemon stopped");
+    Ok(Summary {
+        runtime_seconds: started.elapsed().as_millis() as f64 / 1000.0,
+        ..Summary::default()
+    })
+}
+
+fn daemon(
+    opt: &Opt,
+    mut optimizers: Vec<RepertoireOptimizer>,
+    mut opening_book: Cache,
+    interrupted: &AtomicBool,
+    started: Instant,
+) -> Result<Summary, Error> {
+    let (delay, min_frequency) = match opt.command {
+        Some(Command::Daemon {
+            pause,
+            min_frequency,
+        }) => (Duration::from_secs(pause), min_frequency),
+        _ => unreachable!("Only called for the daemon command"),
+    };
+    if opt.cache_file.is_none() && opt.cache_db.is_none() {
+        return Err(Error::Options(
+            "The daemon requires a cache file or database".to_owned(),
+        ));
+    }
+    info!("Daemon started; Looking up the book moves of your repertoires...");
+    let added = add_book_moves_slowly(opt, &mut optimizers, &mut opening_book, delay, interrupted);
+    match added {
+        Ok(fetched) => info!("{} positions fetched", fetched),
+        Err(Error::Interrupted) => return stop_daemon(opt, &mut opening_book, started),
+        Err(e) => {
+            save_cache(opt, &mut opening_book)?;
+            return Err(e);
+        }
+    }
+    update_frequencies(opt, &mut optimizers);
+    let mut frontier: Vec<&Position> = optimizers
+        .iter()
+        .flat_map(|optimizer| optimizer.own_positions())
+        .filter(|pos| pos.transition_count() == 0 && *pos.frequency() >= min_frequency)
+        .collect();
+    frontier.sort_by(|a, b| {
+        by_frequency_then_fen((*a.frequency(), a.fen()), (*b.frequency(), b.fen()))
+    });
+    info!("Looking beyond {} unprepared positions...", frontier.len());
+    // The cache only knows positions by their Zobrist hash, so it checks those of the repertoires
+    let book_positions: Vec<Fen> = optimizers
+        .iter()
+        .flat_map(RepertoireOptimizer::book_positions)
+        .collect();
+    let max_age = opt.cache_max_age.unwrap_or(DAEMON_MAX_AGE);
+    let mut round = || -> Result<usize, Error> {
+        opening_book.set_max_age(max_age);
+        let stale = opening_book.stale_positions(&book_positions);
+        let mut fetched = 0;
+        // Stale positions first, oldest first, then those which failed to fetch before
+        for fen in stale.iter().chain(&book_positions) {
+            fetch_slowly(&mut opening_book, fen, delay, interrupted, &mut fetched)?;
+        }
+        // My likeliest move in each unprepared position, and the opponent's replies to it
+        for position in &frontier {
+            let book_moves = fetch_slowly(
+                &mut opening_book,
+                position.fen(),
+                delay,
+                interrupted,
+                &mut fetched,
+            )?;
+            let likeliest = book_moves
+                .into_iter()
+                .flatten()
+                .max_by(|a, b| a.frequency.total_cmp(&b.frequency));
+            if let Some(book_move) = likeliest {
+                let after = position.after_uci(&book_move.uci)?;
+                fetch_slowly(&mut opening_book, &after, delay, interrupted, &mut fetched)?;
+            }
+        }
+        save_cache(opt, &mut opening_book)?;
+        Ok(fetched)
+    };
+    loop {
+        match round() {
+            Ok(0) => {
+                info!("Cache is up to date; Checking again in an hour...");
+                if !pause(DAEMON_IDLE, interrupted) {
+                    break;
                 }
-                Ok(_) => info!("Cache file '{}' loaded successfully...", path.display()),
             }
-        } else {
-            info!(
-                "Cache file '{}' not found; Will be created...",
-                path.display()
-            );
+            Ok(fetched) => info!("{} positions fetched and saved", fetched),
+            Err(Error::Interrupted) => break,
+            Err(e) => return Err(e),
         }
     }
+    stop_daemon(opt, &mut opening_book, started)
+}
 
-    info!("Importing lines...");
-    for path in resolve_to_files(opt.white_repertoire) {
-        match RepertoireOptimizer::read_games(&path) {
-            Ok(games) => {
-                info!(
-                    "Import of '{}': Found {} games",
-                    path.display(),
-                    games.len()
-                );
-                for game in games {
-                    if let Err(e) = white_repertoire_optimizer.add_game_to_repertoire(game) {
-                        warn!("'{}' contains bad move: {}", path.display(), e);
+// Time since `stage` started, restarting it for the next stage
+fn lap(stage: &mut Instant) -> Duration {
+    let elapsed = stage.elapsed();
+    *stage = Instant::now();
+    elapsed
+}
+
+fn benchmark(
+    opt: &Opt,
+    lines: usize,
+    plies: usize,
+    book_moves: usize,
+    started: Instant,
+) -> Result<Summary, Error> {
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    info!(
+        "Benchmarking {} games of {} plies per color with seed {}...",
+        lines, plies, seed
+    );
+    let mut book = RandomBook::new(book_moves, seed);
+    let mut stage = Instant::now();
+    let pgns: Vec<(Player, String)> = [Player::White, Player::Black]
+        .iter()
+        .map(|&me| (me, repertoire_pgn(me, lines, plies, seed)))
+        .collect();
+    let mut timings = vec![("generate", lap(&mut stage))];
+
+    let mut optimizers = Vec::new();
+    for (me, pgn) in &pgns {
+        let mut optimizer = RepertoireOptimizer::new(*me);
+        optimizer.add_pgn(pgn)?;
+        optimizer.set_own_move_stats(opt.own_model.uses_stats());
+        optimizers.push(optimizer);
+    }
+    timings.push(("import", lap(&mut stage)));
+
+    for optimizer in optimizers.iter_mut() {
+        optimizer.add_opponents_moves_from_book(&mut book, ErrorPolicy::Abort)?;
+    }
+    timings.push(("book moves", lap(&mut stage)));
+
+    for optimizer in optimizers.iter_mut() {
+        optimizer.set_own_move_frequencies(opt.own_model.as_ref());
+        optimizer.set_opponent_move_frequencies(opt.opponent_model.as_ref());
+        optimizer.update_position_frequencies();
+    }
+    timings.push(("propagation", lap(&mut stage)));
+
+    let positions: Vec<&Position> = optimizers
+        .iter()
+        .flat_map(|optimizer| optimizer.own_positions())
+        .collect();
+    let recommendations = [
+        RepertoireOptimizer::recommend_for_addition(&positions, opt.best),
+        RepertoireOptimizer::recommend_for_removal(&positions, opt.worst),
+    ];
+    timings.push(("recommendation", lap(&mut stage)));
+
+    let mut sink = io::sink();
+    for position in recommendations.iter().flatten() {
+        writeln!(sink, "{}", position)?;
+    }
+    write_trees(&optimizers, &mut sink)?;
+    timings.push(("rendering", lap(&mut stage)));
+
+    let mut out = io::stdout();
+    writeln!(
+        out,
+        "## Benchmark of {} games of {} plies per color (seed {}) ##",
+        lines, plies, seed
+    )?;
+    for (name, duration) in timings {
+        writeln!(
+            out,
+            "{:<16}{:>10.1} ms",
+            name,
+            duration.as_secs_f64() * 1000.0
+        )?;
+    }
+    let mut summary = Summary::new(&optimizers);
+    summary.runtime_seconds = started.elapsed().as_millis() as f64 / 1000.0;
+    Ok(summary)
+}
+
+#[cfg(feature = "sqlite")]
+fn open_cache_db(opening_book: &mut Cache, path: &Path) -> Result<(), Error> {
+    opening_book.set_store(Database::open(path)?);
+    info!("Cache database '{}' opened...", path.display());
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_cache_db(_: &mut Cache, _: &Path) -> Result<(), Error> {
+    Err(Error::Options(
+        "Cache databases require building with the 'sqlite' feature".to_owned(),
+    ))
+}
+
+fn addition_recommender(opt: &Opt) -> Result<Box<dyn Recommender>, Error> {
+    match (&opt.score_expr, &opt.score_script) {
+        (Some(expression), _) => Ok(Box::new(Scored::new(
+            Box::new(Addition),
+            expression.clone(),
+        ))),
+        (None, Some(path)) => load_score_script(path),
+        (None, None) => Ok(Box::new(Addition)),
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn load_score_script(path: &Path) -> Result<Box<dyn Recommender>, Error> {
+    Ok(Box::new(Scripted::from_file(Box::new(Addition), path)?))
+}
+
+#[cfg(not(feature = "scripting"))]
+fn load_score_script(_: &Path) -> Result<Box<dyn Recommender>, Error> {
+    Err(Error::Options(
+        "Scoring scripts require building with the 'scripting' feature".to_owned(),
+    ))
+}
+
+fn load_cache(opening_book: &mut Cache, path: &Path) -> Result<(), Error> {
+    opening_book
+        .load(File::open(path)?)
+        .inspect_err(|e| error!("Failed to read cache file '{}': {:?}", path.display(), e))
+}
+
+// Entries are only named by the Zobrist hash of their position, unless the FEN is known
+fn print_cache_entry(
+    filters: &str,
+    position: &str,
+    entry: &CacheEntry,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let fetched = match entry.fetched {
+        0 => "unknown".to_owned(),
+        seconds => pgn_date(seconds),
+    };
+    writeln!(out, "[{}] {} (fetched {})", filters, position, fetched)?;
+    for book_move in &entry.book_moves {
+        write!(
+            out,
+            "  {}: played in {:.1}% of games, scoring {:.1}%",
+            book_move.uci,
+            100.0 * book_move.frequency,
+            100.0 * book_move.score
+        )?;
+        match book_move.year {
+            Some(year) => writeln!(out, ", last seen in {}", year)?,
+            None => writeln!(out)?,
+        }
+    }
+    Ok(())
+}
+
+fn print_cache_stats(path: &Path, cache: &Cache, out: &mut dyn Write) -> Result<(), Error> {
+    let statistics = cache.statistics();
+    writeln!(
+        out,
+        "Cache file '{}': {} bytes on disk",
+        path.display(),
+        fs::metadata(path)?.len()
+    )?;
+    if let Some(created) = statistics.created {
+        writeln!(out, "Created {}", pgn_date(created))?;
+    }
+    if let Some(last_fetched) = statistics.last_fetched {
+        writeln!(out, "Last fetched {}", pgn_date(last_fetched))?;
+    }
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{} entries in total",
+        statistics.entries.values().sum::<usize>()
+    )?;
+    for (filters, entries) in &statistics.entries {
+        writeln!(out, "{} entries for '{}'", entries, filters)?;
+    }
+    writeln!(out)?;
+    writeln!(out, "Entries by ply:")?;
+    for (ply, entries) in &statistics.plies {
+        match ply {
+            Some(ply) => writeln!(out, "{:>7}: {}", ply, entries)?,
+            None => writeln!(out, "unknown: {}", entries)?,
+        }
+    }
+    Ok(())
+}
+
+fn manage_cache(command: &CacheCommand, filters: &str, started: Instant) -> Result<Summary, Error> {
+    match command {
+        CacheCommand::Merge { inputs, output } => {
+            let mut merged = Cache::detached();
+            merged.set_filters(filters.to_owned());
+            for path in inputs {
+                // Files saved without filters keep the entries of the query they were fetched with
+                let mut cache = Cache::detached();
+                cache.set_filters(filters.to_owned());
+                load_cache(&mut cache, path)?;
+                let entries = merged.merge(cache);
+                info!("{} entries taken from '{}'", entries, path.display());
+            }
+            merged.save(File::create(output)?)?;
+            info!("Merged cache saved to '{}'", output.display());
+        }
+        CacheCommand::Stats { file } => {
+            let mut cache = Cache::detached();
+            cache.set_filters(filters.to_owned());
+            load_cache(&mut cache, file)?;
+            print_cache_stats(file, &cache, &mut io::stdout())?;
+        }
+        CacheCommand::Prune {
+            file,
+            max_ply,
+            unused_for,
+            output,
+        } => {
+            if max_ply.is_none() && unused_for.is_none() {
+                return Err(Error::Options(
+                    "Pruning requires --max-ply or --unused-for".to_owned(),
+                ));
+            }
+            let mut cache = Cache::detached();
+            cache.set_filters(filters.to_owned());
+            load_cache(&mut cache, file)?;
+            let removed = cache.prune(*max_ply, *unused_for);
+            let output = output.as_ref().unwrap_or(file);
+            cache.save(File::create(output)?)?;
+            info!(
+                "{} entries removed; Pruned cache saved to '{}'",
+                removed,
+                output.display()
+            );
+        }
+        CacheCommand::Dump { file, fen } => {
+            let mut cache = Cache::detached();
+            cache.set_filters(filters.to_owned());
+            load_cache(&mut cache, file)?;
+            let mut out = io::stdout();
+            match fen {
+                Some(fen) => {
+                    let entries = cache.lookup(fen);
+                    if entries.is_empty() {
+                        warn!("Position '{}' is not cached", fen.fen_str());
+                    }
+                    for (filters, entry) in entries {
+                        print_cache_entry(filters, fen.fen_str(), entry, &mut out)?;
+                    }
+                }
+                None => {
+                    for (filters, key, entry) in cache.all_entries() {
+                        let position = format!("{:016x}", key);
+                        print_cache_entry(filters, &position, entry, &mut out)?;
                     }
                 }
             }
-            Err(_) => {
-                warn!("Import of '{}' failed", path.display());
+        }
+        CacheCommand::Export {
+            file,
+            format: ExportFormat::Json,
+            output,
+        } => {
+            let mut cache = Cache::detached();
+            cache.set_filters(filters.to_owned());
+            load_cache(&mut cache, file)?;
+            match output {
+                Some(output) => {
+                    cache.export_json(BufWriter::new(File::create(output)?))?;
+                    info!("Cache exported to '{}'", output.display());
+                }
+                None => cache.export_json(io::stdout().lock())?,
             }
         }
+        CacheCommand::Import {
+            input,
+            format: ExportFormat::Json,
+            output,
+        } => {
+            let mut cache = Cache::detached();
+            cache.set_filters(filters.to_owned());
+            if output.exists() {
+                load_cache(&mut cache, output)?;
+            }
+            let entries = cache.import_json(BufReader::new(File::open(input)?))?;
+            cache.save(File::create(output)?)?;
+            info!(
+                "{} entries imported from '{}'; Cache saved to '{}'",
+                entries,
+                input.display(),
+                output.display()
+            );
+        }
     }
-    for path in resolve_to_files(opt.black_repertoire) {
-        match RepertoireOptimizer::read_games(&path) {
-            Ok(games) => {
-                info!(
-                    "Import of '{}': Found {} games",
-                    path.display(),
-                    games.len()
-                );
-                for game in games {
-                    if let Err(e) = black_repertoire_optimizer.add_game_to_repertoire(game) {
-                        warn!("'{}' contains bad move: {}", path.display(), e);
-                    }
-                }
+    Ok(Summary {
+        runtime_seconds: started.elapsed().as_millis() as f64 / 1000.0,
+        ..Summary::default()
+    })
+}
+
+// How explorer requests and repertoire downloads are sent
+fn http_options(opt: &Opt) -> HttpOptions {
+    HttpOptions {
+        timeout: Duration::from_secs(opt.http_timeout),
+        retries: opt.http_retries,
+        concurrency: opt.concurrency,
+        proxy: opt.proxy.clone(),
+        token: opt.api_token.clone(),
+    }
+}
+
+fn import_repertoires(opt: &Opt) -> Result<Vec<RepertoireOptimizer>, Error> {
+    let http = http_options(opt);
+    let included = |player: Player| opt.only.is_none_or(|only| only == player);
+    let mode = if opt.strict {
+        ImportMode::Strict
+    } else {
+        ImportMode::Lenient
+    };
+    let mut white = RepertoireOptimizer::new(Player::White);
+    let mut black = RepertoireOptimizer::new(Player::Black);
+    // The files of each color are imported on a thread of their own
+    thread::scope(|scope| {
+        let threads: Vec<_> = [
+            (&mut white, &opt.white_repertoire),
+            (&mut black, &opt.black_repertoire),
+        ]
+        .into_iter()
+        .filter(|(optimizer, _)| included(optimizer.player()))
+        .map(|(optimizer, files)| {
+            scope.spawn(move || {
+                import_matching(
+                    optimizer,
+                    resolve_to_files(files.clone(), opt.all_extensions),
+                    &opt.filter_header,
+                    mode,
+                    &http,
+                )
+            })
+        })
+        .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic))
+            })
+            .collect::<Result<(), Error>>()
+    })?;
+    if !opt.repertoire.is_empty() {
+        import_mixed(
+            &mut white,
+            &mut black,
+            resolve_to_files(opt.repertoire.clone(), opt.all_extensions),
+            &opt.filter_header,
+            mode,
+            &http,
+        )?;
+    }
+    let own_move_stats = !opt.chapter_priority && opt.own_model.uses_stats();
+    Ok(vec![white, black]
+        .into_iter()
+        .filter(|optimizer| included(optimizer.player()))
+        .map(|mut optimizer| {
+            optimizer.set_own_move_stats(own_move_stats);
+            optimizer
+        })
+        .collect())
+}
+
+// The findings of each structural check of a repertoire, with the target of their warnings and
+// a heading saying what to do about them
+fn structural_checks(
+    optimizer: &RepertoireOptimizer,
+) -> Vec<(&'static str, &'static str, Vec<String>)> {
+    fn described<T: std::fmt::Display>(findings: Vec<T>) -> Vec<String> {
+        findings.iter().map(T::to_string).collect()
+    }
+    vec![
+        (
+            DUPLICATE_LINE,
+            "Duplicate lines; Remove the repeating games",
+            described(optimizer.duplicate_lines()),
+        ),
+        (
+            CONFLICTING_MOVES,
+            "Conflicting moves; Settle on one move, or prepare both in the same file",
+            described(optimizer.conflicting_moves()),
+        ),
+        (
+            UNREACHABLE_LINE,
+            "Unreachable lines; Remove them, or choose their first move in your other lines too",
+            described(optimizer.unreachable_lines()),
+        ),
+        (
+            DETACHED_CHAPTER,
+            "Detached chapters; Add a line leading to where they start, as they get no games",
+            described(optimizer.detached_chapters()),
+        ),
+        (
+            WRONG_SIDE,
+            "Files for the other color; Import them into the repertoire of the other color",
+            described(optimizer.wrong_side_files()),
+        ),
+        (
+            REPETITION_LINE,
+            "Repetition lines; Check they are meant to repeat, as they end the games in book",
+            described(optimizer.repetition_lines()),
+        ),
+        (
+            TRUNCATED_LINE,
+            "Truncated lines; Prepare your reply, or mark the end with a 'book-end' comment",
+            described(optimizer.truncated_lines()),
+        ),
+    ]
+}
+
+// Warns about the findings of the structural checks, before they skew the statistics
+fn warn_about_structure(optimizers: &[RepertoireOptimizer]) {
+    for optimizer in optimizers {
+        for (target, _, findings) in structural_checks(optimizer) {
+            for finding in findings {
+                warn!(target: target, "{}", finding);
+            }
+        }
+    }
+}
+
+// Runs the structural checks on the repertoires without looking up any book moves, listing what
+// to fix by repertoire and check
+// The report file in the output directory, or the terminal without one
+fn report_output(opt: &Opt) -> Result<Box<dyn Write>, Error> {
+    let out: Box<dyn Write> = match opt.output_dir {
+        Some(ref dir) => {
+            fs::create_dir_all(dir)?;
+            Box::new(BufWriter::new(File::create(dir.join(REPORT_FILE))?))
+        }
+        None => Box::new(io::stdout()),
+    };
+    Ok(out)
+}
+
+// Writes the rest of the report, and the summary next to it in the output directory
+fn finish_report(opt: &Opt, mut out: Box<dyn Write>, summary: &Summary) -> Result<(), Error> {
+    out.flush()?;
+    if let Some(ref dir) = opt.output_dir {
+        serde_json::to_writer_pretty(File::create(dir.join(SUMMARY_FILE))?, summary)
+            .map_err(|e| Error::StdIo(e.into()))?;
+        info!("Report written to '{}'", dir.display());
+    }
+    Ok(())
+}
+
+fn lint(opt: &Opt, started: Instant) -> Result<Summary, Error> {
+    info!("Importing lines...");
+    let repertoires = import_repertoires(opt)?;
+    let mut out = report_output(opt)?;
+    let mut total = 0;
+    for optimizer in &repertoires {
+        let checks = structural_checks(optimizer);
+        let problems: usize = checks.iter().map(|(_, _, findings)| findings.len()).sum();
+        if problems == 0 {
+            continue;
+        }
+        total += problems;
+        writeln!(
+            out,
+            "{:?} repertoire: {} problems",
+            optimizer.player(),
+            problems
+        )?;
+        for (_, heading, findings) in &checks {
+            if findings.is_empty() {
+                continue;
             }
-            Err(_) => {
-                warn!("Import of '{}' failed", path.display());
+            writeln!(out, "\n{}", heading)?;
+            for finding in findings {
+                writeln!(out, "  - {}", finding)?;
             }
         }
+        writeln!(out)?;
     }
+    if total == 0 {
+        writeln!(out, "No structural problems found")?;
+    }
+    let summary = Summary {
+        runtime_seconds: started.elapsed().as_millis() as f64 / 1000.0,
+        ..Summary::default()
+    };
+    finish_report(opt, out, &summary)?;
+    Ok(summary)
+}
 
-    info!("checking book moves...");
-    white_repertoire_optimizer.add_opponents_moves_from_book(&mut opening_book)?;
-    black_repertoire_optimizer.add_opponents_moves_from_book(&mut opening_book)?;
-    info!("setting own move frequencies...");
-    white_repertoire_optimizer.set_own_move_frequencies();
-    black_repertoire_optimizer.set_own_move_frequencies();
-    info!("updating position frequencies...");
-    white_repertoire_optimizer.update_position_frequencies();
-    black_repertoire_optimizer.update_position_frequencies();
-
-    let average_book_length = (white_repertoire_optimizer.average_book_length
-        + black_repertoire_optimizer.average_book_length)
-        / 2.0;
-
-    positions.append(&mut white_repertoire_optimizer.own_positions());
-    positions.append(&mut black_repertoire_optimizer.own_positions());
-
-    println!();
-    println!("## Repertoire Statistics ##");
-    println!(
+fn set_move_frequencies(opt: &Opt, optimizer: &mut RepertoireOptimizer) {
+    if opt.chapter_priority {
+        optimizer.set_own_move_frequencies_by_priority();
+    } else {
+        optimizer.set_own_move_frequencies(opt.own_model.as_ref());
+    }
+    optimizer.set_opponent_move_frequencies(opt.opponent_model.as_ref());
+}
+
+// Computes the move and position frequencies of each repertoire on a thread of its own
+fn update_frequencies(opt: &Opt, optimizers: &mut [RepertoireOptimizer]) {
+    thread::scope(|scope| {
+        for optimizer in optimizers.iter_mut() {
+            scope.spawn(move || {
+                set_move_frequencies(opt, optimizer);
+                optimizer.update_position_frequencies();
+            });
+        }
+    });
+}
+
+/// Headline numbers of the instant report, to show what changed with fresh book moves
+struct Snapshot {
+    summary: Summary,
+    /// The positions recommended for addition, with how the likeliest line reaches them
+    additions: Vec<(Fen, String)>,
+}
+
+// Copies of the repertoires with the cached book moves alone, treating uncached positions as out
+// of book, for the instant report
+fn with_cached_book_moves(
+    opt: &Opt,
+    optimizers: &[RepertoireOptimizer],
+    opening_book: &mut Cache,
+) -> Result<Vec<RepertoireOptimizer>, Error> {
+    let mut copies = optimizers.to_vec();
+    opening_book.set_offline(true);
+    let added = copies.iter_mut().try_for_each(|optimizer| {
+        optimizer.add_opponents_moves_from_book(opening_book, ErrorPolicy::Skip)
+    });
+    opening_book.set_offline(opt.offline);
+    added?;
+    Ok(copies)
+}
+
+// Analyzes the repertoires with the cached book moves alone and prints the headline numbers and
+// recommended additions right away
+fn instant_report(
+    opt: &Opt,
+    mut optimizers: Vec<RepertoireOptimizer>,
+    addition: &dyn Recommender,
+    filter: &dyn PositionFilter,
+    out: &mut dyn Write,
+) -> Result<Snapshot, Error> {
+    info!("Analyzing cached book moves...");
+    update_frequencies(opt, &mut optimizers);
+    let mut summary = Summary::new(&optimizers);
+    let out_of_scope = black_out_of_scope(opt, &mut summary, &optimizers)?;
+    let positions: Vec<&Position> = optimizers
+        .iter()
+        .flat_map(|optimizer| optimizer.own_positions())
+        .filter(|pos| filter.matches(pos) && !out_of_scope.contains(pos.fen()))
+        .collect();
+    let additions = addition.recommend(&positions, opt.best);
+
+    writeln!(out, "## Instant report from cached book moves ##")?;
+    writeln!(
+        out,
         "Average moves you stay in book per game: {:.5} (higher is better)",
-        average_book_length
-    );
-    println!(
-        "Your repertoire spans {} positions (lower is better)",
-        positions
-            .iter()
-            .filter(|pos| pos.transition_count() > 0)
-            .count()
-    );
-    println!(
-        "=> Average impact of each move in your repertoire: m{:.5} (higher is better)",
-        average_book_length * 1000.0
-        /
-        ( positions
-             .iter()
-             .filter(|pos| pos.transition_count() > 0)
-             .count() as f64 )
-    );
-    println!(
+        summary.average_book_length
+    )?;
+    writeln!(
+        out,
         "You have {} unprepared positions (lower is better)",
-        positions
+        summary.unprepared_positions
+    )?;
+    for position in &additions {
+        writeln!(out, "{}", position)?;
+    }
+    writeln!(
+        out,
+        "Fetching missing book moves for the refreshed report..."
+    )?;
+    out.flush()?;
+    Ok(Snapshot {
+        additions: additions
             .iter()
-            .filter(|pos| pos.transition_count() == 0)
-            .count()
-    );
+            .map(|pos| (pos.fen().clone(), pos.sequence().to_string()))
+            .collect(),
+        summary,
+    })
+}
 
-    if opt.best > 0 {
-        println!();
-        println!("## Positions you are most likely to encounter where you are out-of-book ##");
-        println!("Consider adding these to your repertoire, as it will improve it the most");
-        println!();
-        for position in RepertoireOptimizer::recommend_for_addition(&positions, opt.best) {
-            println!("{}", position);
+// The positions of the Black repertoire only reached after first moves outside `--black-scope`,
+// which are counted apart from the gaps in the summary
+fn black_out_of_scope<'a>(
+    opt: &Opt,
+    summary: &mut Summary,
+    optimizers: &'a [RepertoireOptimizer],
+) -> Result<HashSet<&'a Fen>, Error> {
+    let mut out_of_scope = HashSet::new();
+    if !opt.black_scope.is_empty() {
+        summary.set_black_scope(optimizers, &opt.black_scope)?;
+        for optimizer in optimizers
+            .iter()
+            .filter(|optimizer| optimizer.player() == Player::Black)
+        {
+            out_of_scope.extend(optimizer.out_of_scope(&opt.black_scope)?);
         }
     }
+    Ok(out_of_scope)
+}
 
-    if opt.worst > 0 {
-        println!();
-        println!(
-            "## Positions you are least likely to encounter where you have a line prepared ##"
-        );
-        println!("Consider removing these from your repertoire, as it will have the least impact");
-        println!();
-        for position in RepertoireOptimizer::recommend_for_removal(&positions, opt.worst) {
-            println!("{}", position);
+fn print_changes(
+    snapshot: &Snapshot,
+    summary: &Summary,
+    additions: &[&Position],
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    writeln!(out)?;
+    writeln!(out, "## Changes since the instant report ##")?;
+    writeln!(
+        out,
+        "Average moves you stay in book per game: {:.5} -> {:.5} ({:+.5})",
+        snapshot.summary.average_book_length,
+        summary.average_book_length,
+        summary.average_book_length - snapshot.summary.average_book_length
+    )?;
+    writeln!(
+        out,
+        "Unprepared positions: {} -> {}",
+        snapshot.summary.unprepared_positions, summary.unprepared_positions
+    )?;
+    for position in additions {
+        if !snapshot
+            .additions
+            .iter()
+            .any(|(fen, _)| fen == position.fen())
+        {
+            writeln!(
+                out,
+                "Newly recommended for addition: {}",
+                position.sequence()
+            )?;
         }
     }
-
-    if opt.most > 0 {
-        println!();
-        println!("## Positions where your prepared moves are least likely to be used ##");
-        println!("Consider reducing the number of different moves you play here");
-        println!();
-        for position in RepertoireOptimizer::recommend_for_narrowing(&positions, opt.most) {
-            println!("{}", position);
+    for (fen, sequence) in &snapshot.additions {
+        if !additions.iter().any(|position| position.fen() == fen) {
+            writeln!(out, "No longer recommended for addition: {}", sequence)?;
         }
     }
+    Ok(())
+}
 
-    if opt.costly > 0 {
-        println!();
-        println!("## Most frequent positions where you have more than one move prepared ##");
-        println!("Reducing your options here would reduce your workload the most, while still keeping you prepared");
-        println!();
-        for position in RepertoireOptimizer::recommend_for_reduction(&positions, opt.costly) {
-            println!("{}", position);
-        }
+// Looks up the book moves of all repertoires in one batch, so the positions missing from the
+// cache are fetched together; Saves the book moves fetched so far if fetching fails or gets
+// interrupted
+fn add_book_moves(
+    opt: &Opt,
+    optimizers: &mut [RepertoireOptimizer],
+    opening_book: &mut Cache,
+) -> Result<(), Error> {
+    let fens: Vec<Vec<Fen>> = optimizers
+        .iter()
+        .map(RepertoireOptimizer::book_positions)
+        .collect();
+    let mut results = opening_book.moves_batch(&fens.concat()).into_iter();
+    let added = optimizers
+        .iter_mut()
+        .zip(&fens)
+        .try_for_each(|(optimizer, fens)| {
+            let results = results.by_ref().take(fens.len()).collect();
+            optimizer.add_book_moves(fens, results, opening_book, opt.on_book_error)
+        });
+    if let Err(e) = added {
+        warn!("Saving the book moves fetched so far before stopping");
+        save_cache(opt, opening_book)?;
+        return Err(e);
     }
+    Ok(())
+}
 
+fn save_cache(opt: &Opt, opening_book: &mut Cache) -> Result<(), Error> {
     if let Some(ref path) = opt.cache_file {
         if opening_book.has_changed() {
             opening_book.save(File::create(path)?)?;
         }
     }
+    Ok(())
+}
 
-    info!(
-        "Total runtime: {:.2} s",
-        now.elapsed().as_millis() as f64 / 1000.0
+// The first Ctrl-C stops fetching book moves gracefully, a second one exits immediately
+fn handle_interrupts() -> Result<Arc<AtomicBool>, Error> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted; Press Ctrl-C again to exit without saving");
+    })
+    .map_err(|e| Error::StdIo(io::Error::other(e)))?;
+    Ok(interrupted)
+}
+
+fn run(opt: &Opt) -> Result<Summary, Error> {
+    let now = Instant::now();
+    let query = Query {
+        ratings: opt.ratings.clone(),
+        speeds: opt.speeds.clone(),
+        since: opt.since.clone(),
+        until: opt.until.clone(),
+    };
+    let filters = query.to_string();
+    if let Some(Command::Cache(ref command)) = opt.command {
+        return manage_cache(command, &filters, now);
+    }
+    if let Some(Command::Benchmark {
+        lines,
+        plies,
+        book_moves,
+    }) = opt.command
+    {
+        return benchmark(opt, lines, plies, book_moves, now);
+    }
+    if let Some(Command::Lint) = opt.command {
+        return lint(opt, now);
+    }
+
+    // The stress test adds to the report, while other commands replace it
+    let replaces_report = !matches!(opt.command, None | Some(Command::StressTest { .. }));
+    let mut out: Box<dyn Write> = match opt.output_dir {
+        None if replaces_report => Box::new(io::sink()),
+        _ => report_output(opt)?,
+    };
+    let mut todos = Vec::new();
+    let mut positions = Vec::new();
+
+    let filter = position_filter(opt);
+    let addition = addition_recommender(opt)?;
+
+    let http = http_options(opt);
+    // Monthly game counts are not cached, so the explorer is only asked for them when needed
+    let explorer = if opt.trends > 0 && !opt.offline {
+        Some(Lichess::new(
+            &opt.explorer_url,
+            0,
+            query.clone(),
+            http.clone(),
+        )?)
+    } else {
+        None
+    };
+    let mut opening_book = match opt.engine {
+        Some(ref path) => Cache::new(Fallback::new(
+            Lichess::new(&opt.explorer_url, opt.engine_min_games, query, http)?,
+            Engine::new(
+                path.clone(),
+                opt.engine_lines,
+                opt.engine_depth,
+                opt.engine_temperature,
+            ),
+        )),
+        None => Cache::new(Lichess::new(&opt.explorer_url, 0, query, http)?),
+    };
+    if let Some(ref path) = opt.cache_db {
+        open_cache_db(&mut opening_book, path)?;
+    }
+    opening_book.set_filters(filters);
+
+    if let Some(ref path) = opt.cache_file {
+        if path.exists() {
+            load_cache(&mut opening_book, path)?;
+            info!("Cache file '{}' loaded successfully...", path.display());
+        } else {
+            info!(
+                "Cache file '{}' not found; Will be created...",
+                path.display()
+            );
+        }
+    }
+
+    opening_book.set_offline(opt.offline);
+    if opt.refresh_cache {
+        opening_book.set_max_age(Duration::ZERO);
+    } else if let Some(max_age) = opt.cache_max_age {
+        opening_book.set_max_age(max_age);
+    }
+    let interrupted = handle_interrupts()?;
+    opening_book.set_interrupt(interrupted.clone());
+    if let Some(ref path) = opt.cache_file {
+        if opt.checkpoint_entries.is_some() || opt.checkpoint_minutes.is_some() {
+            opening_book.set_checkpoint(Checkpoint {
+                path: path.clone(),
+                entries: opt.checkpoint_entries,
+                interval: opt
+                    .checkpoint_minutes
+                    .map(|minutes| Duration::from_secs(minutes * 60)),
+            });
+        }
+    }
+    if !opt.non_interactive {
+        opening_book.set_progress(fetch_progress());
+    }
+
+    info!("Importing lines...");
+    let repertoires = import_repertoires(opt)?;
+    warn_about_structure(&repertoires);
+
+    if let Some(Command::Prefetch) = opt.command {
+        return prefetch(opt, repertoires, opening_book, now);
+    }
+
+    let mut optimizers = Vec::new();
+    let mut beginners = Vec::new();
+    for optimizer in repertoires {
+        let prepared_moves = optimizer.prepared_move_count();
+        if prepared_moves < MIN_PREPARED_MOVES {
+            warn!(
+                "Your {} repertoire has only {} prepared moves; Leaving it out of the statistics",
+                optimizer.player(),
+                prepared_moves
+            );
+            beginners.push(optimizer.player());
+        } else {
+            optimizers.push(optimizer);
+        }
+    }
+
+    if let Some(Command::Daemon { .. }) = opt.command {
+        return daemon(opt, optimizers, opening_book, &interrupted, now);
+    }
+    let instant = match opt.cache_file {
+        Some(ref path) if opt.instant && path.exists() => {
+            Some(with_cached_book_moves(opt, &optimizers, &mut opening_book)?)
+        }
+        _ => None,
+    };
+    info!("checking book moves...");
+    // The missing book moves are fetched on a thread of their own while the instant report is
+    // printed
+    let snapshot = thread::scope(|scope| {
+        let refresh = scope.spawn(|| add_book_moves(opt, &mut optimizers, &mut opening_book));
+        let snapshot = instant
+            .map(|instant| instant_report(opt, instant, addition.as_ref(), &filter, &mut out))
+            .transpose();
+        refresh
+            .join()
+            .unwrap_or_else(|panic| panic::resume_unwind(panic))?;
+        snapshot
+    })?;
+    LOGGER.count_warnings(
+        EMPTY_BOOK,
+        optimizers
+            .iter()
+            .map(RepertoireOptimizer::empty_book_positions)
+            .sum(),
     );
+    info!("updating frequencies...");
+    update_frequencies(opt, &mut optimizers);
+    for optimizer in optimizers.iter() {
+        positions.append(&mut optimizer.own_positions());
+    }
 
-    Ok(())
+    let mut summary = Summary::new(&optimizers);
+    let decisions: Vec<_> = if opt.systems {
+        optimizers
+            .iter()
+            .flat_map(|optimizer| {
+                let player = optimizer.player();
+                optimizer
+                    .own_decisions()
+                    .into_iter()
+                    .map(move |(uci, positions)| (player, uci, positions))
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    if opt.systems {
+        summary.unique_decisions = Some(decisions.len());
+    }
+    let out_of_scope = black_out_of_scope(opt, &mut summary, &optimizers)?;
+    let mut stakes = HashMap::new();
+    if opt.rating_at_stake && !optimizers.is_empty() {
+        info!("estimating rating points at stake...");
+        let gaps: Vec<&Position> = positions
+            .iter()
+            .filter(|pos| pos.transition_count() == 0)
+            .cloned()
+            .collect();
+        let mut engine = opt.engine.as_ref().map(|path| {
+            Engine::new(
+                path.clone(),
+                opt.engine_lines,
+                opt.engine_depth,
+                opt.engine_temperature,
+            )
+        });
+        let points = RepertoireOptimizer::rating_points_at_stake(
+            &gaps,
+            &mut opening_book,
+            engine.as_mut().map(|engine| engine as &mut dyn OpeningBook),
+        )?;
+        let total = points.iter().sum();
+        summary.rating_points_at_stake = Some(total);
+        stakes = gaps.into_iter().map(Position::fen).zip(points).collect();
+    }
+
+    if let Some(Command::StressTest {
+        games,
+        min_share,
+        exits,
+    }) = opt.command
+    {
+        let seed = opt.seed.unwrap_or_else(rand::random);
+        info!("Playing {} games per color with seed {}...", games, seed);
+        let mut rng = ChaChaRng::seed_from_u64(seed);
+        for optimizer in &optimizers {
+            let result = optimizer.stress_test(&mut opening_book, games, min_share, &mut rng)?;
+            print_stress_test(optimizer, &result, exits, seed, &mut out)?;
+        }
+    }
+
+    let recommendable: Vec<&Position> = positions
+        .iter()
+        .filter(|pos| filter.matches(pos) && !out_of_scope.contains(pos.fen()))
+        .cloned()
+        .collect();
+
+    let line_ages: Vec<_> = optimizers
+        .iter()
+        .flat_map(|optimizer| {
+            let player = optimizer.player();
+            optimizer
+                .line_ages()
+                .into_iter()
+                .map(move |(line, year)| (player, line, year))
+        })
+        .collect();
+
+    if let Some(ref snapshot) = snapshot {
+        print_changes(
+            snapshot,
+            &summary,
+            &addition.recommend(&recommendable, opt.best),
+            &mut out,
+        )?;
+    }
+
+    for section in &opt.sections {
+        match section {
+            Section::Completeness => {
+                let scoreboard: Vec<_> = optimizers
+                    .iter()
+                    .flat_map(|optimizer| {
+                        let player = optimizer.player();
+                        optimizer
+                            .completeness(opt.completeness_depth)
+                            .into_iter()
+                            .map(move |chapter| (player, chapter))
+                    })
+                    .collect();
+                if !scoreboard.is_empty() {
+                    writeln!(out)?;
+                    writeln!(out, "## Completeness by ECO chapter ##")?;
+                    writeln!(
+                        out,
+                        "Consider extending the chapters with low scores, where games leave your preparation before ply {}",
+                        opt.completeness_depth
+                    )?;
+                    writeln!(out)?;
+                    for (player, chapter) in scoreboard {
+                        writeln!(
+                            out,
+                            "{} {}0-{}9: {:>3.0}/100 (in {:.2}% of games)",
+                            player,
+                            chapter.chapter,
+                            chapter.chapter,
+                            chapter.score,
+                            100.0 * chapter.frequency
+                        )?;
+                    }
+                }
+            }
+            Section::Statistics if !optimizers.is_empty() => {
+                writeln!(out)?;
+                writeln!(out, "## Repertoire Statistics ##")?;
+                writeln!(
+                    out,
+                    "Average moves you stay in book per game: {:.5} (higher is better)",
+                    summary.average_book_length
+                )?;
+                writeln!(
+                    out,
+                    "Your repertoire spans {} positions (lower is better)",
+                    summary.prepared_positions
+                )?;
+                match summary.unique_decisions {
+                    Some(unique_decisions) => {
+                        writeln!(
+                            out,
+                            "Your repertoire takes {} unique decisions, counting moves prepared in several positions once (lower is better)",
+                            unique_decisions
+                        )?;
+                        writeln!(
+                            out,
+                            "=> Average impact of each decision in your repertoire: m{:.5} (higher is better)",
+                            summary.average_book_length * 1000.0 / unique_decisions as f64
+                        )?;
+                        let reused: Vec<String> = decisions
+                            .iter()
+                            .filter(|(_, _, positions)| *positions > 1)
+                            .take(MOST_REUSED_DECISIONS)
+                            .map(|(player, uci, positions)| {
+                                format!("{} {} in {} positions", player, uci, positions)
+                            })
+                            .collect();
+                        if !reused.is_empty() {
+                            writeln!(out, "Most reused decisions: {}", reused.join(", "))?;
+                        }
+                    }
+                    None => writeln!(
+                        out,
+                        "=> Average impact of each move in your repertoire: m{:.5} (higher is better)",
+                        summary.average_book_length * 1000.0 / summary.prepared_positions as f64
+                    )?,
+                }
+                writeln!(
+                    out,
+                    "You have {} unprepared positions (lower is better)",
+                    summary.unprepared_positions
+                )?;
+                if let Some(ref everything_else) = summary.everything_else {
+                    writeln!(
+                        out,
+                        "Everything else: {:.2}% of your Black games start with a first move out of scope, where you have {} prepared and {} unprepared positions",
+                        100.0 * everything_else.frequency,
+                        everything_else.prepared_positions,
+                        everything_else.unprepared_positions
+                    )?;
+                }
+                if let Some(total) = summary.rating_points_at_stake {
+                    writeln!(
+                        out,
+                        "Improvising in unprepared positions costs you about {:.1} rating points per 100 games (lower is better)",
+                        total
+                    )?;
+                }
+                if opt.simulate > 0 {
+                    let seed = opt.seed.unwrap_or_else(rand::random);
+                    info!("Simulating {} games with seed {}...", opt.simulate, seed);
+                    let mut rng = ChaChaRng::seed_from_u64(seed);
+                    let simulated_book_length = optimizers
+                        .iter()
+                        .map(|optimizer| optimizer.simulate_book_length(opt.simulate, &mut rng))
+                        .sum::<f64>()
+                        / optimizers.len() as f64;
+                    writeln!(
+                        out,
+                        "Simulated average moves you stay in book over {} games per color: {:.5} (seed {})",
+                        opt.simulate, simulated_book_length, seed
+                    )?;
+                }
+            }
+            Section::GettingStarted => {
+                for player in &beginners {
+                    print_getting_started(*player, &mut opening_book, &mut out)?;
+                }
+            }
+            Section::Additions if opt.best > 0 => {
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "## Positions you are most likely to encounter where you are out-of-book ##"
+                )?;
+                writeln!(
+                    out,
+                    "Consider adding these to your repertoire, as it will improve it the most"
+                )?;
+                writeln!(out)?;
+                // Positions of both repertoires take the chapter of the first one holding them
+                let mut chapters = HashMap::new();
+                if opt.chapter_priority {
+                    for optimizer in &optimizers {
+                        for (fen, priority) in optimizer.chapter_priorities() {
+                            chapters.entry(fen).or_insert(priority);
+                        }
+                    }
+                }
+                let chapter_of = |position: &Position| chapters.get(position.fen()).copied();
+                let source_of = |position: &Position| {
+                    optimizers
+                        .iter()
+                        .find_map(|optimizer| optimizer.source(position.fen()))
+                };
+                let mut additions = addition.recommend(&recommendable, opt.best);
+                let mut learning_costs = HashMap::new();
+                if opt.lookahead > 0 || opt.budget.is_some() {
+                    let pool = addition.recommend(&recommendable, opt.best * LOOKAHEAD_POOL);
+                    let costs = RepertoireOptimizer::learning_costs(
+                        &pool,
+                        &mut opening_book,
+                        opt.lookahead.max(1),
+                    )?;
+                    learning_costs = pool.iter().map(|pos| pos.fen()).zip(costs).collect();
+                    if let Some(budget) = opt.budget {
+                        // Moves in book gained per game of the whole repertoire
+                        let gain = |position: &Position| {
+                            position.frequency() * learning_costs[position.fen()].moves
+                        };
+                        let items: Vec<(usize, f64)> = pool
+                            .iter()
+                            .map(|pos| (learning_costs[pos.fen()].positions, gain(pos)))
+                            .collect();
+                        additions = RepertoireOptimizer::within_budget(&items, budget)
+                            .into_iter()
+                            .map(|index| pool[index])
+                            .collect();
+                        additions.sort_by(|a, b| {
+                            by_frequency_then_fen((gain(a), a.fen()), (gain(b), b.fen()))
+                        });
+                        writeln!(
+                            out,
+                            "Within a budget of {} positions: {} positions to learn, keeping you in book for {:.4} more half-moves per game",
+                            budget,
+                            additions
+                                .iter()
+                                .map(|pos| learning_costs[pos.fen()].positions)
+                                .sum::<usize>(),
+                            additions.iter().map(|pos| gain(pos)).sum::<f64>()
+                        )?;
+                        writeln!(out)?;
+                    } else {
+                        let per_position = |position: &Position| {
+                            position.frequency()
+                                / learning_costs[position.fen()].positions.max(1) as f64
+                        };
+                        additions = pool;
+                        additions.sort_by(|a, b| {
+                            by_frequency_then_fen(
+                                (per_position(a), a.fen()),
+                                (per_position(b), b.fen()),
+                            )
+                        });
+                        additions.truncate(opt.best);
+                    }
+                }
+                if opt.chapter_priority {
+                    additions.sort_by_key(|position| chapter_of(position).unwrap_or(u32::MAX));
+                }
+                // Branches are listed in the order of their most important gap
+                let mut branches: Vec<Option<&Fen>> = Vec::new();
+                let mut gaps_by_branch: HashMap<Option<&Fen>, usize> = HashMap::new();
+                let mut branch_roots = HashMap::new();
+                if opt.cluster {
+                    for optimizer in &optimizers {
+                        let gaps: Vec<&Position> = additions
+                            .iter()
+                            .filter(|position| position.turn() == optimizer.player())
+                            .copied()
+                            .collect();
+                        branch_roots.extend(optimizer.branch_roots(&gaps));
+                    }
+                }
+                let branch_of = |position: &Position| branch_roots.get(position.fen()).copied();
+                if opt.cluster {
+                    for position in &additions {
+                        let branch = branch_of(position).map(Position::fen);
+                        if !branches.contains(&branch) {
+                            branches.push(branch);
+                        }
+                        *gaps_by_branch.entry(branch).or_insert(0) += 1;
+                    }
+                    additions.sort_by_key(|position| {
+                        let branch = branch_of(position).map(Position::fen);
+                        branches.iter().position(|known| *known == branch)
+                    });
+                }
+                let mut chapter = None;
+                let mut branch = None;
+                let candidates = if opt.candidates > 0 {
+                    RepertoireOptimizer::candidate_moves(
+                        &additions,
+                        &mut opening_book,
+                        opt.candidates,
+                    )?
+                } else {
+                    vec![Vec::new(); additions.len()]
+                };
+                for (position, candidates) in additions.into_iter().zip(candidates) {
+                    if opt.chapter_priority && chapter != chapter_of(position) {
+                        chapter = chapter_of(position);
+                        match chapter {
+                            Some(priority) => writeln!(out, "### Chapter {} ###", priority)?,
+                            None => writeln!(out, "### Outside your chapters ###")?,
+                        }
+                    }
+                    let root = branch_of(position);
+                    if opt.cluster && branch != Some(root.map(Position::fen)) {
+                        branch = Some(root.map(Position::fen));
+                        let gaps = gaps_by_branch[&root.map(Position::fen)];
+                        match root.map(Position::sequence) {
+                            Some(sequence) if !sequence.is_empty() => writeln!(
+                                out,
+                                "### {} gaps stem from your position after {} ###",
+                                gaps, sequence
+                            )?,
+                            _ => writeln!(out, "### {} gaps from the starting position ###", gaps)?,
+                        }
+                    }
+                    writeln!(out, "{}", position)?;
+                    if let Some(name) = optimizers
+                        .iter()
+                        .find_map(|optimizer| optimizer.line_name(position.fen()))
+                    {
+                        writeln!(out, "In your line '{}'", name)?;
+                    }
+                    if let Some(source) = source_of(position) {
+                        writeln!(out, "Reached from {}", source)?;
+                    }
+                    if let Some(cost) = learning_costs.get(position.fen()) {
+                        writeln!(
+                            out,
+                            "About {} positions to learn, each reached in {:.4}% of games",
+                            cost.positions,
+                            100.0 * position.frequency() / cost.positions.max(1) as f64
+                        )?;
+                    }
+                    if let Some(points) = stakes.get(position.fen()) {
+                        writeln!(
+                            out,
+                            "About {:.1} rating points per 100 games at stake",
+                            points
+                        )?;
+                    }
+                    for candidate in &candidates {
+                        writeln!(
+                            out,
+                            "Candidate {}: played in {:.1}% of games, scoring {:.1}%, {} common answers to prepare",
+                            candidate.uci,
+                            100.0 * candidate.popularity,
+                            100.0 * candidate.score,
+                            candidate.replies
+                        )?;
+                    }
+                    if opt.move_orders {
+                        if let Some(optimizer) = optimizers
+                            .iter()
+                            .find(|optimizer| optimizer.player() == position.turn())
+                        {
+                            for move_order in optimizer.move_orders(position, &mut opening_book)? {
+                                writeln!(out, "{}", move_order)?;
+                            }
+                        }
+                    }
+                    let mut action = if position.sequence().is_empty() {
+                        "Prepare your first move".to_owned()
+                    } else {
+                        format!("Prepare a reply to {}", position.sequence().san())
+                    };
+                    if !candidates.is_empty() {
+                        let moves: Vec<String> = candidates
+                            .iter()
+                            .map(|candidate| {
+                                position
+                                    .san(&candidate.uci)
+                                    .unwrap_or_else(|| candidate.uci.clone())
+                            })
+                            .collect();
+                        action.push_str(&format!(" (candidates: {})", moves.join(", ")));
+                    }
+                    todos.push(Todo {
+                        action,
+                        frequency: *position.frequency(),
+                        cost: 1,
+                    });
+                }
+            }
+            Section::Removals if opt.worst > 0 => {
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "## Positions you are least likely to encounter where you have a line prepared ##"
+                )?;
+                writeln!(
+                    out,
+                    "Consider removing these from your repertoire, as it will have the least impact"
+                )?;
+                writeln!(out)?;
+                let mut archived = Vec::new();
+                for position in
+                    RepertoireOptimizer::recommend_for_removal(&recommendable, opt.worst)
+                {
+                    writeln!(out, "{}", position)?;
+                    if let Some(source) = optimizers
+                        .iter()
+                        .find_map(|optimizer| optimizer.source(position.fen()))
+                    {
+                        writeln!(out, "Prepared in {}", source)?;
+                    }
+                    // The average book length of the report is the mean over the repertoires
+                    let (book_length, positions) = optimizers
+                        .iter()
+                        .filter(|optimizer| optimizer.player() == position.turn())
+                        .map(|optimizer| optimizer.removal_impact(position.fen()))
+                        .fold((0.0, 0), |(book_length, positions), impact| {
+                            (
+                                book_length + impact.book_length / optimizers.len() as f64,
+                                positions + impact.positions,
+                            )
+                        });
+                    writeln!(
+                        out,
+                        "Removing it changes the average moves in book by {:+.5} and drops {} positions",
+                        book_length, positions
+                    )?;
+                    todos.push(Todo {
+                        action: format!("Drop your moves {}", after_line(position)),
+                        frequency: *position.frequency(),
+                        cost: position.transition_count(),
+                    });
+                    for optimizer in &optimizers {
+                        if optimizer.player() != position.turn() {
+                            continue;
+                        }
+                        archived.extend(optimizer.lines_through(position.fen()).into_iter().map(
+                            |line| ArchivedLine {
+                                line,
+                                player: format!("{:?}", optimizer.player()),
+                                reason: format!("Rarely reached, dropped {}", after_line(position)),
+                                frequency: *position.frequency(),
+                                prepared_moves: position.transition_count(),
+                            },
+                        ));
+                    }
+                }
+                if let Some(ref path) = opt.archive {
+                    let seconds = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |elapsed| elapsed.as_secs());
+                    let mut file = fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)?;
+                    write_archive(&archived, &pgn_date(seconds), &mut file)?;
+                    info!("{} lines archived to '{}'", archived.len(), path.display());
+                }
+            }
+            Section::Narrowing if opt.most > 0 => {
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "## Positions where your prepared moves are least likely to be used ##"
+                )?;
+                writeln!(
+                    out,
+                    "Consider reducing the number of different moves you play here"
+                )?;
+                writeln!(out)?;
+                for position in
+                    RepertoireOptimizer::recommend_for_narrowing(&recommendable, opt.most)
+                {
+                    writeln!(out, "{}", position)?;
+                    todos.push(settle_on_fewer_moves(position));
+                }
+            }
+            Section::Reduction if opt.costly > 0 => {
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "## Most frequent positions where you have more than one move prepared ##"
+                )?;
+                writeln!(out, "Reducing your options here would reduce your workload the most, while still keeping you prepared")?;
+                writeln!(out)?;
+                for position in
+                    RepertoireOptimizer::recommend_for_reduction(&recommendable, opt.costly)
+                {
+                    writeln!(out, "{}", position)?;
+                    todos.push(settle_on_fewer_moves(position));
+                }
+            }
+            Section::Difficult if opt.difficult > 0 => {
+                info!("estimating difficulties...");
+                let prepared: Vec<&Position> = recommendable
+                    .iter()
+                    .filter(|pos| pos.transition_count() > 0)
+                    .cloned()
+                    .collect();
+                let difficulties = RepertoireOptimizer::difficulties(&prepared, &mut opening_book)?;
+                let mut difficult: Vec<_> = prepared.into_iter().zip(difficulties).collect();
+                difficult.sort_by(|(a_pos, a), (b_pos, b)| {
+                    by_frequency_then_fen(
+                        (a.drill_weight(), a_pos.fen()),
+                        (b.drill_weight(), b_pos.fen()),
+                    )
+                });
+                difficult.truncate(opt.difficult);
+
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "## Positions where your prepared moves are easiest to confuse ##"
+                )?;
+                writeln!(
+                    out,
+                    "Consider drilling these more often than positions with a single obvious move"
+                )?;
+                writeln!(out)?;
+                for (position, difficulty) in difficult {
+                    writeln!(out, "{}", position)?;
+                    writeln!(
+                        out,
+                        "{} common alternatives, {} of them similar to your move; Drill weight {:.0}",
+                        difficulty.alternatives,
+                        difficulty.similar,
+                        difficulty.drill_weight()
+                    )?;
+                }
+            }
+            Section::Exotic if opt.exotic > 0 => {
+                info!("Looking up your own moves in the book...");
+                let mut prepared: Vec<&Position> = recommendable
+                    .iter()
+                    .filter(|pos| pos.transition_count() > 0)
+                    .cloned()
+                    .collect();
+                prepared.sort_by(|a, b| {
+                    by_frequency_then_fen((*a.frequency(), a.fen()), (*b.frequency(), b.fen()))
+                });
+                let exotic = RepertoireOptimizer::exotic_choices(&prepared, &mut opening_book)?;
+                let exotic: Vec<_> = prepared
+                    .into_iter()
+                    .zip(exotic)
+                    .filter(|(_, choices)| !choices.is_empty())
+                    .take(opt.exotic)
+                    .collect();
+
+                writeln!(out)?;
+                writeln!(out, "## Exotic own choices ##")?;
+                writeln!(
+                    out,
+                    "Consider whether these moves are worth the effort, as players at your level hardly ever choose them"
+                )?;
+                writeln!(out)?;
+                for (position, choices) in exotic {
+                    writeln!(out, "{}", position)?;
+                    for choice in choices {
+                        writeln!(
+                            out,
+                            "{} is played in {:.2}% of book games",
+                            choice.uci,
+                            100.0 * choice.share
+                        )?;
+                    }
+                }
+            }
+            Section::Hotspots if opt.hotspots > 0 => {
+                let mut hotspots: Vec<(&Position, f64)> = optimizers
+                    .iter()
+                    .flat_map(|optimizer| optimizer.deviation_hotspots())
+                    .filter(|(pos, _)| filter.matches(pos))
+                    .collect();
+                hotspots.sort_by(|(a_pos, a), (b_pos, b)| {
+                    by_frequency_then_fen((*a, a_pos.fen()), (*b, b_pos.fen()))
+                });
+                hotspots.truncate(opt.hotspots);
+
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "## Positions where opponents most often leave your prepared lines ##"
+                )?;
+                writeln!(
+                    out,
+                    "Consider covering the missing replies, as this is where each line is weakest"
+                )?;
+                writeln!(out)?;
+                for (position, frequency) in hotspots {
+                    writeln!(
+                        out,
+                        "Opponents leave your repertoire here in {:.6}% of games",
+                        100.0 * frequency
+                    )?;
+                    writeln!(out, "{}", position)?;
+                    todos.push(Todo {
+                        action: format!("Cover more replies {}", after_line(position)),
+                        frequency,
+                        cost: 1,
+                    });
+                }
+            }
+            Section::Trends => {
+                if let Some(ref explorer) = explorer {
+                    print_trends(explorer, &optimizers, opt.trends, &mut out)?;
+                } else if opt.trends > 0 {
+                    warn!("Popularity trends are not available offline");
+                }
+            }
+            Section::LongLines => {
+                if let Some(max_plies) = opt.max_line_length {
+                    let long_lines: Vec<_> = optimizers
+                        .iter()
+                        .flat_map(|optimizer| {
+                            let player = optimizer.player();
+                            optimizer
+                                .long_lines(max_plies)
+                                .into_iter()
+                                .map(move |line| (player, line))
+                        })
+                        .collect();
+                    if !long_lines.is_empty() {
+                        writeln!(out)?;
+                        writeln!(out, "## Lines longer than {} half-moves ##", max_plies)?;
+                        writeln!(
+                            out,
+                            "Consider ending these lines early, where the opponent has just chosen among several popular moves"
+                        )?;
+                        writeln!(out)?;
+                        for (player, long_line) in long_lines {
+                            writeln!(
+                                out,
+                                "{} half-moves ({}): {}",
+                                long_line.plies, player, long_line.line
+                            )?;
+                            for position in long_line.truncation_points {
+                                writeln!(
+                                    out,
+                                    "  Could stop {}, reached in {:.6}% of games",
+                                    after_line(position),
+                                    100.0 * position.frequency()
+                                )?;
+                            }
+                        }
+                    }
+                }
+            }
+            Section::LineAges if opt.line_ages => {
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "## Most recent year each of your lines was seen in strong practice ##"
+                )?;
+                writeln!(out)?;
+                for (player, line, year) in &line_ages {
+                    match year {
+                        Some(year) => writeln!(out, "{} ({}): {}", year, player, line)?,
+                        None => writeln!(out, "unknown ({}): {}", player, line)?,
+                    }
+                }
+            }
+            Section::StaleLines => {
+                let stale_year = current_year().saturating_sub(opt.stale_after);
+                let stale_lines: Vec<_> = line_ages
+                    .iter()
+                    .filter(|(_, _, year)| matches!(year, Some(year) if *year < stale_year))
+                    .collect();
+                if !stale_lines.is_empty() {
+                    writeln!(out)?;
+                    writeln!(
+                        out,
+                        "## Lines not seen in strong practice for over {} years ##",
+                        opt.stale_after
+                    )?;
+                    writeln!(
+                        out,
+                        "Consider reviewing these manually, as their theory may be outdated"
+                    )?;
+                    writeln!(out)?;
+                    for (player, line, year) in stale_lines {
+                        writeln!(out, "{} ({}): {}", year.unwrap_or_default(), player, line)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(ref path) = opt.tree {
+        write_trees(&optimizers, &mut BufWriter::new(File::create(path)?))?;
+        info!("Position trees written to '{}'", path.display());
+    }
+
+    if let Some(Command::Export { todo, ref output }) = opt.command {
+        match output {
+            Some(path) => write_todos(todos, todo, &mut File::create(path)?)?,
+            None => write_todos(todos, todo, &mut io::stdout())?,
+        }
+    }
+
+    save_cache(opt, &mut opening_book)?;
+
+    summary.runtime_seconds = now.elapsed().as_millis() as f64 / 1000.0;
+    finish_report(opt, out, &summary)?;
+    info!("Total runtime: {:.2} s", summary.runtime_seconds);
+
+    Ok(summary)
 }
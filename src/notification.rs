@@ -0,0 +1,100 @@
+use reqwest::blocking::Client;
+use serde::Serialize;
+use std::process::Command;
+use std::str::FromStr;
+
+use chess_repertoire_optimizer::error::Error;
+use chess_repertoire_optimizer::summary::Summary;
+
+/// Where to report the outcome of an analysis run
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notifier {
+    Webhook(String),
+    Desktop,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Outcome<'a> {
+    Completed { summary: &'a Summary },
+    Failed { error: String },
+}
+
+impl FromStr for Notifier {
+    type Err = String;
+
+    fn from_str(notifier: &str) -> Result<Self, Self::Err> {
+        if notifier == "desktop" {
+            Ok(Notifier::Desktop)
+        } else if let Some(url) = notifier.strip_prefix("webhook:") {
+            Ok(Notifier::Webhook(url.to_owned()))
+        } else {
+            Err(format!(
+                "Unknown notifier '{}'; Expected 'webhook:<url>' or 'desktop'",
+                notifier
+            ))
+        }
+    }
+}
+
+impl Notifier {
+    pub fn notify(&self, result: &Result<Summary, Error>) -> Result<(), Error> {
+        let outcome = match result {
+            Ok(summary) => Outcome::Completed { summary },
+            Err(e) => Outcome::Failed {
+                error: e.to_string(),
+            },
+        };
+        match self {
+            Notifier::Webhook(url) => {
+                let response = Client::new().post(url).json(&outcome).send()?;
+                if !response.status().is_success() {
+                    return Err(Error::Http);
+                }
+            }
+            Notifier::Desktop => {
+                let body = match outcome {
+                    Outcome::Completed { summary } => format!(
+                        "Analysis completed: {:.2} moves in book on average",
+                        summary.average_book_length
+                    ),
+                    Outcome::Failed { error } => format!("Analysis failed: {}", error),
+                };
+                Command::new("notify-send")
+                    .arg("Chess Repertoire Optimizer")
+                    .arg(body)
+                    .status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notification::*;
+
+    #[test]
+    fn it_parses_notifiers() {
+        assert_eq!(
+            "webhook:https://example.com/hook".parse(),
+            Ok(Notifier::Webhook("https://example.com/hook".to_owned()))
+        );
+        assert_eq!("desktop".parse(), Ok(Notifier::Desktop));
+        assert!("email:me@example.com".parse::<Notifier>().is_err());
+    }
+
+    #[test]
+    fn it_serializes_outcomes_with_a_status() {
+        let summary = Summary::default();
+        let completed = serde_json::to_value(&Outcome::Completed { summary: &summary }).unwrap();
+        assert_eq!(completed["status"], "completed");
+        assert_eq!(completed["summary"]["prepared_positions"], 0);
+        let failed = serde_json::to_value(&Outcome::Failed {
+            error: "boom".to_owned(),
+        })
+        .unwrap();
+        assert_eq!(failed["status"], "failed");
+        assert_eq!(failed["error"], "boom");
+    }
+}
@@ -1,17 +1,126 @@
+#[cfg(feature = "native")]
 pub mod cache;
+pub mod engine;
+pub mod explorer;
+pub mod fallback;
+#[cfg(feature = "network")]
 pub mod lichess;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
+use crate::error::Error;
+use crate::frequency_model::MoveStats;
 use crate::position::Fen;
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct BookMove {
     pub uci: String,
     pub frequency: f64,
+    pub score: f64,
+    pub year: Option<u32>,
+}
+pub type BookMoves = Vec<BookMove>;
+
+impl BookMove {
+    pub fn stats(&self) -> MoveStats {
+        MoveStats {
+            popularity: self.frequency,
+            score: self.score,
+            year: self.year,
+        }
+    }
 }
-type BookMoves = Vec<BookMove>;
 
 pub trait OpeningBook {
-    fn moves(&mut self, fen: &Fen) -> BookMoves;
+    fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error>;
+
+    /// Looks up several positions at once; books which can look up positions
+    /// concurrently override this
+    fn moves_batch(&mut self, fens: &[Fen]) -> Vec<Result<BookMoves, Error>> {
+        fens.iter().map(|fen| self.moves(fen)).collect()
+    }
+}
+
+/// Answers from a table of book moves by position, for hosts which gathered their statistics
+/// beforehand; Positions missing from the table have no book moves unless told otherwise
+#[derive(Debug, Clone, Default)]
+pub struct MapBook {
+    positions: HashMap<Fen, BookMoves>,
+    elsewhere: BookMoves,
+}
+
+impl MapBook {
+    pub fn new(positions: HashMap<Fen, BookMoves>) -> Self {
+        MapBook {
+            positions,
+            elsewhere: Vec::new(),
+        }
+    }
+
+    /// Answers the positions missing from the table with these moves
+    pub fn set_elsewhere(&mut self, book_moves: BookMoves) {
+        self.elsewhere = book_moves;
+    }
+}
+
+impl OpeningBook for MapBook {
+    fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error> {
+        Ok(self.positions.get(fen).unwrap_or(&self.elsewhere).clone())
+    }
+}
+
+pub type MovesFuture<'a> = Pin<Box<dyn Future<Output = Result<BookMoves, Error>> + Send + 'a>>;
+pub type BatchFuture<'a> = Pin<Box<dyn Future<Output = Vec<Result<BookMoves, Error>>> + Send + 'a>>;
+
+/// Opening book for async applications, which look up positions without blocking a thread
+/// per request; Its futures are `Send`, so they can be spawned on multi-threaded runtimes
+pub trait AsyncOpeningBook: Send {
+    fn moves<'a>(&'a mut self, fen: &'a Fen) -> MovesFuture<'a>;
+
+    /// Looks up several positions at once; books which can look up positions
+    /// concurrently override this
+    fn moves_batch<'a>(&'a mut self, fens: &'a [Fen]) -> BatchFuture<'a> {
+        Box::pin(async move {
+            let mut results = Vec::with_capacity(fens.len());
+            for fen in fens {
+                results.push(self.moves(fen).await);
+            }
+            results
+        })
+    }
+}
+
+/// What to do when the book fails to provide the moves for a position
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPolicy {
+    /// Stop the analysis with the error
+    Abort,
+    /// Treat the position as having no book moves
+    Skip,
+    /// Try again up to this many times, then abort
+    Retry(u32),
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "abort" => Ok(ErrorPolicy::Abort),
+            "skip" => Ok(ErrorPolicy::Skip),
+            _ => match policy.strip_prefix("retry:").map(str::parse) {
+                Some(Ok(retries)) => Ok(ErrorPolicy::Retry(retries)),
+                _ => Err(format!(
+                    "Unknown error policy '{}'; Expected 'abort', 'skip' or 'retry:<count>'",
+                    policy
+                )),
+            },
+        }
+    }
 }
@@ -2,69 +2,872 @@ use crate::error::Error;
 use crate::opening_book::*;
 use crate::position::Fen;
 
-use std::collections::HashMap;
+use indicatif::ProgressBar;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::{Read, Write};
+use std::iter;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Book moves as stored by versions without move statistics
+#[derive(Deserialize)]
+struct LegacyBookMove {
+    uci: String,
+    frequency: f64,
+}
+
+// Cache entries as stored by versions without use times
+#[derive(Serialize, Deserialize)]
+struct LegacyCacheEntry {
+    fetched: u64,
+    book_moves: BookMoves,
+}
+
+// Cache entries as stored by versions keying them by the text of their FEN
+#[derive(Serialize, Deserialize)]
+struct UnhashedCacheEntry {
+    fetched: u64,
+    used: u64,
+    book_moves: BookMoves,
+}
+
+/// Describes the cache data it is saved with, so damaged files are detected
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Seconds since the Unix epoch when the cache was first saved
+    pub created: u64,
+    /// The filters in use when the cache was last saved; Entries of other filters are kept too
+    pub filters: String,
+    /// Over all filters
+    pub entries: usize,
+    /// CRC32 of the serialized entries
+    pub checksum: u32,
+}
+
+/// Book moves of a position, with when they were fetched and last used
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Seconds since the Unix epoch; 0 if unknown, as for entries saved by older versions
+    pub fetched: u64,
+    /// Seconds since the Unix epoch, to the day
+    pub used: u64,
+    /// Half-moves played before the position, as entries are kept by Zobrist hash without
+    /// their FEN; None if unknown
+    #[serde(default)]
+    pub ply: Option<u32>,
+    pub book_moves: BookMoves,
+}
+
+// Entry of the JSON export, which is independent of the layout of the binary format;
+// Exports of older versions name the position by its FEN instead of its Zobrist hash
+#[derive(Serialize, Deserialize)]
+struct ExportedEntry {
+    filters: String,
+    #[serde(default)]
+    key: Option<u64>,
+    #[serde(default, skip_serializing)]
+    fen: Option<Fen>,
+    #[serde(flatten)]
+    entry: CacheEntry,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Export {
+    /// Seconds since the Unix epoch when the cache was first saved
+    created: Option<u64>,
+    entries: Vec<ExportedEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    metadata: Metadata,
+    data: Vec<u8>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Parses an age like '90d', with a unit of s(econds), m(inutes), h(ours), d(ays) or w(eeks)
+pub fn parse_age(age: &str) -> Result<Duration, String> {
+    let error = || {
+        format!(
+            "Invalid age '{}'; Expected a number followed by one of: s, m, h, d, w",
+            age
+        )
+    };
+    let unit = age.chars().last().ok_or_else(error)?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return Err(error()),
+    };
+    let count: u64 = age[..age.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| error())?;
+    Ok(Duration::from_secs(count * seconds))
+}
+
+// Cache files start with these bytes and the format version, followed by the compressed data;
+// Files without them were saved by older versions as plain bincode
+static MAGIC: &[u8] = b"CROCACHE";
+// Version 2 keeps the move counters of positions, which version 1 dropped;
+// Version 3 records when each entry was last used;
+// Version 4 keys entries by the Zobrist hash of their position instead of its FEN
+static FORMAT_VERSION: u32 = 4;
+static COMPRESSION_LEVEL: i32 = 9;
+
+// Uses are only recorded to the day, so merely reading the cache doesn't change it on every run
+static USE_RESOLUTION: u64 = 24 * 60 * 60;
+
+// Missing positions of a batch are fetched in chunks of this size, to report progress in between
+static FETCH_CHUNK: usize = 64;
+
+/// The filters of the query every explorer request used before rating bands, time controls and
+/// dates became configurable, which cache files saved without metadata were fetched with
+pub fn legacy_filters() -> String {
+    explorer::Query {
+        ratings: vec![1600, 1800, 2000, 2200, 2500],
+        speeds: vec![
+            "blitz".to_owned(),
+            "rapid".to_owned(),
+            "classical".to_owned(),
+        ],
+        since: None,
+        until: None,
+    }
+    .to_string()
+}
+
+/// Persistent storage which is consulted for positions missing from memory,
+/// and receives every new entry as soon as it is fetched
+pub trait Store {
+    fn get(&mut self, filters: &str, fen: &Fen) -> Result<Option<CacheEntry>, Error>;
+    fn insert(&mut self, filters: &str, fen: &Fen, entry: &CacheEntry) -> Result<(), Error>;
+}
+
+/// When to write the cache to disk while it is still being filled
+pub struct Checkpoint {
+    pub path: PathBuf,
+    /// After this many new entries
+    pub entries: Option<usize>,
+    /// After this much time since the last save
+    pub interval: Option<Duration>,
+}
+
+/// Overview of the entries of a cache
+#[derive(Debug, Default, PartialEq)]
+pub struct Statistics {
+    /// Number of entries by the filters they were fetched with
+    pub entries: BTreeMap<String, usize>,
+    /// Number of entries by the ply of their position, over all filters;
+    /// Positions saved by older versions have no known ply
+    pub plies: BTreeMap<Option<u32>, usize>,
+    /// Seconds since the Unix epoch when the cache was first saved
+    pub created: Option<u64>,
+    /// Seconds since the Unix epoch of the most recently fetched entry
+    pub last_fetched: Option<u64>,
+}
+
+// Answers no position, for working with cache files on their own
+struct NoBook;
+
+impl OpeningBook for NoBook {
+    fn moves(&mut self, _: &Fen) -> Result<BookMoves, Error> {
+        Ok(Vec::new())
+    }
+}
+
+// Entries by the filters of the games their book moves were taken from, then by the Zobrist
+// hash of their position
+type Namespaces = HashMap<String, HashMap<u64, CacheEntry>>;
+
+// Entry of the position as kept from older versions, which lack its ply
+fn hashed(fen: Fen, fetched: u64, used: u64, book_moves: BookMoves) -> (u64, CacheEntry) {
+    let entry = CacheEntry {
+        fetched,
+        used,
+        ply: fen.ply(),
+        book_moves,
+    };
+    (fen.key(), entry)
+}
 
 pub struct Cache<'a> {
-    cache: HashMap<Fen, BookMoves>,
+    namespaces: Namespaces,
+    // Entries fetched before this are re-fetched
+    stale_before: u64,
+    filters: String,
+    created: Option<u64>,
     has_changed: bool,
-    opening_book: Box<dyn OpeningBook + 'a>,
+    offline: bool,
+    progress: ProgressBar,
+    interrupted: Arc<AtomicBool>,
+    checkpoint: Option<Checkpoint>,
+    unsaved_entries: usize,
+    last_saved: Instant,
+    store: Option<Box<dyn Store + Send + 'a>>,
+    opening_book: Box<dyn OpeningBook + Send + 'a>,
 }
 
 impl<'a> Cache<'a> {
-    pub fn new<T: OpeningBook + 'a>(opening_book: T) -> Self {
+    /// The opening book has to be `Send`, so missing book moves can be fetched on a thread of
+    /// their own
+    pub fn new<T: OpeningBook + Send + 'a>(opening_book: T) -> Self {
         Cache {
-            cache: HashMap::new(),
+            namespaces: HashMap::new(),
+            stale_before: 0,
+            filters: String::new(),
+            created: None,
             has_changed: false,
+            offline: false,
+            progress: ProgressBar::hidden(),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            checkpoint: None,
+            unsaved_entries: 0,
+            last_saved: Instant::now(),
+            store: None,
             opening_book: Box::new(opening_book),
         }
     }
 
+    /// A cache without an opening book behind it, for loading, combining and saving cache files
+    pub fn detached() -> Self {
+        let mut cache = Cache::new(NoBook);
+        cache.offline = true;
+        cache
+    }
+
+    /// Describes the games the book moves are taken from; Entries cached for other filters
+    /// are kept, but never served
+    pub fn set_filters(&mut self, filters: String) {
+        self.filters = filters;
+    }
+
+    /// When offline, positions missing from the cache have no book moves,
+    /// instead of being looked up in the underlying opening book
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    /// Entries fetched longer ago than this are fetched again, unless offline;
+    /// A maximum age of zero refreshes every entry
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.stale_before = now().saturating_sub(max_age.as_secs());
+    }
+
+    /// Reports how many positions of a batch have been looked up so far
+    pub fn set_progress(&mut self, progress: ProgressBar) {
+        self.progress = progress;
+    }
+
+    /// Once the flag is set, positions missing from the cache fail with `Error::Interrupted`,
+    /// so the book moves fetched so far can still be saved
+    pub fn set_interrupt(&mut self, interrupted: Arc<AtomicBool>) {
+        self.interrupted = interrupted;
+    }
+
+    pub fn set_store<T: Store + Send + 'a>(&mut self, store: T) {
+        self.store = Some(Box::new(store));
+    }
+
+    fn entry(&self, fen: &Fen) -> Option<&CacheEntry> {
+        self.namespaces.get(&self.filters)?.get(&fen.key())
+    }
+
+    fn entries_mut(&mut self) -> &mut HashMap<u64, CacheEntry> {
+        self.namespaces.entry(self.filters.clone()).or_default()
+    }
+
+    fn cached_moves(&self, fen: &Fen) -> BookMoves {
+        self.entry(fen)
+            .map_or_else(Vec::new, |entry| entry.book_moves.clone())
+    }
+
+    // Stale entries are still good enough when there is no way to fetch them again
+    fn is_fresh(&self, entry: &CacheEntry) -> bool {
+        self.offline || entry.fetched >= self.stale_before
+    }
+
+    // Moves entries from the store into memory on demand; A failing store is treated as empty
+    fn is_cached(&mut self, fen: &Fen) -> bool {
+        if self.entry(fen).is_some_and(|entry| self.is_fresh(entry)) {
+            self.touch(fen);
+            return true;
+        }
+        let stored = match self.store {
+            Some(ref mut store) => store.get(&self.filters, fen),
+            None => return false,
+        };
+        match stored {
+            Ok(Some(entry)) if self.is_fresh(&entry) => {
+                self.entries_mut().insert(fen.key(), entry);
+                self.touch(fen);
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                warn!(
+                    "Failed to read '{}' from the cache store: {}",
+                    fen.fen_str(),
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    // Records that the entry of the position was used, so pruning keeps it
+    fn touch(&mut self, fen: &Fen) {
+        let today = now();
+        let touched = match self.entries_mut().get_mut(&fen.key()) {
+            Some(entry) if entry.used.saturating_add(USE_RESOLUTION) <= today => {
+                entry.used = today;
+                true
+            }
+            _ => false,
+        };
+        if touched {
+            self.has_changed = true;
+        }
+    }
+
+    fn remember(&mut self, fen: Fen, book_moves: BookMoves) {
+        let entry = CacheEntry {
+            fetched: now(),
+            used: now(),
+            ply: fen.ply(),
+            book_moves,
+        };
+        if let Some(ref mut store) = self.store {
+            if let Err(e) = store.insert(&self.filters, &fen, &entry) {
+                warn!(
+                    "Failed to write '{}' to the cache store: {}",
+                    fen.fen_str(),
+                    e
+                );
+            }
+        }
+        self.entries_mut().insert(fen.key(), entry);
+        self.has_changed = true;
+        self.unsaved_entries += 1;
+    }
+
+    pub fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.checkpoint = Some(checkpoint);
+    }
+
+    // Failing to write a checkpoint is not fatal, as the cache is saved at the end anyway
+    fn checkpoint_if_due(&mut self) {
+        let path = match self.checkpoint {
+            Some(ref checkpoint)
+                if self.unsaved_entries > 0
+                    && (checkpoint
+                        .entries
+                        .is_some_and(|entries| self.unsaved_entries >= entries)
+                        || checkpoint
+                            .interval
+                            .is_some_and(|interval| self.last_saved.elapsed() >= interval)) =>
+            {
+                checkpoint.path.clone()
+            }
+            _ => return,
+        };
+        // Write to a separate file first, so a crash while saving cannot corrupt the cache
+        let partial = path.with_extension("partial");
+        let result = File::create(&partial)
+            .map_err(Error::from)
+            .and_then(|file| self.save(file))
+            .and_then(|_| fs::rename(&partial, &path).map_err(Error::from));
+        match result {
+            Ok(_) => debug!("Checkpointed cache to '{}'", path.display()),
+            Err(e) => warn!("Failed to checkpoint cache to '{}': {}", path.display(), e),
+        }
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
     pub fn load<T: Read>(&mut self, mut source: T) -> Result<(), Error> {
         let mut data = Vec::new();
         source.read_to_end(&mut data)?;
-        self.cache = bincode::deserialize(&data)?;
-        self.has_changed = false;
+        if let Some(versioned) = data.strip_prefix(MAGIC) {
+            let (version, file) = Cache::decompress(versioned)?;
+            self.namespaces = self.verify(file, version)?;
+            // So the next save rewrites files of older versions in the current format
+            self.has_changed = version < FORMAT_VERSION;
+            return Ok(());
+        }
+        self.namespaces = match Cache::deserialize_exactly::<CacheFile>(&data) {
+            // Files saved before the format version was written count as version 0
+            Ok(file) => self.verify(file, 0)?,
+            // Files saved by versions without metadata hold book moves of the fixed query of that time
+            Err(e) => {
+                let cache = match Cache::deserialize_exactly(&data) {
+                    Ok(cache) => Cache::undated(cache),
+                    Err(_) => {
+                        let legacy: HashMap<Fen, Vec<LegacyBookMove>> =
+                            Cache::deserialize_exactly(&data).map_err(|_| e)?;
+                        Cache::undated(
+                            legacy
+                                .into_iter()
+                                .map(|(fen, book_moves)| (fen, Cache::migrate(book_moves)))
+                                .collect(),
+                        )
+                    }
+                };
+                iter::once((legacy_filters(), cache)).collect()
+            }
+        };
+        // So the next save rewrites it in the current format
+        self.has_changed = true;
         Ok(())
     }
 
+    fn decompress(versioned: &[u8]) -> Result<(u32, CacheFile), Error> {
+        if versioned.len() < 4 {
+            return Err(Error::CorruptCache("missing format version".to_owned()));
+        }
+        let (version, compressed) = versioned.split_at(4);
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(version);
+        let version = u32::from_le_bytes(bytes);
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(Error::CorruptCache(format!(
+                "unknown format version {}; It may have been saved by a newer version",
+                version
+            )));
+        }
+        let data = zstd::decode_all(compressed)
+            .map_err(|e| Error::CorruptCache(format!("decompression failed: {}", e)))?;
+        let file =
+            Cache::deserialize_exactly(&data).map_err(|e| Error::CorruptCache(e.to_string()))?;
+        Ok((version, file))
+    }
+
+    fn verify(&mut self, file: CacheFile, version: u32) -> Result<Namespaces, Error> {
+        let metadata = file.metadata;
+        if crc32fast::hash(&file.data) != metadata.checksum {
+            return Err(Error::CorruptCache("checksum mismatch".to_owned()));
+        }
+        let namespaces: Namespaces = match version {
+            4.. => Cache::deserialize_exactly(&file.data)?,
+            3 => Cache::unhashed(Cache::deserialize_exactly(&file.data)?),
+            _ => Cache::without_use_times(&file.data, &metadata.filters)?,
+        };
+        let entries: usize = namespaces.values().map(HashMap::len).sum();
+        if entries != metadata.entries {
+            return Err(Error::CorruptCache(format!(
+                "expected {} entries, found {}",
+                metadata.entries, entries
+            )));
+        }
+        self.created = Some(metadata.created);
+        Ok(namespaces)
+    }
+
+    // Only accept the data if it was consumed completely, so a mismatching layout is detected;
+    // The limit keeps damaged length fields from allocating more than the data could hold
+    fn deserialize_exactly<T: serde::de::DeserializeOwned>(mut data: &[u8]) -> Result<T, Error> {
+        let result = bincode::config()
+            .limit(data.len() as u64)
+            .deserialize_from(&mut data)?;
+        if data.is_empty() {
+            Ok(result)
+        } else {
+            Err(Error::Bincode(Box::new(bincode::ErrorKind::Custom(
+                "Trailing bytes after cache data".to_owned(),
+            ))))
+        }
+    }
+
+    // Entries of older versions count as last used when they were fetched;
+    // Files saved before entries were namespaced hold the entries of their filters only
+    fn without_use_times(data: &[u8], filters: &str) -> Result<Namespaces, Error> {
+        let namespaces: HashMap<String, HashMap<Fen, LegacyCacheEntry>> =
+            match Cache::deserialize_exactly(data) {
+                Ok(namespaces) => namespaces,
+                Err(e) => match Cache::deserialize_exactly(data) {
+                    Ok(cache) => iter::once((filters.to_owned(), cache)).collect(),
+                    Err(_) => {
+                        let cache =
+                            Cache::undated(Cache::deserialize_exactly(data).map_err(|_| e)?);
+                        return Ok(iter::once((filters.to_owned(), cache)).collect());
+                    }
+                },
+            };
+        Ok(namespaces
+            .into_iter()
+            .map(|(filters, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(fen, entry)| hashed(fen, entry.fetched, entry.fetched, entry.book_moves))
+                    .collect();
+                (filters, entries)
+            })
+            .collect())
+    }
+
+    fn unhashed(namespaces: HashMap<String, HashMap<Fen, UnhashedCacheEntry>>) -> Namespaces {
+        namespaces
+            .into_iter()
+            .map(|(filters, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(fen, entry)| hashed(fen, entry.fetched, entry.used, entry.book_moves))
+                    .collect();
+                (filters, entries)
+            })
+            .collect()
+    }
+
+    fn undated(cache: HashMap<Fen, BookMoves>) -> HashMap<u64, CacheEntry> {
+        cache
+            .into_iter()
+            .map(|(fen, book_moves)| hashed(fen, 0, 0, book_moves))
+            .collect()
+    }
+
+    fn migrate(book_moves: Vec<LegacyBookMove>) -> BookMoves {
+        book_moves
+            .into_iter()
+            .map(|book_move| BookMove {
+                uci: book_move.uci,
+                frequency: book_move.frequency,
+                score: 0.5,
+                year: None,
+            })
+            .collect()
+    }
+
     pub fn save<T: Write>(&mut self, mut destination: T) -> Result<(), Error> {
-        let data = bincode::serialize(&self.cache)?;
-        destination.write_all(&data)?;
+        let data = bincode::serialize(&self.namespaces)?;
+        let created = *self.created.get_or_insert_with(now);
+        let file = CacheFile {
+            metadata: Metadata {
+                created,
+                filters: self.filters.clone(),
+                entries: self.namespaces.values().map(HashMap::len).sum(),
+                checksum: crc32fast::hash(&data),
+            },
+            data,
+        };
+        destination.write_all(MAGIC)?;
+        destination.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        let mut encoder = zstd::Encoder::new(destination, COMPRESSION_LEVEL)?;
+        encoder.include_checksum(true)?;
+        encoder.write_all(&bincode::serialize(&file)?)?;
+        encoder.finish()?;
         self.has_changed = false;
+        self.unsaved_entries = 0;
+        self.last_saved = Instant::now();
         Ok(())
     }
 
     pub fn has_changed(&self) -> bool {
         self.has_changed
     }
+
+    pub fn statistics(&self) -> Statistics {
+        let mut statistics = Statistics {
+            created: self.created,
+            ..Statistics::default()
+        };
+        for (filters, entries) in &self.namespaces {
+            statistics.entries.insert(filters.clone(), entries.len());
+            for entry in entries.values() {
+                *statistics.plies.entry(entry.ply).or_default() += 1;
+                statistics.last_fetched = statistics.last_fetched.max(Some(entry.fetched));
+            }
+        }
+        statistics
+    }
+
+    /// Whether the position has a fresh entry for the current filters, in memory or in the
+    /// store, so looking it up doesn't fetch it
+    pub fn contains(&mut self, fen: &Fen) -> bool {
+        self.is_cached(fen)
+    }
+
+    /// Those of the positions whose entries for the current filters are older than the maximum
+    /// age, least recently fetched first; Entries are looked up in memory, then in the store,
+    /// and unlike lookups, this ignores being offline
+    pub fn stale_positions(&mut self, fens: &[Fen]) -> Vec<Fen> {
+        let mut considered = HashSet::new();
+        let mut stale = Vec::new();
+        for fen in fens {
+            if !considered.insert(fen.key()) {
+                continue;
+            }
+            let fetched = match (self.entry(fen).map(|entry| entry.fetched), &mut self.store) {
+                (Some(fetched), _) => Some(fetched),
+                (None, Some(store)) => match store.get(&self.filters, fen) {
+                    Ok(entry) => entry.map(|entry| entry.fetched),
+                    Err(e) => {
+                        warn!(
+                            "Failed to read '{}' from the cache store: {}",
+                            fen.fen_str(),
+                            e
+                        );
+                        None
+                    }
+                },
+                (None, None) => None,
+            };
+            if let Some(fetched) = fetched.filter(|fetched| *fetched < self.stale_before) {
+                stale.push((fetched, fen));
+            }
+        }
+        stale.sort_by(|(a_fetched, a), (b_fetched, b)| {
+            a_fetched.cmp(b_fetched).then_with(|| a.tie_break(b))
+        });
+        stale.into_iter().map(|(_, fen)| fen.clone()).collect()
+    }
+
+    /// The entries of a position for all filters, ordered by filters
+    pub fn lookup(&self, fen: &Fen) -> Vec<(&str, &CacheEntry)> {
+        let mut entries: Vec<(&str, &CacheEntry)> = self
+            .namespaces
+            .iter()
+            .filter_map(|(filters, entries)| Some((filters.as_str(), entries.get(&fen.key())?)))
+            .collect();
+        entries.sort_by_key(|(filters, _)| *filters);
+        entries
+    }
+
+    /// All entries with the Zobrist hash of their position, ordered by filters and hash
+    pub fn all_entries(&self) -> Vec<(&str, u64, &CacheEntry)> {
+        let mut entries: Vec<(&str, u64, &CacheEntry)> = self
+            .namespaces
+            .iter()
+            .flat_map(|(filters, entries)| {
+                entries
+                    .iter()
+                    .map(move |(key, entry)| (filters.as_str(), *key, entry))
+            })
+            .collect();
+        entries.sort_by_key(|(filters, key, _)| (*filters, *key));
+        entries
+    }
+
+    /// Writes all entries as JSON, to be inspected, diffed or imported by other versions
+    pub fn export_json<T: Write>(&self, destination: T) -> Result<(), Error> {
+        let export = Export {
+            created: self.created,
+            entries: self
+                .all_entries()
+                .into_iter()
+                .map(|(filters, key, entry)| ExportedEntry {
+                    filters: filters.to_owned(),
+                    key: Some(key),
+                    fen: None,
+                    entry: entry.clone(),
+                })
+                .collect(),
+        };
+        serde_json::to_writer_pretty(destination, &export)?;
+        Ok(())
+    }
+
+    /// Adds the entries of a JSON export like `merge` does; Returns how many entries were added
+    /// or replaced
+    pub fn import_json<T: Read>(&mut self, source: T) -> Result<usize, Error> {
+        let export: Export = serde_json::from_reader(source)?;
+        let mut imported = Cache::detached();
+        imported.created = export.created;
+        for ExportedEntry {
+            filters,
+            key,
+            fen,
+            entry,
+        } in export.entries
+        {
+            let (key, entry) = match (key, fen) {
+                (Some(key), _) => (key, entry),
+                (None, Some(fen)) => hashed(fen, entry.fetched, entry.used, entry.book_moves),
+                (None, None) => {
+                    return Err(Error::CorruptCache(
+                        "exported entry without a position".to_owned(),
+                    ))
+                }
+            };
+            imported
+                .namespaces
+                .entry(filters)
+                .or_default()
+                .insert(key, entry);
+        }
+        Ok(self.merge(imported))
+    }
+
+    /// Removes the entries of positions deeper than `max_ply` and of those not used for
+    /// `max_idle`, over all filters; Returns how many entries were removed
+    pub fn prune(&mut self, max_ply: Option<u32>, max_idle: Option<Duration>) -> usize {
+        let used_since = max_idle.map_or(0, |idle| now().saturating_sub(idle.as_secs()));
+        let mut removed = 0;
+        for entries in self.namespaces.values_mut() {
+            let before = entries.len();
+            // Positions saved without move counters are kept, as their ply is unknown
+            entries.retain(|_, entry| {
+                entry.used >= used_since
+                    && entry
+                        .ply
+                        .zip(max_ply)
+                        .is_none_or(|(ply, max_ply)| ply <= max_ply)
+            });
+            removed += before - entries.len();
+        }
+        self.namespaces.retain(|_, entries| !entries.is_empty());
+        if removed > 0 {
+            self.has_changed = true;
+        }
+        removed
+    }
+
+    /// Adds the entries of another cache, keeping the more recently fetched one of positions
+    /// cached by both; Returns how many entries were added or replaced
+    pub fn merge(&mut self, other: Cache<'_>) -> usize {
+        let mut merged = 0;
+        for (filters, entries) in other.namespaces {
+            let namespace = self.namespaces.entry(filters).or_default();
+            for (key, entry) in entries {
+                let used = namespace
+                    .get(&key)
+                    .map_or(entry.used, |known| known.used.max(entry.used));
+                match namespace.get_mut(&key) {
+                    Some(known) if known.fetched >= entry.fetched => known.used = used,
+                    _ => {
+                        namespace.insert(key, CacheEntry { used, ..entry });
+                        merged += 1;
+                    }
+                }
+            }
+        }
+        if let Some(created) = other.created {
+            self.created = Some(self.created.map_or(created, |own| own.min(created)));
+        }
+        if merged > 0 {
+            self.has_changed = true;
+        }
+        merged
+    }
 }
 
 impl OpeningBook for Cache<'_> {
-    fn moves(&mut self, fen: &Fen) -> BookMoves {
-        let has_changed = &mut self.has_changed;
-        let cache = &mut self.cache;
-        let opening_book = &mut self.opening_book;
-        cache
-            .entry(fen.clone())
-            .or_insert_with(|| {
-                *has_changed = true;
-                opening_book.moves(fen)
+    fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error> {
+        if self.is_cached(fen) {
+            return Ok(self.cached_moves(fen));
+        }
+        if self.offline {
+            warn!(
+                "Position '{}' is not cached; Treating it as out of book",
+                fen.fen_str()
+            );
+            return Ok(Vec::new());
+        }
+        if self.is_interrupted() {
+            return Err(Error::Interrupted);
+        }
+        let book_moves = self.opening_book.moves(fen)?;
+        self.remember(fen.clone(), book_moves.clone());
+        self.checkpoint_if_due();
+        Ok(book_moves)
+    }
+
+    fn moves_batch(&mut self, fens: &[Fen]) -> Vec<Result<BookMoves, Error>> {
+        let mut requested = HashSet::new();
+        let missing: Vec<Fen> = fens
+            .iter()
+            .filter(|fen| !self.is_cached(fen) && requested.insert(*fen))
+            .cloned()
+            .collect();
+        if self.offline {
+            if !missing.is_empty() {
+                warn!(
+                    "{} positions are not cached; Treating them as out of book",
+                    missing.len()
+                );
+            }
+            for fen in &missing {
+                debug!("Position '{}' is not cached", fen.fen_str());
+            }
+            return fens.iter().map(|fen| Ok(self.cached_moves(fen))).collect();
+        }
+        let total = fens.iter().collect::<HashSet<_>>().len();
+        let cached = total - missing.len();
+        self.progress.set_length(total as u64);
+        self.progress.set_position(cached as u64);
+        self.progress.set_message(format!("{} from cache", cached));
+        self.progress.reset_eta();
+        let mut errors = HashMap::new();
+        for chunk in missing.chunks(FETCH_CHUNK) {
+            if self.is_interrupted() {
+                break;
+            }
+            let fetched = self.opening_book.moves_batch(chunk);
+            for (fen, result) in chunk.iter().zip(fetched) {
+                match result {
+                    Ok(book_moves) => self.remember(fen.clone(), book_moves),
+                    Err(e) => {
+                        errors.insert(fen.clone(), e);
+                    }
+                }
+            }
+            self.progress.inc(chunk.len() as u64);
+            self.checkpoint_if_due();
+        }
+        self.progress.finish_and_clear();
+        fens.iter()
+            .map(|fen| {
+                if self.is_cached(fen) {
+                    Ok(self.cached_moves(fen))
+                } else {
+                    // Repeated positions only get the first error; the following ones retry
+                    errors.remove(fen).map_or_else(|| self.moves(fen), Err)
+                }
             })
-            .clone()
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error::Error;
+    use crate::opening_book::cache::{
+        hashed, legacy_filters, now, parse_age, CacheEntry, CacheFile, Checkpoint,
+        LegacyCacheEntry, Metadata, Store, UnhashedCacheEntry, MAGIC,
+    };
     use crate::opening_book::*;
 
     use std::collections::HashMap;
+    use std::fs::File;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     struct BookDouble {
         configuration: HashMap<Fen, BookMoves>,
     }
 
+    struct FailingBook;
+
+    impl OpeningBook for FailingBook {
+        fn moves(&mut self, _: &Fen) -> Result<BookMoves, Error> {
+            Err(Error::Http)
+        }
+    }
+
     impl BookDouble {
         fn new() -> Self {
             Self {
@@ -79,11 +882,11 @@ mod tests {
     }
 
     impl OpeningBook for BookDouble {
-        fn moves(&mut self, fen: &Fen) -> BookMoves {
+        fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error> {
             // Configurations are single-use only
             // This makes sure that the Book is only called once for each Fen
             // => Caching works correctly
-            self.configuration.remove(fen).unwrap()
+            Ok(self.configuration.remove(fen).unwrap())
         }
     }
 
@@ -95,6 +898,8 @@ mod tests {
             vec![BookMove {
                 uci: "e2e4".to_owned(),
                 frequency: 0.5,
+                score: 0.5,
+                year: None,
             }],
         );
         let (fen_2, book_moves_2) = book.configure(
@@ -102,6 +907,8 @@ mod tests {
             vec![BookMove {
                 uci: "d2d4".to_owned(),
                 frequency: 0.3,
+                score: 0.5,
+                year: None,
             }],
         );
         let (fen_3, book_moves_3) = book.configure(
@@ -109,12 +916,14 @@ mod tests {
             vec![BookMove {
                 uci: "c2c4".to_owned(),
                 frequency: 0.1,
+                score: 0.5,
+                year: None,
             }],
         );
         let mut cache = crate::opening_book::cache::Cache::new(book);
-        let result_1 = cache.moves(&fen_1);
-        let result_2 = cache.moves(&fen_2);
-        let result_3 = cache.moves(&fen_3);
+        let result_1 = cache.moves(&fen_1).unwrap();
+        let result_2 = cache.moves(&fen_2).unwrap();
+        let result_3 = cache.moves(&fen_3).unwrap();
         assert_eq!(result_1, book_moves_1);
         assert_eq!(result_2, book_moves_2);
         assert_eq!(result_3, book_moves_3);
@@ -128,6 +937,8 @@ mod tests {
             vec![BookMove {
                 uci: "e2e4".to_owned(),
                 frequency: 0.5,
+                score: 0.5,
+                year: None,
             }],
         );
         let (fen_2, book_moves_2) = book.configure(
@@ -135,6 +946,8 @@ mod tests {
             vec![BookMove {
                 uci: "d2d4".to_owned(),
                 frequency: 0.3,
+                score: 0.5,
+                year: None,
             }],
         );
         let (fen_3, book_moves_3) = book.configure(
@@ -142,6 +955,8 @@ mod tests {
             vec![BookMove {
                 uci: "c2c4".to_owned(),
                 frequency: 0.1,
+                score: 0.5,
+                year: None,
             }],
         );
         let mut cache = crate::opening_book::cache::Cache::new(book);
@@ -152,15 +967,414 @@ mod tests {
         let _ = cache.moves(&fen_3);
 
         // Repeat requests in different order
-        let result_2 = cache.moves(&fen_2);
-        let result_3 = cache.moves(&fen_3);
-        let result_1 = cache.moves(&fen_1);
+        let result_2 = cache.moves(&fen_2).unwrap();
+        let result_3 = cache.moves(&fen_3).unwrap();
+        let result_1 = cache.moves(&fen_1).unwrap();
 
         assert_eq!(result_1, book_moves_1);
         assert_eq!(result_2, book_moves_2);
         assert_eq!(result_3, book_moves_3);
     }
 
+    #[test]
+    fn it_does_not_cache_errors() {
+        let mut cache = crate::opening_book::cache::Cache::new(FailingBook);
+        assert!(cache.moves(&Fen::starting_board()).is_err());
+        assert!(cache.moves(&Fen::starting_board()).is_err());
+        assert_eq!(cache.has_changed(), false);
+    }
+
+    #[test]
+    fn it_only_answers_from_the_cache_when_offline() {
+        let mut book = BookDouble::new();
+        let (fen_1, book_moves_1) = book.configure(
+            Fen::starting_board(),
+            vec![BookMove {
+                uci: "e2e4".to_owned(),
+                frequency: 0.5,
+                score: 0.5,
+                year: None,
+            }],
+        );
+        let fen_2 = Fen::new("a b c d e f");
+        let mut cache = crate::opening_book::cache::Cache::new(book);
+        let _ = cache.moves(&fen_1);
+        let _ = cache.save(&mut Vec::new());
+        cache.set_offline(true);
+
+        assert_eq!(cache.moves(&fen_1).unwrap(), book_moves_1);
+        assert_eq!(cache.moves(&fen_2).unwrap(), Vec::new());
+        let results: Vec<BookMoves> = cache
+            .moves_batch(&[fen_2.clone(), fen_1.clone()])
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(results, vec![Vec::new(), book_moves_1]);
+        assert!(!cache.has_changed());
+    }
+
+    #[test]
+    fn it_passes_batches_through_the_cache() {
+        let mut book = BookDouble::new();
+        let (fen_1, book_moves_1) = book.configure(
+            Fen::starting_board(),
+            vec![BookMove {
+                uci: "e2e4".to_owned(),
+                frequency: 0.5,
+                score: 0.5,
+                year: None,
+            }],
+        );
+        let (fen_2, book_moves_2) = book.configure(
+            Fen::new("a b c d e f"),
+            vec![BookMove {
+                uci: "d2d4".to_owned(),
+                frequency: 0.3,
+                score: 0.5,
+                year: None,
+            }],
+        );
+        let mut cache = crate::opening_book::cache::Cache::new(book);
+
+        // Request one position on its own, so the batch needs to combine cached and new results
+        let _ = cache.moves(&fen_1);
+        let results: Vec<BookMoves> = cache
+            .moves_batch(&[fen_2.clone(), fen_1.clone(), fen_2.clone()])
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(
+            results,
+            vec![book_moves_2.clone(), book_moves_1, book_moves_2]
+        );
+    }
+
+    #[test]
+    fn it_reports_progress_over_distinct_positions() {
+        let mut book = BookDouble::new();
+        let (fen_1, _) = book.configure(Fen::starting_board(), Vec::new());
+        let (fen_2, _) = book.configure(Fen::new("a b c d e f"), Vec::new());
+        let mut cache = crate::opening_book::cache::Cache::new(book);
+        let progress = indicatif::ProgressBar::hidden();
+        cache.set_progress(progress.clone());
+
+        let _ = cache.moves(&fen_1);
+        let _ = cache.moves_batch(&[fen_2.clone(), fen_1.clone(), fen_2.clone()]);
+
+        assert_eq!(progress.length(), Some(2));
+        assert_eq!(progress.position(), 2);
+        assert_eq!(progress.message(), "1 from cache");
+    }
+
+    #[test]
+    fn it_stops_fetching_when_interrupted() {
+        let mut book = BookDouble::new();
+        let (fen_1, book_moves_1) = book.configure(Fen::starting_board(), Vec::new());
+        let fen_2 = Fen::new("a b c d e f");
+        let mut cache = crate::opening_book::cache::Cache::new(book);
+        let interrupted = Arc::new(AtomicBool::new(false));
+        cache.set_interrupt(interrupted.clone());
+        let _ = cache.moves(&fen_1);
+        interrupted.store(true, Ordering::SeqCst);
+
+        let results = cache.moves_batch(&[fen_1.clone(), fen_2.clone()]);
+        assert_eq!(results[0].as_ref().unwrap(), &book_moves_1);
+        assert!(matches!(results[1], Err(Error::Interrupted)));
+        assert!(matches!(cache.moves(&fen_2), Err(Error::Interrupted)));
+        assert!(cache.has_changed());
+    }
+
+    #[test]
+    fn it_checkpoints_after_enough_new_entries() {
+        let mut book = BookDouble::new();
+        let (fen_1, book_moves_1) = book.configure(Fen::starting_board(), Vec::new());
+        let (fen_2, _) = book.configure(Fen::new("a b c d e f"), Vec::new());
+        let path = std::env::temp_dir().join(format!("cro-checkpoint-{}.bin", std::process::id()));
+        let mut cache = crate::opening_book::cache::Cache::new(book);
+        cache.set_checkpoint(Checkpoint {
+            path: path.clone(),
+            entries: Some(2),
+            interval: None,
+        });
+
+        let _ = cache.moves(&fen_1);
+        assert!(!path.exists());
+        let _ = cache.moves_batch(std::slice::from_ref(&fen_2));
+        assert!(!cache.has_changed());
+
+        let mut restored = crate::opening_book::cache::Cache::new(FailingBook);
+        restored.load(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.moves(&fen_1).unwrap(), book_moves_1);
+        assert!(restored.moves(&fen_2).is_ok());
+    }
+
+    #[derive(Default)]
+    struct StoreDouble {
+        entries: HashMap<Fen, CacheEntry>,
+    }
+
+    impl Store for &mut StoreDouble {
+        fn get(&mut self, _: &str, fen: &Fen) -> Result<Option<CacheEntry>, Error> {
+            Ok(self.entries.get(fen).cloned())
+        }
+
+        fn insert(&mut self, _: &str, fen: &Fen, entry: &CacheEntry) -> Result<(), Error> {
+            self.entries.insert(fen.clone(), entry.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_reads_and_writes_through_the_store() {
+        let mut book = BookDouble::new();
+        let (fen_1, book_moves_1) = book.configure(Fen::starting_board(), Vec::new());
+        let fen_2 = Fen::new("a b c d e f");
+        let mut store = StoreDouble::default();
+        let entry = CacheEntry {
+            fetched: u64::MAX,
+            used: u64::MAX,
+            ply: None,
+            book_moves: Vec::new(),
+        };
+        store.entries.insert(fen_2.clone(), entry);
+        {
+            let mut cache = crate::opening_book::cache::Cache::new(book);
+            cache.set_store(&mut store);
+            let results = cache.moves_batch(&[fen_1.clone(), fen_2.clone()]);
+            assert!(results.iter().all(Result::is_ok));
+        }
+        assert_eq!(store.entries[&fen_1].book_moves, book_moves_1);
+    }
+
+    #[test]
+    fn it_fetches_entries_again_once_they_are_too_old() {
+        let mut book = BookDouble::new();
+        let (fen, _) = book.configure(Fen::starting_board(), Vec::new());
+        let old = vec![BookMove {
+            uci: "e2e4".to_owned(),
+            frequency: 0.5,
+            score: 0.5,
+            year: None,
+        }];
+        let plain: HashMap<Fen, BookMoves> = vec![(fen.clone(), old.clone())].into_iter().collect();
+        let data = bincode::serialize(&plain).unwrap();
+        let mut cache = crate::opening_book::cache::Cache::new(book);
+        cache.load(data.as_slice()).unwrap();
+
+        assert_eq!(cache.moves(&fen).unwrap(), old);
+        cache.set_max_age(Duration::from_secs(90 * 24 * 60 * 60));
+        cache.set_offline(true);
+        assert_eq!(cache.moves(&fen).unwrap(), old);
+        cache.set_offline(false);
+        assert_eq!(cache.moves(&fen).unwrap(), Vec::new());
+        assert_eq!(cache.moves(&fen).unwrap(), Vec::new());
+        assert!(cache.has_changed());
+    }
+
+    #[test]
+    fn it_parses_ages_with_units() {
+        assert_eq!(parse_age("90d"), Ok(Duration::from_secs(90 * 24 * 60 * 60)));
+        assert_eq!(parse_age("2w"), Ok(Duration::from_secs(14 * 24 * 60 * 60)));
+        assert_eq!(parse_age("30s"), Ok(Duration::from_secs(30)));
+        assert!(parse_age("90").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("").is_err());
+    }
+
+    #[test]
+    fn it_keeps_the_newer_entry_when_merging() {
+        let entry = |fen: &Fen, fetched, uci: &str| {
+            let book_moves = vec![BookMove {
+                uci: uci.to_owned(),
+                frequency: 1.0,
+                score: 0.5,
+                year: None,
+            }];
+            hashed(fen.clone(), fetched, fetched, book_moves)
+        };
+        let fen_1 = Fen::starting_board();
+        let fen_2 = Fen::new("a b c d e f");
+        let mut cache = crate::opening_book::cache::Cache::detached();
+        let mut other = crate::opening_book::cache::Cache::detached();
+        let entries = cache.namespaces.entry(String::new()).or_default();
+        entries.extend(vec![entry(&fen_1, 20, "e2e4"), entry(&fen_2, 10, "d2d4")]);
+        let entries = other.namespaces.entry(String::new()).or_default();
+        entries.extend(vec![entry(&fen_1, 10, "c2c4"), entry(&fen_2, 20, "g1f3")]);
+        other
+            .namespaces
+            .entry("ratings 2000".to_owned())
+            .or_default()
+            .extend(vec![entry(&fen_1, 10, "b2b3")]);
+
+        assert_eq!(cache.merge(other), 2);
+        assert!(cache.has_changed());
+        assert_eq!(cache.moves(&fen_1).unwrap()[0].uci, "e2e4");
+        assert_eq!(cache.moves(&fen_2).unwrap()[0].uci, "g1f3");
+        cache.set_filters("ratings 2000".to_owned());
+        assert_eq!(cache.moves(&fen_1).unwrap()[0].uci, "b2b3");
+    }
+
+    #[test]
+    fn it_round_trips_entries_through_json() {
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let mut cache = crate::opening_book::cache::Cache::detached();
+        cache.created = Some(1_500_000_000);
+        cache
+            .namespaces
+            .entry("ratings 2000".to_owned())
+            .or_default()
+            .extend(vec![hashed(
+                after_e4.clone(),
+                1_600_000_000,
+                1_700_000_000,
+                vec![BookMove {
+                    uci: "e7e5".to_owned(),
+                    frequency: 0.4,
+                    score: 0.5,
+                    year: Some(2023),
+                }],
+            )]);
+        let mut json = Vec::new();
+        cache.export_json(&mut json).unwrap();
+
+        let mut imported = crate::opening_book::cache::Cache::detached();
+        assert_eq!(imported.import_json(&json[..]).unwrap(), 1);
+        assert_eq!(imported.all_entries(), cache.all_entries());
+        assert_eq!(imported.all_entries()[0].1, after_e4.key());
+        assert_eq!(imported.statistics().created, Some(1_500_000_000));
+        assert!(imported.import_json(&b"{}"[..]).is_err());
+    }
+
+    #[test]
+    fn it_imports_json_exports_naming_positions_by_fen() {
+        let json = r#"{
+            "created": null,
+            "entries": [{
+                "filters": "",
+                "fen": "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+                "fetched": 10,
+                "used": 10,
+                "book_moves": []
+            }]
+        }"#;
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let mut cache = crate::opening_book::cache::Cache::detached();
+        assert_eq!(cache.import_json(json.as_bytes()).unwrap(), 1);
+        assert_eq!(cache.lookup(&after_e4)[0].1.ply, Some(1));
+    }
+
+    #[test]
+    fn it_lists_stale_positions_oldest_first() {
+        let entry = |fen: &Fen, fetched| hashed(fen.clone(), fetched, fetched, Vec::new());
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let after_d4 = Fen::new("rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1");
+        let after_c4 = Fen::new("rnbqkbnr/pppppppp/8/8/2P5/8/PP1PPPPP/RNBQKBNR b KQkq - 0 1");
+        let mut store = StoreDouble::default();
+        store.entries.insert(
+            after_c4.clone(),
+            CacheEntry {
+                fetched: 15,
+                used: 15,
+                ply: after_c4.ply(),
+                book_moves: Vec::new(),
+            },
+        );
+        let mut cache = crate::opening_book::cache::Cache::detached();
+        cache.set_store(&mut store);
+        let entries = cache.namespaces.entry(String::new()).or_default();
+        entries.extend(vec![
+            entry(&Fen::starting_board(), now()),
+            entry(&after_e4, 20),
+            entry(&after_d4, 10),
+        ]);
+        cache
+            .namespaces
+            .entry("ratings 2000".to_owned())
+            .or_default()
+            .extend(vec![entry(&Fen::starting_board(), 0)]);
+        let fens = vec![
+            Fen::starting_board(),
+            after_e4.clone(),
+            after_d4.clone(),
+            after_c4.clone(),
+        ];
+
+        assert!(cache.stale_positions(&fens).is_empty());
+        cache.set_max_age(Duration::from_secs(60));
+        assert_eq!(
+            cache.stale_positions(&fens),
+            vec![after_d4, after_c4, after_e4.clone()]
+        );
+        assert!(cache.contains(&after_e4));
+        cache.set_offline(false);
+        assert!(!cache.contains(&after_e4));
+        assert!(cache.contains(&Fen::starting_board()));
+    }
+
+    #[test]
+    fn it_prunes_deep_and_unused_entries() {
+        let entry = |fen: &Fen, used| hashed(fen.clone(), 0, used, Vec::new());
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let unused = Fen::new("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2");
+        let deep = Fen::new("rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 9");
+        let mut cache = crate::opening_book::cache::Cache::detached();
+        let entries = cache.namespaces.entry(String::new()).or_default();
+        entries.extend(vec![
+            entry(&Fen::starting_board(), now()),
+            entry(&after_e4, now()),
+            entry(&unused, 0),
+            entry(&deep, now()),
+        ]);
+
+        assert_eq!(cache.prune(None, Some(Duration::from_secs(60))), 1);
+        assert_eq!(cache.prune(Some(0), None), 2);
+        assert!(cache.has_changed());
+        let kept: Vec<u64> = cache
+            .all_entries()
+            .into_iter()
+            .map(|(_, key, _)| key)
+            .collect();
+        assert_eq!(kept, vec![Fen::starting_board().key()]);
+    }
+
+    #[test]
+    fn it_counts_entries_by_filters_and_ply() {
+        let entry = |fen: &Fen, fetched| hashed(fen.clone(), fetched, fetched, Vec::new());
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let mut cache = crate::opening_book::cache::Cache::detached();
+        cache
+            .namespaces
+            .entry("ratings 2000".to_owned())
+            .or_default()
+            .extend(vec![
+                entry(&Fen::starting_board(), 10),
+                entry(&after_e4, 30),
+            ]);
+        cache
+            .namespaces
+            .entry("ratings 1600".to_owned())
+            .or_default()
+            .extend(vec![entry(&after_e4, 20)]);
+        let statistics = cache.statistics();
+
+        assert_eq!(statistics.entries["ratings 2000"], 2);
+        assert_eq!(statistics.entries["ratings 1600"], 1);
+        assert_eq!(
+            statistics.plies.into_iter().collect::<Vec<_>>(),
+            vec![(Some(0), 1), (Some(1), 2)]
+        );
+        assert_eq!(statistics.last_fetched, Some(30));
+        let filters: Vec<&str> = cache
+            .lookup(&after_e4)
+            .into_iter()
+            .map(|(filters, _)| filters)
+            .collect();
+        assert_eq!(filters, vec!["ratings 1600", "ratings 2000"]);
+        assert_eq!(cache.all_entries().len(), 3);
+    }
+
     #[test]
     fn it_has_no_changes_after_creation() {
         let book = BookDouble::new();
@@ -176,6 +1390,8 @@ mod tests {
             vec![BookMove {
                 uci: "e2e4".to_owned(),
                 frequency: 0.5,
+                score: 0.5,
+                year: None,
             }],
         );
         let mut cache = crate::opening_book::cache::Cache::new(book);
@@ -196,6 +1412,8 @@ mod tests {
             vec![BookMove {
                 uci: "e2e4".to_owned(),
                 frequency: 0.5,
+                score: 0.5,
+                year: None,
             }],
         );
         let mut cache = crate::opening_book::cache::Cache::new(book);
@@ -218,6 +1436,8 @@ mod tests {
             vec![BookMove {
                 uci: "e2e4".to_owned(),
                 frequency: 0.5,
+                score: 0.5,
+                year: None,
             }],
         );
         let (fen_2, book_moves_2) = book_1.configure(
@@ -225,6 +1445,8 @@ mod tests {
             vec![BookMove {
                 uci: "d2d4".to_owned(),
                 frequency: 0.3,
+                score: 0.5,
+                year: None,
             }],
         );
         let (fen_3, book_moves_3) = book_2.configure(
@@ -232,6 +1454,8 @@ mod tests {
             vec![BookMove {
                 uci: "c2c4".to_owned(),
                 frequency: 0.1,
+                score: 0.5,
+                year: None,
             }],
         );
         let mut cache = crate::opening_book::cache::Cache::new(book_1);
@@ -246,15 +1470,163 @@ mod tests {
         let _ = cache.load(data.as_slice());
 
         // Make both new requests and ones that should be cached
-        let result_2 = cache.moves(&fen_2);
-        let result_3 = cache.moves(&fen_3);
-        let result_1 = cache.moves(&fen_1);
+        let result_2 = cache.moves(&fen_2).unwrap();
+        let result_3 = cache.moves(&fen_3).unwrap();
+        let result_1 = cache.moves(&fen_1).unwrap();
 
         assert_eq!(result_1, book_moves_1);
         assert_eq!(result_2, book_moves_2);
         assert_eq!(result_3, book_moves_3);
     }
 
+    #[test]
+    fn it_rejects_damaged_save_data() {
+        let mut book = BookDouble::new();
+        let (fen, _) = book.configure(Fen::starting_board(), Vec::new());
+        let mut cache = crate::opening_book::cache::Cache::new(book);
+        let _ = cache.moves(&fen);
+        let mut data = Vec::new();
+        cache.save(&mut data).unwrap();
+
+        let mut damaged = data.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        let mut cache = crate::opening_book::cache::Cache::new(BookDouble::new());
+        assert!(matches!(
+            cache.load(damaged.as_slice()),
+            Err(Error::CorruptCache(_))
+        ));
+        assert!(cache.load(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn it_keeps_but_does_not_serve_entries_of_other_filters() {
+        let mut book = BookDouble::new();
+        let (fen, book_moves) = book.configure(Fen::starting_board(), Vec::new());
+        let mut cache = crate::opening_book::cache::Cache::new(book);
+        cache.set_filters("ratings 2000".to_owned());
+        let _ = cache.moves(&fen);
+        let mut data = Vec::new();
+        cache.save(&mut data).unwrap();
+
+        let mut cache = crate::opening_book::cache::Cache::new(FailingBook);
+        cache.set_filters("ratings 1600".to_owned());
+        cache.load(data.as_slice()).unwrap();
+        assert!(cache.moves(&fen).is_err());
+        let mut resaved = Vec::new();
+        cache.save(&mut resaved).unwrap();
+
+        let mut cache = crate::opening_book::cache::Cache::new(FailingBook);
+        cache.set_filters("ratings 2000".to_owned());
+        cache.load(resaved.as_slice()).unwrap();
+        assert_eq!(cache.moves(&fen).unwrap(), book_moves);
+    }
+
+    #[test]
+    fn it_loads_save_data_without_namespaces() {
+        let entries: HashMap<Fen, LegacyCacheEntry> = vec![(
+            Fen::starting_board(),
+            LegacyCacheEntry {
+                fetched: 0,
+                book_moves: Vec::new(),
+            },
+        )]
+        .into_iter()
+        .collect();
+        let data = bincode::serialize(&entries).unwrap();
+        let file = CacheFile {
+            metadata: Metadata {
+                created: 0,
+                filters: "ratings 2000".to_owned(),
+                entries: 1,
+                checksum: crc32fast::hash(&data),
+            },
+            data,
+        };
+        let data = bincode::serialize(&file).unwrap();
+
+        let mut cache = crate::opening_book::cache::Cache::new(FailingBook);
+        cache.set_filters("ratings 1600".to_owned());
+        cache.load(data.as_slice()).unwrap();
+        assert!(cache.moves(&Fen::starting_board()).is_err());
+        cache.set_filters("ratings 2000".to_owned());
+        assert_eq!(cache.moves(&Fen::starting_board()).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn it_loads_save_data_keyed_by_fen() {
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let entries: HashMap<Fen, UnhashedCacheEntry> = vec![(
+            after_e4.clone(),
+            UnhashedCacheEntry {
+                fetched: 0,
+                used: 0,
+                book_moves: Vec::new(),
+            },
+        )]
+        .into_iter()
+        .collect();
+        let namespaces: HashMap<String, HashMap<Fen, UnhashedCacheEntry>> =
+            vec![(String::new(), entries)].into_iter().collect();
+        let data = bincode::serialize(&namespaces).unwrap();
+        let file = CacheFile {
+            metadata: Metadata {
+                created: 0,
+                filters: String::new(),
+                entries: 1,
+                checksum: crc32fast::hash(&data),
+            },
+            data,
+        };
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&3u32.to_le_bytes());
+        data.extend(zstd::encode_all(&bincode::serialize(&file).unwrap()[..], 0).unwrap());
+
+        let mut cache = crate::opening_book::cache::Cache::new(FailingBook);
+        cache.load(data.as_slice()).unwrap();
+        assert!(cache.has_changed());
+        assert_eq!(cache.moves(&after_e4).unwrap(), Vec::new());
+        assert_eq!(
+            cache.statistics().plies.into_iter().collect::<Vec<_>>(),
+            vec![(Some(1), 1)]
+        );
+    }
+
+    #[test]
+    fn it_loads_save_data_without_metadata() {
+        let plain: HashMap<Fen, BookMoves> = vec![(Fen::starting_board(), Vec::new())]
+            .into_iter()
+            .collect();
+        let data = bincode::serialize(&plain).unwrap();
+        let mut cache = crate::opening_book::cache::Cache::new(FailingBook);
+        cache.set_filters("ratings 2200; speeds blitz; since any; until any".to_owned());
+        cache.load(data.as_slice()).unwrap();
+        assert!(cache.moves(&Fen::starting_board()).is_err());
+        cache.set_filters(legacy_filters());
+        assert_eq!(cache.moves(&Fen::starting_board()).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn it_loads_save_data_without_move_statistics() {
+        let legacy: HashMap<Fen, Vec<(String, f64)>> =
+            vec![(Fen::starting_board(), vec![("e2e4".to_owned(), 0.5)])]
+                .into_iter()
+                .collect();
+        let data = bincode::serialize(&legacy).unwrap();
+        let mut cache = crate::opening_book::cache::Cache::new(BookDouble::new());
+        cache.set_filters(legacy_filters());
+        cache.load(data.as_slice()).unwrap();
+
+        assert_eq!(
+            cache.moves(&Fen::starting_board()).unwrap(),
+            vec![BookMove {
+                uci: "e2e4".to_owned(),
+                frequency: 0.5,
+                score: 0.5,
+                year: None,
+            }]
+        );
+    }
+
     #[test]
     fn it_has_no_changes_after_loading_if_it_didnt_have_changes_before() {
         let mut data = Vec::new();
@@ -265,6 +1637,8 @@ mod tests {
             vec![BookMove {
                 uci: "e2e4".to_owned(),
                 frequency: 0.5,
+                score: 0.5,
+                year: None,
             }],
         );
         let mut cache = crate::opening_book::cache::Cache::new(book_1);
@@ -280,6 +1654,61 @@ mod tests {
         assert_eq!(cache.has_changed(), false);
     }
 
+    #[test]
+    fn it_compresses_save_data() {
+        let mut book = BookDouble::new();
+        let book_moves: BookMoves = (0..20)
+            .map(|i| BookMove {
+                uci: format!("e2e{}", i),
+                frequency: 0.05,
+                score: 0.5,
+                year: None,
+            })
+            .collect();
+        let (fen, _) = book.configure(Fen::starting_board(), book_moves);
+        let mut cache = crate::opening_book::cache::Cache::new(book);
+        let _ = cache.moves(&fen);
+        let mut data = Vec::new();
+        cache.save(&mut data).unwrap();
+
+        assert!(data.starts_with(MAGIC));
+        assert!(data.len() < bincode::serialize(&cache.namespaces).unwrap().len());
+    }
+
+    #[test]
+    fn it_rejects_save_data_of_unknown_format_versions() {
+        let mut data = Vec::new();
+        let mut cache = crate::opening_book::cache::Cache::new(BookDouble::new());
+        cache.save(&mut data).unwrap();
+        data[MAGIC.len()] += 1;
+
+        let mut cache = crate::opening_book::cache::Cache::new(BookDouble::new());
+        assert!(matches!(
+            cache.load(data.as_slice()),
+            Err(Error::CorruptCache(_))
+        ));
+    }
+
+    #[test]
+    fn it_rewrites_uncompressed_save_data_in_the_current_format() {
+        let plain: HashMap<Fen, BookMoves> = vec![(Fen::starting_board(), Vec::new())]
+            .into_iter()
+            .collect();
+        let mut cache = crate::opening_book::cache::Cache::new(FailingBook);
+        cache
+            .load(bincode::serialize(&plain).unwrap().as_slice())
+            .unwrap();
+        assert!(cache.has_changed());
+
+        let mut data = Vec::new();
+        cache.save(&mut data).unwrap();
+        assert!(data.starts_with(MAGIC));
+        let mut cache = crate::opening_book::cache::Cache::new(FailingBook);
+        cache.load(data.as_slice()).unwrap();
+        assert!(!cache.has_changed());
+        assert_eq!(cache.moves(&Fen::starting_board()).unwrap(), Vec::new());
+    }
+
     #[test]
     fn it_has_no_changes_after_loading_if_it_had_changes_before() {
         let mut data = Vec::new();
@@ -289,6 +1718,8 @@ mod tests {
             vec![BookMove {
                 uci: "e2e4".to_owned(),
                 frequency: 0.5,
+                score: 0.5,
+                year: None,
             }],
         );
         let (fen_2, _) = book.configure(
@@ -296,6 +1727,8 @@ mod tests {
             vec![BookMove {
                 uci: "d2d4".to_owned(),
                 frequency: 0.3,
+                score: 0.5,
+                year: None,
             }],
         );
         let mut cache = crate::opening_book::cache::Cache::new(book);
@@ -0,0 +1,239 @@
+use log::debug;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use crate::error::Error;
+use crate::opening_book::{BookMove, BookMoves, OpeningBook};
+use crate::position::Fen;
+
+// Mate scores are mapped to this many centipawns (minus the distance to mate)
+static MATE_SCORE: i32 = 10_000;
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Line {
+    score: i32,
+    uci: String,
+}
+
+/// Synthetic opening book backed by a UCI chess engine (e.g. Stockfish).
+/// The engine's top moves are turned into book moves with frequencies
+/// derived from a softmax over their evaluations.
+pub struct Engine {
+    path: PathBuf,
+    lines: usize,
+    depth: u32,
+    temperature: f64,
+    process: Option<Process>,
+}
+
+impl Engine {
+    pub fn new(path: PathBuf, lines: usize, depth: u32, temperature: f64) -> Self {
+        Engine {
+            path,
+            lines,
+            depth,
+            temperature,
+            process: None,
+        }
+    }
+
+    fn start(&self) -> Result<Process, Error> {
+        let mut child = Command::new(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or(Error::Engine)?;
+        let stdout = BufReader::new(child.stdout.take().ok_or(Error::Engine)?);
+        let mut process = Process {
+            child,
+            stdin,
+            stdout,
+        };
+        process.send("uci")?;
+        process.wait_for("uciok")?;
+        process.send(&format!("setoption name MultiPV value {}", self.lines))?;
+        process.send("isready")?;
+        process.wait_for("readyok")?;
+        Ok(process)
+    }
+
+    fn analyse(&mut self, fen: &Fen) -> Result<Vec<Line>, Error> {
+        if self.process.is_none() {
+            self.process = Some(self.start()?);
+        }
+        let process = self.process.as_mut().unwrap();
+        process.send(&format!("position fen {}", fen.fen_str()))?;
+        process.send(&format!("go depth {}", self.depth))?;
+
+        // Later info lines supersede earlier (shallower) ones
+        let mut lines = BTreeMap::new();
+        loop {
+            let output = process.read_line()?;
+            if output.starts_with("bestmove") {
+                break;
+            }
+            if let Some((multipv, line)) = Engine::parse_info(&output) {
+                lines.insert(multipv, line);
+            }
+        }
+        Ok(lines.into_values().collect())
+    }
+
+    fn parse_info(output: &str) -> Option<(usize, Line)> {
+        let mut tokens = output.split_whitespace();
+        if tokens.next() != Some("info") {
+            return None;
+        }
+        let mut multipv = 1;
+        let mut score = None;
+        while let Some(token) = tokens.next() {
+            match token {
+                "multipv" => multipv = tokens.next()?.parse().ok()?,
+                "score" => {
+                    let kind = tokens.next()?;
+                    let value: i32 = tokens.next()?.parse().ok()?;
+                    score = match kind {
+                        "cp" => Some(value),
+                        "mate" if value > 0 => Some(MATE_SCORE - value),
+                        "mate" => Some(-MATE_SCORE - value),
+                        _ => None,
+                    };
+                }
+                "pv" => {
+                    let uci = tokens.next()?.to_owned();
+                    return Some((multipv, Line { score: score?, uci }));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn to_book_moves(lines: &[Line], temperature: f64) -> BookMoves {
+        let best = match lines.iter().map(|line| line.score).max() {
+            Some(best) => best,
+            None => return Vec::new(),
+        };
+        // Scores are relative to the side to move, so better moves get more weight
+        let weights: Vec<f64> = lines
+            .iter()
+            .map(|line| (f64::from(line.score - best) / 100.0 / temperature).exp())
+            .collect();
+        let total: f64 = weights.iter().sum();
+        lines
+            .iter()
+            .zip(weights)
+            .map(|(line, weight)| BookMove {
+                uci: line.uci.clone(),
+                frequency: weight / total,
+                score: 1.0 / (1.0 + 10f64.powf(-f64::from(line.score) / 400.0)),
+                year: None,
+            })
+            .collect()
+    }
+}
+
+impl Process {
+    fn send(&mut self, command: &str) -> Result<(), Error> {
+        writeln!(self.stdin, "{}", command)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            return Err(Error::Engine);
+        }
+        Ok(line)
+    }
+
+    fn wait_for(&mut self, expected: &str) -> Result<(), Error> {
+        while self.read_line()?.trim() != expected {}
+        Ok(())
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        let _ = self.child.wait();
+    }
+}
+
+impl OpeningBook for Engine {
+    fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error> {
+        match self.analyse(fen) {
+            Ok(lines) => {
+                debug!("Engine analysis of '{}': {:?}", fen.fen_str(), lines);
+                Ok(Engine::to_book_moves(&lines, self.temperature))
+            }
+            Err(e) => {
+                // Restart the engine on the next request
+                self.process = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Engine, Line};
+
+    #[test]
+    fn it_parses_multipv_info_lines() {
+        let output = "info depth 18 seldepth 24 multipv 2 score cp -35 nodes 1234 pv e7e5 g1f3";
+        assert_eq!(
+            Engine::parse_info(output),
+            Some((
+                2,
+                Line {
+                    score: -35,
+                    uci: "e7e5".to_owned()
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn it_parses_mate_scores() {
+        let output = "info depth 10 multipv 1 score mate 3 pv d1h5";
+        assert_eq!(Engine::parse_info(output).unwrap().1.score, 9_997);
+        let output = "info depth 10 multipv 1 score mate -2 pv g8f6";
+        assert_eq!(Engine::parse_info(output).unwrap().1.score, -9_998);
+    }
+
+    #[test]
+    fn it_ignores_info_lines_without_pv() {
+        assert_eq!(Engine::parse_info("info string NNUE enabled"), None);
+        assert_eq!(Engine::parse_info("info depth 1 currmove e2e4"), None);
+    }
+
+    #[test]
+    fn it_derives_softmax_frequencies_from_scores() {
+        let lines = vec![
+            Line {
+                score: 50,
+                uci: "e2e4".to_owned(),
+            },
+            Line {
+                score: -50,
+                uci: "d2d4".to_owned(),
+            },
+        ];
+        let book_moves = Engine::to_book_moves(&lines, 1.0);
+        let expected = 1.0 / (1.0 + (-1.0f64).exp());
+        assert!((book_moves[0].frequency - expected).abs() < 1e-9);
+        assert!((book_moves[0].frequency + book_moves[1].frequency - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,470 @@
+//! The Lichess opening explorer's queries and responses, independent of how requests are
+//! sent; Without network access of its own, e.g. in a browser, the explorer is reached
+//! through an [`HttpTransport`] provided by the host
+
+use serde::Deserialize;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::opening_book::{AsyncOpeningBook, BookMove, BookMoves, MovesFuture, OpeningBook};
+use crate::position::Fen;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Move {
+    uci: String,
+    san: String,
+    white: u32,
+    draws: u32,
+    black: u32,
+    average_rating: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct Player {
+    name: String,
+    rating: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct Game {
+    #[serde(default)]
+    uci: Option<String>,
+    id: String,
+    /// None for draws
+    #[serde(default)]
+    winner: Option<String>,
+    speed: String,
+    white: Player,
+    black: Player,
+    year: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Book {
+    white: u32,
+    draws: u32,
+    black: u32,
+    moves: Vec<Move>,
+    top_games: Vec<Game>,
+    #[serde(default)]
+    recent_games: Vec<Game>,
+}
+
+/// How many games reached a position in a month
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct MonthlyGames {
+    /// Formatted as YYYY-MM
+    pub month: String,
+    pub white: u32,
+    pub draws: u32,
+    pub black: u32,
+}
+
+impl MonthlyGames {
+    pub fn games(&self) -> u32 {
+        self.white + self.draws + self.black
+    }
+}
+
+/// Selects the games the explorer takes its statistics from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub ratings: Vec<u32>,
+    pub speeds: Vec<String>,
+    /// First month to include, formatted as YYYY-MM
+    pub since: Option<String>,
+    /// Last month to include, formatted as YYYY-MM
+    pub until: Option<String>,
+}
+
+impl std::fmt::Display for Query {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ratings: Vec<String> = self.ratings.iter().map(u32::to_string).collect();
+        write!(
+            fmt,
+            "ratings {}; speeds {}; since {}; until {}",
+            ratings.join(","),
+            self.speeds.join(","),
+            self.since.as_deref().unwrap_or("any"),
+            self.until.as_deref().unwrap_or("any")
+        )
+    }
+}
+
+static SPEEDS: [&str; 6] = [
+    "ultraBullet",
+    "bullet",
+    "blitz",
+    "rapid",
+    "classical",
+    "correspondence",
+];
+pub static DEFAULT_BASE_URL: &str = "https://explorer.lichess.ovh";
+static VARIANT: &str = "variant=standard";
+static MOVE_NUMBER: &str = "moves=20";
+static RATING_BANDS: [u32; 9] = [0, 1000, 1200, 1400, 1600, 1800, 2000, 2200, 2500];
+/// Parses a time control as accepted by the explorer
+pub fn parse_speed(speed: &str) -> Result<String, String> {
+    match SPEEDS
+        .iter()
+        .find(|known| known.eq_ignore_ascii_case(speed))
+    {
+        Some(known) => Ok((*known).to_owned()),
+        None => Err(format!(
+            "Unknown speed '{}'; Expected one of: {}",
+            speed,
+            SPEEDS.join(", ")
+        )),
+    }
+}
+
+/// Parses a month as accepted by the explorer, e.g. 2022-01
+pub fn parse_month(month: &str) -> Result<String, String> {
+    let mut parts = month.splitn(2, '-');
+    let year = parts.next().and_then(|year| year.parse::<u32>().ok());
+    let number = parts.next().and_then(|number| number.parse::<u32>().ok());
+    match (year, number) {
+        (Some(year), Some(number)) if year >= 1000 && (1..=12).contains(&number) => {
+            Ok(format!("{:04}-{:02}", year, number))
+        }
+        _ => Err(format!("Invalid month '{}'; Expected YYYY-MM", month)),
+    }
+}
+
+/// Parses a rating band as accepted by the explorer
+pub fn parse_rating(rating: &str) -> Result<u32, String> {
+    match rating.parse() {
+        Ok(rating) if RATING_BANDS.contains(&rating) => Ok(rating),
+        _ => Err(format!(
+            "Unknown rating band '{}'; Expected one of: {:?}",
+            rating, RATING_BANDS
+        )),
+    }
+}
+
+/// The URL of an explorer endpoint, like "lichess", for the position
+pub fn endpoint_url(base_url: &str, endpoint: &str, fen: &str, query: &Query) -> String {
+    let escaped_fen = fen.replace(" ", "%20");
+    let ratings = query
+        .ratings
+        .iter()
+        .map(|rating| format!("ratings%5B%5D={}", rating))
+        .collect::<Vec<_>>()
+        .join("&");
+    let speeds = query
+        .speeds
+        .iter()
+        .map(|speed| format!("speeds%5B%5D={}", speed))
+        .collect::<Vec<_>>()
+        .join("&");
+    let mut url = format!(
+        "{}/{}?fen={}&{}&{}&{}&{}",
+        base_url, endpoint, escaped_fen, MOVE_NUMBER, VARIANT, speeds, ratings
+    );
+    if let Some(ref since) = query.since {
+        url.push_str(&format!("&since={}", since));
+    }
+    if let Some(ref until) = query.until {
+        url.push_str(&format!("&until={}", until));
+    }
+    url
+}
+
+fn convert_to_pleco_uci(uci: &str, san: &str) -> String {
+    if san.starts_with("O-O") {
+        uci.replace('a', "c").replace('h', "g")
+    } else {
+        uci.to_owned()
+    }
+}
+
+// Positions with fewer than `min_games` games are treated as having no book moves
+pub(crate) fn to_book_moves(fen: &Fen, book: Book, min_games: u32) -> BookMoves {
+    let total_games = book.white + book.draws + book.black;
+    if total_games < min_games {
+        return Vec::new();
+    }
+    let total_games = f64::from(total_games);
+    let white_to_move = fen.fen_str().split(' ').nth(1) == Some("w");
+    book.moves
+        .iter()
+        .map(|mv| {
+            let move_games = f64::from(mv.white + mv.draws + mv.black);
+            let wins = if white_to_move { mv.white } else { mv.black };
+            let year = book
+                .top_games
+                .iter()
+                .chain(book.recent_games.iter())
+                .filter(|game| game.uci.as_ref() == Some(&mv.uci))
+                .map(|game| game.year)
+                .max();
+            BookMove {
+                uci: convert_to_pleco_uci(&mv.uci, &mv.san),
+                frequency: move_games / total_games,
+                score: (f64::from(wins) + f64::from(mv.draws) / 2.0) / move_games,
+                year,
+            }
+        })
+        .collect()
+}
+
+/// How requests are sent to the explorer, and repertoires are downloaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpOptions {
+    /// Requests taking longer than this are aborted and retried
+    pub timeout: Duration,
+    /// How often a failed request is retried before giving up
+    pub retries: u32,
+    /// How many requests may be in flight at the same time
+    pub concurrency: usize,
+    /// Proxy to send all requests through. Without one, the proxy
+    /// configured by the `HTTP_PROXY`/`HTTPS_PROXY` environment variables is used
+    pub proxy: Option<String>,
+    /// Personal API token, sent as bearer token with every explorer request and downloads from
+    /// lichess.org
+    pub token: Option<String>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            timeout: Duration::from_secs(30),
+            retries: 5,
+            concurrency: 4,
+            proxy: None,
+            token: None,
+        }
+    }
+}
+
+/// A response to a GET request
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    /// HTTP status code, e.g. 200
+    pub status: u16,
+    /// Time the server asks to wait before sending further requests, if any
+    pub retry_after: Option<Duration>,
+    pub body: String,
+}
+
+/// Why a request got no response
+#[derive(Debug)]
+pub enum TransportError {
+    /// The request may succeed when sent again, like after a timeout
+    Transient(Error),
+    Permanent(Error),
+}
+
+pub type ResponseFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<HttpResponse, TransportError>> + Send + 'a>>;
+
+/// Sends the explorer's GET requests, so hosts, tests and embedding applications can replace
+/// the reqwest client of `Lichess`, e.g. by the `fetch` function of browsers or a transport
+/// replaying recorded responses; It is shared by the concurrent requests of `AsyncLichess`,
+/// whose futures may move between threads
+pub trait HttpTransport: Send + Sync {
+    fn get<'a>(&'a self, url: &'a str) -> ResponseFuture<'a>;
+}
+
+/// Hosts without an async runtime answer requests right away
+impl<F: Fn(&str) -> Result<HttpResponse, TransportError> + Send + Sync> HttpTransport for F {
+    fn get<'a>(&'a self, url: &'a str) -> ResponseFuture<'a> {
+        Box::pin(future::ready(self(url)))
+    }
+}
+
+impl From<TransportError> for Error {
+    fn from(error: TransportError) -> Self {
+        match error {
+            TransportError::Transient(e) | TransportError::Permanent(e) => e,
+        }
+    }
+}
+
+// The response to a request the transport answered right away, as blocking lookups have no
+// runtime to wait on others
+fn answered(mut response: ResponseFuture) -> Result<HttpResponse, Error> {
+    let mut context = Context::from_waker(Waker::noop());
+    match response.as_mut().poll(&mut context) {
+        Poll::Ready(response) => Ok(response?),
+        Poll::Pending => Err(Error::Http),
+    }
+}
+
+/// The opening explorer reached through an `HttpTransport` of the host, for builds without the
+/// `network` feature; As an `OpeningBook`, the transport has to answer right away. Hosts whose
+/// transport answers later, like the `fetch` of browsers, look up positions through
+/// `AsyncOpeningBook` instead. Retries and concurrency are left to the host.
+pub struct Explorer<T: HttpTransport> {
+    transport: T,
+    base_url: String,
+    min_games: u32,
+    query: Query,
+}
+
+impl<T: HttpTransport> Explorer<T> {
+    /// Positions with fewer than `min_games` games are treated as having no book moves
+    pub fn new(transport: T, base_url: &str, min_games: u32, query: Query) -> Self {
+        Explorer {
+            transport,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            min_games,
+            query,
+        }
+    }
+
+    fn url(&self, fen: &Fen) -> String {
+        endpoint_url(&self.base_url, "lichess", fen.fen_str(), &self.query)
+    }
+
+    fn read(&self, fen: &Fen, response: HttpResponse) -> Result<BookMoves, Error> {
+        match response.status {
+            200 => {
+                let book: Book = serde_json::from_str(&response.body)?;
+                Ok(to_book_moves(fen, book, self.min_games))
+            }
+            429 => Err(Error::RateLimited),
+            _ => Err(Error::Http),
+        }
+    }
+}
+
+impl<T: HttpTransport> OpeningBook for Explorer<T> {
+    fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error> {
+        let response = answered(self.transport.get(&self.url(fen)))?;
+        self.read(fen, response)
+    }
+}
+
+impl<T: HttpTransport> AsyncOpeningBook for Explorer<T> {
+    fn moves<'a>(&'a mut self, fen: &'a Fen) -> MovesFuture<'a> {
+        Box::pin(async move {
+            let url = self.url(fen);
+            let response = self.transport.get(&url).await?;
+            self.read(fen, response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opening_book::explorer::*;
+
+    fn query() -> Query {
+        Query {
+            ratings: vec![2000],
+            speeds: vec!["blitz".to_owned()],
+            since: None,
+            until: None,
+        }
+    }
+
+    #[test]
+    fn it_reads_book_moves_from_fetched_responses() {
+        let response = r#"{"white": 6, "draws": 2, "black": 2, "moves": [
+            {"uci": "e2e4", "san": "e4", "white": 5, "draws": 1, "black": 0, "averageRating": 2000},
+            {"uci": "e1h1", "san": "O-O", "white": 1, "draws": 1, "black": 2, "averageRating": 1900}
+        ], "topGames": []}"#;
+        let urls = std::sync::Mutex::new(Vec::new());
+        let mut explorer = Explorer::new(
+            |url: &str| -> Result<HttpResponse, TransportError> {
+                urls.lock().unwrap().push(url.to_owned());
+                Ok(HttpResponse {
+                    status: 200,
+                    retry_after: None,
+                    body: response.to_owned(),
+                })
+            },
+            "http://localhost:9002/",
+            0,
+            query(),
+        );
+        let book_moves = OpeningBook::moves(&mut explorer, &Fen::starting_board()).unwrap();
+        assert_eq!(book_moves.len(), 2);
+        assert!((book_moves[0].frequency - 0.6).abs() < 1e-9);
+        assert!((book_moves[0].score - 11.0 / 12.0).abs() < 1e-9);
+        assert_eq!(book_moves[1].uci, "e1g1");
+        drop(explorer);
+        let urls = urls.into_inner().unwrap();
+        assert!(urls[0].starts_with("http://localhost:9002/lichess?fen="));
+    }
+
+    // Answers every request on the second poll, like a browser's `fetch`
+    struct Deferred;
+
+    impl HttpTransport for Deferred {
+        fn get<'a>(&'a self, _: &'a str) -> ResponseFuture<'a> {
+            let mut polled = false;
+            Box::pin(future::poll_fn(move |context| {
+                if polled {
+                    return Poll::Ready(Ok(HttpResponse {
+                        status: 200,
+                        retry_after: None,
+                        body: r#"{"white":1,"draws":0,"black":1,"moves":[],"topGames":[]}"#
+                            .to_owned(),
+                    }));
+                }
+                polled = true;
+                context.waker().wake_by_ref();
+                Poll::Pending
+            }))
+        }
+    }
+
+    #[test]
+    fn it_awaits_transports_answering_later() {
+        let mut explorer = Explorer::new(Deferred, DEFAULT_BASE_URL, 0, query());
+        assert!(matches!(
+            OpeningBook::moves(&mut explorer, &Fen::starting_board()),
+            Err(Error::Http)
+        ));
+        let fen = Fen::starting_board();
+        let mut lookup = AsyncOpeningBook::moves(&mut explorer, &fen);
+        let mut context = Context::from_waker(Waker::noop());
+        let book_moves = loop {
+            if let Poll::Ready(book_moves) = lookup.as_mut().poll(&mut context) {
+                break book_moves;
+            }
+        };
+        assert_eq!(book_moves.unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn it_reads_drawn_games() {
+        let response = r#"{"white": 0, "draws": 1, "black": 0, "moves": [], "topGames": [
+            {"uci": "e2e4", "id": "abcdefgh", "winner": null, "speed": "blitz",
+             "white": {"name": "A", "rating": 2000}, "black": {"name": "B", "rating": 2000},
+             "year": 2020}
+        ]}"#;
+        let book: Book = serde_json::from_str(response).unwrap();
+        assert_eq!(book.top_games[0].winner, None);
+    }
+
+    #[test]
+    fn it_only_accepts_months() {
+        assert_eq!(parse_month("2022-01"), Ok("2022-01".to_owned()));
+        assert_eq!(parse_month("2024-6"), Ok("2024-06".to_owned()));
+        assert!(parse_month("2024-13").is_err());
+        assert!(parse_month("2024").is_err());
+        assert!(parse_month("June 2024").is_err());
+    }
+
+    #[test]
+    fn it_only_accepts_explorer_speeds() {
+        assert_eq!(parse_speed("rapid"), Ok("rapid".to_owned()));
+        assert_eq!(parse_speed("ultrabullet"), Ok("ultraBullet".to_owned()));
+        assert!(parse_speed("armageddon").is_err());
+    }
+
+    #[test]
+    fn it_only_accepts_explorer_rating_bands() {
+        assert_eq!(parse_rating("2200"), Ok(2200));
+        assert!(parse_rating("2100").is_err());
+        assert!(parse_rating("high").is_err());
+    }
+}
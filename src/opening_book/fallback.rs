@@ -0,0 +1,95 @@
+use crate::error::Error;
+use crate::opening_book::{BookMoves, OpeningBook};
+use crate::position::Fen;
+
+/// Queries the secondary book only for positions the primary book has no moves for.
+pub struct Fallback<P: OpeningBook, S: OpeningBook> {
+    primary: P,
+    secondary: S,
+}
+
+impl<P: OpeningBook, S: OpeningBook> Fallback<P, S> {
+    pub fn new(primary: P, secondary: S) -> Self {
+        Fallback { primary, secondary }
+    }
+}
+
+impl<P: OpeningBook, S: OpeningBook> OpeningBook for Fallback<P, S> {
+    fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error> {
+        let book_moves = self.primary.moves(fen)?;
+        if book_moves.is_empty() {
+            self.secondary.moves(fen)
+        } else {
+            Ok(book_moves)
+        }
+    }
+
+    fn moves_batch(&mut self, fens: &[Fen]) -> Vec<Result<BookMoves, Error>> {
+        let mut results = self.primary.moves_batch(fens);
+        let empty: Vec<usize> = results
+            .iter()
+            .enumerate()
+            .filter(|(_, result)| matches!(result, Ok(book_moves) if book_moves.is_empty()))
+            .map(|(i, _)| i)
+            .collect();
+        let fallback_fens: Vec<Fen> = empty.iter().map(|&i| fens[i].clone()).collect();
+        for (i, result) in empty
+            .into_iter()
+            .zip(self.secondary.moves_batch(&fallback_fens))
+        {
+            results[i] = result;
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::opening_book::fallback::Fallback;
+    use crate::opening_book::*;
+
+    struct FixedBook(BookMoves);
+
+    impl OpeningBook for FixedBook {
+        fn moves(&mut self, _: &Fen) -> Result<BookMoves, Error> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn book_moves(uci: &str) -> BookMoves {
+        vec![BookMove {
+            uci: uci.to_owned(),
+            frequency: 1.0,
+            score: 0.5,
+            year: None,
+        }]
+    }
+
+    #[test]
+    fn it_prefers_the_primary_book() {
+        let mut book = Fallback::new(FixedBook(book_moves("e2e4")), FixedBook(book_moves("d2d4")));
+        assert_eq!(
+            book.moves(&Fen::starting_board()).unwrap(),
+            book_moves("e2e4")
+        );
+    }
+
+    #[test]
+    fn it_falls_back_for_each_empty_position_of_a_batch() {
+        let mut book = Fallback::new(FixedBook(Vec::new()), FixedBook(book_moves("d2d4")));
+        let fens = vec![Fen::starting_board(), Fen::new("a b c d e f")];
+        for result in book.moves_batch(&fens) {
+            assert_eq!(result.unwrap(), book_moves("d2d4"));
+        }
+    }
+
+    #[test]
+    fn it_falls_back_to_the_secondary_book_if_the_primary_is_empty() {
+        let mut book = Fallback::new(FixedBook(Vec::new()), FixedBook(book_moves("d2d4")));
+        assert_eq!(
+            book.moves(&Fen::starting_board()).unwrap(),
+            book_moves("d2d4")
+        );
+    }
+}
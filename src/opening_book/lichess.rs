@@ -1,118 +1,624 @@
-use reqwest::{Client, StatusCode};
+use futures::stream::{self, StreamExt};
+use log::warn;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, RETRY_AFTER};
+use reqwest::{Client, Proxy};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use std::{thread, time};
+use std::time::Duration;
+use tokio::runtime::Runtime;
 
 use crate::error::Error;
-use crate::opening_book::{BookMove, BookMoves, OpeningBook};
+use crate::opening_book::explorer::{self, Book};
+pub use crate::opening_book::explorer::{
+    parse_month, parse_rating, parse_speed, HttpOptions, HttpResponse, HttpTransport, MonthlyGames,
+    Query, ResponseFuture, TransportError, DEFAULT_BASE_URL,
+};
+use crate::opening_book::{AsyncOpeningBook, BatchFuture, BookMoves, MovesFuture, OpeningBook};
 use crate::position::Fen;
 
 #[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct Move {
-    uci: String,
-    san: String,
-    white: u32,
-    draws: u32,
-    black: u32,
-    average_rating: u32,
+struct History {
+    history: Vec<MonthlyGames>,
 }
 
-#[derive(Deserialize, Debug)]
-struct Player {
-    name: String,
-    rating: u32,
+/// The default transport, applying the timeout, proxy and token of the `HttpOptions`
+pub struct ReqwestTransport {
+    client: Client,
 }
 
-#[derive(Deserialize, Debug)]
-struct Game {
-    id: String,
-    winner: String,
-    speed: String,
-    white: Player,
-    black: Player,
-    year: u32,
+impl ReqwestTransport {
+    pub fn new(http: &HttpOptions) -> Result<Self, Error> {
+        let mut client = Client::builder()
+            .timeout(http.timeout)
+            .default_headers(token_headers(http)?);
+        if let Some(ref proxy) = http.proxy {
+            client = client.proxy(Proxy::all(proxy)?);
+        }
+        Ok(ReqwestTransport {
+            client: client.build()?,
+        })
+    }
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct Book {
-    white: u32,
-    draws: u32,
-    black: u32,
-    moves: Vec<Move>,
-    top_games: Vec<Game>,
+// The API token of the options as bearer token, if there is one
+fn token_headers(http: &HttpOptions) -> Result<HeaderMap, Error> {
+    let mut headers = HeaderMap::new();
+    if let Some(ref token) = http.token {
+        let authorization = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| Error::Options("API token contains invalid characters".to_owned()))?;
+        headers.insert(AUTHORIZATION, authorization);
+    }
+    Ok(headers)
 }
 
-pub struct Lichess {
-    client: Client,
+/// Downloads a file like the PGN export of a Lichess study, with the timeout and proxy of the
+/// `HttpOptions`; The API token is only sent to Lichess, never to other hosts
+pub fn download(url: &str, http: &HttpOptions) -> Result<reqwest::blocking::Response, Error> {
+    let mut client = reqwest::blocking::Client::builder().timeout(http.timeout);
+    if let Some(ref proxy) = http.proxy {
+        client = client.proxy(Proxy::all(proxy)?);
+    }
+    let mut request = client.build()?.get(url);
+    if is_lichess(url) {
+        request = request.headers(token_headers(http)?);
+    }
+    Ok(request.send()?.error_for_status()?)
 }
 
-static CLOCK_SPEED: &str = "speeds%5B%5D=rapid&speeds%5B%5D=classical&speeds%5B%5D=blitz";
-static VARIANT: &str = "variant=standard";
-static MOVE_NUMBER: &str = "moves=20";
-static RATING: &str = "ratings%5B%5D=2500&ratings%5B%5D=2200&ratings%5B%5D=2000&ratings%5B%5D=1800&ratings%5B%5D=1600";
+fn is_lichess(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            url.host_str()
+                .map(|host| host == "lichess.org" || host.ends_with(".lichess.org"))
+        })
+        .unwrap_or(false)
+}
 
-impl Lichess {
-    pub fn new() -> Self {
-        Lichess {
-            client: Client::new(),
+impl HttpTransport for ReqwestTransport {
+    fn get<'a>(&'a self, url: &'a str) -> ResponseFuture<'a> {
+        Box::pin(async move {
+            let response = self.client.get(url).send().await.map_err(|e| {
+                if e.is_timeout() || e.is_connect() {
+                    TransportError::Transient(Error::Reqwest(e))
+                } else {
+                    TransportError::Permanent(Error::Reqwest(e))
+                }
+            })?;
+            let status = response.status().as_u16();
+            let retry_after = requested_delay(response.headers());
+            let body = response
+                .text()
+                .await
+                .map_err(|e| TransportError::Transient(Error::Reqwest(e)))?;
+            Ok(HttpResponse {
+                status,
+                retry_after,
+                body,
+            })
+        })
+    }
+}
+
+// Without a hint from the explorer, the first retry waits this many seconds; doubling every time
+static INITIAL_BACKOFF_SECONDS: f64 = 1.0;
+static MAX_BACKOFF_SECONDS: f64 = 60.0;
+static RATE_LIMIT_RESET: &str = "x-ratelimit-reset";
+
+// Time to wait before the given retry, with random jitter to spread out concurrent requests
+fn backoff(retry: u32) -> Duration {
+    let backoff = (INITIAL_BACKOFF_SECONDS * 2f64.powi(retry as i32)).min(MAX_BACKOFF_SECONDS);
+    Duration::from_secs_f64(backoff * rand::thread_rng().gen_range(0.5, 1.0))
+}
+
+// Time the explorer asks us to wait before sending further requests, if any
+fn requested_delay(headers: &HeaderMap) -> Option<Duration> {
+    [RETRY_AFTER.as_str(), RATE_LIMIT_RESET]
+        .iter()
+        .filter_map(|name| {
+            headers
+                .get(*name)?
+                .to_str()
+                .ok()?
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .max()
+        .map(Duration::from_secs)
+}
+
+// The explorer's endpoints and how to reach them, independent of the runtime driving requests
+struct Requests {
+    transport: Box<dyn HttpTransport>,
+    base_url: String,
+    min_games: u32,
+    query: Query,
+    http: HttpOptions,
+}
+
+impl Requests {
+    fn new(
+        transport: Box<dyn HttpTransport>,
+        base_url: &str,
+        min_games: u32,
+        query: Query,
+        http: HttpOptions,
+    ) -> Self {
+        Requests {
+            transport,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            min_games,
+            query,
+            http: HttpOptions {
+                concurrency: http.concurrency.max(1),
+                ..http
+            },
         }
     }
 
     fn url(&self, fen: &str) -> String {
-        let escaped_fen = fen.replace(" ", "%20");
-        format!(
-            "https://explorer.lichess.ovh/lichess?fen={}&{}&{}&{}&{}",
-            escaped_fen, MOVE_NUMBER, VARIANT, CLOCK_SPEED, RATING
-        )
+        self.endpoint_url("lichess", fen)
+    }
+
+    fn endpoint_url(&self, endpoint: &str, fen: &str) -> String {
+        explorer::endpoint_url(&self.base_url, endpoint, fen, &self.query)
     }
 
-    fn get_url(&self, url: &str) -> Result<Book, Error> {
-        let mut response = self.client.get(url).send()?;
-        match response.status() {
-            StatusCode::OK => {
-                match response.json() {
-                   Ok(json) => Ok(json),
-                   Err(some) => {
-                        println!("Error accessing lichess API: {:?}", some);
-                        thread::sleep(time::Duration::from_secs(10));
-                        self.get_url(url)
-                   }
+    async fn get_url<T: DeserializeOwned>(&self, url: &str) -> Result<T, Error> {
+        let mut retry = 0;
+        loop {
+            let response = match self.transport.get(url).await {
+                Ok(response) => response,
+                Err(TransportError::Transient(e)) => {
+                    self.wait_for_retry(retry, e, None).await?;
+                    retry += 1;
+                    continue;
                 }
-                //let lbook: Book = response.json().unwrap();
-                //Ok(lbook)
-            }
-            StatusCode::TOO_MANY_REQUESTS => {
-                thread::sleep(time::Duration::from_secs(10));
-                self.get_url(url)
-            }
-            code => {
-                println!("Error accessing lichess API: HTTP Response Code {}", code);
-                Err(Error::Http)
-            }
+                Err(TransportError::Permanent(e)) => return Err(e),
+            };
+            let (error, delay) = match response.status {
+                200 => match serde_json::from_str(&response.body) {
+                    Ok(json) => return Ok(json),
+                    Err(e) => (Error::Json(e), None),
+                },
+                429 => (Error::RateLimited, response.retry_after),
+                code if (500..600).contains(&code) => (Error::Http, None),
+                code => {
+                    warn!("Error accessing lichess API: HTTP Response Code {}", code);
+                    return Err(Error::Http);
+                }
+            };
+            self.wait_for_retry(retry, error, delay).await?;
+            retry += 1;
         }
     }
 
-    fn convert_to_pleco_uci(uci: &str, san: &str) -> String {
-        if san.starts_with("O-O") {
-            uci.replace('a', "c").replace('h', "g")
-        } else {
-            uci.to_owned()
+    // Waits before the given retry of a failed request, or returns the error if no retries are left
+    async fn wait_for_retry(
+        &self,
+        retry: u32,
+        error: Error,
+        delay: Option<Duration>,
+    ) -> Result<(), Error> {
+        if retry >= self.http.retries {
+            return Err(error);
         }
+        let delay = delay.unwrap_or_else(|| backoff(retry));
+        warn!(
+            "Error accessing lichess API: {}; Retrying in {:.1}s...",
+            error,
+            delay.as_secs_f64()
+        );
+        tokio::time::sleep(delay).await;
+        Ok(())
     }
-}
 
-impl OpeningBook for Lichess {
-    fn moves(&mut self, fen: &Fen) -> BookMoves {
-        /* Here!!! */
-        let book = self.get_url(&self.url(&fen.fen_str())).unwrap();
-        let total_games = f64::from(book.white + book.draws + book.black);
-        book.moves
-            .iter()
-            .map(|mv| BookMove {
-                uci: Lichess::convert_to_pleco_uci(&mv.uci, &mv.san),
-                frequency: f64::from(mv.white + mv.draws + mv.black) / total_games,
+    async fn fetch(&self, fen: &Fen) -> Result<BookMoves, Error> {
+        let book: Book = self.get_url(&self.url(fen.fen_str())).await?;
+        Ok(explorer::to_book_moves(fen, book, self.min_games))
+    }
+
+    async fn fetch_batch(&self, fens: &[Fen]) -> Vec<Result<BookMoves, Error>> {
+        stream::iter(fens)
+            .map(|fen| self.fetch(fen))
+            .buffered(self.http.concurrency)
+            .collect()
+            .await
+    }
+
+    async fn histories(&self, fens: &[Fen]) -> Vec<Result<Vec<MonthlyGames>, Error>> {
+        stream::iter(fens)
+            .map(|fen| async move {
+                let url = self.endpoint_url("lichess/history", fen.fen_str());
+                let history: History = self.get_url(&url).await?;
+                Ok(history.history)
             })
+            .buffered(self.http.concurrency)
             .collect()
+            .await
+    }
+}
+
+pub struct Lichess {
+    requests: Requests,
+    runtime: Runtime,
+}
+
+impl Lichess {
+    /// `base_url` points to the explorer, e.g. a self-hosted lila-openingexplorer instance.
+    /// Positions with fewer than `min_games` games are treated as having no book moves.
+    pub fn new(
+        base_url: &str,
+        min_games: u32,
+        query: Query,
+        http: HttpOptions,
+    ) -> Result<Self, Error> {
+        let transport = ReqwestTransport::new(&http)?;
+        Lichess::with_transport(Box::new(transport), base_url, min_games, query, http)
+    }
+
+    /// Sends all requests through `transport`; Of the `http` options, only the retries and the
+    /// concurrency apply, the others are up to the transport
+    pub fn with_transport(
+        transport: Box<dyn HttpTransport>,
+        base_url: &str,
+        min_games: u32,
+        query: Query,
+        http: HttpOptions,
+    ) -> Result<Self, Error> {
+        Ok(Lichess {
+            requests: Requests::new(transport, base_url, min_games, query, http),
+            runtime: Runtime::new()?,
+        })
+    }
+
+    /// Games reaching each position per month, in chronological order
+    pub fn histories(&self, fens: &[Fen]) -> Vec<Result<Vec<MonthlyGames>, Error>> {
+        self.runtime.block_on(self.requests.histories(fens))
+    }
+}
+
+impl OpeningBook for Lichess {
+    fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error> {
+        self.runtime.block_on(self.requests.fetch(fen))
+    }
+
+    fn moves_batch(&mut self, fens: &[Fen]) -> Vec<Result<BookMoves, Error>> {
+        self.runtime.block_on(self.requests.fetch_batch(fens))
+    }
+}
+
+/// The explorer for async applications; Instead of blocking on a runtime of its own, it
+/// returns futures, which have to be polled on the application's tokio runtime
+pub struct AsyncLichess {
+    requests: Requests,
+}
+
+impl AsyncLichess {
+    /// Takes the same arguments as `Lichess::new`
+    pub fn new(
+        base_url: &str,
+        min_games: u32,
+        query: Query,
+        http: HttpOptions,
+    ) -> Result<Self, Error> {
+        let transport = ReqwestTransport::new(&http)?;
+        Ok(AsyncLichess::with_transport(
+            Box::new(transport),
+            base_url,
+            min_games,
+            query,
+            http,
+        ))
+    }
+
+    /// Takes the same arguments as `Lichess::with_transport`
+    pub fn with_transport(
+        transport: Box<dyn HttpTransport>,
+        base_url: &str,
+        min_games: u32,
+        query: Query,
+        http: HttpOptions,
+    ) -> Self {
+        AsyncLichess {
+            requests: Requests::new(transport, base_url, min_games, query, http),
+        }
+    }
+
+    /// Games reaching each position per month, in chronological order
+    pub async fn histories(&self, fens: &[Fen]) -> Vec<Result<Vec<MonthlyGames>, Error>> {
+        self.requests.histories(fens).await
+    }
+}
+
+impl AsyncOpeningBook for AsyncLichess {
+    fn moves<'a>(&'a mut self, fen: &'a Fen) -> MovesFuture<'a> {
+        Box::pin(self.requests.fetch(fen))
+    }
+
+    fn moves_batch<'a>(&'a mut self, fens: &'a [Fen]) -> BatchFuture<'a> {
+        Box::pin(self.requests.fetch_batch(fens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opening_book::lichess::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn query() -> Query {
+        Query {
+            ratings: vec![2000],
+            speeds: vec!["blitz".to_owned()],
+            since: None,
+            until: None,
+        }
+    }
+
+    fn http() -> HttpOptions {
+        HttpOptions {
+            timeout: Duration::from_secs(30),
+            retries: 0,
+            concurrency: 1,
+            proxy: None,
+            token: None,
+        }
+    }
+
+    #[test]
+    fn it_queries_the_configured_rating_bands() {
+        let lichess = Lichess::new(
+            DEFAULT_BASE_URL,
+            0,
+            Query {
+                ratings: vec![1600, 1800],
+                ..query()
+            },
+            http(),
+        )
+        .unwrap();
+        let url = lichess.requests.url("8/8/8/8/8/8/8/8 w - - 0 1");
+        assert!(url.ends_with("&ratings%5B%5D=1600&ratings%5B%5D=1800"));
+    }
+
+    #[test]
+    fn it_queries_the_configured_speeds() {
+        let lichess = Lichess::new(
+            DEFAULT_BASE_URL,
+            0,
+            Query {
+                speeds: vec!["bullet".to_owned(), "correspondence".to_owned()],
+                ..query()
+            },
+            http(),
+        )
+        .unwrap();
+        let url = lichess.requests.url("8/8/8/8/8/8/8/8 w - - 0 1");
+        assert!(url.contains("&speeds%5B%5D=bullet&speeds%5B%5D=correspondence&"));
+    }
+
+    #[test]
+    fn it_queries_the_configured_date_range() {
+        let lichess = Lichess::new(DEFAULT_BASE_URL, 0, query(), http()).unwrap();
+        assert!(!lichess
+            .requests
+            .url("8/8/8/8/8/8/8/8 w - - 0 1")
+            .contains("since"));
+        let lichess = Lichess::new(
+            DEFAULT_BASE_URL,
+            0,
+            Query {
+                since: Some("2022-01".to_owned()),
+                until: Some("2024-06".to_owned()),
+                ..query()
+            },
+            http(),
+        )
+        .unwrap();
+        let url = lichess.requests.url("8/8/8/8/8/8/8/8 w - - 0 1");
+        assert!(url.ends_with("&since=2022-01&until=2024-06"));
+    }
+
+    #[test]
+    fn it_queries_the_configured_explorer() {
+        let lichess = Lichess::new("http://localhost:9002/", 0, query(), http()).unwrap();
+        let url = lichess.requests.url("8/8/8/8/8/8/8/8 w - - 0 1");
+        assert!(url.starts_with("http://localhost:9002/lichess?fen=8/8/8/8/8/8/8/8%20w%20"));
+    }
+
+    // Answers every request with the given status, recording the requests received
+    fn serve(status: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buffer = [0; 4096];
+                let length = stream.read(&mut buffer).unwrap_or(0);
+                let request = String::from_utf8_lossy(&buffer[..length]).into_owned();
+                received.lock().unwrap().push(request);
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn it_retries_server_errors_a_bounded_number_of_times() {
+        let (url, requests) = serve("503 Service Unavailable");
+        let mut lichess = Lichess::new(
+            &url,
+            0,
+            query(),
+            HttpOptions {
+                retries: 1,
+                ..http()
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            lichess.moves(&Fen::starting_board()),
+            Err(Error::Http)
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn it_requests_histories_from_the_history_endpoint() {
+        let (url, requests) = serve("404 Not Found");
+        let lichess = Lichess::new(&url, 0, query(), http()).unwrap();
+        let results = lichess.histories(&[Fen::starting_board()]);
+        assert!(matches!(results[..], [Err(Error::Http)]));
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("GET /lichess/history?fen="));
+        assert!(requests[0].contains("&ratings%5B%5D=2000"));
+    }
+
+    #[test]
+    fn it_does_not_retry_client_errors() {
+        let (url, requests) = serve("404 Not Found");
+        let mut lichess = Lichess::new(
+            &url,
+            0,
+            query(),
+            HttpOptions {
+                retries: 3,
+                ..http()
+            },
+        )
+        .unwrap();
+        assert!(lichess.moves(&Fen::starting_board()).is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_sends_requests_through_the_configured_proxy() {
+        let (proxy, requests) = serve("404 Not Found");
+        let http = HttpOptions {
+            proxy: Some(proxy),
+            ..http()
+        };
+        let mut lichess = Lichess::new("http://explorer.invalid", 0, query(), http).unwrap();
+        assert!(lichess.moves(&Fen::starting_board()).is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_authenticates_with_the_configured_token() {
+        let (url, requests) = serve("404 Not Found");
+        let http = HttpOptions {
+            token: Some("lip_secret".to_owned()),
+            ..http()
+        };
+        let mut lichess = Lichess::new(&url, 0, query(), http).unwrap();
+        assert!(lichess.moves(&Fen::starting_board()).is_err());
+        let request = requests.lock().unwrap()[0].to_lowercase();
+        assert!(request.contains("authorization: bearer lip_secret\r\n"));
+    }
+
+    #[test]
+    fn it_only_sends_the_token_to_lichess_downloads() {
+        let (url, requests) = serve("404 Not Found");
+        let http = HttpOptions {
+            token: Some("lip_secret".to_owned()),
+            ..http()
+        };
+        assert!(download(&format!("{}/course.pgn", url), &http).is_err());
+        let request = requests.lock().unwrap()[0].to_lowercase();
+        assert!(!request.contains("authorization"));
+        assert!(is_lichess("https://lichess.org/api/study/abcd1234.pgn"));
+        assert!(!is_lichess("https://lichess.org.example.com/course.pgn"));
+    }
+
+    // Replays a recorded response to every request, recording the requested URLs
+    struct Recorded {
+        response: HttpResponse,
+        urls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl HttpTransport for Recorded {
+        fn get<'a>(&'a self, url: &'a str) -> ResponseFuture<'a> {
+            self.urls.lock().unwrap().push(url.to_owned());
+            let response = self.response.clone();
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    fn recorded(urls: &Arc<Mutex<Vec<String>>>) -> Box<dyn HttpTransport> {
+        Box::new(Recorded {
+            response: HttpResponse {
+                status: 200,
+                retry_after: None,
+                body: r#"{"white": 1, "draws": 0, "black": 1, "moves": [
+                    {"uci": "e2e4", "san": "e4", "white": 1, "draws": 0, "black": 1, "averageRating": 2000}
+                ], "topGames": []}"#
+                    .to_owned(),
+            },
+            urls: Arc::clone(urls),
+        })
+    }
+
+    #[test]
+    fn it_sends_requests_through_the_given_transport() {
+        let urls = Arc::new(Mutex::new(Vec::new()));
+        let mut lichess = Lichess::with_transport(
+            recorded(&urls),
+            "http://explorer.invalid",
+            0,
+            query(),
+            http(),
+        )
+        .unwrap();
+        let book_moves = lichess.moves(&Fen::starting_board()).unwrap();
+        assert_eq!(book_moves.len(), 1);
+        assert_eq!(book_moves[0].uci, "e2e4");
+        assert!(urls.lock().unwrap()[0].starts_with("http://explorer.invalid/lichess?fen="));
+    }
+
+    #[test]
+    fn it_looks_up_positions_on_the_callers_runtime() {
+        let urls = Arc::new(Mutex::new(Vec::new()));
+        let mut lichess = AsyncLichess::with_transport(
+            recorded(&urls),
+            "http://explorer.invalid",
+            0,
+            query(),
+            http(),
+        );
+        let fens = [Fen::starting_board(), Fen::starting_board()];
+        let results = Runtime::new().unwrap().block_on(lichess.moves_batch(&fens));
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|book_moves| book_moves.is_ok()));
+        assert_eq!(urls.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn it_backs_off_exponentially_up_to_a_limit() {
+        assert!(backoff(0) <= Duration::from_secs(1));
+        assert!(backoff(3) >= Duration::from_secs(4));
+        assert!(backoff(3) <= Duration::from_secs(8));
+        assert!(backoff(20) <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn it_waits_as_long_as_the_explorer_asks() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(requested_delay(&headers), None);
+        headers.insert(RETRY_AFTER, "30".parse().unwrap());
+        assert_eq!(requested_delay(&headers), Some(Duration::from_secs(30)));
+        headers.insert(RATE_LIMIT_RESET, "45".parse().unwrap());
+        assert_eq!(requested_delay(&headers), Some(Duration::from_secs(45)));
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(requested_delay(&headers), Some(Duration::from_secs(45)));
     }
 }
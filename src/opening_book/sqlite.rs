@@ -0,0 +1,184 @@
+use crate::error::Error;
+use crate::opening_book::cache::{CacheEntry, Store};
+use crate::position::Fen;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::time::Duration;
+
+// Concurrent runs wait this long for each other's writes before giving up
+static BUSY_TIMEOUT_SECONDS: u64 = 30;
+
+/// Stores book moves in an SQLite database, one row per position,
+/// so entries are written as they are fetched and several runs can share them
+pub struct Database {
+    connection: Connection,
+}
+
+impl Database {
+    /// Opens or creates the database, moving entries of older databases to the filters they were fetched with
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let connection = Connection::open(path)?;
+        connection.busy_timeout(Duration::from_secs(BUSY_TIMEOUT_SECONDS))?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries
+                 (filters TEXT NOT NULL, fen TEXT NOT NULL, moves BLOB NOT NULL,
+                  fetched INTEGER NOT NULL, PRIMARY KEY (filters, fen));
+             CREATE TABLE IF NOT EXISTS metadata (key TEXT PRIMARY KEY, value TEXT NOT NULL);",
+        )?;
+        Database::migrate(&connection)?;
+        Ok(Database { connection })
+    }
+
+    // Databases created before entries were namespaced hold the entries of a single set of filters
+    fn migrate(connection: &Connection) -> Result<(), Error> {
+        let has_legacy_table: bool = connection.query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'book_moves'",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_legacy_table {
+            connection.execute_batch(
+                "BEGIN;
+                 INSERT OR IGNORE INTO entries (filters, fen, moves, fetched)
+                     SELECT COALESCE((SELECT value FROM metadata WHERE key = 'filters'), ''),
+                            fen, moves, fetched
+                     FROM book_moves;
+                 DROP TABLE book_moves;
+                 DELETE FROM metadata WHERE key = 'filters';
+                 COMMIT;",
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Store for Database {
+    fn get(&mut self, filters: &str, fen: &Fen) -> Result<Option<CacheEntry>, Error> {
+        let row: Option<(Vec<u8>, i64)> = self
+            .connection
+            .prepare_cached("SELECT moves, fetched FROM entries WHERE filters = ?1 AND fen = ?2")?
+            .query_row(params![filters, fen.fen_str()], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?;
+        match row {
+            // Uses are not recorded in the database, only in memory
+            Some((data, fetched)) => Ok(Some(CacheEntry {
+                fetched: fetched as u64,
+                used: fetched as u64,
+                ply: fen.ply(),
+                book_moves: bincode::deserialize(&data)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, filters: &str, fen: &Fen, entry: &CacheEntry) -> Result<(), Error> {
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO entries (filters, fen, moves, fetched)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?
+            .execute(params![
+                filters,
+                fen.fen_str(),
+                bincode::serialize(&entry.book_moves)?,
+                entry.fetched as i64
+            ])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opening_book::cache::{CacheEntry, Store};
+    use crate::opening_book::sqlite::*;
+    use crate::opening_book::BookMove;
+
+    fn temporary_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("cro-{}-{}.sqlite", name, std::process::id()))
+    }
+
+    fn entry() -> CacheEntry {
+        CacheEntry {
+            fetched: 1_600_000_000,
+            used: 1_600_000_000,
+            ply: Some(0),
+            book_moves: vec![BookMove {
+                uci: "e2e4".to_owned(),
+                frequency: 0.5,
+                score: 0.5,
+                year: Some(2020),
+            }],
+        }
+    }
+
+    #[test]
+    fn it_keeps_entries_across_connections() {
+        let path = temporary_path("entries");
+        let mut database = Database::open(&path).unwrap();
+        assert_eq!(
+            database.get("filters", &Fen::starting_board()).unwrap(),
+            None
+        );
+        database
+            .insert("filters", &Fen::starting_board(), &entry())
+            .unwrap();
+
+        let mut database = Database::open(&path).unwrap();
+        let stored = database.get("filters", &Fen::starting_board()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stored, Some(entry()));
+    }
+
+    #[test]
+    fn it_keeps_entries_of_other_filters_apart() {
+        let path = temporary_path("filters");
+        let mut database = Database::open(&path).unwrap();
+        database
+            .insert("ratings 2000", &Fen::starting_board(), &entry())
+            .unwrap();
+        let other = database
+            .get("ratings 1600", &Fen::starting_board())
+            .unwrap();
+        let same = database
+            .get("ratings 2000", &Fen::starting_board())
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(other, None);
+        assert_eq!(same, Some(entry()));
+    }
+
+    #[test]
+    fn it_migrates_entries_of_databases_without_filters_per_entry() {
+        let path = temporary_path("migration");
+        {
+            let connection = Connection::open(&path).unwrap();
+            connection
+                .execute_batch(
+                    "CREATE TABLE book_moves
+                         (fen TEXT PRIMARY KEY, moves BLOB NOT NULL, fetched INTEGER NOT NULL);
+                     CREATE TABLE metadata (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+                     INSERT INTO metadata (key, value) VALUES ('filters', 'ratings 2000');",
+                )
+                .unwrap();
+            connection
+                .execute(
+                    "INSERT INTO book_moves (fen, moves, fetched) VALUES (?1, ?2, ?3)",
+                    params![
+                        Fen::starting_board().fen_str(),
+                        bincode::serialize(&entry().book_moves).unwrap(),
+                        entry().fetched as i64
+                    ],
+                )
+                .unwrap();
+        }
+        let mut database = Database::open(&path).unwrap();
+        let stored = database
+            .get("ratings 2000", &Fen::starting_board())
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stored, Some(entry()));
+    }
+}
@@ -1,18 +1,110 @@
 use chess_pgn_parser::{Move, Piece};
-use pleco::Board;
+use pleco::{Board, Player};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
 
-use crate::conversion::move_matches_bitmove;
+use crate::conversion::{move_matches_bitmove, san};
 use crate::error::Error;
+use crate::frequency_model::{FrequencyModel, MoveStats};
 
+/// A sequence of moves, stored as a pointer to its last move.
+/// Sequences sharing a common beginning share its storage.
 #[derive(Default, Clone, Debug)]
 pub struct MoveSequence {
-    pub moves: Vec<AnyMove>,
+    last: Option<Arc<SequenceLink>>,
     pub frequency: f64,
 }
 
+#[derive(Debug)]
+struct SequenceLink {
+    mv: AnyMove,
+    previous: Option<Arc<SequenceLink>>,
+}
+
+impl MoveSequence {
+    pub fn new(frequency: f64) -> Self {
+        MoveSequence {
+            last: None,
+            frequency,
+        }
+    }
+
+    /// This sequence followed by `mv`
+    pub fn then(&self, mv: AnyMove, frequency: f64) -> Self {
+        MoveSequence {
+            last: Some(Arc::new(SequenceLink {
+                mv,
+                previous: self.last.clone(),
+            })),
+            frequency,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last.is_none()
+    }
+
+    pub fn moves(&self) -> Vec<AnyMove> {
+        let mut moves = Vec::new();
+        let mut link = &self.last;
+        while let Some(current) = link {
+            moves.push(current.mv.clone());
+            link = &current.previous;
+        }
+        moves.reverse();
+        moves
+    }
+
+    /// The moves from the starting position in algebraic notation, like "1.e4 c5 2.Nc3", as
+    /// the moves of the book are kept in UCI notation; A move that is not legal where it is
+    /// played, which the lines of the tree never have, is written as it is
+    pub fn san(&self) -> String {
+        let mut board = Board::start_pos();
+        let moves: Vec<String> = self
+            .moves()
+            .iter()
+            .map(|mv| {
+                let legal = board.generate_moves().into_iter().find(|bmv| match mv {
+                    AnyMove::ModelMove(mv) => move_matches_bitmove(mv, *bmv, &board),
+                    AnyMove::UCI(uci) => bmv.stringify() == *uci,
+                });
+                match legal {
+                    Some(bmv) => {
+                        let text = san(&board, bmv);
+                        board.apply_move(bmv);
+                        text
+                    }
+                    None => mv.to_string(),
+                }
+            })
+            .collect();
+        numbered(&moves)
+    }
+}
+
+// The moves with the number of each of White's moves, like "1.e4 c5 2.Nc3"
+fn numbered<T: std::fmt::Display>(moves: &[T]) -> String {
+    let mut text = String::new();
+    for (i, mv) in moves.iter().enumerate() {
+        if i > 0 {
+            text.push(' ');
+        }
+        if i % 2 == 0 {
+            text.push_str(&format!("{}.", i / 2 + 1));
+        }
+        text.push_str(&mv.to_string());
+    }
+    text
+}
+
+impl std::fmt::Display for MoveSequence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&numbered(&self.moves()))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum AnyMove {
     ModelMove(Move),
@@ -58,7 +150,8 @@ impl std::fmt::Display for AnyMove {
 #[derive(Debug, Clone)]
 pub struct Position {
     fen: Fen,
-    board: Board,
+    // Built on first use, as most positions are only counted
+    board: OnceLock<Board>,
     frequency: f64,
     transitions: HashMap<Fen, Transition>,
     likeliest_sequence: MoveSequence,
@@ -69,21 +162,104 @@ pub struct Frequency {
     pub frequency: f64,
 }
 
+// Offsets of the features of a position in the sequence of Zobrist keys; Pieces take the
+// indices below, from the character and square they occupy
+static SIDE_TO_MOVE_KEY: u64 = 1 << 32;
+static CASTLING_KEYS: u64 = 2 << 32;
+static EN_PASSANT_KEYS: u64 = 3 << 32;
+
+// The pseudo-random key of a feature of a position, from the SplitMix64 generator
+fn zobrist_key(index: u64) -> u64 {
+    let mut z = index.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+// Zobrist hash of the fields describing the position: piece placement, side to move, castling
+// rights and en passant square; The move counters are ignored
+fn zobrist_hash(fen_str: &str) -> u64 {
+    let mut fields = fen_str.split(' ');
+    let mut hash = 0;
+    let mut square = 0;
+    for c in fields.next().unwrap_or_default().chars() {
+        match c {
+            '/' => {}
+            '1'..='8' => square += u64::from(c) - u64::from('0'),
+            _ => {
+                hash ^= zobrist_key(64 * u64::from(c) + square);
+                square += 1;
+            }
+        }
+    }
+    if fields.next() == Some("b") {
+        hash ^= zobrist_key(SIDE_TO_MOVE_KEY);
+    }
+    let castling = fields.next().unwrap_or_default();
+    for c in castling.chars().filter(|c| *c != '-') {
+        hash ^= zobrist_key(CASTLING_KEYS + u64::from(c));
+    }
+    let en_passant = fields.next().unwrap_or_default();
+    for c in en_passant.chars().filter(|c| *c != '-') {
+        hash ^= zobrist_key(EN_PASSANT_KEYS + u64::from(c));
+    }
+    hash
+}
+
+// The text of the FENs in use, so equal FENs share one allocation; Entries don't keep the text
+// alive, and are split into shards by Zobrist hash so threads creating FENs rarely wait
+static FEN_STRINGS: OnceLock<[Mutex<InternedFens>; FEN_SHARDS]> = OnceLock::new();
+
+const FEN_SHARDS: usize = 16;
+
+#[derive(Default)]
+struct InternedFens {
+    // FENs differing only in their move counters share a hash
+    by_key: HashMap<u64, Vec<Weak<str>>>,
+    // Hashes kept after the last time dropped FENs were pruned
+    pruned_len: usize,
+}
+
+fn intern(fen_str: &str, key: u64) -> Arc<str> {
+    let shards = FEN_STRINGS.get_or_init(|| std::array::from_fn(|_| Mutex::default()));
+    let mut shard = shards[(key % FEN_SHARDS as u64) as usize].lock().unwrap();
+    let shard = &mut *shard;
+    let same_key = shard.by_key.entry(key).or_default();
+    same_key.retain(|interned| interned.strong_count() > 0);
+    if let Some(interned) = same_key
+        .iter()
+        .filter_map(Weak::upgrade)
+        .find(|interned| **interned == *fen_str)
+    {
+        return interned;
+    }
+    let interned: Arc<str> = Arc::from(fen_str);
+    same_key.push(Arc::downgrade(&interned));
+    // Dropped FENs are only pruned once the shard has doubled, so interning stays amortized O(1)
+    if shard.by_key.len() > 2 * shard.pruned_len.max(1024) {
+        shard.by_key.retain(|_, same_key| {
+            same_key.retain(|interned| interned.strong_count() > 0);
+            !same_key.is_empty()
+        });
+        shard.pruned_len = shard.by_key.len();
+    }
+    interned
+}
+
+/// A position in Forsyth-Edwards Notation; Positions are identified by a 64-bit Zobrist hash,
+/// so they compare equal regardless of their move counters, and the text is kept for display
 #[derive(Debug, Clone)]
 pub struct Fen {
-    fen_str: Rc<String>,
-    shortened_fen_str: Rc<String>,
+    fen_str: Arc<str>,
+    key: u64,
 }
 
 impl Fen {
     pub fn new(fen_str: &str) -> Self {
-        let mut shortened_fen_str = fen_str.to_owned();
-        let last_space_index = fen_str.rfind(' ').unwrap();
-        let second_to_last_space_index = fen_str[0..last_space_index].rfind(' ').unwrap();
-        shortened_fen_str.truncate(second_to_last_space_index);
+        let key = zobrist_hash(fen_str);
         Fen {
-            fen_str: Rc::new(fen_str.to_owned()),
-            shortened_fen_str: Rc::new(shortened_fen_str),
+            fen_str: intern(fen_str, key),
+            key,
         }
     }
 
@@ -92,19 +268,53 @@ impl Fen {
     }
 
     pub fn fen_str(&self) -> &str {
-        self.fen_str.as_ref()
+        &self.fen_str
+    }
+
+    /// The Zobrist hash identifying the position
+    pub fn key(&self) -> u64 {
+        self.key
     }
+
+    /// Orders positions by their FEN without the move counters, then by ply, so positions tied
+    /// otherwise are listed the same way on every run
+    pub fn tie_break(&self, other: &Fen) -> Ordering {
+        let without_counters = |fen: &Fen| {
+            let fen_str = fen.fen_str();
+            fen_str
+                .match_indices(' ')
+                .nth(3)
+                .map_or(fen_str, |(index, _)| &fen_str[..index])
+        };
+        without_counters(self)
+            .cmp(without_counters(other))
+            .then_with(|| self.ply().cmp(&other.ply()))
+    }
+
+    /// Half-moves played since the start of the game, if the move counters are known
+    pub fn ply(&self) -> Option<u32> {
+        let mut fields = self.fen_str.split(' ');
+        let white_to_move = fields.nth(1)? == "w";
+        let full_moves: u32 = fields.nth(3)?.parse().ok()?;
+        Some(2 * full_moves.saturating_sub(1) + u32::from(!white_to_move))
+    }
+}
+
+/// Orders positions by descending frequency, or whatever else is weighed, breaking ties by FEN
+/// so they are listed the same way on every run
+pub fn by_frequency_then_fen(a: (f64, &Fen), b: (f64, &Fen)) -> Ordering {
+    b.0.total_cmp(&a.0).then_with(|| a.1.tie_break(b.1))
 }
 
 impl std::hash::Hash for Fen {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.shortened_fen_str.hash(state);
+        state.write_u64(self.key);
     }
 }
 
 impl PartialEq for Fen {
     fn eq(&self, other: &Self) -> bool {
-        self.shortened_fen_str == other.shortened_fen_str
+        self.key == other.key
     }
 }
 impl Eq for Fen {}
@@ -114,7 +324,7 @@ impl Serialize for Fen {
     where
         S: Serializer,
     {
-        self.shortened_fen_str.serialize(serializer)
+        self.fen_str().serialize(serializer)
     }
 }
 
@@ -123,10 +333,15 @@ impl<'de> Deserialize<'de> for Fen {
     where
         S: Deserializer<'de>,
     {
-        let shortened_fen_str = Rc::new(String::deserialize(deserializer)?);
+        let fen_str = String::deserialize(deserializer)?;
+        if fen_str.split(' ').count() == 6 {
+            return Ok(Fen::new(&fen_str));
+        }
+        // Older versions saved the FEN without its move counters
+        let key = zobrist_hash(&fen_str);
         Ok(Self {
-            fen_str: shortened_fen_str.clone(),
-            shortened_fen_str: shortened_fen_str.clone(),
+            fen_str: intern(&fen_str, key),
+            key,
         })
     }
 }
@@ -147,7 +362,7 @@ impl std::fmt::Display for Position {
         pieces.insert('n', '♘');
         pieces.insert('p', '♙');
 
-        let original = self.board.pretty_string();
+        let original = self.board().pretty_string();
         let mut pretty = String::with_capacity(original.len());
         for c in original.chars() {
             match pieces.get(&c) {
@@ -160,14 +375,14 @@ impl std::fmt::Display for Position {
         pretty.pop();
 
         // Turn the board over if it's Black to play
-        if self.board.turn() == pleco::Player::Black {
+        if self.turn() == Player::Black {
             pretty = pretty.chars().rev().collect();
         }
 
         pretty.push_str(&format!(
             "\nEncountered once in ~{:.0} {} games ({:.6}%)\nYou have prepared {} moves here.\n",
             (1.0 / self.frequency()).round(),
-            self.board.turn(),
+            self.turn(),
             100.0 * self.frequency(),
             self.transition_count()
         ));
@@ -177,14 +392,9 @@ impl std::fmt::Display for Position {
                 100.0 * self.frequency() / self.transition_count() as f64
             ));
         }
-        if self.likeliest_sequence.moves.len() > 0 {
+        if !self.likeliest_sequence.is_empty() {
             pretty.push_str("Most likely reached by: ");
-            for (i, mv) in self.likeliest_sequence.moves.iter().enumerate() {
-                if i % 2 == 0 {
-                pretty.push_str(&format!("{}.", i / 2 + 1));
-                }
-                pretty.push_str(&format!("{} ", mv));
-            }
+            pretty.push_str(&format!("{} ", self.likeliest_sequence));
             pretty.push_str(&format!("[{:.2}%]\n", 100.0 * self.likeliest_sequence.frequency / self.frequency));
         }
         pretty.fmt(f)
@@ -222,11 +432,11 @@ impl Position {
     }
 
     pub fn apply_move(&mut self, mv: &Move) -> Result<Fen, Error> {
-        let mut new_board = self.board.clone();
+        let mut new_board = self.board().clone();
         let mut candidates = new_board
             .generate_moves()
             .into_iter()
-            .filter(|bmv| move_matches_bitmove(mv, *bmv, &self.board));
+            .filter(|bmv| move_matches_bitmove(mv, *bmv, self.board()));
         let bmv = candidates.next().ok_or_else(|| self.illegal_move(mv))?;
         if candidates.next().is_some() {
             return Err(self.ambiguous_move(mv));
@@ -234,17 +444,53 @@ impl Position {
         new_board.apply_move(bmv);
         let new_fen = Fen::new(&new_board.fen());
         self.transitions
-            .insert(new_fen.clone(), Transition { frequency: 0.0, mv: AnyMove::ModelMove(mv.clone()) });
+            .insert(new_fen.clone(), Transition { frequency: 0.0, mv: AnyMove::ModelMove(mv.clone()), stats: None });
         Ok(new_fen)
     }
 
-    pub fn apply_uci(&mut self, uci: &str, frequency: &f64) -> Result<Fen, Error> {
-        let mut new_board = self.board.clone();
+    /// The position reached by playing `uci`, without recording the move
+    pub fn after_uci(&self, uci: &str) -> Result<Fen, Error> {
+        let mut new_board = self.board().clone();
         if !new_board.apply_uci_move(uci) {
             return Err(self.illegal_uci_move(uci));
         }
-        let new_fen = Fen::new(&new_board.fen());
-        self.transitions.entry(new_fen.clone()).or_insert( Transition { frequency: 0.0, mv: AnyMove::UCI(uci.to_owned()) } ).frequency = *frequency;
+        Ok(Fen::new(&new_board.fen()))
+    }
+
+    /// The legal move leading to the position `to` in UCI notation, if there is one
+    pub fn uci_to(&self, to: &Fen) -> Option<String> {
+        self.board().generate_moves().into_iter().find_map(|bmv| {
+            let mut new_board = self.board().clone();
+            new_board.apply_move(bmv);
+            if Fen::new(&new_board.fen()) == *to {
+                Some(bmv.stringify())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The move `uci` in algebraic notation, if it is legal in the position
+    pub fn san(&self, uci: &str) -> Option<String> {
+        self.board()
+            .generate_moves()
+            .into_iter()
+            .find(|bmv| bmv.stringify() == uci)
+            .map(|bmv| san(self.board(), bmv))
+    }
+
+    /// Attaches the book statistics to the move `uci` if it is one of the position's moves
+    pub fn set_move_stats(&mut self, uci: &str, stats: MoveStats) -> Result<(), Error> {
+        let new_fen = self.after_uci(uci)?;
+        if let Some(transition) = self.transitions.get_mut(&new_fen) {
+            transition.stats = Some(stats);
+        }
+        Ok(())
+    }
+
+    pub fn apply_uci(&mut self, uci: &str, stats: MoveStats) -> Result<Fen, Error> {
+        let new_fen = self.after_uci(uci)?;
+        self.transitions.entry(new_fen.clone()).or_insert( Transition { frequency: 0.0, mv: AnyMove::UCI(uci.to_owned()), stats: None } ).stats = Some(stats);
         Ok(new_fen)
     }
 
@@ -256,64 +502,141 @@ impl Position {
         &self.fen
     }
 
+    /// The board of the position, set up from its FEN the first time it is needed
     pub fn board(&self) -> &Board {
-        &self.board
+        self.board
+            .get_or_init(|| Board::from_fen(&self.fen.fen_str).unwrap())
+    }
+
+    /// The player to move, read from the FEN without setting up the board
+    pub fn turn(&self) -> Player {
+        match self.fen.fen_str.split(' ').nth(1) {
+            Some("b") => Player::Black,
+            _ => Player::White,
+        }
     }
 
     pub fn increase_frequency(&mut self, fdelta: f64) {
         self.frequency += fdelta;
     }
 
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+    }
+
     pub fn transitions(&self) -> impl Iterator<Item = (&Fen, &Transition)> {
         self.transitions.iter()
     }
 
-    pub fn frequencies_mut(&mut self) -> impl Iterator<Item = &mut Transition> {
-        self.transitions.values_mut()
+    /// The move leading to the position `to`, if any
+    pub fn transition(&self, to: &Fen) -> Option<&Transition> {
+        self.transitions.get(to)
     }
 
     pub fn transition_count(&self) -> usize {
         self.transitions.len()
     }
+
+    pub fn set_frequencies(&mut self, model: &dyn FrequencyModel) {
+        let candidates: Vec<Option<&MoveStats>> = self
+            .transitions
+            .values()
+            .map(|transition| transition.stats.as_ref())
+            .collect();
+        let frequencies = model.frequencies(&candidates);
+        for (transition, frequency) in self.transitions.values_mut().zip(frequencies) {
+            transition.frequency = frequency;
+        }
+    }
+
+    /// Sets the frequencies of the moves to the positions with a declared weight to that weight;
+    /// The other moves share the rest in proportion to their frequencies before. Declared
+    /// weights are scaled to add up to 1 if they exceed it, or if every move has one.
+    pub fn set_declared_frequencies(&mut self, weights: &HashMap<Fen, f64>) {
+        let declared: f64 = self
+            .transitions
+            .keys()
+            .filter_map(|to| weights.get(to))
+            .sum();
+        let undeclared: f64 = self
+            .transitions
+            .iter()
+            .filter(|(to, _)| !weights.contains_key(*to))
+            .map(|(_, transition)| transition.frequency)
+            .sum();
+        if declared <= 0.0 {
+            return;
+        }
+        let scaled = declared > 1.0 || undeclared <= 0.0;
+        for (to, transition) in self.transitions.iter_mut() {
+            transition.frequency = match weights.get(to) {
+                Some(weight) if scaled => weight / declared,
+                Some(weight) => *weight,
+                None if scaled => 0.0,
+                None => transition.frequency / undeclared * (1.0 - declared),
+            };
+        }
+    }
+
+    /// Sets the frequency of each move in proportion to the weight of the position it leads
+    /// to; Moves are equally likely if no weight is positive
+    pub fn set_weighted_frequencies<F>(&mut self, weight: F)
+    where
+        F: Fn(&Fen) -> f64,
+    {
+        let total: f64 = self.transitions.keys().map(&weight).sum();
+        let count = self.transitions.len() as f64;
+        for (to, transition) in self.transitions.iter_mut() {
+            transition.frequency = if total > 0.0 {
+                weight(to) / total
+            } else {
+                1.0 / count
+            };
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Transition {
   pub mv: AnyMove,
   pub frequency: f64,
+  pub stats: Option<MoveStats>,
 }
 
+#[derive(Default, Clone)]
 pub struct PositionCache {
-    map: HashMap<Fen, Position>,
+    map: HashMap<u64, Position>,
 }
 
 impl PositionCache {
     pub fn new() -> Self {
-        PositionCache {
-            map: std::collections::HashMap::new(),
-        }
+        PositionCache::default()
     }
 
     pub fn position(&mut self, fen: &Fen) -> &mut Position {
-        self.map.entry(fen.clone()).or_insert_with(|| Position {
+        self.map.entry(fen.key()).or_insert_with(|| Position {
             fen: fen.clone(),
-            board: Board::from_fen(&fen.fen_str).unwrap(),
+            board: OnceLock::new(),
             frequency: 0.0,
             transitions: HashMap::new(),
             likeliest_sequence: MoveSequence::default(),
         })
     }
 
-    pub fn position_w_sequence(&mut self, fen: &Fen, sequence: Vec<AnyMove>) -> &mut Position {
-        self.map.entry(fen.clone()).or_insert_with(|| Position {
+    pub fn position_w_sequence(&mut self, fen: &Fen, sequence: MoveSequence) -> &mut Position {
+        self.map.entry(fen.key()).or_insert_with(|| Position {
             fen: fen.clone(),
-            board: Board::from_fen(&fen.fen_str).unwrap(),
+            board: OnceLock::new(),
             frequency: 0.0,
             transitions: HashMap::new(),
-            likeliest_sequence: MoveSequence { moves: sequence, frequency: 0.0 },
+            likeliest_sequence: sequence,
         })
     }
 
+    pub fn get(&self, fen: &Fen) -> Option<&Position> {
+        self.map.get(&fen.key())
+    }
+
     pub fn all_positions(&self) -> impl Iterator<Item = &Position> {
         self.map.values()
     }
@@ -322,3 +645,104 @@ impl PositionCache {
         self.map.values_mut()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::position::*;
+
+    #[test]
+    fn it_reconstructs_move_sequences_from_their_last_move() {
+        let start = MoveSequence::new(1.0);
+        let e4 = start.then(AnyMove::UCI("e2e4".to_owned()), 0.5);
+        let e5 = e4.then(AnyMove::UCI("e7e5".to_owned()), 0.25);
+        let c5 = e4.then(AnyMove::UCI("c7c5".to_owned()), 0.2);
+
+        assert!(start.is_empty());
+        let moves = |sequence: &MoveSequence| -> Vec<String> {
+            sequence.moves().iter().map(|mv| mv.to_string()).collect()
+        };
+        assert_eq!(moves(&e5), vec!["e2e4", "e7e5"]);
+        assert_eq!(moves(&c5), vec!["e2e4", "c7c5"]);
+        assert_eq!(c5.frequency, 0.2);
+    }
+
+    #[test]
+    fn it_identifies_positions_by_their_zobrist_hash() {
+        let start = Fen::starting_board();
+        let later = Fen::new("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 4 3");
+        assert_eq!(start.key(), later.key());
+        assert_eq!(start, later);
+        let other_side = Fen::new("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1");
+        let no_castling = Fen::new("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w - - 0 1");
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let after_e4_ep = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1");
+        assert_ne!(start, other_side);
+        assert_ne!(start, no_castling);
+        assert_ne!(other_side, after_e4);
+        assert_ne!(after_e4, after_e4_ep);
+
+        let mut cache = PositionCache::new();
+        cache.position(&start);
+        assert!(cache.get(&later).is_some());
+        assert!(cache.get(&after_e4).is_none());
+    }
+
+    #[test]
+    fn it_shares_the_text_of_equal_fens() {
+        let after_e4 = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        let restored: Fen = bincode::deserialize(&bincode::serialize(after_e4).unwrap()).unwrap();
+        assert!(Arc::ptr_eq(&Fen::new(after_e4).fen_str, &restored.fen_str));
+        let later = Fen::new("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 4 3");
+        assert!(!Arc::ptr_eq(&Fen::starting_board().fen_str, &later.fen_str));
+    }
+
+    #[test]
+    fn it_frees_the_text_of_dropped_fens() {
+        // A position no other test reaches, as tests running in parallel would keep it alive
+        let fen_str = "8/3k4/8/8/2R5/8/5K2/8 w - - 37 91";
+        let fen = Fen::new(fen_str);
+        let text = Arc::downgrade(&fen.fen_str);
+        drop(fen);
+        assert!(text.upgrade().is_none());
+        assert_eq!(Fen::new(fen_str).fen_str(), fen_str);
+    }
+
+    #[test]
+    fn it_counts_plies_from_the_move_counters() {
+        assert_eq!(Fen::starting_board().ply(), Some(0));
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        assert_eq!(after_e4.ply(), Some(1));
+        let after_nf3 = Fen::new("rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2");
+        assert_eq!(after_nf3.ply(), Some(3));
+
+        let restored: Fen = bincode::deserialize(&bincode::serialize(&after_nf3).unwrap()).unwrap();
+        assert_eq!(restored.ply(), Some(3));
+        let shortened = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq -".to_owned();
+        let legacy: Fen = bincode::deserialize(&bincode::serialize(&shortened).unwrap()).unwrap();
+        assert_eq!(legacy, Fen::starting_board());
+        assert_eq!(legacy.ply(), None);
+    }
+
+    #[test]
+    fn it_numbers_the_moves_of_a_sequence() {
+        let sequence = MoveSequence::new(1.0)
+            .then(AnyMove::UCI("e2e4".to_owned()), 1.0)
+            .then(AnyMove::UCI("c7c5".to_owned()), 1.0)
+            .then(AnyMove::UCI("b1c3".to_owned()), 1.0);
+        assert_eq!(sequence.to_string(), "1.e2e4 c7c5 2.b1c3");
+        assert_eq!(sequence.san(), "1.e4 c5 2.Nc3");
+        assert_eq!(MoveSequence::new(1.0).to_string(), "");
+    }
+
+    #[test]
+    fn it_sets_up_the_board_only_when_needed() {
+        let mut cache = PositionCache::new();
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let position = cache.position(&after_e4);
+        assert_eq!(position.turn(), Player::Black);
+        assert!(position.board.get().is_none());
+        let after_c5 = position.after_uci("c7c5").unwrap();
+        assert!(position.board.get().is_some());
+        assert_eq!(cache.position(&after_c5).turn(), Player::White);
+    }
+}
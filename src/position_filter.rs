@@ -0,0 +1,142 @@
+use pleco::core::PieceType;
+use pleco::{Board, Player};
+
+use crate::position::Position;
+
+// Total non-pawn material (both sides, in pawns) at or below which a position counts as endgame
+static ENDGAME_MATERIAL: u32 = 26;
+
+/// A predicate deciding whether a position may be recommended
+pub trait PositionFilter {
+    fn matches(&self, position: &Position) -> bool;
+}
+
+impl<F: Fn(&Position) -> bool> PositionFilter for F {
+    fn matches(&self, position: &Position) -> bool {
+        self(position)
+    }
+}
+
+/// Matches positions matched by every one of its filters
+#[derive(Default)]
+pub struct All {
+    filters: Vec<Box<dyn PositionFilter>>,
+}
+
+impl All {
+    pub fn new() -> Self {
+        All::default()
+    }
+
+    pub fn add<F: PositionFilter + 'static>(&mut self, filter: F) {
+        self.filters.push(Box::new(filter));
+    }
+}
+
+impl PositionFilter for All {
+    fn matches(&self, position: &Position) -> bool {
+        self.filters.iter().all(|filter| filter.matches(position))
+    }
+}
+
+fn material(board: &Board, player: Player) -> u32 {
+    u32::from(board.count_piece(player, PieceType::P)) + non_pawn_material(board, player)
+}
+
+fn non_pawn_material(board: &Board, player: Player) -> u32 {
+    3 * u32::from(board.count_piece(player, PieceType::N))
+        + 3 * u32::from(board.count_piece(player, PieceType::B))
+        + 5 * u32::from(board.count_piece(player, PieceType::R))
+        + 9 * u32::from(board.count_piece(player, PieceType::Q))
+}
+
+/// Material difference between the two sides, in pawns
+pub fn material_imbalance(position: &Position) -> u32 {
+    let board = position.board();
+    material(board, Player::White).abs_diff(material(board, Player::Black))
+}
+
+// Material of the player minus that of the opponent, in pawns
+fn balance(board: &Board, player: Player) -> i64 {
+    i64::from(material(board, player)) - i64::from(material(board, player.other_player()))
+}
+
+/// One side has given up material, as is typical for gambits; While the side to move can win
+/// it back with a capture, the position is taken to be in the middle of an exchange instead
+pub fn is_gambit(position: &Position) -> bool {
+    let board = position.board();
+    let mover = board.turn();
+    match balance(board, mover) {
+        0 => false,
+        ahead if ahead > 0 => true,
+        _ => !board
+            .generate_moves()
+            .into_iter()
+            .filter(|bmv| bmv.is_capture())
+            .any(|bmv| {
+                let mut after = board.clone();
+                after.apply_move(bmv);
+                balance(&after, mover) >= 0
+            }),
+    }
+}
+
+pub fn is_endgame(position: &Position) -> bool {
+    let board = position.board();
+    non_pawn_material(board, Player::White) + non_pawn_material(board, Player::Black)
+        <= ENDGAME_MATERIAL
+}
+
+pub fn piece_count(position: &Position) -> u32 {
+    u32::from(position.board().count_all_pieces())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::position::{Fen, PositionCache};
+    use crate::position_filter::*;
+
+    #[test]
+    fn it_detects_material_imbalance() {
+        let mut cache = PositionCache::new();
+        let start = Fen::starting_board();
+        // 1.e4 d5 2.d4 dxe4
+        let gambit = Fen::new("rnbqkbnr/ppp1pppp/8/8/3Pp3/8/PPP2PPP/RNBQKBNR w KQkq - 0 3");
+        assert!(!is_gambit(cache.position(&start)));
+        assert!(is_gambit(cache.position(&gambit)));
+        assert_eq!(material_imbalance(cache.position(&gambit)), 1);
+    }
+
+    #[test]
+    fn it_tells_gambits_from_exchanges() {
+        let mut cache = PositionCache::new();
+        // 1.e4 d5 2.exd5, before Black recaptures
+        let exchange = Fen::new("rnbqkbnr/ppp1pppp/8/3P4/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 2");
+        // 1.d4 e5 2.dxe5 Nc6, where White is to move a pawn up
+        let englund = Fen::new("r1bqkbnr/pppp1ppp/2n5/4P3/8/8/PPP1PPPP/RNBQKBNR w KQkq - 1 3");
+        assert!(!is_gambit(cache.position(&exchange)));
+        assert_eq!(material_imbalance(cache.position(&exchange)), 1);
+        assert!(is_gambit(cache.position(&englund)));
+    }
+
+    #[test]
+    fn it_detects_endgames() {
+        let mut cache = PositionCache::new();
+        let rook_endgame = Fen::new("4k3/pppr1ppp/8/8/8/8/PPP2PPP/3RK3 w - - 0 30");
+        assert!(is_endgame(cache.position(&rook_endgame)));
+        assert!(!is_endgame(cache.position(&Fen::starting_board())));
+        assert_eq!(piece_count(cache.position(&rook_endgame)), 16);
+    }
+
+    #[test]
+    fn it_combines_filters() {
+        let mut cache = PositionCache::new();
+        let position = cache.position(&Fen::starting_board());
+        let mut filters = All::new();
+        assert!(filters.matches(position));
+        filters.add(|pos: &Position| piece_count(pos) >= 32);
+        assert!(filters.matches(position));
+        filters.add(is_gambit);
+        assert!(!filters.matches(position));
+    }
+}
@@ -0,0 +1,152 @@
+#[cfg(feature = "native")]
+use rayon::prelude::*;
+
+use std::cmp::Ordering;
+
+use crate::position::{by_frequency_then_fen, Position};
+
+/// A strategy ranking positions for a kind of change to the repertoire
+pub trait Recommender {
+    /// Whether the change applies to the position at all
+    fn applies_to(&self, position: &Position) -> bool;
+
+    /// How much the change is worth in the position; Higher scores are recommended first
+    fn score(&self, position: &Position) -> f64;
+
+    /// The `count` positions with the highest scores among those the change applies to;
+    /// Positions scoring the same are ordered by FEN, then ply
+    fn recommend<'a>(&self, positions: &[&'a Position], count: usize) -> Vec<&'a Position> {
+        let mut recommendations: Vec<(f64, &Position)> = positions
+            .iter()
+            .filter(|pos| self.applies_to(pos))
+            .map(|pos| (self.score(pos), *pos))
+            .collect();
+        sort_by_score(&mut recommendations);
+        recommendations
+            .into_iter()
+            .take(count)
+            .map(|(_, pos)| pos)
+            .collect()
+    }
+}
+
+fn by_score(a: &(f64, &Position), b: &(f64, &Position)) -> Ordering {
+    by_frequency_then_fen((a.0, a.1.fen()), (b.0, b.1.fen()))
+}
+
+// Sorts by descending score, breaking ties by FEN
+#[cfg(feature = "native")]
+fn sort_by_score(scored: &mut [(f64, &Position)]) {
+    scored.par_sort_by(by_score);
+}
+
+#[cfg(not(feature = "native"))]
+fn sort_by_score(scored: &mut [(f64, &Position)]) {
+    scored.sort_by(by_score);
+}
+
+/// Prepare the unprepared positions reached most often
+pub struct Addition;
+
+/// Drop the prepared positions reached least often
+pub struct Removal;
+
+/// Settle on fewer moves where each of several prepared moves is reached least often
+pub struct Narrowing;
+
+/// Settle on fewer moves where several prepared moves weigh most in total
+pub struct Reduction;
+
+impl Recommender for Addition {
+    fn applies_to(&self, position: &Position) -> bool {
+        position.transition_count() == 0
+    }
+
+    fn score(&self, position: &Position) -> f64 {
+        *position.frequency()
+    }
+}
+
+impl Recommender for Removal {
+    fn applies_to(&self, position: &Position) -> bool {
+        position.transition_count() > 0
+    }
+
+    fn score(&self, position: &Position) -> f64 {
+        -position.frequency()
+    }
+}
+
+impl Recommender for Narrowing {
+    fn applies_to(&self, position: &Position) -> bool {
+        position.transition_count() > 1
+    }
+
+    fn score(&self, position: &Position) -> f64 {
+        -position.frequency() / position.transition_count() as f64
+    }
+}
+
+impl Recommender for Reduction {
+    fn applies_to(&self, position: &Position) -> bool {
+        position.transition_count() > 1
+    }
+
+    fn score(&self, position: &Position) -> f64 {
+        position.frequency() * position.transition_count() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::position::{Fen, PositionCache};
+    use crate::recommender::*;
+
+    // Prefers shallow positions, whose preparation is easier to remember
+    struct PlyDiscounted;
+
+    impl Recommender for PlyDiscounted {
+        fn applies_to(&self, position: &Position) -> bool {
+            Addition.applies_to(position)
+        }
+
+        fn score(&self, position: &Position) -> f64 {
+            let ply = position.fen().ply().unwrap_or(0);
+            position.frequency() * 0.5f64.powi(ply as i32)
+        }
+    }
+
+    #[test]
+    fn it_ranks_positions_by_a_custom_score() {
+        let mut cache = PositionCache::new();
+        let shallow = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let deep = Fen::new("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 3");
+        cache.position(&shallow).increase_frequency(0.3);
+        cache.position(&deep).increase_frequency(0.5);
+        let positions = vec![cache.get(&shallow).unwrap(), cache.get(&deep).unwrap()];
+
+        let by_frequency = Addition.recommend(&positions, 2);
+        assert_eq!(by_frequency[0].fen(), &deep);
+        let by_depth = PlyDiscounted.recommend(&positions, 1);
+        assert_eq!(by_depth.len(), 1);
+        assert_eq!(by_depth[0].fen(), &shallow);
+        assert!(Removal.recommend(&positions, 2).is_empty());
+    }
+
+    #[test]
+    fn it_breaks_ties_by_fen() {
+        let mut cache = PositionCache::new();
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let after_d4 = Fen::new("rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1");
+        cache.position(&after_e4).increase_frequency(0.4);
+        cache.position(&after_d4).increase_frequency(0.4);
+        let e4_first = vec![cache.get(&after_e4).unwrap(), cache.get(&after_d4).unwrap()];
+        let d4_first = vec![cache.get(&after_d4).unwrap(), cache.get(&after_e4).unwrap()];
+
+        for positions in [e4_first, d4_first] {
+            let recommended = Addition.recommend(&positions, 2);
+            assert_eq!(recommended[0].fen(), &after_d4);
+            assert_eq!(recommended[1].fen(), &after_e4);
+        }
+    }
+}
@@ -1,183 +1,3398 @@
-use pleco::Player;
-use std::fs;
-use std::path::PathBuf;
+use log::{debug, warn};
+use pleco::{Board, Player};
+use rand::Rng;
+#[cfg(feature = "native")]
+use rayon::prelude::*;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::iter;
+use std::path::{Path, PathBuf};
 
 use crate::error::Error;
-use crate::opening_book::OpeningBook;
-use crate::position::{Fen, Position, PositionCache, AnyMove, MoveSequence};
+use crate::frequency_model::{FrequencyModel, Popularity, Uniform};
+use crate::import::{self, import_repertoire};
+use crate::opening_book::{AsyncOpeningBook, BookMoves, ErrorPolicy, OpeningBook};
+use crate::position::{
+    by_frequency_then_fen, AnyMove, Fen, MoveSequence, Position, PositionCache, Transition,
+};
+use crate::recommender::{Addition, Narrowing, Recommender, Reduction, Removal};
+use crate::warnings::{EMPTY_BOOK, FAILED_LOOKUP};
 
+/// The prepared lines of one color as a tree of positions, with how often each position is
+/// reached in games against the opponents described by the opening book
+#[derive(Clone)]
 pub struct RepertoireOptimizer {
     me: Player,
     tree: PositionCache,
+    // Positions along each imported game, to recognize games imported before
+    imported_games: HashMap<Vec<Fen>, ImportedGame>,
+    // Games repeating the moves of a game imported before, which were skipped
+    skipped_duplicates: Vec<DuplicateLine>,
+    // Weights declared by "weight=" comments after my moves, by the positions before and after
+    declared_weights: HashMap<Fen, HashMap<Fen, f64>>,
+    // Where each position was first reached in the imported games
+    sources: HashMap<Fen, Source>,
+    // Priority and name of the highest priority named line through each position
+    line_names: HashMap<Fen, (u32, String)>,
+    // The positions of the opponent before each position added from the book
+    book_parents: HashMap<Fen, Vec<Fen>>,
+    // Positions marked by a "book-end" comment as the deliberate end of my preparation
+    book_ends: HashSet<Fen>,
+    // Book moves are only added up to this ply
+    max_depth: Option<u32>,
+    // Book moves played less often than this are left out
+    min_book_share: f64,
+    // Whether the book statistics of my prepared moves are looked up too
+    own_move_stats: bool,
+    // Positions reached from the starting position when the frequencies were last updated
+    reachable_positions: usize,
+    // Positions of the opponent the book had no moves for, which are too many to warn about
+    empty_book_positions: usize,
 
     pub average_book_length: f64,
 }
 
+// Rating change per game for each point scored above or below expectation, as for most FIDE players
+static ELO_K_FACTOR: f64 = 20.0;
+// Rarer moves are not trusted to be the best move, as their score is mostly noise
+static BEST_MOVE_MIN_SHARE: f64 = 0.05;
+
+// Opponent's replies played less often than this are not counted as theory to prepare
+static CANDIDATE_REPLY_MIN_SHARE: f64 = 0.05;
+
+// Book moves played less often than this are not considered plausible alternatives to mine
+static PLAUSIBLE_MOVE_MIN_SHARE: f64 = 0.05;
+
+// Earlier moves avoiding an unprepared position are only suggested when at least this share of
+// games after them reach positions I have prepared
+static MOVE_ORDER_MIN_PREPARED_SHARE: f64 = 0.5;
+
+// Simulated games are cut off after this many plies, in case the repertoire contains cycles
+static MAX_SIMULATED_PLIES: usize = 500;
+
+/// A move I could prepare in a position, as the opening book sees it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub uci: String,
+    pub popularity: f64,
+    pub score: f64,
+    /// Opponent's replies common enough to need preparation after the move
+    pub replies: usize,
+}
+
+/// An earlier move of mine avoiding an unprepared position, which reaches positions I have
+/// prepared by another move order instead
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveOrder {
+    /// The position of the earlier decision
+    pub fen: Fen,
+    pub uci: String,
+    /// The move in algebraic notation
+    pub san: String,
+    /// The move prepared there now in algebraic notation, which leads towards the unprepared
+    /// position
+    pub instead_of: String,
+    /// Share of games after the move reaching positions I have prepared
+    pub prepared_share: f64,
+}
+
+impl fmt::Display for MoveOrder {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let ply = self.fen.ply().unwrap_or(0);
+        let dots = if ply % 2 == 0 { "." } else { "..." };
+        write!(
+            fmt,
+            "You could sidestep this by playing {} on move {}{} instead of {}; {:.0}% of games \
+             then reach positions you have prepared",
+            self.san,
+            ply / 2 + 1,
+            dots,
+            self.instead_of,
+            100.0 * self.prepared_share
+        )
+    }
+}
+
+// My moves played less often than this at my level are exotic choices, which are hard to recall
+static EXOTIC_MOVE_MAX_SHARE: f64 = 0.01;
+
+/// A prepared line longer than the personal budget, with where it could be cut short
+#[derive(Debug, Clone)]
+pub struct LongLine<'a> {
+    pub line: MoveSequence,
+    pub plies: usize,
+    /// My positions within the budget right after the opponent chose among several popular
+    /// book moves, in the order of the line; Preparation can stop there at the cost of the
+    /// position's frequency
+    pub truncation_points: Vec<&'a Position>,
+}
+
+/// A prepared move which players at my level hardly ever choose
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExoticChoice {
+    pub uci: String,
+    /// Share of the book games continuing with the move; 0 if it is not in the book at all
+    pub share: f64,
+}
+
+/// What covering a position some half-moves deep takes and brings
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LearningCost {
+    /// Positions of mine to learn, counting the covered position itself
+    pub positions: usize,
+    /// Expected number of half-moves in book gained per game reaching the covered position,
+    /// mine and the opponent's
+    pub moves: f64,
+}
+
+/// What dropping my moves in a position would change
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RemovalImpact {
+    /// Positions no longer reached, on either side's turn
+    pub positions: usize,
+    /// Change of the average number of my moves in book, never positive
+    pub book_length: f64,
+}
+
+/// How easily my move in a position is confused with the book moves commonly played instead
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Difficulty {
+    pub alternatives: usize,
+    /// Alternatives moving from or to the same square as one of my moves
+    pub similar: usize,
+}
+
+impl Difficulty {
+    /// How often to drill the position relative to one with a single obvious move
+    pub fn drill_weight(&self) -> f64 {
+        (1 + self.alternatives + self.similar) as f64
+    }
+}
+
+/// How well the lines of a chapter of the ECO classification are prepared
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completeness {
+    /// Like "B2" for the codes B20 to B29
+    pub chapter: String,
+    /// Share of all games entering the chapter's lines
+    pub frequency: f64,
+    /// Percentage of those games that stay within my preparation up to the target depth
+    pub score: f64,
+}
+
+// The name of a game from its "Event" header, or else its "White" header, where studies put
+// chapter names like "Chapter 4: Classical Variation"
+fn game_name(tags: &[(String, String)]) -> Option<&str> {
+    ["Event", "White"].iter().find_map(|header| {
+        tags.iter()
+            .find(|(key, _)| key == header)
+            .map(|(_, value)| value.trim())
+            .filter(|value| !value.is_empty() && *value != "?")
+    })
+}
+
+// The chapter of the game's ECO code from its PGN headers, like "B2" for B20 to B29
+fn eco_chapter(tags: &[(String, String)]) -> Option<&str> {
+    let (_, eco) = tags.iter().find(|(key, _)| key == "ECO")?;
+    let mut chars = eco.chars();
+    match (chars.next(), chars.next(), chars.next(), chars.next()) {
+        (Some('A'..='E'), Some(tens), Some(ones), None)
+            if tens.is_ascii_digit() && ones.is_ascii_digit() =>
+        {
+            Some(&eco[..2])
+        }
+        _ => None,
+    }
+}
+
+// An imported game with its PGN headers and the priority of its chapter, 1 being the main line
+#[derive(Clone)]
+struct ImportedGame {
+    tags: Vec<(String, String)>,
+    priority: u32,
+    // Where the game was imported from, at its last move
+    source: Source,
+}
+
+// The priority of a chapter from its "Priority" header, or else from its number in the file
+fn chapter_priority(tags: &[(String, String)], chapter: u32) -> u32 {
+    tags.iter()
+        .find(|(key, _)| key == "Priority")
+        .and_then(|(_, priority)| priority.trim().parse().ok())
+        .filter(|priority| *priority > 0)
+        .unwrap_or(chapter)
+}
+
+// Comment marking the position after a move as the deliberate end of my preparation
+static BOOK_END: &str = "book-end";
+
+// The share of games I choose a move in, as declared by a comment like `{weight=0.7}` after it
+fn declared_weight(comment: &str) -> Option<f64> {
+    comment
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix("weight="))
+        .filter_map(|weight| weight.parse::<f64>().ok())
+        .find(|weight| weight.is_finite() && *weight >= 0.0)
+}
+
+// The position a game starts from: the one of its "FEN" header, unless "SetUp" says otherwise
+fn game_start(tags: &[(String, String)]) -> Result<Fen, Error> {
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.trim())
+    };
+    match tag("FEN") {
+        Some(fen_str) if tag("SetUp") != Some("0") => {
+            let board =
+                Board::from_fen(fen_str).map_err(|_| Error::InvalidFen(fen_str.to_owned()))?;
+            Ok(Fen::new(&board.fen()))
+        }
+        _ => Ok(Fen::starting_board()),
+    }
+}
+
+/// The game and move of an imported PGN file a position was first reached by
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    /// None for games added as PGN text
+    pub file: Option<PathBuf>,
+    /// The number of the game in its file, counting from 1
+    pub game: u32,
+    /// Half-moves played up to the position
+    pub ply: u32,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.file.as_ref().and_then(|file| file.file_name()) {
+            write!(fmt, "{}, ", name.to_string_lossy())?;
+        }
+        let dots = if self.ply % 2 == 1 { "." } else { "..." };
+        write!(
+            fmt,
+            "game {}, move {}{}",
+            self.game,
+            (self.ply + 1) / 2,
+            dots
+        )
+    }
+}
+
+/// Two imported games of which one repeats the moves of the other, so the line is counted twice
+/// or its second game is redundant
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateLine {
+    /// The game whose moves are repeated, at its last move
+    pub repeated: Source,
+    /// The game repeating them, at its last move
+    pub repeating: Source,
+    /// Whether the repeating game goes on after the repeated moves, rather than being identical
+    pub continued: bool,
+}
+
+impl fmt::Display for DuplicateLine {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let how = if self.continued {
+            "continued"
+        } else {
+            "repeated"
+        };
+        write!(
+            fmt,
+            "The line of {} is {} by {}",
+            self.repeated, how, self.repeating
+        )
+    }
+}
+
+/// A position where repertoire files prepare different moves for me
+#[derive(Debug, Clone)]
+pub struct MoveConflict {
+    pub fen: Fen,
+    /// The moves prepared by each file, with the first game of the file playing them
+    pub moves: Vec<(String, Source)>,
+}
+
+impl fmt::Display for MoveConflict {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "Your files prepare different moves in '{}':",
+            self.fen.fen_str()
+        )?;
+        for (index, (mv, source)) in self.moves.iter().enumerate() {
+            let separator = if index == 0 { "" } else { ";" };
+            write!(fmt, "{} {} in {}", separator, mv, source)?;
+        }
+        Ok(())
+    }
+}
+
+/// A line starting with a move of mine that my other lines never choose in its position
+#[derive(Debug, Clone)]
+pub struct UnreachableLine {
+    /// The position before the move
+    pub fen: Fen,
+    pub mv: String,
+    /// The first game playing the move
+    pub source: Source,
+    /// How many positions are on the line after the move
+    pub positions: usize,
+}
+
+impl fmt::Display for UnreachableLine {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "Your other lines never choose {} in '{}', so the {} positions of the line from {} \
+             are dead weight",
+            self.mv,
+            self.fen.fen_str(),
+            self.positions,
+            self.source
+        )
+    }
+}
+
+/// A game starting from the position of its "FEN" header, which none of my lines from the
+/// starting position lead to
+#[derive(Debug, Clone)]
+pub struct DetachedChapter {
+    pub fen: Fen,
+    /// Where the game starts
+    pub source: Source,
+}
+
+impl fmt::Display for DetachedChapter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "None of your lines lead to '{}', where {} starts, so its positions get no games",
+            self.fen.fen_str(),
+            self.source
+        )
+    }
+}
+
+/// A file of my repertoire whose games branch on my moves but never on the opponent's, as the
+/// file of a repertoire for the other color would
+#[derive(Debug, Clone)]
+pub struct WrongSideFile {
+    /// None for games added as PGN text
+    pub file: Option<PathBuf>,
+    /// The color the repertoire is for, rather than the file
+    pub me: Player,
+    pub games: usize,
+    /// In how many positions the games play different moves of mine
+    pub branches: usize,
+}
+
+impl fmt::Display for WrongSideFile {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match &self.file {
+            Some(file) => file.display().to_string(),
+            None => "The PGN text".to_owned(),
+        };
+        let (me, other) = match self.me {
+            Player::White => ("White", "Black"),
+            Player::Black => ("Black", "White"),
+        };
+        write!(
+            fmt,
+            "'{}' branches on {}'s moves in {} positions but never on {}'s; It looks like \
+             preparation for {} passed as the {} repertoire",
+            name, me, self.branches, other, other, me
+        )
+    }
+}
+
+/// An imported game ending on an opponent's move I have no reply prepared for
+#[derive(Debug, Clone)]
+pub struct TruncatedLine {
+    /// The position after the opponent's move
+    pub fen: Fen,
+    /// The first game ending there, at the opponent's move
+    pub source: Source,
+}
+
+impl fmt::Display for TruncatedLine {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "The line of {} ends on the opponent's move without your reply",
+            self.source
+        )
+    }
+}
+
+/// A move returning to a position reached before on the way to it, closing a cycle of positions
+#[derive(Debug, Clone)]
+pub struct RepetitionLine {
+    /// The position returned to
+    pub fen: Fen,
+    pub mv: String,
+    /// The first game playing the move, at the move; None if no imported game plays it
+    pub source: Option<Source>,
+}
+
+impl fmt::Display for RepetitionLine {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(
+                fmt,
+                "The line of {} repeats an earlier position with {}",
+                source, self.mv
+            ),
+            None => write!(
+                fmt,
+                "A line repeats the position '{}' with {}",
+                self.fen.fen_str(),
+                self.mv
+            ),
+        }
+    }
+}
+
+// Moves prepared by each file, with the first game of the file playing them
+type MovesByFile<'a> = HashMap<&'a Option<PathBuf>, Vec<(String, Source)>>;
+
+// The positions the games of a file reach from each position
+type NextPositions<'a> = HashMap<&'a Fen, HashSet<&'a Fen>>;
+
+// How the imported games choose one of my moves
+struct Choice<'a> {
+    mv: String,
+    // The files of the games playing the move
+    files: HashSet<&'a Option<PathBuf>>,
+    games: usize,
+    // The first game playing the move, at the move
+    first: Source,
+}
+
+/// Outcome of playing the repertoire against an opponent sticking to popular book moves
+#[derive(Debug, Default)]
+pub struct StressTest {
+    pub games: usize,
+    /// Games in which the opponent reached a position I have no move prepared for
+    pub left_preparation: usize,
+    /// Moves I played from my preparation, over all games
+    pub prepared_moves_played: usize,
+    /// Positions in which games left my preparation, with how often and the first line reaching them
+    pub exits: HashMap<Fen, (usize, MoveSequence)>,
+}
+
+impl StressTest {
+    fn record_exit(&mut self, fen: Fen, line: MoveSequence) {
+        self.left_preparation += 1;
+        self.exits.entry(fen).or_insert((0, line)).0 += 1;
+    }
+
+    /// Positions in which games left my preparation most often, with their count
+    pub fn most_frequent_exits(&self, count: usize) -> Vec<(usize, &MoveSequence)> {
+        let mut exits: Vec<_> = self
+            .exits
+            .values()
+            .map(|(games, line)| (*games, line))
+            .collect();
+        exits.sort_by(|(a, a_line), (b, b_line)| {
+            b.cmp(a)
+                .then_with(|| a_line.to_string().cmp(&b_line.to_string()))
+        });
+        exits.truncate(count);
+        exits
+    }
+}
+
+// The share of games flowing into a position, with the moves of mine they played on the way
+#[derive(Default)]
 struct FrequencyDelta {
-    fen: Fen,
     fdelta: f64,
-    ply: usize,
+    my_moves: f64,
     sequence: MoveSequence,
 }
 
+// The games a position passes on to each position after it, and the moves of mine of the games
+// ending in book by repeating a position instead
+type Outflow = (f64, Vec<(Fen, FrequencyDelta)>);
+
+fn outflow(
+    tree: &PositionCache,
+    me: Player,
+    fen: &Fen,
+    inflow: &FrequencyDelta,
+    repeating: &HashSet<(u64, u64)>,
+) -> Outflow {
+    let position = match tree.get(fen) {
+        Some(position) => position,
+        None => return (0.0, Vec::new()),
+    };
+    // The games reaching this position play one more move of mine if I am to move
+    let my_moves = if position.turn() == me {
+        inflow.my_moves + inflow.fdelta
+    } else {
+        inflow.my_moves
+    };
+    let mut repeated_my_moves = 0.0;
+    let mut outflows = Vec::with_capacity(position.transition_count());
+    for (to_fen, transition) in position.transitions() {
+        if repeating.contains(&(fen.key(), to_fen.key())) {
+            repeated_my_moves += my_moves * transition.frequency;
+            continue;
+        }
+        let frequency = inflow.sequence.frequency * transition.frequency;
+        outflows.push((
+            to_fen.clone(),
+            FrequencyDelta {
+                fdelta: inflow.fdelta * transition.frequency,
+                my_moves: my_moves * transition.frequency,
+                sequence: inflow.sequence.then(transition.mv.clone(), frequency),
+            },
+        ));
+    }
+    (repeated_my_moves, outflows)
+}
+
+#[cfg(feature = "native")]
+fn outflows(
+    tree: &PositionCache,
+    me: Player,
+    wave: &[(Fen, FrequencyDelta)],
+    repeating: &HashSet<(u64, u64)>,
+) -> Vec<Outflow> {
+    wave.par_iter()
+        .map(|(fen, inflow)| outflow(tree, me, fen, inflow, repeating))
+        .collect()
+}
+
+#[cfg(not(feature = "native"))]
+fn outflows(
+    tree: &PositionCache,
+    me: Player,
+    wave: &[(Fen, FrequencyDelta)],
+    repeating: &HashSet<(u64, u64)>,
+) -> Vec<Outflow> {
+    wave.iter()
+        .map(|(fen, inflow)| outflow(tree, me, fen, inflow, repeating))
+        .collect()
+}
+
 impl RepertoireOptimizer {
+    /// An empty repertoire for the player `me`
     pub fn new(me: Player) -> Self {
         RepertoireOptimizer {
             me,
             tree: PositionCache::new(),
+            imported_games: HashMap::new(),
+            skipped_duplicates: Vec::new(),
+            declared_weights: HashMap::new(),
+            sources: HashMap::new(),
+            line_names: HashMap::new(),
+            book_parents: HashMap::new(),
+            book_ends: HashSet::new(),
+            max_depth: None,
+            min_book_share: 0.0,
+            own_move_stats: false,
+            reachable_positions: 0,
+            empty_book_positions: 0,
             average_book_length: 0.0,
         }
     }
 
+    /// Configures a repertoire for the player `me` up front, to be set up by a single call
+    pub fn builder<'a>(me: Player) -> RepertoireOptimizerBuilder<'a> {
+        RepertoireOptimizerBuilder {
+            me,
+            files: Vec::new(),
+            pgn: Vec::new(),
+            max_depth: None,
+            min_book_share: 0.0,
+            book: None,
+            policy: ErrorPolicy::Abort,
+            own_model: &Uniform,
+            opponent_model: &Popularity,
+        }
+    }
+
+    /// The color the repertoire is prepared for
+    pub fn player(&self) -> Player {
+        self.me
+    }
+
+    /// Number of moves prepared for my own side
+    pub fn prepared_move_count(&self) -> usize {
+        self.tree
+            .all_positions()
+            .filter(|pos| pos.turn() == self.me)
+            .map(|pos| pos.transition_count())
+            .sum()
+    }
+
+    /// Reads all games of a PGN file, to be added with `add_game_to_repertoire`;
+    /// `import::read_games` reads them one at a time instead
     pub fn read_games(filename: &PathBuf) -> Result<Vec<chess_pgn_parser::Game>, Error> {
-        let contents = fs::read_to_string(filename)?;
-        Ok(chess_pgn_parser::read_games(&contents).map_err(|_| Error::PgnParser)?)
+        import::read_games(BufReader::new(File::open(filename)?)).collect()
+    }
+
+    /// Adds the moves of a game to the repertoire.
+    /// Returns false if a game with the same move sequence was added before.
+    pub fn add_game_to_repertoire(&mut self, game: chess_pgn_parser::Game) -> Result<bool, Error> {
+        self.add_chapter_to_repertoire(game, 1)
+    }
+
+    /// Adds the moves of a game to the repertoire as chapter `chapter` of a study or a PGN file
+    /// with several games, counting from 1; A "Priority" header takes precedence.
+    /// Games with a "FEN" header start from that position, which is only reached in games, and
+    /// gets a frequency, through lines of other chapters leading to it.
+    /// Returns false if a game with the same move sequence was added before. On an illegal or
+    /// ambiguous move, the moves before it are added and the error returned.
+    pub fn add_chapter_to_repertoire(
+        &mut self,
+        game: chess_pgn_parser::Game,
+        chapter: u32,
+    ) -> Result<bool, Error> {
+        self.add_chapter(game, chapter, None)
+    }
+
+    /// Like `add_chapter_to_repertoire`, remembering `file` as the source of the positions
+    /// first reached in this game
+    pub fn add_chapter_from_file(
+        &mut self,
+        game: chess_pgn_parser::Game,
+        chapter: u32,
+        file: &Path,
+    ) -> Result<bool, Error> {
+        self.add_chapter(game, chapter, Some(file))
     }
 
-    pub fn add_game_to_repertoire(&mut self, game: chess_pgn_parser::Game) -> Result<(), Error> {
-        let mut fen = Fen::starting_board();
+    fn add_chapter(
+        &mut self,
+        game: chess_pgn_parser::Game,
+        chapter: u32,
+        file: Option<&Path>,
+    ) -> Result<bool, Error> {
+        let mut fen = game_start(&game.tags)?;
+        let mut line = Vec::with_capacity(game.moves.len() + 1);
+        if fen != Fen::starting_board() {
+            line.push(fen.clone());
+        }
         let mut pos = self.tree.position(&fen);
-        let mut sequence = Vec::<AnyMove>::new();
-        for mv in game.moves {
-            sequence.push(AnyMove::ModelMove(mv.move_.move_.clone()));
-            fen = pos.apply_move(&mv.move_.move_)?;
+        let mut sequence = MoveSequence::new(0.0);
+        let mut bad_move = None;
+        for (index, mv) in (1..).zip(game.moves) {
+            sequence = sequence.then(AnyMove::ModelMove(mv.move_.move_.clone()), 0.0);
+            let from = fen;
+            let my_move = pos.turn() == self.me;
+            fen = match pos.apply_move(&mv.move_.move_) {
+                Ok(fen) => fen,
+                Err(e) => {
+                    bad_move = Some(e);
+                    break;
+                }
+            };
+            self.sources.entry(fen.clone()).or_insert_with(|| Source {
+                file: file.map(Path::to_path_buf),
+                game: chapter,
+                ply: fen.ply().unwrap_or(index),
+            });
+            if let Some(comment) = mv.comment.as_deref() {
+                if let Some(weight) = declared_weight(comment).filter(|_| my_move) {
+                    self.declared_weights
+                        .entry(from)
+                        .or_default()
+                        .insert(fen.clone(), weight);
+                }
+                if comment.split_whitespace().any(|word| word == BOOK_END) {
+                    self.book_ends.insert(fen.clone());
+                }
+            }
+            line.push(fen.clone());
             pos = self.tree.position_w_sequence(&fen, sequence.clone());
         }
-        Ok(())
+        let source = Source {
+            file: file.map(Path::to_path_buf),
+            game: chapter,
+            ply: line.last().and_then(Fen::ply).unwrap_or(0),
+        };
+        let added = match self.imported_games.entry(line) {
+            Entry::Occupied(entry) => {
+                self.skipped_duplicates.push(DuplicateLine {
+                    repeated: entry.get().source.clone(),
+                    repeating: source,
+                    continued: false,
+                });
+                false
+            }
+            Entry::Vacant(entry) => {
+                let priority = chapter_priority(&game.tags, chapter);
+                if let Some(name) = game_name(&game.tags) {
+                    let named = (priority, name.to_owned());
+                    for fen in entry.key() {
+                        let best = self
+                            .line_names
+                            .entry(fen.clone())
+                            .or_insert_with(|| named.clone());
+                        if named < *best {
+                            *best = named.clone();
+                        }
+                    }
+                }
+                entry.insert(ImportedGame {
+                    tags: game.tags,
+                    priority,
+                    source,
+                });
+                true
+            }
+        };
+        match bad_move {
+            Some(e) => Err(e),
+            None => Ok(added),
+        }
+    }
+
+    /// Adds all games of a PGN text, as read from a file or the clipboard;
+    /// Returns how many of them were not added before
+    pub fn add_pgn(&mut self, pgn: &str) -> Result<usize, Error> {
+        let games = import::parse_games(pgn)?;
+        let mut added = 0;
+        for (chapter, game) in (1..).zip(games) {
+            if self.add_chapter_to_repertoire(game, chapter)? {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// How many positions of the opponent the book had no moves for, so my lines leave the book
+    /// before them; Each is only logged at debug level
+    pub fn empty_book_positions(&self) -> usize {
+        self.empty_book_positions
     }
 
+    /// Adds the book moves of every position where it is the opponent's turn, which is
+    /// required before frequencies are assigned; `policy` decides about failed lookups. With
+    /// `set_own_move_stats`, my prepared moves get their book statistics as well.
     pub fn add_opponents_moves_from_book(
         &mut self,
         book: &mut dyn OpeningBook,
+        policy: ErrorPolicy,
+    ) -> Result<(), Error> {
+        let fens = self.book_positions();
+        let results = book.moves_batch(&fens);
+        self.add_book_moves(&fens, results, book, policy)
+    }
+
+    /// Like `add_opponents_moves_from_book`, for books answering later, like the `Explorer` on
+    /// the `fetch` of a browser, which the page must not block on; The frequencies are then
+    /// assigned by the host, as the builder only takes blocking books
+    pub async fn add_opponents_moves_from_async_book(
+        &mut self,
+        book: &mut dyn AsyncOpeningBook,
+        policy: ErrorPolicy,
     ) -> Result<(), Error> {
+        let fens = self.book_positions();
+        let mut results = book.moves_batch(&fens).await;
+        if let ErrorPolicy::Retry(retries) = policy {
+            for (fen, result) in fens.iter().zip(&mut results) {
+                for attempt in 1..=retries {
+                    match result {
+                        Err(Error::Interrupted) | Ok(_) => break,
+                        Err(e) => warn!(
+                            "Retrying book moves for '{}' ({}/{}): {}",
+                            fen.fen_str(),
+                            attempt,
+                            retries,
+                            e
+                        ),
+                    }
+                    *result = book.moves(fen).await;
+                }
+            }
+        }
+        self.insert_book_moves(&fens, results, policy)
+    }
+
+    /// Looks up the book statistics of my prepared moves along with the opponent's moves, for
+    /// own frequency models weighing my moves by them
+    pub fn set_own_move_stats(&mut self, enabled: bool) {
+        self.own_move_stats = enabled;
+    }
+
+    /// The positions `add_opponents_moves_from_book` looks up, for callers batching the lookups
+    /// of several repertoires
+    pub fn book_positions(&self) -> Vec<Fen> {
         let me = self.me;
-        let fens: Vec<Result<Fen, Error>> = self
-            .tree
-            .all_positions_mut()
-            .filter(|pos| pos.board().turn() != me)
-            .flat_map(|pos| {
-                book.moves(pos.fen())
-                    .into_iter()
-                    .map(move |book_move| {
-                        pos.apply_uci(&book_move.uci, &book_move.frequency)
-                    })
+        let max_depth = self.max_depth;
+        self.tree
+            .all_positions()
+            .filter(|pos| {
+                if pos.turn() == me {
+                    self.own_move_stats && pos.transition_count() > 0
+                } else {
+                    !self.book_ends.contains(pos.fen())
+                }
             })
-            .collect::<Vec<_>>();
-        for fen in fens {
-            self.tree.position(&fen?);
+            .filter(|pos| {
+                max_depth
+                    .zip(pos.fen().ply())
+                    .is_none_or(|(max_depth, ply)| ply < max_depth)
+            })
+            .map(|pos| pos.fen().clone())
+            .collect()
+    }
+
+    /// Adds the looked up book moves of the `book_positions`; Failed lookups are retried in
+    /// `book` as `policy` says
+    pub fn add_book_moves(
+        &mut self,
+        fens: &[Fen],
+        results: Vec<Result<BookMoves, Error>>,
+        book: &mut dyn OpeningBook,
+        policy: ErrorPolicy,
+    ) -> Result<(), Error> {
+        // Retried lazily, so nothing more is retried once a lookup failed for good
+        let results = fens.iter().zip(results).map(|(fen, result)| {
+            result.or_else(|e| RepertoireOptimizer::retry(book, fen, policy, e))
+        });
+        self.insert_book_moves(fens, results, policy)
+    }
+
+    // Adds book moves whose failed lookups were already retried
+    fn insert_book_moves(
+        &mut self,
+        fens: &[Fen],
+        results: impl IntoIterator<Item = Result<BookMoves, Error>>,
+        policy: ErrorPolicy,
+    ) -> Result<(), Error> {
+        let mut new_fens = Vec::new();
+        for (fen, result) in fens.iter().zip(results) {
+            let book_moves = match result {
+                Ok(book_moves) => book_moves,
+                Err(Error::Interrupted) => return Err(Error::Interrupted),
+                Err(e) if policy == ErrorPolicy::Skip => {
+                    warn!(
+                        target: FAILED_LOOKUP,
+                        "Skipping book moves for '{}': {}",
+                        fen.fen_str(),
+                        e
+                    );
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let me = self.me;
+            let pos = self.tree.position(fen);
+            if pos.turn() == me {
+                for book_move in book_moves {
+                    pos.set_move_stats(&book_move.uci, book_move.stats())?;
+                }
+                continue;
+            }
+            if book_moves.is_empty() {
+                self.empty_book_positions += 1;
+                debug!(
+                    target: EMPTY_BOOK,
+                    "The book has no moves for '{}'; Your line leaves the book before it",
+                    fen.fen_str()
+                );
+            }
+            for book_move in book_moves {
+                if book_move.frequency >= self.min_book_share {
+                    let to = pos.apply_uci(&book_move.uci, book_move.stats())?;
+                    let parents = self.book_parents.entry(to.clone()).or_default();
+                    if !parents.contains(fen) {
+                        parents.push(fen.clone());
+                    }
+                    new_fens.push(to);
+                }
+            }
+        }
+        for fen in new_fens {
+            self.tree.position(&fen);
         }
         Ok(())
     }
 
-    pub fn set_own_move_frequencies(&mut self) {
+    fn retry(
+        book: &mut dyn OpeningBook,
+        fen: &Fen,
+        policy: ErrorPolicy,
+        error: Error,
+    ) -> Result<BookMoves, Error> {
+        let retries = match (policy, &error) {
+            (_, Error::Interrupted) => return Err(error),
+            (ErrorPolicy::Retry(retries), _) => retries,
+            _ => return Err(error),
+        };
+        let mut error = error;
+        for attempt in 1..=retries {
+            warn!(
+                "Retrying book moves for '{}' ({}/{}): {}",
+                fen.fen_str(),
+                attempt,
+                retries,
+                error
+            );
+            match book.moves(fen) {
+                Ok(book_moves) => return Ok(book_moves),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Assigns how often I choose each of my prepared moves
+    /// Moves with a "weight=" comment, like `{weight=0.7}`, keep the declared share instead
+    pub fn set_own_move_frequencies(&mut self, model: &dyn FrequencyModel) {
         let me = self.me;
+        let declared_weights = &self.declared_weights;
         for position in self
             .tree
             .all_positions_mut()
-            .filter(|pos| pos.board().turn() == me && pos.transition_count() > 0)
+            .filter(|pos| pos.turn() == me && pos.transition_count() > 0)
         {
-            let proportional_frequency = 1.0 / position.transition_count() as f64;
-            for mut frequency in position.frequencies_mut() {
-                frequency.frequency = proportional_frequency;
+            position.set_frequencies(model);
+            if let Some(weights) = declared_weights.get(position.fen()) {
+                position.set_declared_frequencies(weights);
             }
         }
     }
 
-    pub fn update_position_frequencies(&mut self) {
-        let mut positions_to_update = Vec::<FrequencyDelta>::new();
-        positions_to_update.push(FrequencyDelta {
-            fen: Fen::starting_board(),
-            fdelta: 1.0,
-            ply: 0,
-            sequence: MoveSequence { moves: Vec::new(), frequency: 1.0 },
-        });
+    /// Assigns how often I choose each of my prepared moves by the priority of the chapters
+    /// playing it, as the first chapters of a study usually hold the author's main
+    /// recommendations; A move first played in chapter 2 is chosen half as often as one
+    /// played in chapter 1
+    pub fn set_own_move_frequencies_by_priority(&mut self) {
+        let me = self.me;
+        let priorities = self.line_priorities();
+        for position in self
+            .tree
+            .all_positions_mut()
+            .filter(|pos| pos.turn() == me && pos.transition_count() > 0)
+        {
+            position.set_weighted_frequencies(|to| {
+                priorities
+                    .get(to)
+                    .map_or(0.0, |priority| 1.0 / f64::from(*priority))
+            });
+        }
+    }
 
-        while let Some(FrequencyDelta { fen, fdelta, ply, sequence }) = positions_to_update.pop() {
-            if fdelta == 0.0 {
-                continue;
+    // The highest priority, i.e. the lowest number, of the chapters along each position
+    fn line_priorities(&self) -> HashMap<Fen, u32> {
+        let mut priorities: HashMap<Fen, u32> = HashMap::new();
+        for (line, game) in &self.imported_games {
+            for fen in line {
+                let priority = priorities.entry(fen.clone()).or_insert(game.priority);
+                *priority = (*priority).min(game.priority);
             }
-            let position = self.tree.position(&fen);
-            if position.transition_count() == 0 { // Leaf node -> cumulate frequency
-                if position.board().turn() == self.me {
-                    self.average_book_length += (ply / 2) as f64 * fdelta;
-                } else {
-                    self.average_book_length += ((ply + 1) / 2) as f64 * fdelta;
-                }
-            }
-            position.increase_frequency(fdelta);
-            if position.sequence().frequency < sequence.frequency {
-                position.set_sequence(sequence.clone());
-            }
-            for (to_fen, transition) in position.transitions() {
-                let mut new_sequence = sequence.clone();
-                new_sequence.moves.push(transition.mv.clone());
-                new_sequence.frequency = sequence.frequency * transition.frequency;
-                positions_to_update.push(FrequencyDelta {
-                    fen: to_fen.clone(),
-                    fdelta: fdelta * transition.frequency,
-                    ply: ply + 1,
-                    sequence: new_sequence,
-                });
+        }
+        priorities
+    }
+
+    /// The priority of the chapter each position belongs to, 1 being the main line; Positions
+    /// left by the opponent belong to the chapter of the position before
+    pub fn chapter_priorities(&self) -> HashMap<Fen, u32> {
+        let lines = self.line_priorities();
+        let mut priorities = lines.clone();
+        for position in self.tree.all_positions() {
+            let priority = match lines.get(position.fen()) {
+                Some(priority) => *priority,
+                None => continue,
+            };
+            for (to, _) in position.transitions() {
+                if !lines.contains_key(to) {
+                    let left = priorities.entry(to.clone()).or_insert(priority);
+                    *left = (*left).min(priority);
+                }
             }
         }
+        priorities
     }
 
-    pub fn own_positions(&self) -> Vec<&Position> {
-        self.tree
-            .all_positions()
-            .filter(|pos| pos.board().turn() == self.me)
-            .collect()
+    /// The name of the highest priority line through the position, from the "Event" or "White"
+    /// header of its game; Positions left by the opponent take the name of the position before
+    pub fn line_name(&self, fen: &Fen) -> Option<&str> {
+        let name_through = |fen: &Fen| self.line_names.get(fen).map(|(_, name)| name.as_str());
+        name_through(fen).or_else(|| self.book_parents.get(fen)?.iter().find_map(name_through))
     }
 
-    pub fn recommend_for_addition<'a>(
-        positions: &[&'a Position],
-        count: usize,
-    ) -> Vec<&'a Position> {
-        let mut recommendations = positions.to_owned();
-        recommendations.retain(|pos| pos.transition_count() == 0);
-        recommendations.sort_by(|a, b| b.frequency().partial_cmp(a.frequency()).unwrap());
-        recommendations.truncate(count);
-        recommendations
+    /// Imported games with the same moves as another game, or with the moves of another game
+    /// followed by more, ordered by the source of the repeating game
+    pub fn duplicate_lines(&self) -> Vec<DuplicateLine> {
+        let mut duplicates = self.skipped_duplicates.clone();
+        for (line, game) in &self.imported_games {
+            for end in 1..line.len() {
+                if let Some(shorter) = self.imported_games.get(&line[..end]) {
+                    duplicates.push(DuplicateLine {
+                        repeated: shorter.source.clone(),
+                        repeating: game.source.clone(),
+                        continued: true,
+                    });
+                }
+            }
+        }
+        duplicates.sort_by_key(|duplicate| {
+            (
+                duplicate.repeating.file.clone(),
+                duplicate.repeating.game,
+                duplicate.repeated.file.clone(),
+                duplicate.repeated.game,
+            )
+        });
+        duplicates
     }
 
-    pub fn recommend_for_removal<'a>(
-        positions: &[&'a Position],
-        count: usize,
-    ) -> Vec<&'a Position> {
-        let mut recommendations = positions.to_owned();
-        recommendations.retain(|pos| pos.transition_count() > 0);
-        recommendations.sort_by(|a, b| a.frequency().partial_cmp(b.frequency()).unwrap());
-        recommendations.truncate(count);
-        recommendations
+    /// Positions where two repertoire files prepare different moves for me, each a move the other
+    /// does not, which is rather a conflict than a choice of moves; Alternatives prepared in the
+    /// same file are deliberate
+    pub fn conflicting_moves(&self) -> Vec<MoveConflict> {
+        let mut games: Vec<_> = self.imported_games.iter().collect();
+        games.sort_by_key(|(_, game)| (game.source.file.clone(), game.source.game));
+        // My moves in each position by the file preparing them, with the first game doing so
+        let mut prepared: HashMap<&Fen, MovesByFile> = HashMap::new();
+        for (line, game) in games {
+            for (pos, to, transition) in self.steps_along(line) {
+                if pos.turn() != self.me {
+                    continue;
+                }
+                let moves = prepared
+                    .entry(pos.fen())
+                    .or_default()
+                    .entry(&game.source.file)
+                    .or_default();
+                let mv = transition.mv.to_string();
+                if moves.iter().all(|(known, _)| *known != mv) {
+                    let source = Source {
+                        file: game.source.file.clone(),
+                        game: game.source.game,
+                        ply: to.ply().unwrap_or(0),
+                    };
+                    moves.push((mv, source));
+                }
+            }
+        }
+        let prepares_other = |moves: &[(String, Source)], others: &[(String, Source)]| {
+            moves
+                .iter()
+                .any(|(mv, _)| others.iter().all(|(other, _)| other != mv))
+        };
+        let mut conflicts: Vec<MoveConflict> = prepared
+            .into_iter()
+            .filter(|(_, by_file)| {
+                by_file.values().any(|moves| {
+                    by_file.values().any(|others| {
+                        prepares_other(moves, others) && prepares_other(others, moves)
+                    })
+                })
+            })
+            .map(|(fen, by_file)| {
+                let mut moves: Vec<_> = by_file.into_values().flatten().collect();
+                moves.sort_by_key(|(_, source)| (source.file.clone(), source.game));
+                MoveConflict {
+                    fen: fen.clone(),
+                    moves,
+                }
+            })
+            .collect();
+        conflicts.sort_by_key(|conflict| (conflict.fen.ply(), conflict.fen.fen_str().to_owned()));
+        conflicts
     }
 
-    pub fn recommend_for_narrowing<'a>(
-        positions: &[&'a Position],
-        count: usize,
-    ) -> Vec<&'a Position> {
-        let mut recommendations = positions.to_owned();
-        recommendations.retain(|pos| pos.transition_count() > 1);
-        recommendations.sort_by(|a, b| {
-            (a.frequency() / a.transition_count() as f64)
-                .partial_cmp(&(b.frequency() / b.transition_count() as f64))
-                .unwrap()
-        });
-        recommendations.truncate(count);
-        recommendations
+    /// Lines starting with a move of mine that only one file chooses in its position, while my
+    /// other lines go another way more often, like a 1.d4 file imported into an otherwise 1.e4
+    /// repertoire; Their positions are dead weight inflating the position count
+    pub fn unreachable_lines(&self) -> Vec<UnreachableLine> {
+        let mut choices: HashMap<&Fen, HashMap<&Fen, Choice>> = HashMap::new();
+        for (line, game) in &self.imported_games {
+            for (pos, to, transition) in self.steps_along(line) {
+                if pos.turn() != self.me {
+                    continue;
+                }
+                let source = Source {
+                    ply: to.ply().unwrap_or(0),
+                    ..game.source.clone()
+                };
+                let choice = choices
+                    .entry(pos.fen())
+                    .or_default()
+                    .entry(to)
+                    .or_insert_with(|| Choice {
+                        mv: transition.mv.to_string(),
+                        files: HashSet::new(),
+                        games: 0,
+                        first: source.clone(),
+                    });
+                choice.files.insert(&game.source.file);
+                choice.games += 1;
+                if (&source.file, source.game) < (&choice.first.file, choice.first.game) {
+                    choice.first = source;
+                }
+            }
+        }
+        let mut candidates = Vec::new();
+        for (fen, moves) in &choices {
+            for (to, choice) in moves {
+                let file = match choice.files.iter().next() {
+                    Some(file) if choice.files.len() == 1 => file,
+                    _ => continue,
+                };
+                let others = move || moves.iter().filter(move |(other, _)| *other != to);
+                let shared = others().any(|(_, other)| other.files.contains(file));
+                let outnumbered = others().any(|(_, other)| other.games > choice.games);
+                if !shared && outnumbered {
+                    candidates.push((fen, choice, self.positions_from(to)));
+                }
+            }
+        }
+        // Lines within an unreachable line are reported along with it
+        let mut unreachable: Vec<UnreachableLine> = candidates
+            .iter()
+            .filter(|(fen, _, _)| {
+                candidates
+                    .iter()
+                    .all(|(_, _, positions)| !positions.contains(*fen))
+            })
+            .map(|(fen, choice, positions)| UnreachableLine {
+                fen: (**fen).clone(),
+                mv: choice.mv.clone(),
+                source: choice.first.clone(),
+                positions: positions.len(),
+            })
+            .collect();
+        unreachable.sort_by_key(|line| (line.source.file.clone(), line.source.game));
+        unreachable
     }
 
-    pub fn recommend_for_reduction<'a>(
-        positions: &[&'a Position],
-        count: usize,
-    ) -> Vec<&'a Position> {
-        let mut recommendations = positions.to_owned();
-        recommendations.retain(|pos| pos.transition_count() > 1);
-        recommendations.sort_by(|a, b| {
-            (b.frequency() * b.transition_count() as f64)
-                .partial_cmp(&(a.frequency() * a.transition_count() as f64))
-                .unwrap()
+    // The positions of the imported games through `fen`, from it on
+    fn positions_from(&self, fen: &Fen) -> HashSet<&Fen> {
+        self.imported_games
+            .keys()
+            .flat_map(|line| {
+                let at = line.iter().position(|other| other == fen);
+                &line[at.unwrap_or(line.len())..]
+            })
+            .collect()
+    }
+
+    /// Games starting from the position of their "FEN" header which no line from the starting
+    /// position reaches, so they keep a frequency of 0, ordered by source
+    pub fn detached_chapters(&self) -> Vec<DetachedChapter> {
+        let mut reached: HashSet<&Fen> = HashSet::new();
+        let mut queue: Vec<&Position> = self.tree.get(&Fen::starting_board()).into_iter().collect();
+        while let Some(position) = queue.pop() {
+            for (to, _) in position.transitions() {
+                if reached.insert(to) {
+                    queue.extend(self.tree.get(to));
+                }
+            }
+        }
+        let mut detached: Vec<DetachedChapter> = self
+            .imported_games
+            .values()
+            .filter_map(|game| {
+                let fen = game_start(&game.tags)
+                    .ok()
+                    .filter(|fen| *fen != Fen::starting_board() && !reached.contains(fen))?;
+                let source = Source {
+                    ply: fen.ply().unwrap_or(0),
+                    ..game.source.clone()
+                };
+                Some(DetachedChapter { fen, source })
+            })
+            .collect();
+        detached.sort_by_key(|chapter| (chapter.source.file.clone(), chapter.source.game));
+        detached
+    }
+
+    /// Files whose games have alternatives for my moves but never for the opponent's, a common
+    /// sign of a file passed as the repertoire of the wrong color
+    pub fn wrong_side_files(&self) -> Vec<WrongSideFile> {
+        // How many games each file has, and the positions they reach
+        let mut files: HashMap<&Option<PathBuf>, (usize, NextPositions)> = HashMap::new();
+        for (line, game) in &self.imported_games {
+            let (games, moves) = files.entry(&game.source.file).or_default();
+            *games += 1;
+            for (pos, to, _) in self.steps_along(line) {
+                moves.entry(pos.fen()).or_default().insert(to);
+            }
+        }
+        let mut wrong_side: Vec<WrongSideFile> = files
+            .into_iter()
+            .filter_map(|(file, (games, moves))| {
+                let (mine, theirs): (Vec<_>, Vec<_>) = moves
+                    .iter()
+                    .filter(|(_, next)| next.len() > 1)
+                    .partition(|(fen, _)| {
+                        self.tree.get(fen).is_some_and(|pos| pos.turn() == self.me)
+                    });
+                if mine.is_empty() || !theirs.is_empty() {
+                    return None;
+                }
+                Some(WrongSideFile {
+                    file: file.clone(),
+                    me: self.me,
+                    games,
+                    branches: mine.len(),
+                })
+            })
+            .collect();
+        wrong_side.sort_by_key(|file| file.file.clone());
+        wrong_side
+    }
+
+    /// Imported games ending on an opponent's move that no game prepares a reply to; Positions
+    /// marked "book-end" are the deliberate end of my preparation instead
+    pub fn truncated_lines(&self) -> Vec<TruncatedLine> {
+        let mut truncated: HashMap<&Fen, &Source> = HashMap::new();
+        for (line, game) in &self.imported_games {
+            let last = match line.last() {
+                Some(last) if !self.book_ends.contains(last) => last,
+                _ => continue,
+            };
+            let unanswered = self
+                .tree
+                .get(last)
+                .is_some_and(|pos| pos.turn() == self.me && pos.transition_count() == 0);
+            if unanswered {
+                let first = truncated.entry(last).or_insert(&game.source);
+                if (&game.source.file, game.source.game) < (&first.file, first.game) {
+                    *first = &game.source;
+                }
+            }
+        }
+        let mut truncated: Vec<TruncatedLine> = truncated
+            .into_iter()
+            .map(|(fen, source)| TruncatedLine {
+                fen: fen.clone(),
+                source: source.clone(),
+            })
+            .collect();
+        truncated.sort_by_key(|line| (line.source.file.clone(), line.source.game));
+        truncated
+    }
+
+    /// Moves returning to a position the games passed before, like a piece retreating and
+    /// coming back; Sorted by the first game playing them
+    pub fn repetition_lines(&self) -> Vec<RepetitionLine> {
+        let mut repetitions: Vec<RepetitionLine> = self
+            .repeating_moves(&Fen::starting_board(), None)
+            .into_iter()
+            .map(|(from, to)| {
+                let source = self
+                    .imported_games
+                    .iter()
+                    .filter_map(|(line, game)| {
+                        line.windows(2)
+                            .find(|pair| pair[0] == from && pair[1] == to)
+                            .map(|pair| Source {
+                                ply: pair[1].ply().unwrap_or(0),
+                                ..game.source.clone()
+                            })
+                    })
+                    .min_by_key(|source| (source.file.clone(), source.game));
+                let mv = self
+                    .tree
+                    .get(&from)
+                    .and_then(|pos| pos.transition(&to))
+                    .map(|transition| transition.mv.to_string())
+                    .unwrap_or_default();
+                RepetitionLine {
+                    fen: to,
+                    mv,
+                    source,
+                }
+            })
+            .collect();
+        repetitions.sort_by_key(|line| {
+            line.source
+                .as_ref()
+                .map(|source| (source.file.clone(), source.game, source.ply))
         });
-        recommendations.truncate(count);
-        recommendations
+        repetitions
+    }
+
+    /// Where a position was first reached in the imported games; Positions left by the
+    /// opponent are attributed to the position before
+    pub fn source(&self, fen: &Fen) -> Option<&Source> {
+        self.sources.get(fen).or_else(|| {
+            self.book_parents
+                .get(fen)?
+                .iter()
+                .find_map(|parent| self.sources.get(parent))
+        })
+    }
+
+    /// Assigns how often the opponent chooses each book move; Call after
+    /// `add_opponents_moves_from_book`
+    pub fn set_opponent_move_frequencies(&mut self, model: &dyn FrequencyModel) {
+        let me = self.me;
+        for position in self
+            .tree
+            .all_positions_mut()
+            .filter(|pos| pos.turn() != me && pos.transition_count() > 0)
+        {
+            position.set_frequencies(model);
+        }
+    }
+
+    /// Propagates the move frequencies from the starting position, so every position knows the
+    /// share of games reaching it; Call after both move frequencies are set. Each position is
+    /// expanded once, after the games of all moves leading to it have been counted. Games
+    /// repeating a position end in book there, as if drawn by repetition. Calling it again
+    /// after the repertoire changed computes the frequencies anew.
+    pub fn update_position_frequencies(&mut self) {
+        let (reached, book_length) = self.propagate(None);
+        self.average_book_length = book_length;
+        self.reachable_positions = reached.len();
+        for position in self.tree.all_positions_mut() {
+            position.set_frequency(0.0);
+        }
+        for (fen, inflow) in reached {
+            let position = self.tree.position(&fen);
+            position.set_frequency(inflow.fdelta);
+            if position.sequence().frequency < inflow.sequence.frequency {
+                position.set_sequence(inflow.sequence);
+            }
+        }
+    }
+
+    // The games reaching each position reachable from the starting position, and the average
+    // number of my moves in book; Games reaching `cut` end there, as if I had no moves prepared
+    fn propagate(&self, cut: Option<&Fen>) -> (HashMap<Fen, FrequencyDelta>, f64) {
+        let start = Fen::starting_board();
+        // The repeating moves by the Zobrist hashes of their positions, so looking them up for
+        // every move clones no FENs
+        let repeating: HashSet<(u64, u64)> = self
+            .repeating_moves(&start, cut)
+            .iter()
+            .map(|(from, to)| (from.key(), to.key()))
+            .collect();
+        let mut parents = self.parent_counts(&start, &repeating, cut);
+        let mut inflows = HashMap::<Fen, FrequencyDelta>::new();
+        inflows.insert(
+            start.clone(),
+            FrequencyDelta {
+                fdelta: 1.0,
+                my_moves: 0.0,
+                sequence: MoveSequence::new(1.0),
+            },
+        );
+        let mut ready = vec![start];
+        let mut reached = HashMap::new();
+        let mut book_length = 0.0;
+
+        // Positions whose games have all been counted are expanded together, in parallel
+        while !ready.is_empty() {
+            let wave: Vec<(Fen, FrequencyDelta)> = ready
+                .drain(..)
+                .map(|fen| {
+                    let inflow = inflows.remove(&fen).unwrap_or_default();
+                    (fen, inflow)
+                })
+                .collect();
+            let (stopped, wave): (Vec<_>, Vec<_>) =
+                wave.into_iter().partition(|(fen, _)| cut == Some(fen));
+            for (fen, inflow) in stopped.iter().chain(&wave) {
+                let is_leaf = self
+                    .tree
+                    .get(fen)
+                    .map_or(true, |position| position.transition_count() == 0);
+                if is_leaf || cut == Some(fen) {
+                    // Leaf node -> cumulate the number of my moves of the games ending here
+                    book_length += inflow.my_moves;
+                }
+            }
+            for (repeated_my_moves, next) in outflows(&self.tree, self.me, &wave, &repeating) {
+                book_length += repeated_my_moves;
+                for (to_fen, outflow) in next {
+                    let inflow = inflows.entry(to_fen.clone()).or_default();
+                    inflow.fdelta += outflow.fdelta;
+                    inflow.my_moves += outflow.my_moves;
+                    if inflow.sequence.frequency < outflow.sequence.frequency {
+                        inflow.sequence = outflow.sequence;
+                    }
+                    if let Some(count) = parents.get_mut(&to_fen) {
+                        *count -= 1;
+                        if *count == 0 {
+                            ready.push(to_fen);
+                        }
+                    }
+                }
+            }
+            reached.extend(stopped);
+            reached.extend(wave);
+        }
+        (reached, book_length)
+    }
+
+    /// What dropping my moves in a position would change, exactly: The positions only reached
+    /// through them would go, and the games reaching it would end in book there; Compared to
+    /// the frequencies as last updated
+    pub fn removal_impact(&self, fen: &Fen) -> RemovalImpact {
+        let (after, book_length_after) = self.propagate(Some(fen));
+        RemovalImpact {
+            positions: self.reachable_positions.saturating_sub(after.len()),
+            book_length: book_length_after - self.average_book_length,
+        }
+    }
+
+    // How many moves lead to each position reachable from `start`, counting only the moves from
+    // positions reachable from it, except the `repeating` ones
+    fn parent_counts(
+        &self,
+        start: &Fen,
+        repeating: &HashSet<(u64, u64)>,
+        cut: Option<&Fen>,
+    ) -> HashMap<Fen, usize> {
+        let mut counts = HashMap::new();
+        let mut visited = HashSet::new();
+        visited.insert(start.clone());
+        let mut to_visit = vec![start.clone()];
+        while let Some(fen) = to_visit.pop() {
+            let position = match self.tree.get(&fen) {
+                Some(position) if cut != Some(&fen) => position,
+                _ => continue,
+            };
+            for (to_fen, _) in position.transitions() {
+                if repeating.contains(&(fen.key(), to_fen.key())) {
+                    continue;
+                }
+                *counts.entry(to_fen.clone()).or_insert(0) += 1;
+                if visited.insert(to_fen.clone()) {
+                    to_visit.push(to_fen.clone());
+                }
+            }
+        }
+        counts
+    }
+
+    // The moves from a position to one on the way from `start` to it, which close the cycles of
+    // the position graph; Searched depth-first in a fixed order, so the same moves are found
+    // every time
+    fn repeating_moves(&self, start: &Fen, cut: Option<&Fen>) -> HashSet<(Fen, Fen)> {
+        let sorted_children = |fen: &Fen| {
+            let mut children: Vec<Fen> = self
+                .tree
+                .get(fen)
+                .filter(|_| cut != Some(fen))
+                .map(|pos| pos.transitions().map(|(to, _)| to.clone()).collect())
+                .unwrap_or_default();
+            // Popping from the back visits the children in order
+            children.sort_by(|a, b| b.fen_str().cmp(a.fen_str()));
+            children
+        };
+        let mut repeating = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut on_path = HashSet::new();
+        visited.insert(start.clone());
+        on_path.insert(start.clone());
+        let mut stack = vec![(start.clone(), sorted_children(start))];
+        while let Some((fen, children)) = stack.last_mut() {
+            let fen = fen.clone();
+            match children.pop() {
+                Some(child) if on_path.contains(&child) => {
+                    repeating.insert((fen, child));
+                }
+                Some(child) => {
+                    if visited.insert(child.clone()) {
+                        on_path.insert(child.clone());
+                        let grandchildren = sorted_children(&child);
+                        stack.push((child, grandchildren));
+                    }
+                }
+                None => {
+                    on_path.remove(&fen);
+                    stack.pop();
+                }
+            }
+        }
+        repeating
+    }
+
+    /// Estimates the average number of moves in book by playing random games through the
+    /// repertoire, choosing each move according to its frequency
+    pub fn simulate_book_length<R: Rng>(&self, games: usize, rng: &mut R) -> f64 {
+        let mut total_length = 0;
+        for _ in 0..games {
+            let mut fen = Fen::starting_board();
+            let mut ply = 0;
+            while ply < MAX_SIMULATED_PLIES {
+                match self.sample_transition(&fen, rng) {
+                    Some((to_fen, _)) => {
+                        fen = to_fen.clone();
+                        ply += 1;
+                    }
+                    None => break,
+                }
+            }
+            let white_to_move = ply % 2 == 0;
+            total_length += if white_to_move == (self.me == Player::White) {
+                ply / 2
+            } else {
+                (ply + 1) / 2
+            };
+        }
+        total_length as f64 / games as f64
+    }
+
+    fn sample_transition<R: Rng>(&self, fen: &Fen, rng: &mut R) -> Option<(&Fen, &Transition)> {
+        // Sort the transitions, so the same seed always leads to the same game
+        let mut transitions: Vec<_> = self.tree.get(fen)?.transitions().collect();
+        transitions.sort_by(|(a, _), (b, _)| a.fen_str().cmp(b.fen_str()));
+        // Frequencies may add up to less than one; the rest of the time the game leaves the book
+        let mut remaining: f64 = rng.gen();
+        for (to_fen, transition) in transitions {
+            if remaining < transition.frequency {
+                return Some((to_fen, transition));
+            }
+            remaining -= transition.frequency;
+        }
+        None
+    }
+
+    /// Plays `games` games through the repertoire against an opponent choosing among the
+    /// book moves played in at least `min_share` of games, weighted by their popularity
+    pub fn stress_test<R: Rng>(
+        &self,
+        book: &mut dyn OpeningBook,
+        games: usize,
+        min_share: f64,
+        rng: &mut R,
+    ) -> Result<StressTest, Error> {
+        let mut result = StressTest {
+            games,
+            ..StressTest::default()
+        };
+        for _ in 0..games {
+            let mut fen = Fen::starting_board();
+            let mut line = MoveSequence::new(1.0);
+            for _ in 0..MAX_SIMULATED_PLIES {
+                // My moves stay within the repertoire, so only the opponent can leave it
+                let position = match self.tree.get(&fen) {
+                    Some(position) if position.turn() != self.me => position,
+                    Some(position) if position.transition_count() > 0 => position,
+                    _ => {
+                        result.record_exit(fen, line);
+                        break;
+                    }
+                };
+                let mv = if position.turn() == self.me {
+                    let (to_fen, transition) = match self.sample_transition(&fen, rng) {
+                        Some(sampled) => sampled,
+                        None => break,
+                    };
+                    result.prepared_moves_played += 1;
+                    fen = to_fen.clone();
+                    transition.mv.clone()
+                } else {
+                    let mut book_moves = book.moves(&fen)?;
+                    book_moves.retain(|book_move| book_move.frequency >= min_share);
+                    book_moves.sort_by(|a, b| a.uci.cmp(&b.uci));
+                    let total: f64 = book_moves.iter().map(|book_move| book_move.frequency).sum();
+                    let mut remaining = rng.gen::<f64>() * total;
+                    let book_move = match book_moves.iter().find(|book_move| {
+                        remaining -= book_move.frequency;
+                        remaining < 0.0
+                    }) {
+                        Some(book_move) => book_move,
+                        // The opponent is out of book as well
+                        None => break,
+                    };
+                    fen = position.after_uci(&book_move.uci)?;
+                    AnyMove::UCI(book_move.uci.clone())
+                };
+                line = line.then(mv, 1.0);
+            }
+        }
+        Ok(result)
+    }
+
+    /// All positions of the tree, on either side's turn
+    pub fn positions(&self) -> impl Iterator<Item = &Position> {
+        self.tree.all_positions()
+    }
+
+    /// All positions where it is my turn, prepared or not, for the `recommend_for_*` functions;
+    /// Unprepared positions marked by a `{book-end}` comment are left out, as their lack of
+    /// preparation is deliberate. Book moves are not added after such positions either.
+    pub fn own_positions(&self) -> Vec<&Position> {
+        self.tree
+            .all_positions()
+            .filter(|pos| pos.turn() == self.me)
+            .filter(|pos| pos.transition_count() > 0 || !self.book_ends.contains(pos.fen()))
+            .collect()
+    }
+
+    /// When playing Black, the positions only reached after the opponent's first moves other
+    /// than `first_moves` in UCI, like "e2e4"; Positions transposing into one of those first
+    /// moves are in scope
+    pub fn out_of_scope(&self, first_moves: &[String]) -> Result<HashSet<&Fen>, Error> {
+        let start = match self.tree.get(&Fen::starting_board()) {
+            Some(start) => start,
+            None => return Ok(HashSet::new()),
+        };
+        let mut in_scope = HashSet::new();
+        let mut to_visit = Vec::new();
+        for uci in first_moves {
+            to_visit.push(start.after_uci(uci)?);
+        }
+        while let Some(fen) = to_visit.pop() {
+            if let Some(position) = self.tree.get(&fen) {
+                if in_scope.insert(position.fen()) {
+                    to_visit.extend(position.transitions().map(|(to, _)| to.clone()));
+                }
+            }
+        }
+        Ok(self
+            .tree
+            .all_positions()
+            .map(Position::fen)
+            .filter(|fen| **fen != Fen::starting_board() && !in_scope.contains(fen))
+            .collect())
+    }
+
+    /// My prepared moves, counting identical moves played in different positions once, as in
+    /// system openings; Each with the number of positions it is prepared in, most reused first
+    pub fn own_decisions(&self) -> Vec<(String, usize)> {
+        let mut decisions: HashMap<String, usize> = HashMap::new();
+        for position in self
+            .tree
+            .all_positions()
+            .filter(|pos| pos.turn() == self.me)
+        {
+            for (fen, _) in position.transitions() {
+                if let Some(uci) = position.uci_to(fen) {
+                    *decisions.entry(uci).or_default() += 1;
+                }
+            }
+        }
+        let mut decisions: Vec<(String, usize)> = decisions.into_iter().collect();
+        decisions.sort_by(|(a_uci, a), (b_uci, b)| b.cmp(a).then_with(|| a_uci.cmp(b_uci)));
+        decisions
+    }
+
+    /// For each prepared line, the opponent's position where play most often leaves the
+    /// repertoire, together with the share of all games that leave it there
+    pub fn deviation_hotspots(&self) -> Vec<(&Position, f64)> {
+        let mut hotspots = HashMap::new();
+        for line in self.imported_games.keys() {
+            let hotspot = iter::once(&Fen::starting_board())
+                .chain(line)
+                .filter_map(|fen| self.tree.get(fen))
+                .filter(|pos| pos.turn() != self.me)
+                .map(|pos| (pos, self.deviation_frequency(pos)))
+                .filter(|(_, frequency)| *frequency > 0.0)
+                .max_by(|(_, a), (_, b)| a.total_cmp(b));
+            if let Some((position, frequency)) = hotspot {
+                hotspots.insert(position.fen().clone(), (position, frequency));
+            }
+        }
+        let mut hotspots: Vec<_> = hotspots.into_values().collect();
+        hotspots.sort_by(|(a_pos, a), (b_pos, b)| {
+            by_frequency_then_fen((*a, a_pos.fen()), (*b, b_pos.fen()))
+        });
+        hotspots
+    }
+
+    /// Opponent's moves into positions I have prepared, where the opponent has more than one
+    /// such move, as pairs of the positions before and after; most frequent first
+    pub fn branching_moves(&self) -> Vec<(&Position, &Position)> {
+        let mut moves: Vec<(&Position, &Position)> = Vec::new();
+        for position in self
+            .tree
+            .all_positions()
+            .filter(|pos| pos.turn() != self.me)
+        {
+            let prepared: Vec<&Position> = position
+                .transitions()
+                .filter_map(|(fen, _)| self.tree.get(fen))
+                .filter(|pos| pos.transition_count() > 0)
+                .collect();
+            if prepared.len() > 1 {
+                moves.extend(prepared.into_iter().map(|child| (position, child)));
+            }
+        }
+        moves.sort_by(|(a_parent, a), (b_parent, b)| {
+            by_frequency_then_fen(
+                (*a.frequency(), a_parent.fen()),
+                (*b.frequency(), b_parent.fen()),
+            )
+            .then_with(|| a.fen().tie_break(b.fen()))
+        });
+        moves
+    }
+
+    /// Every prepared line, with the most recent year its deepest known move was
+    /// seen in the opening book's sample games, oldest first
+    pub fn line_ages(&self) -> Vec<(MoveSequence, Option<u32>)> {
+        let mut ages: Vec<_> = self
+            .imported_games
+            .keys()
+            .map(|line| {
+                let mut sequence = MoveSequence::new(1.0);
+                let mut year = None;
+                for transition in self.transitions_along(line) {
+                    sequence = sequence.then(transition.mv.clone(), 1.0);
+                    if let Some(known) = transition.stats.as_ref().and_then(|stats| stats.year) {
+                        year = Some(known);
+                    }
+                }
+                (sequence, year)
+            })
+            .collect();
+        ages.sort_by_cached_key(|(sequence, year)| (*year, sequence.to_string()));
+        ages
+    }
+
+    /// Every prepared line passing through the position, in alphabetical order
+    pub fn lines_through(&self, fen: &Fen) -> Vec<MoveSequence> {
+        let mut lines: Vec<MoveSequence> = self
+            .imported_games
+            .keys()
+            .filter(|line| *fen == Fen::starting_board() || line.contains(fen))
+            .map(|line| {
+                self.transitions_along(line)
+                    .fold(MoveSequence::new(1.0), |sequence, transition| {
+                        sequence.then(transition.mv.clone(), 1.0)
+                    })
+            })
+            .collect();
+        lines.sort_by_cached_key(MoveSequence::to_string);
+        lines
+    }
+
+    /// Every prepared line with more than `max_plies` half-moves, longest first
+    pub fn long_lines(&self, max_plies: usize) -> Vec<LongLine> {
+        let start = Fen::starting_board();
+        let mut lines: Vec<LongLine> = self
+            .imported_games
+            .keys()
+            .filter(|line| line.len() > max_plies)
+            .map(|line| {
+                let truncation_points = iter::once(&start)
+                    .chain(line)
+                    .zip(&line[..max_plies])
+                    .filter_map(|(before, after)| {
+                        Some((self.tree.get(before)?, self.tree.get(after)?))
+                    })
+                    .filter(|(before, after)| {
+                        after.turn() == self.me
+                            && before
+                                .transitions()
+                                .filter(|(_, transition)| {
+                                    transition.frequency >= CANDIDATE_REPLY_MIN_SHARE
+                                })
+                                .count()
+                                > 1
+                    })
+                    .map(|(_, after)| after)
+                    .collect();
+                LongLine {
+                    line: self
+                        .transitions_along(line)
+                        .fold(MoveSequence::new(1.0), |sequence, transition| {
+                            sequence.then(transition.mv.clone(), 1.0)
+                        }),
+                    plies: line.len(),
+                    truncation_points,
+                }
+            })
+            .collect();
+        lines.sort_by_cached_key(|line| (std::cmp::Reverse(line.plies), line.line.to_string()));
+        lines
+    }
+
+    // The moves along an imported game, as far as they are still in the tree; Games starting
+    // from a "FEN" header begin with that position rather than a move from the starting board
+    fn transitions_along<'a>(&'a self, line: &'a [Fen]) -> impl Iterator<Item = &'a Transition> {
+        self.steps_along(line).map(|(_, _, transition)| transition)
+    }
+
+    // Like `transitions_along`, with the position each move is played in and the one it leads to
+    fn steps_along<'a>(
+        &'a self,
+        line: &'a [Fen],
+    ) -> impl Iterator<Item = (&'a Position, &'a Fen, &'a Transition)> {
+        let start = Fen::starting_board();
+        let set_up = line.first().map_or(false, |first| {
+            self.tree
+                .get(&start)
+                .and_then(|pos| pos.transition(first))
+                .is_none()
+        });
+        let skipped = if set_up { 1 } else { 0 };
+        iter::once(start)
+            .chain(line.iter().cloned())
+            .skip(skipped)
+            .zip(line.iter().skip(skipped))
+            .map_while(move |(from, to)| {
+                let pos = self.tree.get(&from)?;
+                Some((pos, to, pos.transition(to)?))
+            })
+    }
+
+    /// For each ECO chapter named in the PGN headers of my lines, the share of games entering it
+    /// that stay within my preparation for `depth` plies from the start, or until the opponent
+    /// leaves the book; Chapters are entered where their lines part from those of other chapters
+    pub fn completeness(&self, depth: usize) -> Vec<Completeness> {
+        let lines: Vec<(&Vec<Fen>, &str)> = self
+            .imported_games
+            .iter()
+            .filter_map(|(line, game)| Some((line, eco_chapter(&game.tags)?)))
+            .collect();
+        let mut chapters_through: HashMap<&Fen, HashSet<&str>> = HashMap::new();
+        for (line, chapter) in &lines {
+            for fen in line.iter() {
+                chapters_through.entry(fen).or_default().insert(chapter);
+            }
+        }
+        // The positions entering each chapter, with their ply; Lines from a "FEN" header start
+        // later than their index says
+        let mut entries: HashMap<&str, HashMap<&Fen, usize>> = HashMap::new();
+        for (line, chapter) in &lines {
+            let entry = line
+                .iter()
+                .enumerate()
+                .find(|(_, fen)| chapters_through[fen].len() == 1);
+            if let Some((index, fen)) = entry {
+                let ply = fen.ply().map_or(index + 1, |ply| ply as usize);
+                entries.entry(chapter).or_default().insert(fen, ply);
+            }
+        }
+        let mut chapters: Vec<Completeness> = entries
+            .into_iter()
+            .map(|(chapter, entries)| {
+                let mut frequency = 0.0;
+                let mut covered = 0.0;
+                for (fen, ply) in entries {
+                    if let Some(position) = self.tree.get(fen) {
+                        frequency += position.frequency();
+                        covered +=
+                            self.covered_frequency(position, *position.frequency(), ply, depth);
+                    }
+                }
+                Completeness {
+                    chapter: chapter.to_owned(),
+                    frequency,
+                    score: if frequency > 0.0 {
+                        100.0 * covered / frequency
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect();
+        chapters.sort_by(|a, b| a.chapter.cmp(&b.chapter));
+        chapters
+    }
+
+    // Part of the games reaching the position at `ply` that stay within my preparation up to
+    // `depth`; Games in which the opponent leaves the book need no further preparation
+    fn covered_frequency(
+        &self,
+        position: &Position,
+        frequency: f64,
+        ply: usize,
+        depth: usize,
+    ) -> f64 {
+        if ply >= depth {
+            return frequency;
+        }
+        let mine = position.turn() == self.me;
+        let mut covered = 0.0;
+        let mut out_of_book = frequency;
+        for (fen, transition) in position.transitions() {
+            let share = frequency * transition.frequency;
+            out_of_book -= share;
+            if let Some(next) = self.tree.get(fen) {
+                covered += self.covered_frequency(next, share, ply + 1, depth);
+            }
+        }
+        if mine {
+            covered
+        } else {
+            covered + out_of_book.max(0.0)
+        }
+    }
+
+    // Share of all games reaching the position that continue with a move I have not prepared for
+    fn deviation_frequency(&self, position: &Position) -> f64 {
+        let uncovered: f64 = position
+            .transitions()
+            .filter(|(fen, _)| {
+                self.tree
+                    .get(fen)
+                    .is_none_or(|pos| pos.transition_count() == 0)
+            })
+            .map(|(_, transition)| transition.frequency)
+            .sum();
+        position.frequency() * uncovered
+    }
+
+    /// The unprepared positions reached most often
+    pub fn recommend_for_addition<'a>(
+        positions: &[&'a Position],
+        count: usize,
+    ) -> Vec<&'a Position> {
+        Addition.recommend(positions, count)
+    }
+
+    /// Rating points lost over 100 games by improvising in each position instead of playing
+    /// the best move, assuming improvised moves are as common as in the book; With an `engine`,
+    /// they are scored by its evaluation against its best move, otherwise by their book scores
+    pub fn rating_points_at_stake(
+        positions: &[&Position],
+        book: &mut dyn OpeningBook,
+        mut engine: Option<&mut dyn OpeningBook>,
+    ) -> Result<Vec<f64>, Error> {
+        let fens: Vec<Fen> = positions.iter().map(|pos| pos.fen().clone()).collect();
+        let mut points = Vec::with_capacity(positions.len());
+        for (result, pos) in book.moves_batch(&fens).into_iter().zip(positions) {
+            let book_moves = result?;
+            let evaluated = engine.as_deref_mut().map(|engine| {
+                RepertoireOptimizer::evaluated_loss(pos, &book_moves, engine).unwrap_or_else(|e| {
+                    warn!(
+                        "Falling back to book scores for '{}': {}",
+                        pos.fen().fen_str(),
+                        e
+                    );
+                    None
+                })
+            });
+            let loss = evaluated
+                .flatten()
+                .unwrap_or_else(|| RepertoireOptimizer::improvisation_loss(&book_moves));
+            points.push(ELO_K_FACTOR * 100.0 * pos.frequency() * loss);
+        }
+        Ok(points)
+    }
+
+    // Expected score given away by improvising one of the common book moves instead of the
+    // engine's best move, with each scored by the engine; None if the engine has no moves
+    fn evaluated_loss(
+        position: &Position,
+        book_moves: &BookMoves,
+        engine: &mut dyn OpeningBook,
+    ) -> Result<Option<f64>, Error> {
+        let total: f64 = book_moves.iter().map(|book_move| book_move.frequency).sum();
+        let lines = engine.moves(position.fen())?;
+        let best = match RepertoireOptimizer::best_score(&lines) {
+            Some(best) => best,
+            None => return Ok(None),
+        };
+        let mut share = 0.0;
+        let mut improvised = 0.0;
+        for book_move in book_moves
+            .iter()
+            .filter(|book_move| book_move.frequency / total >= BEST_MOVE_MIN_SHARE)
+        {
+            // Moves outside the engine's top lines are scored by its best reply to them
+            let score = match lines.iter().find(|line| line.uci == book_move.uci) {
+                Some(line) => line.score,
+                None => {
+                    let replies = engine.moves(&position.after_uci(&book_move.uci)?)?;
+                    match RepertoireOptimizer::best_score(&replies) {
+                        Some(reply) => 1.0 - reply,
+                        // Mate or stalemate, which the book knows the outcome of
+                        None => book_move.score,
+                    }
+                }
+            };
+            share += book_move.frequency;
+            improvised += book_move.frequency * score;
+        }
+        if share <= 0.0 {
+            return Ok(None);
+        }
+        Ok(Some((best - improvised / share).max(0.0)))
+    }
+
+    fn best_score(book_moves: &BookMoves) -> Option<f64> {
+        book_moves
+            .iter()
+            .map(|book_move| book_move.score)
+            .max_by(f64::total_cmp)
+    }
+
+    // Expected score given away by playing a book move at random instead of the best one
+    fn improvisation_loss(book_moves: &BookMoves) -> f64 {
+        let total: f64 = book_moves.iter().map(|book_move| book_move.frequency).sum();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let improvised = book_moves
+            .iter()
+            .map(|book_move| book_move.frequency * book_move.score)
+            .sum::<f64>()
+            / total;
+        let best = book_moves
+            .iter()
+            .filter(|book_move| book_move.frequency / total >= BEST_MOVE_MIN_SHARE)
+            .map(|book_move| book_move.score)
+            .fold(improvised, f64::max);
+        best - improvised
+    }
+
+    /// The `count` most popular book moves in each position, as candidates for filling it
+    pub fn candidate_moves(
+        positions: &[&Position],
+        book: &mut dyn OpeningBook,
+        count: usize,
+    ) -> Result<Vec<Vec<Candidate>>, Error> {
+        let fens: Vec<Fen> = positions.iter().map(|pos| pos.fen().clone()).collect();
+        let mut candidates = Vec::new();
+        let mut after_fens = Vec::new();
+        for (result, position) in book.moves_batch(&fens).into_iter().zip(positions) {
+            let mut book_moves = result?;
+            book_moves.sort_by(|a, b| {
+                b.frequency
+                    .total_cmp(&a.frequency)
+                    .then_with(|| a.uci.cmp(&b.uci))
+            });
+            book_moves.truncate(count);
+            for book_move in &book_moves {
+                after_fens.push(position.after_uci(&book_move.uci)?);
+            }
+            candidates.push(book_moves);
+        }
+        let mut replies = book.moves_batch(&after_fens).into_iter();
+        candidates
+            .into_iter()
+            .map(|book_moves| {
+                book_moves
+                    .into_iter()
+                    .map(|book_move| {
+                        let replies = replies.next().unwrap_or_else(|| Ok(Vec::new()))?;
+                        Ok(Candidate {
+                            uci: book_move.uci,
+                            popularity: book_move.frequency,
+                            score: book_move.score,
+                            replies: replies
+                                .iter()
+                                .filter(|reply| reply.frequency >= CANDIDATE_REPLY_MIN_SHARE)
+                                .count(),
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// How many positions of mine I would learn to cover each position `plies` half-moves deep,
+    /// counting it and the positions after the opponent's common replies to the most popular
+    /// book moves
+    pub fn learning_costs(
+        positions: &[&Position],
+        book: &mut dyn OpeningBook,
+        plies: u32,
+    ) -> Result<Vec<LearningCost>, Error> {
+        positions
+            .iter()
+            .map(|position| RepertoireOptimizer::learning_cost(position.board(), book, plies))
+            .collect()
+    }
+
+    fn learning_cost(
+        board: &Board,
+        book: &mut dyn OpeningBook,
+        plies: u32,
+    ) -> Result<LearningCost, Error> {
+        let mut cost = LearningCost::default();
+        // Boards with the share of the games reaching the covered position that get there,
+        // before my moves and after them
+        let mut to_learn = vec![(board.clone(), 1.0)];
+        let mut after_mine = Vec::new();
+        for ply in 0..plies {
+            let boards = if ply % 2 == 0 { &to_learn } else { &after_mine };
+            if boards.is_empty() {
+                break;
+            }
+            if ply % 2 == 0 {
+                cost.positions += to_learn.len();
+            }
+            cost.moves += boards.iter().map(|(_, share)| share).sum::<f64>();
+            // The moves after the last half-move counted are not needed
+            if ply + 1 == plies {
+                break;
+            }
+            let fens: Vec<Fen> = boards
+                .iter()
+                .map(|(board, _)| Fen::new(&board.fen()))
+                .collect();
+            let results = book.moves_batch(&fens);
+            if ply % 2 == 0 {
+                after_mine = Vec::new();
+                for (result, (board, share)) in results.into_iter().zip(to_learn.drain(..)) {
+                    let most_popular = result?.into_iter().max_by(|a, b| {
+                        a.frequency
+                            .total_cmp(&b.frequency)
+                            .then_with(|| b.uci.cmp(&a.uci))
+                    });
+                    let mut after = board;
+                    if most_popular.is_some_and(|book_move| after.apply_uci_move(&book_move.uci)) {
+                        after_mine.push((after, share));
+                    }
+                }
+            } else {
+                for (result, (board, share)) in results.into_iter().zip(after_mine.drain(..)) {
+                    for reply in result? {
+                        let mut after = board.clone();
+                        if reply.frequency >= CANDIDATE_REPLY_MIN_SHARE
+                            && after.apply_uci_move(&reply.uci)
+                        {
+                            to_learn.push((after, share * reply.frequency));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(cost)
+    }
+
+    /// Indices of the items, given as positions to learn and expected moves in book gained,
+    /// gaining the most moves with at most `budget` positions to learn; Solved exactly as a
+    /// knapsack problem
+    pub fn within_budget(items: &[(usize, f64)], budget: usize) -> Vec<usize> {
+        // A larger budget takes every item, so it doesn't need to be tabulated
+        let budget = budget.min(items.iter().map(|(positions, _)| positions).sum());
+        // best[b] is the most gained with b positions using the items seen so far
+        let mut best = vec![0.0; budget + 1];
+        let mut taken = vec![vec![false; budget + 1]; items.len()];
+        for (index, &(positions, gain)) in items.iter().enumerate() {
+            for b in (positions..=budget).rev() {
+                if best[b - positions] + gain > best[b] {
+                    best[b] = best[b - positions] + gain;
+                    taken[index][b] = true;
+                }
+            }
+        }
+        let mut chosen = Vec::new();
+        let mut b = budget;
+        for index in (0..items.len()).rev() {
+            if taken[index][b] {
+                chosen.push(index);
+                b -= items[index].0;
+            }
+        }
+        chosen.reverse();
+        chosen
+    }
+
+    /// How hard it is to recall my moves in each position, judged by the book moves
+    /// commonly played there instead
+    pub fn difficulties(
+        positions: &[&Position],
+        book: &mut dyn OpeningBook,
+    ) -> Result<Vec<Difficulty>, Error> {
+        let fens: Vec<Fen> = positions.iter().map(|pos| pos.fen().clone()).collect();
+        book.moves_batch(&fens)
+            .into_iter()
+            .zip(positions)
+            .map(|(result, position)| {
+                let mine: Vec<String> = position
+                    .transitions()
+                    .filter_map(|(fen, _)| position.uci_to(fen))
+                    .collect();
+                let mut difficulty = Difficulty::default();
+                for book_move in result? {
+                    if book_move.frequency < PLAUSIBLE_MOVE_MIN_SHARE
+                        || mine.contains(&book_move.uci)
+                    {
+                        continue;
+                    }
+                    difficulty.alternatives += 1;
+                    if mine.iter().any(|uci| {
+                        uci.get(..2) == book_move.uci.get(..2)
+                            || uci.get(2..4) == book_move.uci.get(2..4)
+                    }) {
+                        difficulty.similar += 1;
+                    }
+                }
+                Ok(difficulty)
+            })
+            .collect()
+    }
+
+    /// Earlier moves of mine which avoid the unprepared position and reach positions I have
+    /// prepared by other move orders, found by looking up the book moves at each of my decisions
+    /// along the likeliest line to it; Best first
+    pub fn move_orders(
+        &self,
+        position: &Position,
+        book: &mut dyn OpeningBook,
+    ) -> Result<Vec<MoveOrder>, Error> {
+        let parents = self.likeliest_parents();
+        let mut move_orders = Vec::new();
+        let mut visited = HashSet::new();
+        let mut fen = position.fen();
+        while let Some(parent) = parents.get(fen).copied() {
+            if !visited.insert(parent.fen()) {
+                break;
+            }
+            if parent.turn() == self.me {
+                let instead_of = match parent.uci_to(fen).and_then(|uci| parent.san(&uci)) {
+                    Some(instead_of) => instead_of,
+                    None => break,
+                };
+                for book_move in book.moves(parent.fen())? {
+                    let after = parent.after_uci(&book_move.uci)?;
+                    if book_move.frequency < PLAUSIBLE_MOVE_MIN_SHARE
+                        || parent.transition(&after).is_some()
+                    {
+                        continue;
+                    }
+                    let prepared_share = self.prepared_share(&after, book)?;
+                    if prepared_share >= MOVE_ORDER_MIN_PREPARED_SHARE {
+                        let san = parent
+                            .san(&book_move.uci)
+                            .ok_or_else(|| Error::IllegalMove {
+                                fen_str: parent.fen().fen_str().to_owned(),
+                                mv: book_move.uci.clone(),
+                            })?;
+                        move_orders.push(MoveOrder {
+                            fen: parent.fen().clone(),
+                            san,
+                            uci: book_move.uci,
+                            instead_of: instead_of.clone(),
+                            prepared_share,
+                        });
+                    }
+                }
+            }
+            fen = parent.fen();
+        }
+        move_orders.sort_by(|a, b| {
+            by_frequency_then_fen((a.prepared_share, &a.fen), (b.prepared_share, &b.fen))
+                .then_with(|| a.uci.cmp(&b.uci))
+        });
+        Ok(move_orders)
+    }
+
+    /// The deepest position each of the gaps shares with another of them along their likeliest
+    /// lines, or the position it is most often reached from if it shares none, by gap; The gaps
+    /// stemming from the same branch root can be prepared together
+    pub fn branch_roots(&self, gaps: &[&Position]) -> HashMap<Fen, &Position> {
+        let parents = self.likeliest_parents();
+        // The positions each gap is most likely reached through, deepest first
+        let lines: Vec<Vec<&Position>> = gaps
+            .iter()
+            .map(|gap| {
+                let mut ancestors = Vec::new();
+                let mut visited = HashSet::new();
+                let mut fen = gap.fen();
+                while let Some(parent) = parents.get(fen).copied() {
+                    if !visited.insert(parent.fen()) {
+                        break;
+                    }
+                    ancestors.push(parent);
+                    fen = parent.fen();
+                }
+                ancestors
+            })
+            .collect();
+        let mut gaps_through: HashMap<&Fen, usize> = HashMap::new();
+        for ancestor in lines.iter().flatten() {
+            *gaps_through.entry(ancestor.fen()).or_default() += 1;
+        }
+        gaps.iter()
+            .zip(&lines)
+            .filter_map(|(gap, ancestors)| {
+                let root = ancestors
+                    .iter()
+                    .find(|ancestor| gaps_through[ancestor.fen()] > 1)
+                    .or_else(|| ancestors.first())?;
+                Some((gap.fen().clone(), *root))
+            })
+            .collect()
+    }
+
+    // The position leading to each position in the most games
+    fn likeliest_parents(&self) -> HashMap<&Fen, &Position> {
+        let mut parents: HashMap<&Fen, &Position> = HashMap::new();
+        for position in self.tree.all_positions() {
+            for (to, _) in position.transitions() {
+                match parents.entry(to) {
+                    Entry::Occupied(mut parent) => {
+                        let likelier = position
+                            .frequency()
+                            .total_cmp(parent.get().frequency())
+                            .then_with(|| parent.get().fen().tie_break(position.fen()))
+                            .is_gt();
+                        if likelier {
+                            parent.insert(position);
+                        }
+                    }
+                    Entry::Vacant(parent) => {
+                        parent.insert(position);
+                    }
+                }
+            }
+        }
+        parents
+    }
+
+    // Share of games after a move of mine that reach positions I have prepared, either right
+    // away or after the opponent's book moves
+    fn prepared_share(&self, after: &Fen, book: &mut dyn OpeningBook) -> Result<f64, Error> {
+        if self.tree.get(after).is_some() {
+            return Ok(1.0);
+        }
+        let board = Board::from_fen(after.fen_str())
+            .map_err(|_| Error::InvalidFen(after.fen_str().to_owned()))?;
+        let mut total = 0.0;
+        let mut prepared = 0.0;
+        for reply in book.moves(after)? {
+            let mut next = board.clone();
+            if !next.apply_uci_move(&reply.uci) {
+                continue;
+            }
+            total += reply.frequency;
+            if self
+                .tree
+                .get(&Fen::new(&next.fen()))
+                .is_some_and(|pos| pos.transition_count() > 0)
+            {
+                prepared += reply.frequency;
+            }
+        }
+        Ok(if total > 0.0 { prepared / total } else { 0.0 })
+    }
+
+    /// My prepared moves in each position which are hardly ever played in the book, so the
+    /// common moves are likely to crowd them out of my memory under pressure; Positions which
+    /// are not in the book at all have none
+    pub fn exotic_choices(
+        positions: &[&Position],
+        book: &mut dyn OpeningBook,
+    ) -> Result<Vec<Vec<ExoticChoice>>, Error> {
+        let fens: Vec<Fen> = positions.iter().map(|pos| pos.fen().clone()).collect();
+        book.moves_batch(&fens)
+            .into_iter()
+            .zip(positions)
+            .map(|(result, position)| {
+                let book_moves = result?;
+                if book_moves.is_empty() {
+                    return Ok(Vec::new());
+                }
+                Ok(position
+                    .transitions()
+                    .filter_map(|(fen, _)| position.uci_to(fen))
+                    .filter_map(|uci| {
+                        let share = book_moves
+                            .iter()
+                            .find(|book_move| book_move.uci == uci)
+                            .map_or(0.0, |book_move| book_move.frequency);
+                        if share < EXOTIC_MOVE_MAX_SHARE {
+                            Some(ExoticChoice { uci, share })
+                        } else {
+                            None
+                        }
+                    })
+                    .collect())
+            })
+            .collect()
+    }
+
+    /// The prepared positions reached least often
+    pub fn recommend_for_removal<'a>(
+        positions: &[&'a Position],
+        count: usize,
+    ) -> Vec<&'a Position> {
+        Removal.recommend(positions, count)
+    }
+
+    /// The positions with several prepared moves whose moves are each reached least often
+    pub fn recommend_for_narrowing<'a>(
+        positions: &[&'a Position],
+        count: usize,
+    ) -> Vec<&'a Position> {
+        Narrowing.recommend(positions, count)
+    }
+
+    /// The positions with several prepared moves where those moves weigh most in total
+    pub fn recommend_for_reduction<'a>(
+        positions: &[&'a Position],
+        count: usize,
+    ) -> Vec<&'a Position> {
+        Reduction.recommend(positions, count)
+    }
+}
+
+/// Sets up a `RepertoireOptimizer` from its configuration, taking care of the order in which
+/// games, book moves and frequencies have to be added
+pub struct RepertoireOptimizerBuilder<'a> {
+    me: Player,
+    files: Vec<PathBuf>,
+    pgn: Vec<String>,
+    max_depth: Option<u32>,
+    min_book_share: f64,
+    book: Option<&'a mut dyn OpeningBook>,
+    policy: ErrorPolicy,
+    own_model: &'a dyn FrequencyModel,
+    opponent_model: &'a dyn FrequencyModel,
+}
+
+impl<'a> RepertoireOptimizerBuilder<'a> {
+    /// PGN files to import; Unreadable files and games with bad moves are skipped with a warning
+    pub fn files(mut self, files: Vec<PathBuf>) -> Self {
+        self.files.extend(files);
+        self
+    }
+
+    /// Games to import as PGN text; Bad moves fail the build
+    pub fn pgn(mut self, pgn: &str) -> Self {
+        self.pgn.push(pgn.to_owned());
+        self
+    }
+
+    /// Only add book moves up to this many half-moves into the game
+    pub fn max_depth(mut self, plies: u32) -> Self {
+        self.max_depth = Some(plies);
+        self
+    }
+
+    /// Leave out book moves played in less than this share of games
+    pub fn min_book_share(mut self, share: f64) -> Self {
+        self.min_book_share = share;
+        self
+    }
+
+    /// Where the opponent's moves are taken from; Without a book, only the opponent's moves in
+    /// the imported games are known
+    pub fn book(mut self, book: &'a mut dyn OpeningBook, policy: ErrorPolicy) -> Self {
+        self.book = Some(book);
+        self.policy = policy;
+        self
+    }
+
+    /// How often I choose each of my prepared moves; Uniform by default
+    pub fn own_model(mut self, model: &'a dyn FrequencyModel) -> Self {
+        self.own_model = model;
+        self
+    }
+
+    /// How often the opponent chooses each book move; By popularity by default
+    pub fn opponent_model(mut self, model: &'a dyn FrequencyModel) -> Self {
+        self.opponent_model = model;
+        self
+    }
+
+    /// Imports the games, adds the book moves and computes the frequencies of all positions
+    pub fn build(self) -> Result<RepertoireOptimizer, Error> {
+        let mut optimizer = RepertoireOptimizer::new(self.me);
+        optimizer.max_depth = self.max_depth;
+        optimizer.min_book_share = self.min_book_share;
+        optimizer.own_move_stats = self.own_model.uses_stats();
+        import_repertoire(&mut optimizer, self.files);
+        for pgn in &self.pgn {
+            optimizer.add_pgn(pgn)?;
+        }
+        if let Some(book) = self.book {
+            optimizer.add_opponents_moves_from_book(book, self.policy)?;
+        }
+        optimizer.set_own_move_frequencies(self.own_model);
+        optimizer.set_opponent_move_frequencies(self.opponent_model);
+        optimizer.update_position_frequencies();
+        Ok(optimizer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frequency_model::{MoveStats, Popularity, Uniform};
+    use crate::opening_book::MapBook;
+    use crate::repertoire_optimizer::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    fn optimizer(pgn: &str) -> RepertoireOptimizer {
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        for game in chess_pgn_parser::read_games(pgn).unwrap() {
+            optimizer.add_game_to_repertoire(game).unwrap();
+        }
+        optimizer.set_own_move_frequencies(&Uniform);
+        optimizer.set_opponent_move_frequencies(&Uniform);
+        optimizer
+    }
+
+    // A White repertoire of the games of each PGN, imported as the file it is paired with
+    fn optimizer_from_files(files: &[(&str, &str)]) -> RepertoireOptimizer {
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        for (file, pgn) in files {
+            let games = chess_pgn_parser::read_games(pgn).unwrap();
+            for (chapter, game) in (1..).zip(games) {
+                optimizer
+                    .add_chapter_from_file(game, chapter, Path::new(file))
+                    .unwrap();
+            }
+        }
+        optimizer
+    }
+
+    // Book moves with the given shares and scores
+    fn scored_book_moves(moves: &[(&str, f64, f64)]) -> BookMoves {
+        moves
+            .iter()
+            .map(|(uci, frequency, score)| crate::opening_book::BookMove {
+                uci: (*uci).to_owned(),
+                frequency: *frequency,
+                score: *score,
+                year: None,
+            })
+            .collect()
+    }
+
+    // Book moves with the given shares, all scoring even
+    fn book_moves(moves: &[(&str, f64)]) -> BookMoves {
+        let scored: Vec<(&str, f64, f64)> = moves
+            .iter()
+            .map(|(uci, frequency)| (*uci, *frequency, 0.5))
+            .collect();
+        scored_book_moves(&scored)
+    }
+
+    // Knows the moves of the given positions, and the moves of every other position if given
+    fn map_book(positions: &[(&str, BookMoves)], elsewhere: BookMoves) -> MapBook {
+        let mut book = MapBook::new(
+            positions
+                .iter()
+                .map(|(fen, book_moves)| (Fen::new(fen), book_moves.clone()))
+                .collect(),
+        );
+        book.set_elsewhere(elsewhere);
+        book
+    }
+
+    // Offers the same scored moves in every position
+    fn scored_book() -> MapBook {
+        let moves = [("a2a3", 0.5, 0.6), ("b2b3", 0.48, 0.4), ("c2c3", 0.02, 1.0)];
+        map_book(&[], scored_book_moves(&moves))
+    }
+
+    // Fails the first `failures` requests, then answers with a single move
+    struct FlakyBook {
+        failures: u32,
+    }
+
+    impl OpeningBook for FlakyBook {
+        fn moves(&mut self, fen: &Fen) -> Result<BookMoves, Error> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(Error::Http);
+            }
+            let uci = if fen == &Fen::starting_board() {
+                "e2e4"
+            } else {
+                "e7e5"
+            };
+            Ok(vec![crate::opening_book::BookMove {
+                uci: uci.to_owned(),
+                frequency: 1.0,
+                score: 0.5,
+                year: None,
+            }])
+        }
+    }
+
+    #[test]
+    fn it_recognizes_duplicate_games() {
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        let pgn = "1. e4 e5 2. Nf3 *\n\n1. e4 e5 2. Ngf3 *\n\n1. e4 e5 *\n";
+        let added: Vec<bool> = chess_pgn_parser::read_games(pgn)
+            .unwrap()
+            .into_iter()
+            .map(|game| optimizer.add_game_to_repertoire(game).unwrap())
+            .collect();
+        assert_eq!(added, vec![true, false, true]);
+    }
+
+    #[test]
+    fn it_aborts_on_book_errors() {
+        let mut optimizer = optimizer("1. e4 *\n\n");
+        let mut book = FlakyBook { failures: 1 };
+        assert!(optimizer
+            .add_opponents_moves_from_book(&mut book, ErrorPolicy::Abort)
+            .is_err());
+    }
+
+    #[test]
+    fn it_skips_positions_on_book_errors() {
+        let mut optimizer = optimizer("1. e4 *\n\n");
+        let mut book = FlakyBook { failures: 1 };
+        optimizer
+            .add_opponents_moves_from_book(&mut book, ErrorPolicy::Skip)
+            .unwrap();
+        assert_eq!(optimizer.own_positions().len(), 1);
+    }
+
+    #[test]
+    fn it_retries_book_errors() {
+        let mut optimizer = optimizer("1. e4 *\n\n");
+        let mut book = FlakyBook { failures: 2 };
+        optimizer
+            .add_opponents_moves_from_book(&mut book, ErrorPolicy::Retry(2))
+            .unwrap();
+        assert_eq!(optimizer.own_positions().len(), 2);
+    }
+
+    // Answers right away, like a host's transport serving a cached response
+    impl AsyncOpeningBook for FlakyBook {
+        fn moves<'a>(&'a mut self, fen: &'a Fen) -> crate::opening_book::MovesFuture<'a> {
+            Box::pin(std::future::ready(OpeningBook::moves(self, fen)))
+        }
+    }
+
+    #[test]
+    fn it_retries_async_book_errors() {
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+
+        let mut optimizer = optimizer("1. e4 *\n\n");
+        let mut book = FlakyBook { failures: 2 };
+        let mut added = Box::pin(
+            optimizer.add_opponents_moves_from_async_book(&mut book, ErrorPolicy::Retry(2)),
+        );
+        let mut context = Context::from_waker(Waker::noop());
+        assert!(matches!(
+            added.as_mut().poll(&mut context),
+            Poll::Ready(Ok(()))
+        ));
+        drop(added);
+        assert_eq!(optimizer.own_positions().len(), 2);
+    }
+
+    #[test]
+    fn it_gives_up_after_the_last_retry() {
+        let mut optimizer = optimizer("1. e4 *\n\n");
+        let mut book = FlakyBook { failures: 3 };
+        assert!(optimizer
+            .add_opponents_moves_from_book(&mut book, ErrorPolicy::Retry(2))
+            .is_err());
+    }
+
+    #[test]
+    fn it_simulates_reproducibly_with_the_same_seed() {
+        let optimizer = optimizer("1. e4 e5 2. Nf3 *\n\n1. d4 d5 *\n\n1. e4 c5 *\n\n");
+        let simulate =
+            |seed| optimizer.simulate_book_length(100, &mut ChaChaRng::seed_from_u64(seed));
+        assert_eq!(simulate(42), simulate(42));
+    }
+
+    #[test]
+    fn it_simulates_the_book_length_of_a_single_line() {
+        let optimizer = optimizer("1. e4 e5 2. Nf3 Nc6 3. Bb5 *\n\n");
+        let mut rng = ChaChaRng::seed_from_u64(0);
+        assert_eq!(optimizer.simulate_book_length(10, &mut rng), 3.0);
+    }
+
+    #[test]
+    fn it_adds_up_the_games_of_transpositions_before_expanding_them() {
+        let mut optimizer = optimizer("1. Nf3 Nf6 2. Nc3 Nc6 *\n\n1. Nc3 Nf6 2. Nf3 *\n\n");
+        optimizer.update_position_frequencies();
+        let after_nc6 =
+            Fen::new("r1bqkb1r/pppppppp/2n2n2/8/8/2N2N2/PPPPPPPP/R1BQKB1R w KQkq - 6 4");
+        assert_eq!(*optimizer.tree.get(&after_nc6).unwrap().frequency(), 1.0);
+        assert_eq!(optimizer.average_book_length, 2.0);
+    }
+
+    #[test]
+    fn it_finds_the_branch_gaps_stem_from() {
+        let mut optimizer =
+            optimizer("1. e4 e5 2. Nf3 Nc6 *\n\n1. e4 e5 2. Nf3 d6 *\n\n1. e4 c5 *\n\n");
+        optimizer.update_position_frequencies();
+        let after_nf3 = Fen::new("rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2");
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let after_c5 = Fen::new("rnbqkbnr/pp1ppppp/8/2p5/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2");
+        let gaps: Vec<&Position> = optimizer
+            .own_positions()
+            .into_iter()
+            .filter(|pos| pos.transition_count() == 0)
+            .collect();
+        assert_eq!(gaps.len(), 3);
+        let roots = optimizer.branch_roots(&gaps);
+        let from_nf3 = gaps
+            .iter()
+            .filter(|gap| roots[gap.fen()].fen() == &after_nf3)
+            .count();
+        assert_eq!(from_nf3, 2);
+        // 1... c5 only shares the position after 1. e4 with the other gaps
+        assert_eq!(roots[&after_c5].fen(), &after_e4);
+    }
+
+    #[test]
+    fn it_ends_games_repeating_a_position_in_book() {
+        let mut optimizer = optimizer("1. Nf3 Nf6 2. Ng1 Ng8 3. Nf3 *\n\n");
+        optimizer.update_position_frequencies();
+        let after_nf3 = Fen::new("rnbqkbnr/pppppppp/8/8/8/5N2/PPPPPPPP/RNBQKB1R b KQkq - 1 1");
+        let start = Fen::starting_board();
+        assert_eq!(*optimizer.tree.get(&start).unwrap().frequency(), 1.0);
+        assert_eq!(*optimizer.tree.get(&after_nf3).unwrap().frequency(), 1.0);
+        assert_eq!(optimizer.average_book_length, 2.0);
+        let repetitions = optimizer.repetition_lines();
+        assert_eq!(repetitions.len(), 1);
+        assert_eq!(repetitions[0].fen, start);
+        assert!(repetitions[0]
+            .to_string()
+            .starts_with("The line of game 1, move 2... repeats"));
+    }
+
+    #[test]
+    fn it_measures_the_impact_of_a_removal_exactly() {
+        let mut optimizer = optimizer("1. Nf3 Nf6 2. g3 g6 3. Bg2 *\n\n1. Nf3 Nc6 2. d4 *\n\n");
+        optimizer.update_position_frequencies();
+        let after_nf6 = Fen::new("rnbqkb1r/pppppppp/5n2/8/8/5N2/PPPPPPPP/RNBQKB1R w KQkq - 2 2");
+        let impact = optimizer.removal_impact(&after_nf6);
+        // After 1... Nf6, half of the games would leave book after 1. Nf3 instead of 3. Bg2
+        assert_eq!(impact.positions, 3);
+        assert!((impact.book_length + 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_finds_where_opponents_leave_each_line() {
+        let mut optimizer = optimizer(
+            "1. e4 e5 2. Nf3 Nc6 *\n\n1. e4 e5 2. Nf3 d6 3. d4 *\n\n1. e4 c5 2. Nf3 *\n\n",
+        );
+        optimizer.update_position_frequencies();
+        let hotspots = optimizer.deviation_hotspots();
+        assert_eq!(hotspots.len(), 1);
+        let (position, frequency) = hotspots[0];
+        assert_eq!(
+            position.fen(),
+            &Fen::new("rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2")
+        );
+        assert!((frequency - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_finds_opponent_moves_branching_into_prepared_lines() {
+        let mut optimizer = optimizer(
+            "1. e4 e5 2. Nf3 Nc6 3. Bb5 *\n\n1. e4 e5 2. Nf3 d6 3. d4 *\n\n1. e4 c5 *\n\n",
+        );
+        optimizer.update_position_frequencies();
+        let moves = optimizer.branching_moves();
+        let sequences: Vec<String> = moves
+            .iter()
+            .map(|(_, child)| child.sequence().to_string())
+            .collect();
+        assert_eq!(sequences.len(), 2);
+        assert!(sequences
+            .iter()
+            .all(|sequence| sequence.starts_with("1.e4 e5 2.Nf3")));
+    }
+
+    #[test]
+    fn it_offers_the_most_popular_book_moves_as_candidates() {
+        let optimizer = optimizer("1. e4 e5 *\n\n");
+        let positions = optimizer.own_positions();
+        let gaps: Vec<&Position> = positions
+            .iter()
+            .filter(|pos| pos.transition_count() == 0)
+            .cloned()
+            .collect();
+        let candidates =
+            RepertoireOptimizer::candidate_moves(&gaps, &mut scored_book(), 2).unwrap();
+        assert_eq!(candidates.len(), 1);
+        let uci: Vec<&str> = candidates[0]
+            .iter()
+            .map(|candidate| candidate.uci.as_str())
+            .collect();
+        assert_eq!(uci, ["a2a3", "b2b3"]);
+        assert_eq!(candidates[0][0].score, 0.6);
+        // The rare third move of the book is not counted as a reply to prepare
+        assert_eq!(candidates[0][0].replies, 2);
+    }
+
+    #[test]
+    fn it_stress_tests_against_popular_book_moves() {
+        let mut optimizer = optimizer("1. e4 e5 2. Nf3 *\n\n");
+        // Knows the replies to 1.e4 only
+        let replies = book_moves(&[("e7e5", 0.6), ("c7c5", 0.3), ("a7a6", 0.01)]);
+        let after_e4 = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        let mut book = map_book(&[(after_e4, replies)], Vec::new());
+        optimizer
+            .add_opponents_moves_from_book(&mut book, ErrorPolicy::Abort)
+            .unwrap();
+        let mut rng = ChaChaRng::seed_from_u64(0);
+        let result = optimizer
+            .stress_test(&mut book, 100, 0.05, &mut rng)
+            .unwrap();
+        assert!(result.left_preparation > 0 && result.left_preparation < 100);
+        assert_eq!(
+            result.prepared_moves_played,
+            100 + (100 - result.left_preparation)
+        );
+        let exits = result.most_frequent_exits(5);
+        assert_eq!(exits.len(), 1);
+        assert_eq!(exits[0].0, result.left_preparation);
+        assert_eq!(exits[0].1.to_string(), "1.e4 c7c5");
+    }
+
+    #[test]
+    fn it_estimates_rating_points_at_stake_against_the_engines_best_move() {
+        let mut optimizer = optimizer("1. e4 e5 *\n\n");
+        optimizer.update_position_frequencies();
+        let positions = optimizer.own_positions();
+        let gaps: Vec<&Position> = positions
+            .iter()
+            .filter(|pos| pos.transition_count() == 0)
+            .cloned()
+            .collect();
+        // Prefers a2a3 after 1. e4 e5 and evaluates every other position as 0.7 for the side
+        // to move
+        let after_e5 = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2";
+        let best = scored_book_moves(&[("a2a3", 1.0, 0.6)]);
+        let elsewhere = scored_book_moves(&[("h7h6", 1.0, 0.7)]);
+        let mut engine = map_book(&[(after_e5, best)], elsewhere);
+        let points = RepertoireOptimizer::rating_points_at_stake(
+            &gaps,
+            &mut scored_book(),
+            Some(&mut engine),
+        )
+        .unwrap();
+        // a2a3 scores 0.6 and b2b3 0.3 after the engine's reply, while c2c3 is too rare to count
+        let improvised = (0.5 * 0.6 + 0.48 * 0.3) / 0.98;
+        assert!((points[0] - 2000.0 * gaps[0].frequency() * (0.6 - improvised)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_estimates_rating_points_at_stake_against_the_best_common_move() {
+        let mut optimizer = optimizer("1. e4 e5 *\n\n1. e4 c5 *\n\n");
+        optimizer.update_position_frequencies();
+        let positions = optimizer.own_positions();
+        let gaps: Vec<&Position> = positions
+            .iter()
+            .filter(|pos| pos.transition_count() == 0)
+            .cloned()
+            .collect();
+        assert_eq!(gaps.len(), 2);
+        let points =
+            RepertoireOptimizer::rating_points_at_stake(&gaps, &mut scored_book(), None).unwrap();
+        // Improvising scores 0.512 on average, while the rare move scoring 1.0 does not count
+        for (position, points) in gaps.iter().zip(points) {
+            assert!((points - 2000.0 * position.frequency() * 0.088).abs() < 1e-9);
+            assert!((position.frequency() - 0.5).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn it_rates_positions_with_similar_alternatives_as_difficult() {
+        let optimizer = optimizer("1. b4 e5 *\n\n");
+        let positions = optimizer.own_positions();
+        let prepared: Vec<&Position> = positions
+            .iter()
+            .filter(|pos| pos.transition_count() > 0)
+            .cloned()
+            .collect();
+        let difficulties =
+            RepertoireOptimizer::difficulties(&prepared, &mut scored_book()).unwrap();
+        // Both common moves are alternatives, but only b2b3 moves the same pawn as b2b4
+        assert_eq!(
+            difficulties,
+            vec![Difficulty {
+                alternatives: 2,
+                similar: 1,
+            }]
+        );
+        assert_eq!(difficulties[0].drill_weight(), 4.0);
+    }
+
+    #[test]
+    fn it_finds_prepared_moves_hardly_played_in_the_book() {
+        let optimizer = optimizer("1. c4 e5 *\n\n1. a3 e5 *\n\n1. c3 e5 *\n\n");
+        let positions = optimizer.own_positions();
+        let prepared: Vec<&Position> = positions
+            .iter()
+            .filter(|pos| pos.transition_count() > 0)
+            .cloned()
+            .collect();
+        let exotic = RepertoireOptimizer::exotic_choices(&prepared, &mut scored_book()).unwrap();
+        // c2c4 is missing from the book, while even the rare c2c3 is played often enough
+        assert_eq!(
+            exotic,
+            vec![vec![ExoticChoice {
+                uci: "c2c4".to_owned(),
+                share: 0.0,
+            }]]
+        );
+    }
+
+    #[test]
+    fn it_suggests_where_to_cut_lines_exceeding_the_budget() {
+        let mut optimizer = optimizer("1. e4 e5 2. Nf3 Nc6 3. Bc4 Bc5 *\n\n1. e4 c5 *\n\n");
+        optimizer.update_position_frequencies();
+        let long_lines = optimizer.long_lines(4);
+        assert_eq!(long_lines.len(), 1);
+        assert_eq!(long_lines[0].plies, 6);
+        // Only 1...e5 follows a choice between several popular moves; 2...Nc6 is forced
+        let points: Vec<(Option<u32>, f64)> = long_lines[0]
+            .truncation_points
+            .iter()
+            .map(|pos| (pos.fen().ply(), *pos.frequency()))
+            .collect();
+        assert_eq!(points, vec![(Some(2), 0.5)]);
+        assert!(optimizer.long_lines(6).is_empty());
+    }
+
+    #[test]
+    fn it_builds_an_optimizer_from_its_configuration() {
+        let unprepared = |builder: RepertoireOptimizerBuilder| -> Vec<f64> {
+            let optimizer = builder.pgn("1. d4 *\n\n").build().unwrap();
+            optimizer
+                .own_positions()
+                .iter()
+                .filter(|pos| pos.transition_count() == 0)
+                .map(|pos| *pos.frequency())
+                .collect()
+        };
+        // Answers every position with a common and a rare reply to 1. d4
+        let mut book = map_book(&[], book_moves(&[("e7e5", 0.9), ("h7h6", 0.01)]));
+        let frequencies = unprepared(
+            RepertoireOptimizer::builder(Player::White)
+                .book(&mut book, ErrorPolicy::Abort)
+                .min_book_share(0.05),
+        );
+        // The rare 1...h6 is left out
+        assert_eq!(frequencies.len(), 1);
+        assert!((frequencies[0] - 0.9).abs() < 1e-9);
+        let frequencies = unprepared(
+            RepertoireOptimizer::builder(Player::White)
+                .book(&mut book, ErrorPolicy::Abort)
+                .max_depth(1),
+        );
+        assert!(frequencies.is_empty());
+        let frequencies = unprepared(
+            RepertoireOptimizer::builder(Player::White).book(&mut book, ErrorPolicy::Abort),
+        );
+        assert_eq!(frequencies.len(), 2);
+    }
+
+    #[test]
+    fn it_counts_identical_moves_in_different_positions_as_one_decision() {
+        let optimizer =
+            optimizer("1. d4 d5 2. Bf4 *\n\n1. d4 Nf6 2. Bf4 *\n\n1. d4 e6 2. Nf3 *\n\n");
+        assert_eq!(optimizer.prepared_move_count(), 4);
+        assert_eq!(
+            optimizer.own_decisions(),
+            vec![
+                ("c1f4".to_owned(), 2),
+                ("d2d4".to_owned(), 1),
+                ("g1f3".to_owned(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_chooses_moves_from_earlier_chapters_more_often() {
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        let pgn = "1. e4 e5 *\n\n1. d4 d5 *\n\n[Priority \"1\"]\n\n1. c4 e5 *\n\n";
+        assert_eq!(optimizer.add_pgn(pgn).unwrap(), 3);
+        optimizer.set_own_move_frequencies_by_priority();
+        let start = optimizer.tree.get(&Fen::starting_board()).unwrap();
+        let frequency = |uci| {
+            start
+                .transition(&start.after_uci(uci).unwrap())
+                .unwrap()
+                .frequency
+        };
+        assert!((frequency("e2e4") - 0.4).abs() < 1e-9);
+        assert!((frequency("d2d4") - 0.2).abs() < 1e-9);
+        assert!((frequency("c2c4") - 0.4).abs() < 1e-9);
+
+        let after_d4 = start.after_uci("d2d4").unwrap();
+        let stats = MoveStats {
+            popularity: 0.3,
+            score: 0.5,
+            year: None,
+        };
+        let after_nf6 = optimizer
+            .tree
+            .position(&after_d4)
+            .apply_uci("g8f6", stats)
+            .unwrap();
+        let priorities = optimizer.chapter_priorities();
+        assert_eq!(priorities.get(&after_d4), Some(&2));
+        assert_eq!(priorities.get(&after_nf6), Some(&2));
+    }
+
+    #[test]
+    fn it_scores_the_completeness_of_eco_chapters() {
+        let mut optimizer = optimizer(
+            "[ECO \"C50\"]\n\n1. e4 e5 2. Nf3 Nc6 3. Bc4 *\n\n[ECO \"B20\"]\n\n1. e4 c5 2. Nf3 d6 *\n\n",
+        );
+        optimizer.update_position_frequencies();
+        let scores = |depth| -> Vec<(String, f64)> {
+            optimizer
+                .completeness(depth)
+                .into_iter()
+                .map(|chapter| (chapter.chapter, chapter.score.round()))
+                .collect()
+        };
+        // The Sicilian line ends where I would have to find a move after 2...d6
+        assert_eq!(
+            scores(6),
+            vec![("B2".to_owned(), 0.0), ("C5".to_owned(), 100.0)]
+        );
+        assert_eq!(
+            scores(4),
+            vec![("B2".to_owned(), 100.0), ("C5".to_owned(), 100.0)]
+        );
+        assert!((optimizer.completeness(4)[0].frequency - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_splits_my_moves_as_declared_in_comments() {
+        let optimizer = optimizer(
+            "1. e4 {Main line weight=0.6} e5 {weight=0.9} *\n\n1. d4 *\n\n1. c4 {weight=x} *\n\n",
+        );
+        let start = optimizer.tree.get(&Fen::starting_board()).unwrap();
+        let frequency = |uci: &str| {
+            let fen = start.after_uci(uci).unwrap();
+            start.transition(&fen).unwrap().frequency
+        };
+        assert!((frequency("e2e4") - 0.6).abs() < 1e-9);
+        assert!((frequency("d2d4") - 0.2).abs() < 1e-9);
+        assert!((frequency("c2c4") - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_leaves_out_positions_marked_as_the_end_of_preparation() {
+        let mut optimizer = optimizer("1. e4 e5 {book-end} *\n\n1. d4 e5 2. dxe5 {book-end} *\n\n");
+        let mut book = FlakyBook { failures: 0 };
+        optimizer
+            .add_opponents_moves_from_book(&mut book, ErrorPolicy::Abort)
+            .unwrap();
+        let positions = optimizer.own_positions();
+        assert_eq!(positions.len(), 2);
+        assert!(RepertoireOptimizer::recommend_for_addition(&positions, 5).is_empty());
+    }
+
+    #[test]
+    fn it_remembers_where_positions_were_first_reached() {
+        let optimizer = optimizer_from_files(&[(
+            "repertoire/sicilian.pgn",
+            "1. e4 e5 *\n\n1. e4 c5 2. Nf3 *\n\n",
+        )]);
+        let start = optimizer.tree.get(&Fen::starting_board()).unwrap();
+        let after_e4 = start.after_uci("e2e4").unwrap();
+        let after_c5 = optimizer
+            .tree
+            .get(&after_e4)
+            .unwrap()
+            .after_uci("c7c5")
+            .unwrap();
+        let source = optimizer.source(&after_c5).unwrap();
+        assert_eq!(source.to_string(), "sicilian.pgn, game 2, move 1...");
+        assert_eq!(optimizer.source(&after_e4).unwrap().game, 1);
+    }
+
+    #[test]
+    fn it_finds_games_repeating_the_moves_of_others() {
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        let pgn = "1. e4 e5 *\n\n1. e4 e5 2. Nf3 *\n\n1. d4 *\n\n1. e4 e5 *\n\n";
+        assert_eq!(optimizer.add_pgn(pgn).unwrap(), 3);
+        let duplicates: Vec<String> = optimizer
+            .duplicate_lines()
+            .iter()
+            .map(DuplicateLine::to_string)
+            .collect();
+        assert_eq!(
+            duplicates,
+            vec![
+                "The line of game 1, move 1... is continued by game 2, move 2.",
+                "The line of game 1, move 1... is repeated by game 4, move 1...",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_flags_files_preparing_different_moves() {
+        let optimizer = optimizer_from_files(&[
+            ("repertoire/open.pgn", "1. e4 e5 2. Nf3 *\n\n1. d4 *\n\n"),
+            ("repertoire/vienna.pgn", "1. e4 e5 2. Nc3 *\n\n"),
+        ]);
+        let conflicts = optimizer.conflicting_moves();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].fen.ply(), Some(2));
+        let games: Vec<String> = conflicts[0]
+            .moves
+            .iter()
+            .map(|(_, source)| source.to_string())
+            .collect();
+        assert_eq!(
+            games,
+            vec!["open.pgn, game 1, move 2.", "vienna.pgn, game 1, move 2."]
+        );
+    }
+
+    #[test]
+    fn it_finds_lines_my_other_lines_never_choose() {
+        let optimizer = optimizer_from_files(&[
+            ("repertoire/e4.pgn", "1. e4 e5 2. Nf3 *\n\n1. e4 c5 *\n\n"),
+            ("repertoire/d4.pgn", "1. d4 d5 2. c4 *\n\n"),
+        ]);
+        let unreachable = optimizer.unreachable_lines();
+        assert_eq!(unreachable.len(), 1);
+        assert_eq!(unreachable[0].fen, Fen::starting_board());
+        assert_eq!(unreachable[0].source.to_string(), "d4.pgn, game 1, move 1.");
+        assert_eq!(unreachable[0].positions, 3);
+    }
+
+    #[test]
+    fn it_finds_files_prepared_for_the_other_color() {
+        let optimizer = optimizer_from_files(&[
+            ("repertoire/open.pgn", "1. e4 e5 2. Nf3 *\n\n1. e4 c5 *\n\n"),
+            ("repertoire/caro.pgn", "1. e4 c6 2. d4 *\n\n1. d4 c6 *\n\n"),
+        ]);
+        let wrong_side = optimizer.wrong_side_files();
+        assert_eq!(wrong_side.len(), 1);
+        assert_eq!(
+            wrong_side[0].file.as_deref(),
+            Some(Path::new("repertoire/caro.pgn"))
+        );
+        assert_eq!(wrong_side[0].branches, 1);
+    }
+
+    #[test]
+    fn it_finds_lines_ending_without_my_reply() {
+        let mut optimizer = RepertoireOptimizer::new(Player::Black);
+        let pgn = "1. e4 c5 2. Nf3 *\n\n1. e4 c5 2. Nf3 d6 *\n\n1. d4 *\n\n\
+                   1. c4 { book-end } *\n\n";
+        optimizer.add_pgn(pgn).unwrap();
+        let truncated: Vec<String> = optimizer
+            .truncated_lines()
+            .iter()
+            .map(TruncatedLine::to_string)
+            .collect();
+        assert_eq!(
+            truncated,
+            vec!["The line of game 3, move 1. ends on the opponent's move without your reply"]
+        );
+    }
+
+    // Knows the starting position and the replies to 1. Nf3 only
+    fn first_moves_book() -> MapBook {
+        let start = Fen::starting_board();
+        let after_nf3 = "rnbqkbnr/pppppppp/8/8/8/5N2/PPPPPPPP/RNBQKB1R b KQkq - 1 1";
+        let positions = [
+            (start.fen_str(), book_moves(&[("g1f3", 0.6), ("b1c3", 0.4)])),
+            (
+                after_nf3,
+                book_moves(&[("g8f6", 0.5), ("b8c6", 0.45), ("a7a6", 0.01)]),
+            ),
+        ];
+        map_book(&positions, Vec::new())
+    }
+
+    #[test]
+    fn it_weighs_my_moves_by_their_book_statistics() {
+        let mut optimizer = optimizer("1. Nf3 *\n\n1. Nc3 *\n\n");
+        optimizer.set_own_move_stats(true);
+        optimizer
+            .add_opponents_moves_from_book(&mut first_moves_book(), ErrorPolicy::Abort)
+            .unwrap();
+        optimizer.set_own_move_frequencies(&Popularity);
+        let start = optimizer.tree.get(&Fen::starting_board()).unwrap();
+        let nf3 = start.after_uci("g1f3").unwrap();
+        assert!((start.transition(&nf3).unwrap().frequency - 0.6).abs() < 1e-9);
+        // Only my prepared moves are kept, not the other book moves
+        assert_eq!(start.transition_count(), 2);
+    }
+
+    #[test]
+    fn it_counts_the_positions_to_learn_for_an_addition() {
+        let optimizer = optimizer("1. e4 *\n\n");
+        let start = optimizer.tree.get(&Fen::starting_board()).unwrap();
+        let costs = |plies| {
+            RepertoireOptimizer::learning_costs(&[start], &mut first_moves_book(), plies).unwrap()
+        };
+        // 1. Nf3, then both common replies; 1... a6 is too rare to prepare
+        let cost = &costs(3)[0];
+        assert_eq!(cost.positions, 3);
+        assert!((cost.moves - 2.95).abs() < 1e-9);
+        // The opponent's move after mine counts as another half-move in book
+        assert_eq!(costs(1)[0].positions, 1);
+        assert_eq!(costs(1)[0].moves, 1.0);
+        assert_eq!(costs(2)[0].positions, 1);
+        assert_eq!(costs(2)[0].moves, 2.0);
+    }
+
+    #[test]
+    fn it_picks_the_best_additions_within_a_budget() {
+        let items = [(3, 0.3), (1, 0.2), (2, 0.25), (4, 0.5)];
+        // Taking the two cheap items beats the single most valuable one
+        assert_eq!(RepertoireOptimizer::within_budget(&items, 3), vec![1, 2]);
+        assert_eq!(RepertoireOptimizer::within_budget(&items, 5), vec![1, 3]);
+        assert!(RepertoireOptimizer::within_budget(&items, 0).is_empty());
+        assert_eq!(
+            RepertoireOptimizer::within_budget(&items, usize::MAX),
+            vec![0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn it_finds_move_orders_reaching_prepared_positions() {
+        let optimizer = optimizer("1. Nf3 Nf6 2. g3 g6 *\n\n1. Nc3 Nf6 2. Nf3 d5 *\n\n");
+        let after_g6 = Fen::new("rnbqkb1r/pppppp1p/5np1/8/8/5NP1/PPPPPP1P/RNBQKB1R w KQkq - 0 3");
+        let position = optimizer.tree.get(&after_g6).unwrap();
+        // Knows White's second moves after 1. Nf3 Nf6 only
+        let after_nf6 = "rnbqkb1r/pppppppp/5n2/8/8/5N2/PPPPPPPP/RNBQKB1R w KQkq - 2 2";
+        let second_moves = book_moves(&[("g2g3", 0.5), ("b1c3", 0.3), ("c2c4", 0.2)]);
+        let mut book = map_book(&[(after_nf6, second_moves)], Vec::new());
+        let move_orders = optimizer.move_orders(position, &mut book).unwrap();
+        // 2. Nc3 transposes to the line starting with 1. Nc3, unlike 2. c4
+        assert_eq!(move_orders.len(), 1);
+        assert_eq!(move_orders[0].uci, "b1c3");
+        assert_eq!(move_orders[0].prepared_share, 1.0);
+        assert!(move_orders[0]
+            .to_string()
+            .contains("playing Nc3 on move 2. instead of g3"));
+    }
+
+    #[test]
+    fn it_names_lines_by_their_headers() {
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        let pgn = "[Event \"Study: Chapter 2: Sidelines\"]\n\n1. e4 c5 2. c3 *\n\n\
+             [Event \"?\"]\n[White \"Chapter 1: Open Sicilian\"]\n\n1. e4 c5 2. Nf3 *\n\n\
+             [Event \"?\"]\n\n1. d4 d5 *\n\n";
+        optimizer.add_pgn(pgn).unwrap();
+        let start = optimizer.tree.get(&Fen::starting_board()).unwrap();
+        let after_e4 = start.after_uci("e2e4").unwrap();
+        let after_d4 = start.after_uci("d2d4").unwrap();
+        assert_eq!(
+            optimizer.line_name(&after_e4),
+            Some("Study: Chapter 2: Sidelines")
+        );
+        assert_eq!(optimizer.line_name(&after_d4), None);
+    }
+
+    #[test]
+    fn it_attributes_positions_left_by_the_opponent_to_the_position_before() {
+        let mut optimizer = optimizer_from_files(&[(
+            "repertoire/french.pgn",
+            "[Event \"French: Advance\"]\n\n1. e4 e6 2. d4 d5 3. e5 *\n\n",
+        )]);
+        let after_e4 = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        let replies = book_moves(&[("e7e6", 0.6), ("c7c5", 0.4)]);
+        let mut book = map_book(&[(after_e4, replies)], Vec::new());
+        optimizer
+            .add_opponents_moves_from_book(&mut book, ErrorPolicy::Abort)
+            .unwrap();
+        let after_c5 = optimizer
+            .tree
+            .get(&Fen::new(after_e4))
+            .unwrap()
+            .after_uci("c7c5")
+            .unwrap();
+        assert_eq!(optimizer.line_name(&after_c5), Some("French: Advance"));
+        let source = optimizer.source(&after_c5).unwrap();
+        assert_eq!(source.to_string(), "french.pgn, game 1, move 1.");
+    }
+
+    #[test]
+    fn it_starts_games_from_their_fen_header() {
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        let pgn = "1. e4 e5 2. Nf3 *\n\n\
+                   [SetUp \"1\"]\n\
+                   [FEN \"rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2\"]\n\n\
+                   2... Nc6 3. Bb5 *\n\n";
+        assert_eq!(optimizer.add_pgn(pgn).unwrap(), 2);
+        assert_eq!(optimizer.prepared_move_count(), 3);
+        let mut after_nc6 = Fen::starting_board();
+        for uci in &["e2e4", "e7e5", "g1f3", "b8c6"] {
+            let position = optimizer.tree.get(&after_nc6).unwrap();
+            after_nc6 = position.after_uci(uci).unwrap();
+        }
+        let lines: Vec<String> = optimizer
+            .lines_through(&after_nc6)
+            .iter()
+            .map(MoveSequence::to_string)
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("Bb5"));
+        let invalid = "[FEN \"8/8/8 w - - 0 1\"]\n\n1. e4 *\n\n";
+        assert!(matches!(
+            optimizer.add_pgn(invalid),
+            Err(Error::InvalidFen(_))
+        ));
+        assert!(optimizer.detached_chapters().is_empty());
+    }
+
+    #[test]
+    fn it_finds_chapters_no_line_leads_to() {
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        let pgn = "1. e4 e5 2. Nf3 *\n\n\
+                   [FEN \"rnbqkbnr/pppp1ppp/8/4p3/4P3/2N5/PPPP1PPP/R1BQKBNR b KQkq - 1 2\"]\n\n\
+                   2... Nc6 3. Bc4 *\n\n";
+        assert_eq!(optimizer.add_pgn(pgn).unwrap(), 2);
+        let detached = optimizer.detached_chapters();
+        assert_eq!(detached.len(), 1);
+        assert_eq!(detached[0].source.game, 2);
+        assert_eq!(detached[0].source.ply, 3);
+    }
+
+    #[test]
+    fn it_keeps_the_moves_before_a_bad_one() {
+        let mut optimizer = RepertoireOptimizer::new(Player::White);
+        let game = chess_pgn_parser::read_games("1. e4 e5 2. Nf3 Nc6 3. Ke3 *\n\n")
+            .unwrap()
+            .remove(0);
+        assert!(matches!(
+            optimizer.add_game_to_repertoire(game),
+            Err(Error::IllegalMove { .. })
+        ));
+        assert_eq!(optimizer.prepared_move_count(), 2);
+        let after_e4 = optimizer
+            .tree
+            .get(&Fen::starting_board())
+            .unwrap()
+            .after_uci("e2e4")
+            .unwrap();
+        let lines = optimizer.lines_through(&after_e4);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].to_string().ends_with("Nc6"));
+    }
+
+    #[test]
+    fn it_finds_the_prepared_lines_through_a_position() {
+        let optimizer = optimizer("1. e4 e5 2. Nf3 *\n\n1. e4 c5 2. Nf3 *\n\n1. d4 d5 *\n");
+        let start = optimizer.tree.get(&Fen::starting_board()).unwrap();
+        let after_e4 = start.after_uci("e2e4").unwrap();
+        let lines: Vec<String> = optimizer
+            .lines_through(&after_e4)
+            .iter()
+            .map(MoveSequence::to_string)
+            .collect();
+        assert_eq!(lines, vec!["1.e4 c5 2.Nf3", "1.e4 e5 2.Nf3"]);
+        assert_eq!(optimizer.lines_through(&Fen::starting_board()).len(), 3);
+    }
+
+    #[test]
+    fn it_dates_lines_by_their_deepest_known_move() {
+        let mut optimizer = optimizer("1. e4 e5 2. Nf3 *\n\n1. d4 d5 *\n\n");
+        let after_e4 = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let stats = MoveStats {
+            popularity: 0.5,
+            score: 0.5,
+            year: Some(2012),
+        };
+        let position = optimizer.tree.position(&after_e4);
+        position.apply_uci("e7e5", stats).unwrap();
+        let ages: Vec<(String, Option<u32>)> = optimizer
+            .line_ages()
+            .into_iter()
+            .map(|(sequence, year)| (sequence.to_string(), year))
+            .collect();
+        assert_eq!(
+            ages,
+            vec![
+                ("1.d4 d5".to_owned(), None),
+                ("1.e4 e5 2.Nf3".to_owned(), Some(2012)),
+            ]
+        );
     }
 }
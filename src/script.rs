@@ -0,0 +1,129 @@
+//! Scoring positions for recommendations by a user-supplied Rhai script, see https://rhai.rs
+//!
+//! The script defines `fn score(position)`, where `position` has the fields `frequency`
+//! (share of games reaching it), `transitions` (number of known moves), `ply` (-1 if unknown)
+//! and `moves`, holding `popularity`, `score` and `year` (-1 if unknown) of each move with
+//! book statistics. It returns a number; Higher scores are recommended first.
+
+use log::warn;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::path::Path;
+
+use crate::error::Error;
+use crate::position::Position;
+use crate::recommender::Recommender;
+
+/// Ranks the positions a base recommender applies to by the score computed by a script
+pub struct Scripted {
+    base: Box<dyn Recommender>,
+    engine: Engine,
+    ast: AST,
+}
+
+impl Scripted {
+    pub fn new(base: Box<dyn Recommender>, script: &str) -> Result<Self, Error> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile(script)
+            .map_err(|e| Error::Script(e.to_string()))?;
+        if !ast
+            .iter_functions()
+            .any(|function| function.name == "score")
+        {
+            return Err(Error::Script(
+                "The script defines no 'score' function".to_owned(),
+            ));
+        }
+        Ok(Scripted { base, engine, ast })
+    }
+
+    pub fn from_file(base: Box<dyn Recommender>, path: &Path) -> Result<Self, Error> {
+        Scripted::new(base, &std::fs::read_to_string(path)?)
+    }
+
+    fn evaluate(&self, position: &Position) -> Result<f64, Error> {
+        let moves: Array = position
+            .transitions()
+            .filter_map(|(_, transition)| transition.stats.as_ref())
+            .map(|stats| {
+                let mut book_move = Map::new();
+                book_move.insert("popularity".into(), Dynamic::from(stats.popularity));
+                book_move.insert("score".into(), Dynamic::from(stats.score));
+                book_move.insert(
+                    "year".into(),
+                    Dynamic::from(stats.year.map_or(-1, i64::from)),
+                );
+                Dynamic::from(book_move)
+            })
+            .collect();
+        let mut argument = Map::new();
+        argument.insert("frequency".into(), Dynamic::from(*position.frequency()));
+        argument.insert(
+            "transitions".into(),
+            Dynamic::from(position.transition_count() as i64),
+        );
+        argument.insert(
+            "ply".into(),
+            Dynamic::from(position.fen().ply().map_or(-1, i64::from)),
+        );
+        argument.insert("moves".into(), Dynamic::from(moves));
+        let result: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, "score", (argument,))
+            .map_err(|e| Error::Script(e.to_string()))?;
+        result
+            .as_float()
+            .or_else(|_| result.as_int().map(|score| score as f64))
+            .map_err(|type_name| {
+                Error::Script(format!("'score' returned {}; Expected a number", type_name))
+            })
+    }
+}
+
+impl Recommender for Scripted {
+    fn applies_to(&self, position: &Position) -> bool {
+        self.base.applies_to(position)
+    }
+
+    // Positions the script fails on, or scores as not a number, are recommended last
+    fn score(&self, position: &Position) -> f64 {
+        match self.evaluate(position) {
+            Ok(score) if !score.is_nan() => score,
+            Ok(_) => f64::NEG_INFINITY,
+            Err(e) => {
+                warn!("Scoring '{}' failed: {}", position.fen().fen_str(), e);
+                f64::NEG_INFINITY
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::position::{Fen, PositionCache};
+    use crate::recommender::Addition;
+    use crate::script::*;
+
+    #[test]
+    fn it_ranks_positions_by_the_script_score() {
+        let mut cache = PositionCache::new();
+        let shallow = Fen::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let deep = Fen::new("rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 3");
+        cache.position(&shallow).increase_frequency(0.3);
+        cache.position(&deep).increase_frequency(0.5);
+        let positions = vec![cache.get(&shallow).unwrap(), cache.get(&deep).unwrap()];
+
+        let scripted = Scripted::new(
+            Box::new(Addition),
+            "fn score(position) { position.frequency / (1 + position.ply) }",
+        )
+        .unwrap();
+        assert!((scripted.score(positions[0]) - 0.15).abs() < 1e-9);
+        assert_eq!(scripted.recommend(&positions, 1)[0].fen(), &shallow);
+
+        let failing = Scripted::new(Box::new(Addition), "fn score(position) { \"high\" }").unwrap();
+        assert_eq!(failing.score(positions[0]), f64::NEG_INFINITY);
+        assert!(Scripted::new(Box::new(Addition), "fn rank(position) { 1 }").is_err());
+        assert!(Scripted::new(Box::new(Addition), "fn score(position) {").is_err());
+    }
+}
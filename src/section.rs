@@ -0,0 +1,66 @@
+use std::str::FromStr;
+
+/// Parts of the report, which can be left out or reordered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Completeness,
+    Statistics,
+    GettingStarted,
+    Additions,
+    Removals,
+    Narrowing,
+    Reduction,
+    Difficult,
+    Exotic,
+    Hotspots,
+    Trends,
+    LineAges,
+    LongLines,
+    StaleLines,
+}
+
+/// All sections, in the order they are printed unless configured otherwise
+pub static DEFAULT_SECTIONS: &str = "completeness,statistics,getting-started,additions,removals,\
+                                     narrowing,reduction,difficult,exotic,hotspots,trends,\
+                                     line-ages,stale-lines,long-lines";
+
+impl FromStr for Section {
+    type Err = String;
+
+    fn from_str(section: &str) -> Result<Self, Self::Err> {
+        match section {
+            "completeness" => Ok(Section::Completeness),
+            "statistics" => Ok(Section::Statistics),
+            "getting-started" => Ok(Section::GettingStarted),
+            "additions" => Ok(Section::Additions),
+            "removals" => Ok(Section::Removals),
+            "narrowing" => Ok(Section::Narrowing),
+            "reduction" => Ok(Section::Reduction),
+            "difficult" => Ok(Section::Difficult),
+            "exotic" => Ok(Section::Exotic),
+            "hotspots" => Ok(Section::Hotspots),
+            "trends" => Ok(Section::Trends),
+            "line-ages" => Ok(Section::LineAges),
+            "stale-lines" => Ok(Section::StaleLines),
+            "long-lines" => Ok(Section::LongLines),
+            _ => Err(format!(
+                "Unknown report section '{}'; Expected one of: {}",
+                section,
+                DEFAULT_SECTIONS.replace(',', ", ")
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::section::*;
+
+    #[test]
+    fn it_knows_all_default_sections() {
+        let sections: Result<Vec<Section>, String> =
+            DEFAULT_SECTIONS.split(',').map(str::parse).collect();
+        assert_eq!(sections.unwrap().len(), 14);
+        assert!("sharpness".parse::<Section>().is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use pleco::Player;
+use serde::Serialize;
+
+use crate::error::Error;
+use crate::repertoire_optimizer::RepertoireOptimizer;
+
+/// Headline numbers of an analysis run
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub average_book_length: f64,
+    pub prepared_positions: usize,
+    pub unprepared_positions: usize,
+    /// Only estimated when requested, as it needs the book moves of every unprepared position
+    pub rating_points_at_stake: Option<f64>,
+    /// Prepared moves counting identical moves in different positions once; Only counted for
+    /// system openings
+    pub unique_decisions: Option<usize>,
+    /// Positions after the opponent's first moves left out of my Black repertoire on purpose;
+    /// Not counted in the prepared and unprepared positions above
+    pub everything_else: Option<EverythingElse>,
+    pub runtime_seconds: f64,
+}
+
+/// The part of a Black repertoire outside the opponent's first moves declared in scope
+#[derive(Debug, Default, Serialize)]
+pub struct EverythingElse {
+    /// Share of games in which the opponent starts with a move out of scope
+    pub frequency: f64,
+    pub prepared_positions: usize,
+    pub unprepared_positions: usize,
+}
+
+impl Summary {
+    /// Summarizes repertoires whose position frequencies are up to date
+    pub fn new(optimizers: &[RepertoireOptimizer]) -> Self {
+        let mut summary = Summary::default();
+        if optimizers.is_empty() {
+            return summary;
+        }
+        summary.average_book_length = optimizers
+            .iter()
+            .map(|optimizer| optimizer.average_book_length)
+            .sum::<f64>()
+            / optimizers.len() as f64;
+        for position in optimizers
+            .iter()
+            .flat_map(|optimizer| optimizer.own_positions())
+        {
+            if position.transition_count() > 0 {
+                summary.prepared_positions += 1;
+            } else {
+                summary.unprepared_positions += 1;
+            }
+        }
+        summary
+    }
+
+    /// Moves the positions of my Black repertoire only reached after the opponent's first
+    /// moves other than `first_moves` in UCI into the everything-else bucket
+    pub fn set_black_scope(
+        &mut self,
+        optimizers: &[RepertoireOptimizer],
+        first_moves: &[String],
+    ) -> Result<(), Error> {
+        let mut everything_else = EverythingElse::default();
+        for optimizer in optimizers
+            .iter()
+            .filter(|optimizer| optimizer.player() == Player::Black)
+        {
+            let out_of_scope = optimizer.out_of_scope(first_moves)?;
+            for position in optimizer
+                .own_positions()
+                .into_iter()
+                .filter(|pos| out_of_scope.contains(pos.fen()))
+            {
+                if position.fen().ply() == Some(1) {
+                    everything_else.frequency += position.frequency();
+                }
+                if position.transition_count() > 0 {
+                    self.prepared_positions -= 1;
+                    everything_else.prepared_positions += 1;
+                } else {
+                    self.unprepared_positions -= 1;
+                    everything_else.unprepared_positions += 1;
+                }
+            }
+        }
+        self.everything_else = Some(everything_else);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frequency_model::Uniform;
+    use crate::summary::*;
+
+    #[test]
+    fn it_separates_first_moves_out_of_scope_from_gaps() {
+        let mut optimizer = RepertoireOptimizer::new(Player::Black);
+        optimizer
+            .add_pgn("1. e4 c5 2. Nf3 d6 *\n\n1. d4 Nf6 2. Nf3 e6 *\n\n1. Nf3 Nf6 2. d4 e6 *\n\n1. c4 *\n\n")
+            .unwrap();
+        optimizer.set_own_move_frequencies(&Uniform);
+        optimizer.set_opponent_move_frequencies(&Uniform);
+        optimizer.update_position_frequencies();
+        let optimizers = vec![optimizer];
+        let mut summary = Summary::new(&optimizers);
+        assert_eq!(summary.prepared_positions, 5);
+        assert_eq!(summary.unprepared_positions, 1);
+
+        let scope = vec!["e2e4".to_owned(), "d2d4".to_owned()];
+        summary.set_black_scope(&optimizers, &scope).unwrap();
+        // 1. Nf3 Nf6 2. d4 transposes into the scope, while 1. Nf3 and 1. c4 are out of it
+        let everything_else = summary.everything_else.unwrap();
+        assert_eq!(everything_else.prepared_positions, 1);
+        assert_eq!(everything_else.unprepared_positions, 1);
+        assert!((everything_else.frequency - 0.5).abs() < 1e-9);
+        assert_eq!(summary.prepared_positions, 4);
+        assert_eq!(summary.unprepared_positions, 0);
+    }
+}
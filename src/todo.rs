@@ -0,0 +1,142 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use chess_repertoire_optimizer::error::Error;
+
+/// Checklist formats understood by ordinary task managers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TodoFormat {
+    /// Markdown checkboxes
+    Markdown,
+    /// One task per line, see http://todotxt.org
+    TodoTxt,
+}
+
+impl FromStr for TodoFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "markdown" => Ok(TodoFormat::Markdown),
+            "todotxt" => Ok(TodoFormat::TodoTxt),
+            _ => Err(format!(
+                "Unknown todo format '{}'; Expected 'markdown' or 'todotxt'",
+                format
+            )),
+        }
+    }
+}
+
+/// A recommended change to the repertoire
+#[derive(Debug, Clone, PartialEq)]
+pub struct Todo {
+    pub action: String,
+    /// Share of games the change matters in
+    pub frequency: f64,
+    /// Number of moves to prepare or drop
+    pub cost: usize,
+}
+
+impl Todo {
+    fn priority(&self) -> char {
+        if self.frequency >= 0.01 {
+            'A'
+        } else if self.frequency >= 0.001 {
+            'B'
+        } else {
+            'C'
+        }
+    }
+}
+
+/// Writes the todos as a checklist, most important first
+pub fn write_todos(
+    mut todos: Vec<Todo>,
+    format: TodoFormat,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    todos.sort_by(|a, b| b.frequency.total_cmp(&a.frequency));
+    let mut written = Vec::new();
+    for todo in todos {
+        if written.contains(&todo.action) {
+            continue;
+        }
+        let moves = if todo.cost == 1 { "move" } else { "moves" };
+        match format {
+            TodoFormat::Markdown => writeln!(
+                out,
+                "- [ ] {} (priority {}, {} {}, {:.2}% of games)",
+                todo.action,
+                todo.priority(),
+                todo.cost,
+                moves,
+                100.0 * todo.frequency
+            )?,
+            TodoFormat::TodoTxt => writeln!(
+                out,
+                "({}) {} +repertoire moves:{} games:{:.2}%",
+                todo.priority(),
+                todo.action,
+                todo.cost,
+                100.0 * todo.frequency
+            )?,
+        }
+        written.push(todo.action);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::todo::*;
+
+    fn todos() -> Vec<Todo> {
+        vec![
+            Todo {
+                action: "Remove your moves after 1.a3".to_owned(),
+                frequency: 0.0002,
+                cost: 3,
+            },
+            Todo {
+                action: "Prepare a reply to 1.e4 c5".to_owned(),
+                frequency: 0.05,
+                cost: 1,
+            },
+            Todo {
+                action: "Prepare a reply to 1.e4 c5".to_owned(),
+                frequency: 0.05,
+                cost: 1,
+            },
+        ]
+    }
+
+    fn written(format: TodoFormat) -> String {
+        let mut out = Vec::new();
+        write_todos(todos(), format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn it_writes_markdown_checkboxes() {
+        assert_eq!(
+            written(TodoFormat::Markdown),
+            "- [ ] Prepare a reply to 1.e4 c5 (priority A, 1 move, 5.00% of games)\n\
+             - [ ] Remove your moves after 1.a3 (priority C, 3 moves, 0.02% of games)\n"
+        );
+    }
+
+    #[test]
+    fn it_writes_todo_txt() {
+        assert_eq!(
+            written(TodoFormat::TodoTxt),
+            "(A) Prepare a reply to 1.e4 c5 +repertoire moves:1 games:5.00%\n\
+             (C) Remove your moves after 1.a3 +repertoire moves:3 games:0.02%\n"
+        );
+    }
+
+    #[test]
+    fn it_only_accepts_known_formats() {
+        assert_eq!("todotxt".parse(), Ok(TodoFormat::TodoTxt));
+        assert!("org".parse::<TodoFormat>().is_err());
+    }
+}
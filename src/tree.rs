@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::io::Write;
+
+use chess_repertoire_optimizer::error::Error;
+use chess_repertoire_optimizer::repertoire_optimizer::RepertoireOptimizer;
+
+#[derive(Serialize)]
+struct ExportedMove {
+    #[serde(rename = "move")]
+    mv: String,
+    /// FEN of the position the move leads to
+    to: String,
+    /// Share of games in the position continuing with the move, as assigned by the frequency
+    /// model in use
+    frequency: f64,
+    /// Share of book games continuing with the move; Unknown where the book was not consulted,
+    /// as for my own moves
+    popularity: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct ExportedPosition {
+    fen: String,
+    /// Share of all games reaching the position
+    frequency: f64,
+    /// Whether it is my turn
+    mine: bool,
+    moves: Vec<ExportedMove>,
+}
+
+#[derive(Serialize)]
+struct ExportedTree {
+    player: String,
+    /// Most frequent first
+    positions: Vec<ExportedPosition>,
+}
+
+/// Writes the position trees of the repertoires as JSON, with every move's assigned frequency
+/// next to its popularity in the book, to show where the expected play diverges from it
+pub fn write_trees(optimizers: &[RepertoireOptimizer], out: &mut dyn Write) -> Result<(), Error> {
+    let trees: Vec<ExportedTree> = optimizers
+        .iter()
+        .map(|optimizer| {
+            let mut positions: Vec<ExportedPosition> = optimizer
+                .positions()
+                .map(|position| {
+                    let mut moves: Vec<ExportedMove> = position
+                        .transitions()
+                        .map(|(fen, transition)| ExportedMove {
+                            mv: transition.mv.to_string(),
+                            to: fen.fen_str().to_owned(),
+                            frequency: transition.frequency,
+                            popularity: transition.stats.as_ref().map(|stats| stats.popularity),
+                        })
+                        .collect();
+                    moves.sort_by(|a, b| {
+                        b.frequency
+                            .total_cmp(&a.frequency)
+                            .then_with(|| a.to.cmp(&b.to))
+                    });
+                    ExportedPosition {
+                        fen: position.fen().fen_str().to_owned(),
+                        frequency: *position.frequency(),
+                        mine: position.turn() == optimizer.player(),
+                        moves,
+                    }
+                })
+                .collect();
+            positions.sort_by(|a, b| {
+                b.frequency
+                    .total_cmp(&a.frequency)
+                    .then_with(|| a.fen.cmp(&b.fen))
+            });
+            ExportedTree {
+                player: optimizer.player().to_string(),
+                positions,
+            }
+        })
+        .collect();
+    serde_json::to_writer_pretty(out, &trees)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tree::*;
+    use chess_repertoire_optimizer::frequency_model::Popularity;
+    use chess_repertoire_optimizer::opening_book::{BookMove, ErrorPolicy, MapBook};
+    use pleco::Player;
+
+    #[test]
+    fn it_exports_assigned_frequencies_next_to_book_popularity() {
+        // Answers every position with the replies to 1. e4 seen in the book
+        let mut book = MapBook::default();
+        book.set_elsewhere(
+            [("e7e5", 0.3), ("c7c5", 0.5)]
+                .iter()
+                .map(|(uci, frequency)| BookMove {
+                    uci: (*uci).to_owned(),
+                    frequency: *frequency,
+                    score: 0.5,
+                    year: None,
+                })
+                .collect(),
+        );
+        let optimizer = RepertoireOptimizer::builder(Player::White)
+            .pgn("1. e4 e5 *\n\n")
+            .book(&mut book, ErrorPolicy::Abort)
+            .opponent_model(&Popularity)
+            .build()
+            .unwrap();
+        let mut out = Vec::new();
+        write_trees(&[optimizer], &mut out).unwrap();
+        let trees: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(trees[0]["player"], "White");
+        let positions = trees[0]["positions"].as_array().unwrap();
+        assert_eq!(positions.len(), 4);
+        // The starting position and the one after 1. e4 are reached in every game
+        let start = positions.iter().find(|pos| pos["mine"] == true).unwrap();
+        assert_eq!(start["frequency"], 1.0);
+        assert_eq!(start["moves"][0]["frequency"], 1.0);
+        assert_eq!(start["moves"][0]["popularity"], serde_json::Value::Null);
+        let after_e4 = positions.iter().find(|pos| pos["mine"] == false).unwrap();
+        let replies = after_e4["moves"].as_array().unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0]["popularity"], 0.5);
+        assert_eq!(replies[1]["popularity"], 0.3);
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::opening_book::explorer::MonthlyGames;
+
+// The most recent months are compared against the same number of months before them
+static TREND_MONTHS: usize = 12;
+// Relative change in share below which a move counts as stable
+static TREND_THRESHOLD: f64 = 0.2;
+
+/// How the share of a position's games continuing with a certain move developed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trend {
+    /// Share over the months before the recent ones
+    pub earlier: f64,
+    /// Share over the most recent months
+    pub recent: f64,
+}
+
+impl Trend {
+    /// Compares the recent share of the parent position's games reaching the child position
+    /// against the share before; None if either period has no games
+    pub fn new(parent: &[MonthlyGames], child: &[MonthlyGames]) -> Option<Self> {
+        let child: HashMap<&str, u32> = child
+            .iter()
+            .map(|month| (month.month.as_str(), month.games()))
+            .collect();
+        let share = |months: &[MonthlyGames]| {
+            let total: u32 = months.iter().map(MonthlyGames::games).sum();
+            let continued: u32 = months
+                .iter()
+                .filter_map(|month| child.get(month.month.as_str()))
+                .sum();
+            if total == 0 {
+                None
+            } else {
+                Some(f64::from(continued) / f64::from(total))
+            }
+        };
+        let split = parent.len().saturating_sub(TREND_MONTHS);
+        let start = split.saturating_sub(TREND_MONTHS);
+        Some(Trend {
+            earlier: share(&parent[start..split])?,
+            recent: share(&parent[split..])?,
+        })
+    }
+
+    pub fn is_rising(&self) -> bool {
+        self.recent > self.earlier * (1.0 + TREND_THRESHOLD)
+    }
+
+    pub fn is_falling(&self) -> bool {
+        self.recent < self.earlier * (1.0 - TREND_THRESHOLD)
+    }
+}
+
+impl Display for Trend {
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        let direction = if self.is_rising() {
+            "rising"
+        } else if self.is_falling() {
+            "falling"
+        } else {
+            "stable"
+        };
+        write!(
+            fmt,
+            "{} ({:.1}% -> {:.1}%)",
+            direction,
+            self.earlier * 100.0,
+            self.recent * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opening_book::explorer::MonthlyGames;
+    use crate::trend::*;
+
+    fn months(games: &[u32]) -> Vec<MonthlyGames> {
+        games
+            .iter()
+            .enumerate()
+            .map(|(i, &games)| MonthlyGames {
+                month: format!("{}-{:02}", 2000 + i / 12, i % 12 + 1),
+                white: games,
+                draws: 0,
+                black: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_compares_the_last_year_against_the_year_before() {
+        let parent = months(&[100; 30]);
+        let child = months(&[&[0; 6][..], &[10; 12], &[20; 12]].concat());
+        let trend = Trend::new(&parent, &child).unwrap();
+        assert_eq!(trend.earlier, 0.1);
+        assert_eq!(trend.recent, 0.2);
+        assert!(trend.is_rising());
+        assert_eq!(trend.to_string(), "rising (10.0% -> 20.0%)");
+    }
+
+    #[test]
+    fn it_needs_games_in_both_periods() {
+        let parent = months(&[100; 12]);
+        assert_eq!(Trend::new(&parent, &parent), None);
+    }
+}
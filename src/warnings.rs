@@ -0,0 +1,102 @@
+//! Log targets of the warnings counted for the summary at the end of a run; On big runs, warnings
+//! interleaved with the other log messages scroll off screen
+
+/// A repertoire file or URL could not be read
+pub static FAILED_FILE: &str = "failed-file";
+/// A game was not imported
+pub static SKIPPED_GAME: &str = "skipped-game";
+/// A game was imported only up to an illegal or ambiguous move
+pub static REJECTED_MOVE: &str = "rejected-move";
+/// An imported game repeats the moves of another
+pub static DUPLICATE_LINE: &str = "duplicate-line";
+/// Repertoire files prepare different moves in a position
+pub static CONFLICTING_MOVES: &str = "conflicting-moves";
+/// A line starts with a move of mine my other lines never choose
+pub static UNREACHABLE_LINE: &str = "unreachable-line";
+/// A chapter starts from a position no line leads to
+pub static DETACHED_CHAPTER: &str = "detached-chapter";
+/// A file looks like preparation for the other color
+pub static WRONG_SIDE: &str = "wrong-side";
+/// A line returns to a position it passed before
+pub static REPETITION_LINE: &str = "repetition-line";
+/// A line ends on the opponent's move without my reply
+pub static TRUNCATED_LINE: &str = "truncated-line";
+/// Looking up the book moves of a position failed
+pub static FAILED_LOOKUP: &str = "failed-lookup";
+/// The opening book has no moves for a position of the repertoire; Logged at debug level, as
+/// there are many, and counted in the summary by their total
+pub static EMPTY_BOOK: &str = "empty-book";
+
+// The counted targets in the order of the summary, with what their warnings are about
+fn descriptions() -> Vec<(&'static str, &'static str)> {
+    vec![
+        (FAILED_FILE, "files could not be read"),
+        (SKIPPED_GAME, "games skipped"),
+        (REJECTED_MOVE, "moves rejected, cutting their games short"),
+        (DUPLICATE_LINE, "games repeating the moves of another game"),
+        (
+            CONFLICTING_MOVES,
+            "positions where files prepare different moves",
+        ),
+        (UNREACHABLE_LINE, "lines your other lines never lead to"),
+        (DETACHED_CHAPTER, "chapters starting where no line leads to"),
+        (
+            WRONG_SIDE,
+            "files looking like preparation for the other color",
+        ),
+        (REPETITION_LINE, "lines repeating an earlier position"),
+        (TRUNCATED_LINE, "lines ending without your reply"),
+        (
+            FAILED_LOOKUP,
+            "positions without book moves due to failed lookups",
+        ),
+        (EMPTY_BOOK, "positions with empty book responses"),
+    ]
+}
+
+/// Lines like "3 games skipped" for the warnings logged per target, where warnings of other
+/// targets are counted together
+pub fn summary<'a>(counts: impl IntoIterator<Item = (&'a str, usize)>) -> Vec<String> {
+    let descriptions = descriptions();
+    let mut counted = vec![0; descriptions.len()];
+    let mut other = 0;
+    for (target, count) in counts {
+        match descriptions.iter().position(|(known, _)| *known == target) {
+            Some(index) => counted[index] += count,
+            None => other += count,
+        }
+    }
+    let mut lines: Vec<String> = descriptions
+        .iter()
+        .zip(counted)
+        .filter(|(_, count)| *count > 0)
+        .map(|((_, description), count)| format!("{} {}", count, description))
+        .collect();
+    if other > 0 {
+        lines.push(format!("{} other warnings", other));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::warnings::*;
+
+    #[test]
+    fn it_summarizes_warnings_by_target() {
+        let counts = vec![
+            (EMPTY_BOOK, 4),
+            ("chess_repertoire_optimizer::opening_book::cache", 1),
+            (SKIPPED_GAME, 2),
+            ("chess_repertoire_optimizer", 1),
+        ];
+        assert_eq!(
+            summary(counts),
+            vec![
+                "2 games skipped",
+                "4 positions with empty book responses",
+                "2 other warnings"
+            ]
+        );
+    }
+}